@@ -0,0 +1,95 @@
+//! CCR Do-Not-Disturb Schedule
+//!
+//! A daily window (local time) during which alerts are suppressed and
+//! permission requests are delegated back to the desktop instead of
+//! waiting on the device. Stored in the `ccr_dnd` user preference as
+//! `HH:MM-HH:MM`; the window may wrap past midnight.
+
+extern crate alloc;
+use alloc::string::String;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// Daily do-not-disturb window
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DndSchedule {
+    /// Start, in minutes after local midnight
+    start: u16,
+    /// End (exclusive), in minutes after local midnight
+    end: u16,
+}
+
+impl DndSchedule {
+    /// Parse `HH:MM-HH:MM`
+    pub fn parse(text: &str) -> Option<Self> {
+        let (start, end) = text.trim().split_once('-')?;
+        let schedule = Self { start: parse_hhmm(start)?, end: parse_hhmm(end)? };
+        if schedule.start == schedule.end {
+            return None;
+        }
+        Some(schedule)
+    }
+
+    /// Serialize for storage
+    pub fn to_settings(&self) -> String {
+        alloc::format!(
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+
+    /// Check whether `minute` (after local midnight) falls inside the window
+    pub fn contains(&self, minute: u16) -> bool {
+        if self.start < self.end {
+            minute >= self.start && minute < self.end
+        } else {
+            // Window wraps past midnight
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    /// Check whether a local timestamp falls inside the window
+    pub fn is_active_at(&self, local_time_ms: u64) -> bool { self.contains(minute_of_day(local_time_ms)) }
+}
+
+/// Minutes after midnight for a local timestamp in milliseconds
+pub fn minute_of_day(local_time_ms: u64) -> u16 { ((local_time_ms / 60_000) % MINUTES_PER_DAY as u64) as u16 }
+
+fn parse_hhmm(text: &str) -> Option<u16> {
+    let (h, m) = text.trim().split_once(':')?;
+    let h: u16 = h.parse().ok()?;
+    let m: u16 = m.parse().ok()?;
+    if h >= 24 || m >= 60 {
+        return None;
+    }
+    Some(h * 60 + m)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_roundtrip() {
+        let dnd = DndSchedule::parse("22:30-7:00").unwrap();
+        assert_eq!(dnd.to_settings(), "22:30-07:00");
+        assert!(DndSchedule::parse("25:00-07:00").is_none());
+        assert!(DndSchedule::parse("07:00-07:00").is_none());
+    }
+
+    #[test]
+    fn test_window_wraps_midnight() {
+        let dnd = DndSchedule::parse("22:00-07:00").unwrap();
+        assert!(dnd.contains(23 * 60));
+        assert!(dnd.contains(0));
+        assert!(!dnd.contains(7 * 60));
+        assert!(!dnd.contains(12 * 60));
+
+        let day = DndSchedule::parse("09:00-17:00").unwrap();
+        assert!(day.is_active_at(10 * 3_600_000));
+        assert!(!day.is_active_at(18 * 3_600_000 + 86_400_000));
+    }
+}
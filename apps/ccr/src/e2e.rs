@@ -0,0 +1,256 @@
+//! CCR End-to-End Link
+//!
+//! Keeps session traffic away from the broker with `ccr-e2e`. Once the
+//! bridge's public key has been provisioned (`/e2e key <hex>`), the topics in
+//! [`PROTECTED_TOPICS`] only carry sealed messages and plaintext on them is
+//! dropped. Without a key CCR talks in the clear, as before.
+//!
+//! Every MQTT connect starts a fresh handshake: the device publishes its
+//! ephemeral key on [`TOPIC_DEVICE_HELLO`], and the bridge answers on
+//! [`TOPIC_BRIDGE_HELLO`] with its own and a sealed confirmation. An empty
+//! message on the bridge topic means the bridge restarted and wants a new
+//! hello; it is ignored while a session is up, since anyone can send one.
+//! Sealed messages and keys travel hex-encoded, since payloads reach the app
+//! as text.
+
+extern crate alloc;
+use alloc::borrow::Cow;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use ccr_e2e::{E2eError, Handshake, KEY_LEN, PublicKey, Role, Session, StaticSecret};
+use rand_core::{CryptoRng, RngCore};
+
+use crate::{TOPIC_DEBUG_COMMAND, TOPIC_EVENTS, TOPIC_PERM_REQUEST, TOPIC_PERM_RESPONSE, TOPIC_USER_INPUT};
+
+/// Device ephemeral key, published on each connect
+pub const TOPIC_DEVICE_HELLO: &str = "ccr/e2e/device";
+/// Bridge ephemeral key and confirmation, or empty to ask for a new hello
+pub const TOPIC_BRIDGE_HELLO: &str = "ccr/e2e/bridge";
+
+/// Topics sealed end to end once a bridge key is provisioned. Debug commands
+/// are typed straight into the app, so they count as session traffic.
+pub const PROTECTED_TOPICS: [&str; 5] =
+    [TOPIC_EVENTS, TOPIC_PERM_REQUEST, TOPIC_PERM_RESPONSE, TOPIC_USER_INPUT, TOPIC_DEBUG_COMMAND];
+
+/// Why a message couldn't be sealed or opened
+#[derive(Debug, PartialEq)]
+pub enum LinkError {
+    /// No handshake has completed since the last connect
+    NoSession,
+    /// A bridge hello arrived without a handshake of ours to answer
+    Unexpected,
+    /// Not valid hex
+    Hex,
+    /// Opened, but not UTF-8
+    Utf8,
+    E2e(E2eError),
+}
+
+impl From<E2eError> for LinkError {
+    fn from(err: E2eError) -> Self { Self::E2e(err) }
+}
+
+/// Parse a hex bridge public key
+pub fn parse_key(text: &str) -> Result<PublicKey, LinkError> {
+    let mut bytes = [0u8; KEY_LEN];
+    hex::decode_to_slice(text.trim(), &mut bytes).map_err(|_| LinkError::Hex)?;
+    Ok(PublicKey::from(bytes))
+}
+
+/// Whether `topic` is sealed once a bridge key is provisioned
+pub fn protects(topic: &str) -> bool { PROTECTED_TOPICS.contains(&topic) }
+
+/// Handshake and session state for the link to the bridge
+pub struct Link {
+    device_key: StaticSecret,
+    bridge_key: Option<PublicKey>,
+    handshake: Option<Handshake>,
+    session: Option<Session>,
+}
+
+impl Link {
+    pub fn new(device_key: StaticSecret, bridge_key: Option<PublicKey>) -> Self {
+        Self { device_key, bridge_key, handshake: None, session: None }
+    }
+
+    /// Public half of the device key, for provisioning the bridge
+    pub fn device_public(&self) -> PublicKey { PublicKey::from(&self.device_key) }
+
+    /// A bridge key is provisioned, so protected topics must be sealed
+    pub fn is_enabled(&self) -> bool { self.bridge_key.is_some() }
+
+    /// The provisioned bridge key
+    pub fn bridge_key(&self) -> Option<PublicKey> { self.bridge_key }
+
+    /// A handshake has completed on this connection
+    pub fn is_secure(&self) -> bool { self.session.is_some() }
+
+    /// Change the bridge key; any session with the old one ends
+    pub fn set_bridge_key(&mut self, bridge_key: Option<PublicKey>) {
+        self.bridge_key = bridge_key;
+        self.reset();
+    }
+
+    /// Forget the session, e.g. when the connection drops
+    pub fn reset(&mut self) {
+        self.handshake = None;
+        self.session = None;
+    }
+
+    /// Begin a new handshake; returns the hello to publish on [`TOPIC_DEVICE_HELLO`]
+    pub fn start(&mut self, rng: &mut (impl RngCore + CryptoRng)) -> Option<String> {
+        let bridge_key = self.bridge_key?;
+        let handshake = Handshake::new(Role::Device, &self.device_key, bridge_key, rng);
+        let hello = hex::encode(handshake.public().as_bytes());
+        self.session = None;
+        self.handshake = Some(handshake);
+        Some(hello)
+    }
+
+    /// Complete the handshake with the bridge's answer
+    pub fn on_bridge_hello(&mut self, payload: &str) -> Result<(), LinkError> {
+        let bytes = hex::decode(payload.trim()).map_err(|_| LinkError::Hex)?;
+        if bytes.len() < KEY_LEN {
+            return Err(E2eError::Malformed.into());
+        }
+        let (ephemeral, confirmation) = bytes.split_at(KEY_LEN);
+        let handshake = self.handshake.as_ref().ok_or(LinkError::Unexpected)?;
+        let mut session = handshake.finish(&ccr_e2e::public_key(ephemeral)?)?;
+        // Only the real bridge could have sealed this under the new keys. Until
+        // it has, the handshake stays open, so anyone else's junk can't use it up.
+        session.open(TOPIC_BRIDGE_HELLO, confirmation)?;
+        self.handshake = None;
+        self.session = Some(session);
+        Ok(())
+    }
+
+    /// Seal `plaintext` for `topic`, hex-encoded for publishing
+    pub fn seal(&mut self, topic: &str, plaintext: &str) -> Result<String, LinkError> {
+        let session = self.session.as_mut().ok_or(LinkError::NoSession)?;
+        Ok(hex::encode(session.seal(topic, plaintext.as_bytes())))
+    }
+
+    /// Open a hex-encoded message received on `topic`
+    pub fn open(&mut self, topic: &str, payload: &str) -> Result<String, LinkError> {
+        let session = self.session.as_mut().ok_or(LinkError::NoSession)?;
+        let bytes: Vec<u8> = hex::decode(payload.trim()).map_err(|_| LinkError::Hex)?;
+        let plaintext = session.open(topic, &bytes)?;
+        String::from_utf8(plaintext).map_err(|_| LinkError::Utf8)
+    }
+
+    /// Plaintext of a message received on `topic`: opened if the topic is
+    /// protected and a bridge key is provisioned, passed through otherwise
+    pub fn receive<'a>(&mut self, topic: &str, payload: &'a str) -> Result<Cow<'a, str>, LinkError> {
+        if self.is_enabled() && protects(topic) {
+            self.open(topic, payload).map(Cow::Owned)
+        } else {
+            Ok(Cow::Borrowed(payload))
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Counter generator; fine for tests, never for keys
+    pub(crate) struct TestRng(pub u8);
+
+    impl RngCore for TestRng {
+        fn next_u32(&mut self) -> u32 { rand_core::impls::next_u32_via_fill(self) }
+
+        fn next_u64(&mut self) -> u64 { rand_core::impls::next_u64_via_fill(self) }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for byte in dest {
+                self.0 = self.0.wrapping_add(1);
+                *byte = self.0;
+            }
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for TestRng {}
+
+    /// A bridge answering `hello`, as the real one would
+    fn bridge_answer(bridge_key: &StaticSecret, device: PublicKey, hello: &str) -> (String, Session) {
+        let handshake = Handshake::new(Role::Bridge, bridge_key, device, &mut TestRng(100));
+        let public = handshake.public();
+        let mut session = handshake.finish(&parse_key(hello).unwrap()).unwrap();
+        let mut answer = public.as_bytes().to_vec();
+        answer.extend(session.seal(TOPIC_BRIDGE_HELLO, b""));
+        (hex::encode(answer), session)
+    }
+
+    #[test]
+    fn test_handshake_and_traffic() {
+        let bridge_key = StaticSecret::from([7u8; 32]);
+        let mut link = Link::new(StaticSecret::from([3u8; 32]), Some(PublicKey::from(&bridge_key)));
+        assert_eq!(link.seal(TOPIC_PERM_RESPONSE, "{}"), Err(LinkError::NoSession));
+
+        let hello = link.start(&mut TestRng(0)).unwrap();
+        let (answer, mut bridge) = bridge_answer(&bridge_key, link.device_public(), &hello);
+        link.on_bridge_hello(&answer).unwrap();
+        assert!(link.is_secure());
+
+        let event = hex::encode(bridge.seal(TOPIC_EVENTS, br#"{"type":"status"}"#));
+        assert_eq!(link.open(TOPIC_EVENTS, &event).unwrap(), r#"{"type":"status"}"#);
+        assert!(link.open(TOPIC_EVENTS, r#"{"type":"status"}"#).is_err());
+        let response = hex::decode(link.seal(TOPIC_PERM_RESPONSE, "allow").unwrap()).unwrap();
+        assert_eq!(bridge.open(TOPIC_PERM_RESPONSE, &response).unwrap(), b"allow");
+
+        link.reset();
+        assert_eq!(link.open(TOPIC_EVENTS, &event), Err(LinkError::NoSession));
+    }
+
+    #[test]
+    fn test_impostor_bridge() {
+        let bridge_key = StaticSecret::from([7u8; 32]);
+        let impostor_key = StaticSecret::from([9u8; 32]);
+        let mut link = Link::new(StaticSecret::from([3u8; 32]), Some(PublicKey::from(&bridge_key)));
+        let hello = link.start(&mut TestRng(0)).unwrap();
+        let (answer, _) = bridge_answer(&impostor_key, link.device_public(), &hello);
+        assert_eq!(link.on_bridge_hello(&answer), Err(LinkError::E2e(E2eError::Forged)));
+        assert!(link.on_bridge_hello("00").is_err());
+        assert!(!link.is_secure());
+
+        // Neither used up the handshake, so the real bridge still completes it
+        let (answer, _) = bridge_answer(&bridge_key, link.device_public(), &hello);
+        link.on_bridge_hello(&answer).unwrap();
+        assert!(link.is_secure());
+        // Once complete, a replayed answer needs a new hello
+        assert_eq!(link.on_bridge_hello(&answer), Err(LinkError::Unexpected));
+        assert!(link.is_secure());
+    }
+
+    #[test]
+    fn test_disabled_without_key() {
+        let mut link = Link::new(StaticSecret::from([3u8; 32]), None);
+        assert!(!link.is_enabled());
+        assert!(link.start(&mut TestRng(0)).is_none());
+        assert!(protects(TOPIC_EVENTS) && !protects(crate::TOPIC_POLICY));
+        assert_eq!(link.receive(TOPIC_DEBUG_COMMAND, "/help").unwrap(), "/help");
+    }
+
+    #[test]
+    fn test_unsealed_debug_command_dropped() {
+        let bridge_key = StaticSecret::from([7u8; 32]);
+        let mut link = Link::new(StaticSecret::from([3u8; 32]), Some(PublicKey::from(&bridge_key)));
+        assert_eq!(link.receive(TOPIC_DEBUG_COMMAND, "/allow"), Err(LinkError::NoSession));
+
+        let hello = link.start(&mut TestRng(0)).unwrap();
+        let (answer, mut bridge) = bridge_answer(&bridge_key, link.device_public(), &hello);
+        link.on_bridge_hello(&answer).unwrap();
+        assert_eq!(link.receive(TOPIC_DEBUG_COMMAND, "/allow"), Err(LinkError::Hex));
+        let sealed = hex::encode(bridge.seal(TOPIC_DEBUG_COMMAND, b"/allow"));
+        assert_eq!(link.receive(TOPIC_DEBUG_COMMAND, &sealed).unwrap(), "/allow");
+        // Sealed for another topic doesn't open here either
+        let misdirected = hex::encode(bridge.seal(TOPIC_USER_INPUT, b"/allow"));
+        assert!(link.receive(TOPIC_DEBUG_COMMAND, &misdirected).is_err());
+    }
+}
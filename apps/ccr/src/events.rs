@@ -12,6 +12,9 @@ pub const MAX_EVENTS: usize = 64;
 /// Maximum characters per event field
 pub const MAX_TEXT_LEN: usize = 200;
 
+/// Tab stop width used when expanding tabs in tool output
+pub const TAB_WIDTH: usize = 4;
+
 /// Event types from Claude Code (via ccr_bridge.py)
 ///
 /// MQTT Topics:
@@ -23,75 +26,43 @@ pub enum CcrEvent {
     /// Session started
     SessionStart {
         session_id: String,
-        source: String,  // "startup", "resume", "clear", "compact"
+        source: String, // "startup", "resume", "clear", "compact"
         model: String,
     },
 
     /// Session ended
-    SessionEnd {
-        session_id: String,
-        reason: String,
-    },
+    SessionEnd { session_id: String, reason: String },
 
     /// Claude stopped responding
-    Stop {
-        session_id: String,
-    },
+    Stop { session_id: String },
 
     /// User input/prompt
-    UserInput {
-        text: String,
-        session_id: String,
-    },
+    UserInput { text: String, session_id: String },
 
     /// Tool call (Bash, Read, Write, Edit, Grep, Glob, Task)
-    ToolCall {
-        id: String,
-        tool: String,
-        args: String,
-        session_id: String,
-    },
+    ToolCall { id: String, tool: String, args: String, session_id: String },
 
     /// Tool result/output
-    ToolResult {
-        id: String,
-        output: String,
-        session_id: String,
-    },
+    ToolResult { id: String, output: String, session_id: String },
 
     /// Permission request pending (needs user approval)
-    PermissionPending {
-        request_id: String,
-        tool: String,
-        command: String,
-        session_id: String,
-    },
+    PermissionPending { request_id: String, tool: String, command: String, session_id: String },
 
     /// Permission resolved (allow/deny)
     PermissionResolved {
         request_id: String,
-        decision: String,  // "allow" or "deny"
+        decision: String, // "allow" or "deny"
         session_id: String,
     },
 
     /// Permission timed out
-    PermissionTimeout {
-        request_id: String,
-        session_id: String,
-    },
+    PermissionTimeout { request_id: String, session_id: String },
 
     /// Notification from Claude Code
-    Notification {
-        notification_type: String,
-        message: String,
-        session_id: String,
-    },
+    Notification { notification_type: String, message: String, session_id: String },
 
     /// Connection status (internal)
-    Status {
-        connected: bool,
-        message: String,
-    },
+    Status { connected: bool, message: String },
 }
 
 impl CcrEvent {
@@ -123,13 +94,13 @@ impl CcrEvent {
             "tool_call" => Some(CcrEvent::ToolCall {
                 id: Self::get_json_string(text, "id").unwrap_or_default(),
                 tool: Self::get_json_string(text, "tool").unwrap_or_default(),
-                args: Self::get_json_string(text, "args").unwrap_or_default(),
+                args: sanitize_text(&Self::get_json_string(text, "args").unwrap_or_default()),
                 session_id: Self::get_json_string(text, "session_id").unwrap_or_default(),
             }),
 
             "tool_result" => Some(CcrEvent::ToolResult {
                 id: Self::get_json_string(text, "id").unwrap_or_default(),
-                output: Self::get_json_string(text, "output").unwrap_or_default(),
+                output: sanitize_text(&Self::get_json_string(text, "output").unwrap_or_default()),
                 session_id: Self::get_json_string(text, "session_id").unwrap_or_default(),
             }),
 
@@ -183,6 +154,14 @@ impl CcrEvent {
         })
     }
 
+    /// Bridge send time (`"sent_ms"`, milliseconds since the Unix epoch), if present
+    pub fn sent_ms(text: &str) -> Option<u64> {
+        let start_idx = text.find("\"sent_ms\":")?;
+        let value = text[start_idx + "\"sent_ms\":".len()..].trim_start();
+        let end = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+        value[..end].parse().ok()
+    }
+
     /// Simple JSON string field extractor
     fn get_json_string(text: &str, key: &str) -> Option<String> {
         let pattern = alloc::format!("\"{}\":", key);
@@ -196,11 +175,7 @@ impl CcrEvent {
             let value_end = trimmed[1..].find('"')? + 1;
             let value = &trimmed[value_start..value_end];
             // Truncate to max length
-            let truncated = if value.len() > MAX_TEXT_LEN {
-                &value[..MAX_TEXT_LEN]
-            } else {
-                value
-            };
+            let truncated = if value.len() > MAX_TEXT_LEN { &value[..MAX_TEXT_LEN] } else { value };
             // Unescape basic sequences
             Some(Self::unescape_json(truncated))
         } else {
@@ -222,6 +197,14 @@ impl CcrEvent {
                     Some('"') => result.push('"'),
                     Some('\\') => result.push('\\'),
                     Some('/') => result.push('/'),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                            Some(decoded) => result.push(decoded),
+                            // Surrogate halves and malformed escapes
+                            None => result.push(char::REPLACEMENT_CHARACTER),
+                        }
+                    }
                     Some(other) => {
                         result.push('\\');
                         result.push(other);
@@ -236,9 +219,7 @@ impl CcrEvent {
     }
 
     /// Check if this event is a pending permission request
-    pub fn is_permission_pending(&self) -> bool {
-        matches!(self, CcrEvent::PermissionPending { .. })
-    }
+    pub fn is_permission_pending(&self) -> bool { matches!(self, CcrEvent::PermissionPending { .. }) }
 
     /// Get request_id if this is a permission event
     pub fn request_id(&self) -> Option<&str> {
@@ -277,12 +258,20 @@ impl CcrEvent {
             CcrEvent::ToolResult { .. } => '→',
             CcrEvent::PermissionPending { .. } => '!',
             CcrEvent::PermissionResolved { decision, .. } => {
-                if decision == "allow" { '✓' } else { '✗' }
+                if decision == "allow" {
+                    '✓'
+                } else {
+                    '✗'
+                }
             }
             CcrEvent::PermissionTimeout { .. } => '⏱',
             CcrEvent::Notification { .. } => '🔔',
             CcrEvent::Status { connected, .. } => {
-                if *connected { '●' } else { '○' }
+                if *connected {
+                    '●'
+                } else {
+                    '○'
+                }
             }
         }
     }
@@ -297,16 +286,12 @@ impl CcrEvent {
                 alloc::format!("End: {}", reason)
             }
             CcrEvent::Stop { .. } => String::from("Stopped"),
-            CcrEvent::UserInput { text, .. } => {
-                truncate(text, 35)
-            }
+            CcrEvent::UserInput { text, .. } => truncate(text, 35),
             CcrEvent::ToolCall { tool, args, .. } => {
                 let args_short = truncate(args, 25);
                 alloc::format!("{}: {}", tool, args_short)
             }
-            CcrEvent::ToolResult { output, .. } => {
-                truncate(output, 35)
-            }
+            CcrEvent::ToolResult { output, .. } => truncate(output, 35),
             CcrEvent::PermissionPending { tool, command, .. } => {
                 let cmd_short = truncate(command, 20);
                 alloc::format!("{}: {}", tool, cmd_short)
@@ -315,14 +300,71 @@ impl CcrEvent {
                 alloc::format!("Permission {}", decision)
             }
             CcrEvent::PermissionTimeout { .. } => String::from("Permission timeout"),
-            CcrEvent::Notification { message, .. } => {
-                truncate(message, 35)
+            CcrEvent::Notification { message, .. } => truncate(message, 35),
+            CcrEvent::Status { message, .. } => truncate(message, 35),
+        }
+    }
+}
+
+/// Make terminal output displayable: strip ANSI escape sequences and other
+/// control characters, expand tabs, and normalize CR/CRLF line endings to LF
+pub fn sanitize_text(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut column = 0;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\u{1b}' => match chars.next() {
+                // CSI: parameters and intermediates up to a final byte in 0x40..=0x7E
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if ('\u{40}'..='\u{7e}').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+                // OSC: terminated by BEL or ST (ESC \)
+                Some(']') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\u{07}' {
+                            break;
+                        }
+                        if c == '\u{1b}' && chars.peek() == Some(&'\\') {
+                            chars.next();
+                            break;
+                        }
+                    }
+                }
+                // Two-character escape
+                _ => {}
+            },
+            '\r' => {
+                if chars.peek() == Some(&'\n') {
+                    chars.next();
+                }
+                result.push('\n');
+                column = 0;
+            }
+            '\n' => {
+                result.push('\n');
+                column = 0;
             }
-            CcrEvent::Status { message, .. } => {
-                truncate(message, 35)
+            '\t' => {
+                let spaces = TAB_WIDTH - (column % TAB_WIDTH);
+                for _ in 0..spaces {
+                    result.push(' ');
+                }
+                column += spaces;
+            }
+            c if c.is_control() => {}
+            c => {
+                result.push(c);
+                column += 1;
             }
         }
     }
+    result
 }
 
 /// Truncate string with ellipsis
@@ -350,12 +392,7 @@ impl EventQueue {
     /// Create new empty queue
     pub const fn new() -> Self {
         const NONE: Option<CcrEvent> = None;
-        Self {
-            events: [NONE; MAX_EVENTS],
-            head: 0,
-            tail: 0,
-            count: 0,
-        }
+        Self { events: [NONE; MAX_EVENTS], head: 0, tail: 0, count: 0 }
     }
 
     /// Push event to queue (drops oldest on overflow)
@@ -373,14 +410,10 @@ impl EventQueue {
     }
 
     /// Get number of events in queue
-    pub fn len(&self) -> usize {
-        self.count
-    }
+    pub fn len(&self) -> usize { self.count }
 
     /// Check if queue is empty
-    pub fn is_empty(&self) -> bool {
-        self.count == 0
-    }
+    pub fn is_empty(&self) -> bool { self.count == 0 }
 
     /// Get event at index (0 = oldest)
     pub fn get(&self, index: usize) -> Option<&CcrEvent> {
@@ -412,6 +445,11 @@ impl EventQueue {
         None
     }
 
+    /// Number of permission requests still awaiting an answer
+    pub fn pending_permissions(&self) -> usize {
+        self.iter().filter(|event| event.is_permission_pending()).count()
+    }
+
     /// Find permission by request_id
     pub fn find_by_request_id(&self, request_id: &str) -> Option<(usize, &CcrEvent)> {
         for i in 0..self.count {
@@ -435,18 +473,11 @@ impl EventQueue {
     }
 
     /// Iterate over events (oldest first)
-    pub fn iter(&self) -> EventQueueIter<'_> {
-        EventQueueIter {
-            queue: self,
-            index: 0,
-        }
-    }
+    pub fn iter(&self) -> EventQueueIter<'_> { EventQueueIter { queue: self, index: 0 } }
 }
 
 impl Default for EventQueue {
-    fn default() -> Self {
-        Self::new()
-    }
+    fn default() -> Self { Self::new() }
 }
 
 /// Iterator over event queue
@@ -501,22 +532,50 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_sent_ms() {
+        assert_eq!(
+            CcrEvent::sent_ms(r#"{"type":"stop","sent_ms": 1728000000123,"session_id":"s1"}"#),
+            Some(1728000000123)
+        );
+        assert_eq!(CcrEvent::sent_ms(r#"{"type":"stop","session_id":"s1"}"#), None);
+        assert_eq!(CcrEvent::sent_ms(r#"{"sent_ms":"soon"}"#), None);
+    }
+
+    #[test]
+    fn test_sanitize_text() {
+        assert_eq!(sanitize_text("\u{1b}[1;31merror\u{1b}[0m: bad"), "error: bad");
+        assert_eq!(sanitize_text("\u{1b}]0;title\u{07}ok"), "ok");
+        assert_eq!(sanitize_text("a\tb\r\nab\tc\rd"), "a   b\nab  c\nd");
+        assert_eq!(sanitize_text("bell\u{07}"), "bell");
+    }
+
+    #[test]
+    fn test_parse_tool_result_with_ansi() {
+        let json =
+            r#"{"type":"tool_result","id":"t1","output":"\u001b[32mok\u001b[0m\tdone","session_id":"s1"}"#;
+        if let Some(CcrEvent::ToolResult { output, .. }) = CcrEvent::from_json(json) {
+            assert_eq!(output, "ok  done");
+        } else {
+            panic!("Wrong event type");
+        }
+    }
+
     #[test]
     fn test_event_queue() {
         let mut queue = EventQueue::new();
         assert!(queue.is_empty());
 
-        queue.push(CcrEvent::Status {
-            connected: true,
-            message: String::from("test"),
-        });
+        queue.push(CcrEvent::Status { connected: true, message: String::from("test") });
         assert_eq!(queue.len(), 1);
 
-        queue.push(CcrEvent::UserInput {
-            text: String::from("hello"),
-            session_id: String::from("s1"),
-        });
+        queue.push(CcrEvent::UserInput { text: String::from("hello"), session_id: String::from("s1") });
         assert_eq!(queue.len(), 2);
+        assert_eq!(queue.pending_permissions(), 0);
+
+        let json = r#"{"type":"permission_pending","request_id":"r1","tool":"Bash","command":"ls","session_id":"s1"}"#;
+        queue.push(CcrEvent::from_json(json).unwrap());
+        assert_eq!(queue.pending_permissions(), 1);
     }
 
     #[test]
@@ -524,10 +583,7 @@ mod tests {
         let mut queue = EventQueue::new();
 
         for i in 0..MAX_EVENTS + 5 {
-            queue.push(CcrEvent::Status {
-                connected: true,
-                message: alloc::format!("msg{}", i),
-            });
+            queue.push(CcrEvent::Status { connected: true, message: alloc::format!("msg{}", i) });
         }
 
         assert_eq!(queue.len(), MAX_EVENTS);
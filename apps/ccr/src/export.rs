@@ -0,0 +1,136 @@
+//! CCR Session Export
+//!
+//! Renders the event queue as a Markdown transcript so a session can be
+//! pulled off-device and attached to a PR or incident report.
+
+extern crate alloc;
+use alloc::string::String;
+use core::fmt::Write;
+
+use crate::events::{CcrEvent, EventQueue};
+
+/// Render the queued events of a session as Markdown
+pub fn render_markdown(queue: &EventQueue, session_id: &str) -> String {
+    let mut output = String::new();
+
+    if session_id.is_empty() {
+        writeln!(output, "# CCR session").ok();
+    } else {
+        writeln!(output, "# CCR session `{}`", session_id).ok();
+    }
+
+    for event in queue.iter() {
+        writeln!(output).ok();
+        match event {
+            CcrEvent::SessionStart { source, model, .. } => {
+                if model.is_empty() {
+                    writeln!(output, "**Session start** ({})", source).ok();
+                } else {
+                    writeln!(output, "**Session start** ({}, {})", source, model).ok();
+                }
+            }
+            CcrEvent::SessionEnd { reason, .. } => {
+                writeln!(output, "**Session end**: {}", reason).ok();
+            }
+            CcrEvent::Stop { .. } => {
+                writeln!(output, "_Stopped_").ok();
+            }
+            CcrEvent::UserInput { text, .. } => {
+                writeln!(output, "## User").ok();
+                writeln!(output).ok();
+                for line in text.lines() {
+                    writeln!(output, "> {}", line).ok();
+                }
+            }
+            CcrEvent::ToolCall { id, tool, args, .. } => {
+                writeln!(output, "**Tool call: {}** (`{}`)", tool, id).ok();
+                writeln!(output).ok();
+                write_code_block(&mut output, args);
+            }
+            CcrEvent::ToolResult { id, output: result, .. } => {
+                writeln!(output, "**Tool result** (`{}`)", id).ok();
+                writeln!(output).ok();
+                write_code_block(&mut output, result);
+            }
+            CcrEvent::PermissionPending { request_id, tool, command, .. } => {
+                writeln!(output, "**Permission requested** for {} (`{}`)", tool, request_id).ok();
+                writeln!(output).ok();
+                write_code_block(&mut output, command);
+            }
+            CcrEvent::PermissionResolved { request_id, decision, .. } => {
+                writeln!(output, "**Permission {}** (`{}`)", decision, request_id).ok();
+            }
+            CcrEvent::PermissionTimeout { request_id, .. } => {
+                writeln!(output, "**Permission timed out** (`{}`)", request_id).ok();
+            }
+            CcrEvent::Notification { notification_type, message, .. } => {
+                writeln!(output, "_Notification ({})_: {}", notification_type, message).ok();
+            }
+            CcrEvent::Status { message, .. } => {
+                writeln!(output, "_{}_", message).ok();
+            }
+        }
+    }
+
+    output
+}
+
+/// PDDB key name for an export of `session_id`
+pub fn export_key(session_id: &str) -> String {
+    let id: String = session_id.chars().filter(|c| c.is_ascii_alphanumeric() || *c == '-').take(36).collect();
+    if id.is_empty() { String::from("session.md") } else { alloc::format!("{}.md", id) }
+}
+
+/// Write `text` as a fenced code block, using a fence longer than any
+/// backtick run inside the text
+fn write_code_block(output: &mut String, text: &str) {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in text.chars() {
+        if c == '`' {
+            run += 1;
+            longest = longest.max(run);
+        } else {
+            run = 0;
+        }
+    }
+    let fence = "`".repeat(longest.max(2) + 1);
+    writeln!(output, "{}", fence).ok();
+    writeln!(output, "{}", text).ok();
+    writeln!(output, "{}", fence).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_markdown() {
+        let mut queue = EventQueue::new();
+        queue.push(CcrEvent::UserInput { text: String::from("fix it"), session_id: String::from("s1") });
+        queue.push(CcrEvent::ToolCall {
+            id: String::from("t1"),
+            tool: String::from("Bash"),
+            args: String::from("echo ```"),
+            session_id: String::from("s1"),
+        });
+        queue.push(CcrEvent::PermissionResolved {
+            request_id: String::from("p1"),
+            decision: String::from("deny"),
+            session_id: String::from("s1"),
+        });
+
+        let md = render_markdown(&queue, "s1");
+        assert!(md.starts_with("# CCR session `s1`\n"));
+        assert!(md.contains("> fix it\n"));
+        assert!(md.contains("````\necho ```\n````\n"));
+        assert!(md.contains("**Permission deny** (`p1`)"));
+    }
+
+    #[test]
+    fn test_export_key() {
+        assert_eq!(export_key("abc-123"), "abc-123.md");
+        assert_eq!(export_key("../x"), "x.md");
+        assert_eq!(export_key(""), "session.md");
+    }
+}
@@ -0,0 +1,71 @@
+//! CCR Heartbeat
+//!
+//! While connected, CCR publishes a retained status message on
+//! `ccr/device/<id>/status` every `ccr_heartbeat_secs` seconds, so the
+//! bridge can raise an alarm when the handheld stops responding:
+//!
+//! ```text
+//! {"uptime_ms":812345,"events":42,"pending_permissions":1,"firmware":"v0.9.16","interval_s":30}
+//! ```
+//!
+//! `interval_s` tells the bridge how long to wait before it counts a beat as
+//! missed. The device id is the SoC's unique DNA, in hex.
+
+extern crate alloc;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use serde::Serialize;
+use xous_mqtt::codec::{self, CodecError};
+use xous_mqtt::topic::{Topic, TopicError};
+
+/// Seconds between heartbeats when `ccr_heartbeat_secs` is unset
+pub const DEFAULT_INTERVAL_SECS: u64 = 30;
+
+/// Topic a device's heartbeat is published on
+pub fn status_topic(device_id: &str) -> Result<Topic, TopicError> {
+    Topic::new("ccr")?.join("device")?.join(device_id)?.join("status")
+}
+
+/// Device id from the SoC DNA
+pub fn device_id(dna: u64) -> String { format!("{:016x}", dna) }
+
+/// One heartbeat
+#[derive(Serialize)]
+pub struct Status<'a> {
+    /// Time since boot
+    pub uptime_ms: u64,
+    /// Session events received since boot
+    pub events: u64,
+    pub pending_permissions: usize,
+    pub firmware: &'a str,
+    #[serde(rename = "interval_s")]
+    pub interval_secs: u64,
+}
+
+impl Status<'_> {
+    pub fn to_json(&self) -> Result<Vec<u8>, CodecError> { codec::to_json(self) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_json() {
+        assert_eq!(status_topic(&device_id(0xABC)).unwrap().as_str(), "ccr/device/0000000000000abc/status");
+        assert!(status_topic("a/b").is_err());
+        let status = Status {
+            uptime_ms: 5000,
+            events: 3,
+            pending_permissions: 1,
+            firmware: "v0.9.16-\"dirty\"",
+            interval_secs: 30,
+        };
+        assert_eq!(
+            String::from_utf8(status.to_json().unwrap()).unwrap(),
+            r#"{"uptime_ms":5000,"events":3,"pending_permissions":1,"firmware":"v0.9.16-\"dirty\"","interval_s":30}"#
+        );
+    }
+}
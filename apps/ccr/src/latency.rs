@@ -0,0 +1,109 @@
+//! CCR End-to-End Latency
+//!
+//! The bridge stamps each payload with `"sent_ms"` (milliseconds since the
+//! Unix epoch) when it publishes. The difference to the device's wall clock
+//! on arrival is the end-to-end latency: bridge, broker, WiFi and the
+//! device's own message handling. Recent samples are kept for the stats
+//! view, and a sample above [`LATENCY_WARN_MS`] raises an on-screen warning
+//! so a stale permission prompt isn't mistaken for a live one.
+
+/// Samples kept for the percentiles
+pub const LATENCY_WINDOW: usize = 64;
+
+/// Latency above which the chat view shows a warning
+pub const LATENCY_WARN_MS: u32 = 2000;
+
+/// Rolling window of latency samples
+pub struct LatencyStats {
+    samples: [u32; LATENCY_WINDOW],
+    /// Next slot to write
+    next: usize,
+    /// Valid samples in the window
+    len: usize,
+    /// Samples recorded since start, including those that left the window
+    total: u32,
+    /// Samples above the warning threshold since start
+    slow: u32,
+    /// Most recent sample
+    last: Option<u32>,
+}
+
+impl LatencyStats {
+    pub const fn new() -> Self {
+        Self { samples: [0; LATENCY_WINDOW], next: 0, len: 0, total: 0, slow: 0, last: None }
+    }
+
+    /// Record the latency of an event sent at `sent_ms` and received at `now_ms`
+    ///
+    /// Returns the latency, or `None` when the sender's clock is ahead of
+    /// ours and no meaningful value can be computed.
+    pub fn record(&mut self, sent_ms: u64, now_ms: u64) -> Option<u32> {
+        let latency = u32::try_from(now_ms.checked_sub(sent_ms)?).unwrap_or(u32::MAX);
+        self.samples[self.next] = latency;
+        self.next = (self.next + 1) % LATENCY_WINDOW;
+        self.len = (self.len + 1).min(LATENCY_WINDOW);
+        self.total = self.total.saturating_add(1);
+        if latency > LATENCY_WARN_MS {
+            self.slow = self.slow.saturating_add(1);
+        }
+        self.last = Some(latency);
+        Some(latency)
+    }
+
+    /// Latency at percentile `pct` (0-100) over the window, nearest-rank
+    pub fn percentile(&self, pct: u32) -> Option<u32> {
+        if self.len == 0 {
+            return None;
+        }
+        let mut sorted = [0u32; LATENCY_WINDOW];
+        let sorted = &mut sorted[..self.len];
+        sorted.copy_from_slice(&self.samples[..self.len]);
+        sorted.sort_unstable();
+        let rank = (pct.min(100) as usize * self.len).div_ceil(100).max(1);
+        Some(sorted[rank - 1])
+    }
+
+    /// Whether the most recent sample was above the warning threshold
+    pub fn is_slow(&self) -> bool { self.last.is_some_and(|latency| latency > LATENCY_WARN_MS) }
+
+    pub fn last(&self) -> Option<u32> { self.last }
+
+    pub fn total(&self) -> u32 { self.total }
+
+    pub fn slow(&self) -> u32 { self.slow }
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self { Self::new() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let mut stats = LatencyStats::new();
+        assert_eq!(stats.percentile(50), None);
+        for latency in 1..=100u64 {
+            stats.record(1_000, 1_000 + latency);
+        }
+        // Only the last LATENCY_WINDOW samples count: 37..=100
+        assert_eq!(stats.percentile(50), Some(68));
+        assert_eq!(stats.percentile(95), Some(97));
+        assert_eq!(stats.total(), 100);
+        assert!(!stats.is_slow());
+    }
+
+    #[test]
+    fn test_slow_and_skewed_samples() {
+        let mut stats = LatencyStats::new();
+        assert_eq!(stats.record(5_000, 4_000), None);
+        assert_eq!(stats.record(0, LATENCY_WARN_MS as u64 + 1), Some(LATENCY_WARN_MS + 1));
+        assert!(stats.is_slow());
+        stats.record(0, 10);
+        assert!(!stats.is_slow());
+        assert_eq!(stats.slow(), 1);
+        assert_eq!(stats.total(), 2);
+    }
+}
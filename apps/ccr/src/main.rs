@@ -7,77 +7,90 @@
 //! - ccr/events: All events for display
 //! - ccr/permissions/request: Permission requests (subscribe)
 //! - ccr/permissions/response: Permission responses (publish)
+//! - ccr/policy: Signed auto-allow/deny rules from the desktop (subscribe)
+//! - ccr/debug/command: Input lines, console-only builds (subscribe, sealed under E2E)
+//!
+//! Built with `console-fallback`, CCR keeps running when the GAM can't be
+//! reached (e.g. a minimal image): views are written to the log instead of the
+//! screen, and input lines arrive on `ccr/debug/command`.
 
 #![cfg_attr(target_os = "none", no_std)]
 #![cfg_attr(target_os = "none", no_main)]
 
 extern crate alloc;
 
+mod dnd;
+mod e2e;
 mod events;
+mod export;
+mod heartbeat;
+mod latency;
 mod mqtt;
+mod pairing;
+mod policy;
+mod provision;
+mod quick_reply;
+mod storage;
 mod ui_improved;
 
-use alloc::string::String;
 use alloc::format;
+use alloc::string::String;
 use core::fmt::Write;
-use num_traits::*;
 
-use events::{CcrEvent, EventQueue};
+use ccr_api::{CcrOp, SERVER_NAME_CCR};
+use dnd::DndSchedule;
+use events::{CcrEvent, EventQueue, sanitize_text};
+use latency::LatencyStats;
+use num_traits::*;
+use pairing::ToolPairing;
+use policy::{Policy, PolicyError};
+use quick_reply::QuickReplies;
 use ui_improved::{UiState, ViewMode};
 
 /// Truncate string for display
-fn truncate_str(s: &str, max_len: usize) -> &str {
-    if s.len() <= max_len {
-        s
-    } else {
-        &s[..max_len]
-    }
-}
+fn truncate_str(s: &str, max_len: usize) -> &str { if s.len() <= max_len { s } else { &s[..max_len] } }
 
 // Xous imports
-use blitstr2::GlyphStyle;
-use ux_api::minigfx::*;
-use ux_api::service::api::Gid;
-
+#[cfg(feature = "hosted")]
+use std::io::{Read, Write as IoWrite};
 // Networking imports (hosted mode uses std)
 #[cfg(feature = "hosted")]
 use std::net::TcpStream;
 #[cfg(feature = "hosted")]
-use std::io::{Read, Write as IoWrite};
-#[cfg(feature = "hosted")]
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 #[cfg(feature = "hosted")]
 use std::sync::{Arc, Mutex};
 #[cfg(feature = "hosted")]
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-/// Server name for xous-names registration
-pub const SERVER_NAME_CCR: &str = "_Claude Code Remote_";
+use blitstr2::GlyphStyle;
+use ux_api::minigfx::*;
+use ux_api::service::api::Gid;
 
-/// MQTT broker address (localhost for hosted mode)
+/// Default MQTT broker address (localhost for hosted mode), used when the
+/// `ccr_broker` user preference is empty
 pub const MQTT_BROKER: &str = "127.0.0.1:1883";
 
 /// MQTT topics
 pub const TOPIC_EVENTS: &str = "ccr/events";
 pub const TOPIC_PERM_REQUEST: &str = "ccr/permissions/request";
 pub const TOPIC_PERM_RESPONSE: &str = "ccr/permissions/response";
-
-/// Message opcodes
-#[derive(Debug, num_derive::FromPrimitive, num_derive::ToPrimitive)]
-pub enum CcrOp {
-    /// Redraw the UI
-    Redraw = 0,
-    /// A line of text has arrived from IME
-    Line,
-    /// Raw key event (for d-pad navigation)
-    RawKey,
-    /// MQTT message received (scalar: connection status, or memory: topic+payload)
-    MqttMessage,
-    /// Timer tick (for MQTT polling)
-    Tick,
-    /// Quit the application
-    Quit,
-}
+pub const TOPIC_POLICY: &str = "ccr/policy";
+pub const TOPIC_USER_INPUT: &str = "ccr/user_input";
+/// Input lines for a CCR running without a display
+pub const TOPIC_DEBUG_COMMAND: &str = "ccr/debug/command";
+/// Local event-bus topic carrying the pending permission count (decimal text)
+pub const BUS_TOPIC_PENDING: &str = "ccr/pending";
+
+/// PDDB key under `storage::CCR_KEY_DICT` holding the X25519 device secret
+const E2E_DEVICE_KEY: &str = "e2e.device";
+
+/// Topics subscribed on connect, in one SUBSCRIBE
+#[cfg(not(feature = "console-fallback"))]
+const SUBSCRIBE_TOPICS: [&str; 4] = [TOPIC_EVENTS, TOPIC_PERM_REQUEST, TOPIC_POLICY, e2e::TOPIC_BRIDGE_HELLO];
+#[cfg(feature = "console-fallback")]
+const SUBSCRIBE_TOPICS: [&str; 5] =
+    [TOPIC_EVENTS, TOPIC_PERM_REQUEST, TOPIC_POLICY, e2e::TOPIC_BRIDGE_HELLO, TOPIC_DEBUG_COMMAND];
 
 /// MQTT connection state for thread communication
 #[cfg(feature = "hosted")]
@@ -85,16 +98,25 @@ struct MqttThreadState {
     stream: Option<TcpStream>,
     connected: bool,
     packet_id: u16,
+    /// Broker address and credentials, read on each connection attempt
+    broker: BrokerSettings,
+    /// Why the last connection attempt or session failed, cleared on connect
+    last_error: Option<String>,
+}
+
+/// Where the MQTT thread connects, and as whom
+#[cfg(feature = "hosted")]
+#[derive(Clone)]
+struct BrokerSettings {
+    address: String,
+    username: Option<String>,
+    password: Option<String>,
 }
 
 #[cfg(feature = "hosted")]
 impl MqttThreadState {
-    fn new() -> Self {
-        Self {
-            stream: None,
-            connected: false,
-            packet_id: 1,
-        }
+    fn new(broker: BrokerSettings) -> Self {
+        Self { stream: None, connected: false, packet_id: 1, broker, last_error: None }
     }
 
     fn next_packet_id(&mut self) -> u16 {
@@ -112,6 +134,63 @@ const MARGIN_X: isize = 8;
 const MARGIN_Y: isize = 4;
 const BUBBLE_SPACE: isize = 2;
 const BUBBLE_RADIUS: u16 = 4;
+/// Keys held together to allow a permission request in chord-approval mode (F1 + d-pad center)
+const APPROVAL_CHORD: [char; 2] = ['\u{0011}', '∴'];
+
+/// Share of the canvas height given to the pinned detail pane in split view
+const SPLIT_DETAIL_PERCENT: isize = 40;
+/// Most recent events written out by the console chat view
+const CONSOLE_EVENTS: usize = 8;
+/// Lookups of the GAM before deciding the image has none
+#[cfg(feature = "console-fallback")]
+const GAM_PROBE_ATTEMPTS: usize = 10;
+
+/// GAM resources backing the on-screen UI
+struct Screen {
+    gam: gam::Gam,
+    _token: [u32; 4],
+    content: Gid,
+}
+
+/// Connect to the GAM, register the UX and claim the content canvas
+fn open_screen(xns: &xous_names::XousNames, sid: xous::SID) -> Result<(Screen, Point), xous::Error> {
+    log::info!("CCR: Connecting to GAM...");
+    // `Gam::new` waits forever for the server, so find out first whether there is one
+    #[cfg(feature = "console-fallback")]
+    {
+        let tt = ticktimer_server::Ticktimer::new().unwrap();
+        let mut attempts = 0;
+        while xns.request_connection(gam::SERVER_NAME_GAM).is_err() {
+            attempts += 1;
+            if attempts == GAM_PROBE_ATTEMPTS {
+                return Err(xous::Error::ServerNotFound);
+            }
+            tt.sleep_ms(500).ok();
+        }
+    }
+    let gam = gam::Gam::new(xns)?;
+
+    log::info!("CCR: Registering UX context as '{}'...", gam::APP_NAME_CCR);
+    let token = gam
+        .register_ux(gam::UxRegistration {
+            app_name: String::from(gam::APP_NAME_CCR),
+            ux_type: gam::UxType::Chat,
+            predictor: Some(String::from(ime_plugin_shell::SERVER_NAME_IME_PLUGIN_SHELL)),
+            listener: sid.to_array(),
+            redraw_id: CcrOp::Redraw.to_u32().unwrap(),
+            gotinput_id: Some(CcrOp::Line.to_u32().unwrap()),
+            audioframe_id: None,
+            rawkeys_id: Some(CcrOp::RawKey.to_u32().unwrap()),
+            focuschange_id: None,
+        })?
+        .ok_or(xous::Error::AccessDenied)?;
+    log::info!("CCR: UX registered successfully, token: {:x?}", token);
+
+    let content = gam.request_content_canvas(token)?;
+    let screensize = gam.get_canvas_bounds(content)?;
+    log::info!("CCR: Canvas acquired, size: {}x{}", screensize.x, screensize.y);
+    Ok((Screen { gam, _token: token, content }, screensize))
+}
 
 /// Application state
 struct CcrApp {
@@ -121,24 +200,69 @@ struct CcrApp {
     ui: UiState,
     /// Server ID
     sid: xous::SID,
-    /// GAM connection
-    gam: gam::Gam,
-    /// GAM token
-    _gam_token: [u32; 4],
-    /// Content canvas
-    content: Gid,
-    /// Screen size
+    /// On-screen UI; `None` when views go to the log instead
+    screen: Option<Screen>,
+    /// Last view written to the log, so unchanged views aren't repeated
+    console_view: String,
+    /// Screen size (zero without a display)
     screensize: Point,
     /// Bubble width (80% of screen)
     bubble_width: u16,
     /// Bubble margin
     bubble_margin: Point,
-    /// Connection to self for MQTT thread messages
-    #[cfg(feature = "hosted")]
+    /// PDDB storage for exports
+    store: storage::Store,
+    /// User preferences (broker, quick replies, DnD)
+    prefs: userprefs::Manager,
+    /// Quick-reply templates
+    quick_replies: QuickReplies,
+    /// Do-not-disturb schedule
+    dnd: Option<DndSchedule>,
+    /// Key that signs policy updates, if one has been provisioned
+    policy_key: Option<ed25519_dalek::VerifyingKey>,
+    /// Auto-allow/deny rules in force
+    policy: Policy,
+    /// End-to-end encryption with the bridge
+    e2e: e2e::Link,
+    /// Pairing code on display, waiting for broker credentials
+    provisioning: Option<provision::Pairing>,
+    /// Bridge-to-display latency of stamped events
+    latency: LatencyStats,
+    /// Allow only via `APPROVAL_CHORD`, never via typed text
+    chord_approval: bool,
+    /// Local time source for the DnD schedule
+    localtime: llio::LocalTime,
+    /// Vibration motor for alerts
+    llio: llio::Llio,
+    /// Connectivity coordinator; holds our subscription open
+    _net_power: net_power::NetPower,
+    /// Last connectivity reported by net-power
+    connectivity: net_power::Connectivity,
+    /// In-device event bus, for other processes tracking CCR state
+    bus: event_bus::EventBus,
+    /// Pending permission count last published on the bus
+    bus_pending: Option<usize>,
+    /// Tool results held until their call arrives
+    pairing: ToolPairing,
+    /// A `Tick` is on its way
+    tick_pending: bool,
+    /// Time source for held tool results
+    ticktimer: ticktimer_server::Ticktimer,
+    /// Connection to self, for the MQTT thread and timer ticks
     self_cid: xous::CID,
+    /// Session events received since boot, reported in heartbeats
+    events_received: u64,
+    /// SoC DNA in hex, naming the device in status and benchmark topics
+    device_id: String,
+    /// Seconds between heartbeats, read by the heartbeat thread
+    #[cfg(feature = "hosted")]
+    heartbeat_secs: Arc<AtomicU64>,
     /// MQTT thread running flag
     #[cfg(feature = "hosted")]
     mqtt_running: Arc<AtomicBool>,
+    /// Set while the network is usable; the MQTT thread doesn't reconnect otherwise
+    #[cfg(feature = "hosted")]
+    net_available: Arc<AtomicBool>,
     /// MQTT thread state
     #[cfg(feature = "hosted")]
     mqtt_state: Arc<Mutex<MqttThreadState>>,
@@ -147,70 +271,119 @@ struct CcrApp {
 impl CcrApp {
     /// Create new CCR application
     fn new(xns: &xous_names::XousNames, sid: xous::SID) -> Self {
-        log::info!("CCR: Connecting to GAM...");
-        let gam = gam::Gam::new(xns).expect("Can't connect to GAM");
-
-        log::info!("CCR: Registering UX context as '{}'...", gam::APP_NAME_CCR);
-        let gam_token = gam
-            .register_ux(gam::UxRegistration {
-                app_name: String::from(gam::APP_NAME_CCR),
-                ux_type: gam::UxType::Chat,
-                predictor: Some(String::from(ime_plugin_shell::SERVER_NAME_IME_PLUGIN_SHELL)),
-                listener: sid.to_array(),
-                redraw_id: CcrOp::Redraw.to_u32().unwrap(),
-                gotinput_id: Some(CcrOp::Line.to_u32().unwrap()),
-                audioframe_id: None,
-                rawkeys_id: Some(CcrOp::RawKey.to_u32().unwrap()),
-                focuschange_id: None,
-            })
-            .expect("Could not register GAM UX")
-            .unwrap();
-        log::info!("CCR: UX registered successfully, token: {:x?}", gam_token);
-
-        let content = gam.request_content_canvas(gam_token).expect("Could not get content canvas");
-        let screensize = gam.get_canvas_bounds(content).expect("Could not get canvas dimensions");
-        log::info!("CCR: Canvas acquired, size: {}x{}", screensize.x, screensize.y);
+        let (screen, screensize) = match open_screen(xns, sid) {
+            Ok((screen, screensize)) => (Some(screen), screensize),
+            #[cfg(feature = "console-fallback")]
+            Err(e) => {
+                log::warn!(
+                    "CCR: No display ({:?}), views go to the log; input on {}",
+                    e,
+                    TOPIC_DEBUG_COMMAND
+                );
+                (None, Point::new(0, 0))
+            }
+            #[cfg(not(feature = "console-fallback"))]
+            Err(e) => panic!("CCR: Could not open the display: {:?}", e),
+        };
 
         // Calculate bubble dimensions (80% width)
         let bubble_width = ((screensize.x * 4) / 5) as u16;
         let bubble_margin = Point::new(4, 2);
 
-        // Initialize MQTT thread (hosted mode only)
-        #[cfg(feature = "hosted")]
+        let store = storage::Store::new();
+        let prefs = userprefs::Manager::new();
+        let (quick_replies, dnd) = load_settings(&prefs);
+        let (policy_key, policy) = load_policy(&prefs);
+        let e2e = load_e2e(&store, &prefs);
+        let chord_approval = prefs.ccr_chord_approval_or_default().unwrap_or(false);
+
+        let net_power = net_power::NetPower::new();
+        let connectivity = net_power.connectivity().unwrap_or(net_power::Connectivity::Offline);
+        net_power
+            .subscribe(sid, CcrOp::Connectivity.to_u32().unwrap())
+            .expect("couldn't subscribe to connectivity updates");
+
         let self_cid = xous::connect(sid).expect("Can't connect to self");
 
         #[cfg(feature = "hosted")]
         let mqtt_running = Arc::new(AtomicBool::new(true));
 
         #[cfg(feature = "hosted")]
-        let mqtt_state = Arc::new(Mutex::new(MqttThreadState::new()));
+        let mqtt_state = Arc::new(Mutex::new(MqttThreadState::new(load_broker(&prefs, &store))));
+
+        #[cfg(feature = "hosted")]
+        let net_available = Arc::new(AtomicBool::new(connectivity == net_power::Connectivity::Online));
 
         // Start MQTT thread
         #[cfg(feature = "hosted")]
         {
             let running = mqtt_running.clone();
             let state = mqtt_state.clone();
+            let available = net_available.clone();
+            let cid = self_cid;
+            std::thread::spawn(move || {
+                mqtt_thread_main(running, available, state, cid);
+            });
+        }
+
+        #[cfg(feature = "hosted")]
+        let heartbeat_secs = Arc::new(AtomicU64::new(load_heartbeat_secs(&prefs)));
+        #[cfg(feature = "hosted")]
+        {
+            let interval = heartbeat_secs.clone();
             let cid = self_cid;
             std::thread::spawn(move || {
-                mqtt_thread_main(MQTT_BROKER, running, state, cid);
+                let tt = ticktimer_server::Ticktimer::new().unwrap();
+                loop {
+                    tt.sleep_ms(interval.load(Ordering::Relaxed) as usize * 1000).ok();
+                    let beat = xous::Message::new_scalar(CcrOp::Heartbeat.to_usize().unwrap(), 0, 0, 0, 0);
+                    if xous::send_message(cid, beat).is_err() {
+                        break;
+                    }
+                }
             });
         }
+        let llio = llio::Llio::new(xns);
+        let device_id = heartbeat::device_id(llio.soc_dna().unwrap_or(0));
 
         Self {
             events: EventQueue::new(),
             ui: UiState::new(),
             sid,
-            gam,
-            _gam_token: gam_token,
-            content,
+            screen,
+            console_view: String::new(),
             screensize,
             bubble_width,
             bubble_margin,
-            #[cfg(feature = "hosted")]
+            store,
+            prefs,
+            quick_replies,
+            dnd,
+            policy_key,
+            policy,
+            e2e,
+            provisioning: None,
+            latency: LatencyStats::new(),
+            chord_approval,
+            localtime: llio::LocalTime::new(),
+            llio,
+            _net_power: net_power,
+            connectivity,
+            bus: event_bus::EventBus::new(),
+            bus_pending: None,
+            pairing: ToolPairing::new(),
+            tick_pending: false,
+            ticktimer: ticktimer_server::Ticktimer::new().expect("Can't connect to ticktimer"),
             self_cid,
+            events_received: 0,
+            device_id,
+            #[cfg(feature = "hosted")]
+            heartbeat_secs,
             #[cfg(feature = "hosted")]
             mqtt_running,
             #[cfg(feature = "hosted")]
+            net_available,
+            #[cfg(feature = "hosted")]
             mqtt_state,
         }
     }
@@ -219,6 +392,39 @@ impl CcrApp {
     fn handle_mqtt_message(&mut self, topic: &str, payload: &str) {
         log::debug!("CCR: MQTT {} -> {}", topic, &payload[..payload.len().min(50)]);
 
+        if topic == e2e::TOPIC_BRIDGE_HELLO {
+            self.handle_bridge_hello(payload);
+            return;
+        }
+        if provision::is_pairing_topic(topic) {
+            self.handle_pairing_message(topic, payload);
+            return;
+        }
+        let payload = match self.e2e.receive(topic, payload) {
+            Ok(plaintext) => plaintext,
+            Err(e) => {
+                log::warn!("CCR: Dropping message on {}: {:?}", topic, e);
+                return;
+            }
+        };
+        let payload = payload.as_ref();
+
+        if topic == TOPIC_POLICY {
+            self.apply_policy(payload);
+            return;
+        }
+        if topic == TOPIC_DEBUG_COMMAND {
+            // With a screen and keyboard present, input only comes from the IME
+            if self.screen.is_some() {
+                log::warn!("CCR: Ignoring {} while the display is up", TOPIC_DEBUG_COMMAND);
+                return;
+            }
+            for line in payload.lines() {
+                self.handle_line(line);
+            }
+            return;
+        }
+
         let event = if topic == TOPIC_EVENTS {
             CcrEvent::from_json(payload)
         } else if topic == TOPIC_PERM_REQUEST {
@@ -228,24 +434,140 @@ impl CcrApp {
         };
 
         if let Some(event) = event {
+            self.events_received += 1;
+            if let Some(sent_ms) = CcrEvent::sent_ms(payload) {
+                self.record_latency(&event, sent_ms);
+            }
             self.handle_event(event);
         }
     }
 
+    /// The MQTT connection came up or went down
+    fn set_mqtt_connected(&mut self, connected: bool) {
+        if connected {
+            // Subscriptions don't outlive the connection
+            if let Some(topic) = self.provisioning.as_ref().map(|p| p.topic()) {
+                self.subscribe(&topic);
+            }
+            self.start_e2e();
+            self.publish_heartbeat();
+        } else {
+            self.e2e.reset();
+        }
+    }
+
+    /// Publish a fresh handshake hello, if a bridge key is provisioned
+    fn start_e2e(&mut self) {
+        if let Some(hello) = self.e2e.start(&mut rand_core::OsRng) {
+            self.publish(e2e::TOPIC_DEVICE_HELLO, &hello);
+        }
+    }
+
+    /// Complete the handshake, or start over if the bridge asks
+    fn handle_bridge_hello(&mut self, payload: &str) {
+        if !self.e2e.is_enabled() {
+            return;
+        }
+        // Anyone on the broker can publish here, so a live session is only
+        // replaced by reconnecting
+        if self.e2e.is_secure() {
+            log::debug!("CCR: Ignoring bridge hello, session already established");
+            return;
+        }
+        if payload.trim().is_empty() {
+            log::info!("CCR: Bridge asked for a new handshake");
+            self.start_e2e();
+            return;
+        }
+        match self.e2e.on_bridge_hello(payload) {
+            Ok(()) => {
+                log::info!("CCR: End-to-end session established");
+                self.notify("e2e", "Secure session with bridge");
+            }
+            Err(e) => {
+                log::warn!("CCR: Bridge handshake failed: {:?}", e);
+                self.notify("e2e", &format!("Bridge handshake failed: {:?}", e));
+                // The bridge answers a fresh hello
+                self.start_e2e();
+            }
+        }
+    }
+
+    /// Log and record how long `event` took from the bridge to here
+    fn record_latency(&mut self, event: &CcrEvent, sent_ms: u64) {
+        let now_ms = match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+            Ok(now) => now.as_millis() as u64,
+            Err(_) => return,
+        };
+        match self.latency.record(sent_ms, now_ms) {
+            Some(ms) if ms > latency::LATENCY_WARN_MS => {
+                log::warn!("CCR: {} latency {} ms", event.summary(), ms)
+            }
+            Some(ms) => log::info!("CCR: {} latency {} ms", event.summary(), ms),
+            None => log::debug!("CCR: Bridge clock ahead of ours, latency not recorded"),
+        }
+    }
+
     /// Handle incoming event
+    ///
+    /// A tool result that beats its call is held back so the two stay in order.
     fn handle_event(&mut self, event: CcrEvent) {
+        if matches!(event, CcrEvent::ToolResult { .. }) {
+            let now_ms = self.ticktimer.elapsed_ms();
+            for ready in self.pairing.on_result(event, now_ms) {
+                self.show_event(ready);
+            }
+            self.schedule_tick();
+            return;
+        }
+        let held_result = match &event {
+            CcrEvent::ToolCall { id, .. } => self.pairing.on_call(id),
+            _ => None,
+        };
+        self.show_event(event);
+        if let Some(result) = held_result {
+            self.show_event(result);
+        }
+    }
+
+    /// Show tool results whose calls never arrived
+    fn release_orphans(&mut self) {
+        self.tick_pending = false;
+        for event in self.pairing.expired(self.ticktimer.elapsed_ms()) {
+            self.show_event(event);
+        }
+        self.schedule_tick();
+    }
+
+    /// Have a `Tick` sent while tool results are held, so they're shown on time
+    fn schedule_tick(&mut self) {
+        if self.tick_pending || !self.pairing.is_holding() {
+            return;
+        }
+        self.tick_pending = true;
+        let cid = self.self_cid;
+        std::thread::spawn(move || {
+            let tt = ticktimer_server::Ticktimer::new().unwrap();
+            tt.sleep_ms(pairing::ORPHAN_HOLD_MS as usize).ok();
+            xous::send_message(cid, xous::Message::new_scalar(CcrOp::Tick.to_usize().unwrap(), 0, 0, 0, 0))
+                .ok();
+        });
+    }
+
+    /// Add an event to the timeline, acting on session and permission state
+    fn show_event(&mut self, event: CcrEvent) {
         // Extract session ID from event
         match &event {
-            CcrEvent::SessionStart { session_id, .. } |
-            CcrEvent::SessionEnd { session_id, .. } |
-            CcrEvent::Stop { session_id } |
-            CcrEvent::UserInput { session_id, .. } |
-            CcrEvent::ToolCall { session_id, .. } |
-            CcrEvent::ToolResult { session_id, .. } |
-            CcrEvent::PermissionPending { session_id, .. } |
-            CcrEvent::PermissionResolved { session_id, .. } |
-            CcrEvent::PermissionTimeout { session_id, .. } |
-            CcrEvent::Notification { session_id, .. } => {
+            CcrEvent::SessionStart { session_id, .. }
+            | CcrEvent::SessionEnd { session_id, .. }
+            | CcrEvent::Stop { session_id }
+            | CcrEvent::UserInput { session_id, .. }
+            | CcrEvent::ToolCall { session_id, .. }
+            | CcrEvent::ToolResult { session_id, .. }
+            | CcrEvent::PermissionPending { session_id, .. }
+            | CcrEvent::PermissionResolved { session_id, .. }
+            | CcrEvent::PermissionTimeout { session_id, .. }
+            | CcrEvent::Notification { session_id, .. } => {
                 if !session_id.is_empty() {
                     self.ui.session_id = session_id.clone();
                 }
@@ -256,14 +578,32 @@ impl CcrApp {
         }
 
         // Handle permission events specially
-        if let CcrEvent::PermissionPending { request_id, .. } = &event {
+        if let CcrEvent::PermissionPending { request_id, tool, command, .. } = &event {
+            if let Some(action) = self.policy.decide(tool, command) {
+                log::info!("CCR: Policy v{} decided {} for {}", self.policy.version(), action.as_str(), tool);
+                let request_id = request_id.clone();
+                self.events.push(event);
+                self.publish_permission_decision(&request_id, action.as_str());
+                self.ui.auto_scroll(self.events.len());
+                return;
+            }
+            if self.update_dnd() {
+                // Hand the decision back to the desktop without alerting
+                let request_id = request_id.clone();
+                self.events.push(event);
+                self.publish_permission_decision(&request_id, "delegate");
+                self.ui.auto_scroll(self.events.len());
+                return;
+            }
             self.ui.set_pending_permission(request_id);
+            self.alert();
             // Permission shown inline in Chat view, no view switch needed
         }
 
         // Clear pending permission if resolved/timeout
-        if let CcrEvent::PermissionResolved { request_id, .. } |
-               CcrEvent::PermissionTimeout { request_id, .. } = &event {
+        if let CcrEvent::PermissionResolved { request_id, .. }
+        | CcrEvent::PermissionTimeout { request_id, .. } = &event
+        {
             if self.ui.pending_permission.as_deref() == Some(request_id) {
                 self.ui.clear_pending_permission();
             }
@@ -276,6 +616,21 @@ impl CcrApp {
         self.ui.auto_scroll(self.events.len());
     }
 
+    /// Handle the keys from one raw key message, which are held down together
+    fn handle_rawkeys(&mut self, keys: &[char]) {
+        if APPROVAL_CHORD.iter().all(|k| keys.contains(k)) {
+            if self.ui.has_pending_permission() {
+                self.ui.permission_choice = true;
+                self.send_permission_response();
+            }
+            // The chord never doubles as its individual keys
+            return;
+        }
+        for key in keys {
+            self.handle_rawkey(*key);
+        }
+    }
+
     /// Handle raw key event for d-pad navigation
     fn handle_rawkey(&mut self, key: char) {
         // D-pad navigation:
@@ -283,7 +638,24 @@ impl CcrApp {
         // Down (↓ U+2193): move selection down (visually down = lower index = older event)
         // Right (→ U+2192): expand selected bubble (detail view)
         // Left (← U+2190): collapse/clear selection
+        // F1: open quick-reply picker, then F1-F4 sends a template
+        // F2: toggle split view; in split view Right pins the selection below the chat
+        if self.ui.view == ViewMode::QuickReply {
+            if let Some(slot) = quick_reply::function_key_slot(key) {
+                self.send_quick_reply(slot);
+            } else if key == '←' {
+                self.ui.view = ViewMode::Chat;
+            }
+            return;
+        }
+
         match key {
+            '\u{0011}' if self.ui.view == ViewMode::Chat => {
+                self.ui.view = ViewMode::QuickReply;
+            }
+            '\u{0012}' if self.ui.view == ViewMode::Chat => {
+                self.ui.toggle_split(&self.events);
+            }
             '↑' | '\u{2191}' => {
                 // Move selection up (visually) = to older event = lower index
                 if self.ui.selected > 0 {
@@ -297,15 +669,19 @@ impl CcrApp {
                 }
             }
             '→' | '\u{2192}' => {
-                // Expand: switch to detail view
+                // Expand: pin into the split pane, or switch to detail view
                 if self.ui.has_selection() && !self.events.is_empty() {
-                    self.ui.view = ViewMode::Detail;
+                    if self.ui.split {
+                        self.ui.pin_selected(&self.events);
+                    } else {
+                        self.ui.view = ViewMode::Detail;
+                    }
                 }
             }
             '←' | '\u{2190}' => {
                 // Collapse: if in detail view, go back to chat
                 // If in chat view, clear selection
-                if self.ui.view == ViewMode::Detail {
+                if self.ui.view == ViewMode::Detail || self.ui.view == ViewMode::Stats {
                     self.ui.view = ViewMode::Chat;
                 } else {
                     self.ui.clear_selection();
@@ -325,9 +701,19 @@ impl CcrApp {
             return;
         }
 
+        if let Some(command) = trimmed.strip_prefix('/') {
+            self.handle_command(command);
+            return;
+        }
+
         // Check for permission commands
         if self.ui.has_pending_permission() {
             match trimmed.to_lowercase().as_str() {
+                "allow" | "yes" | "y" | "a" if self.chord_approval => {
+                    // Typed text (or an IME completion) can't approve in this mode
+                    self.notify("permission", "Hold F1 + center to allow");
+                    return;
+                }
                 "allow" | "yes" | "y" | "a" => {
                     self.ui.permission_choice = true;
                     self.send_permission_response();
@@ -347,44 +733,447 @@ impl CcrApp {
         self.send_user_input();
     }
 
-    /// Send user input via MQTT
-    fn send_user_input(&mut self) {
-        let text = self.ui.input_get().to_string();
-        if text.is_empty() {
+    /// Handle a `/command` typed into the input line
+    fn handle_command(&mut self, command: &str) {
+        let mut args = command.split_whitespace();
+        match (args.next(), args.next()) {
+            (Some("export"), Some("md")) | (Some("export"), None) => self.export_markdown(),
+            (Some("dnd"), arg) => self.edit_dnd(arg),
+            (Some("stats"), None) => self.ui.view = ViewMode::Stats,
+            (Some("secure"), arg) => self.edit_chord_approval(arg),
+            (Some("policy"), arg) => self.edit_policy(arg, args.next()),
+            (Some("e2e"), arg) => self.edit_e2e(arg, args.next()),
+            (Some("pair"), None) => self.start_pairing(),
+            (Some("pair"), Some("cancel")) => self.end_pairing(),
+            (Some("reply"), slot) => {
+                let rest = command.trim_start()["reply".len()..].trim_start();
+                let text = rest[slot.map_or(0, |s| s.len())..].trim();
+                self.edit_quick_reply(slot, text);
+            }
+            _ => self.notify("command", &format!("Unknown command: /{}", command)),
+        }
+    }
+
+    /// Render the session as Markdown into the PDDB export dictionary
+    fn export_markdown(&mut self) {
+        let markdown = export::render_markdown(&self.events, &self.ui.session_id);
+        let key = export::export_key(&self.ui.session_id);
+        let message = match self.store.write(storage::CCR_EXPORT_DICT, &key, markdown.as_bytes()) {
+            Ok(()) => {
+                log::info!("CCR: Exported {} bytes to {}:{}", markdown.len(), storage::CCR_EXPORT_DICT, key);
+                format!("Exported to {}:{}", storage::CCR_EXPORT_DICT, key)
+            }
+            Err(e) => {
+                log::error!("CCR: Export failed: {:?}", e);
+                format!("Export failed: {:?}", e.kind())
+            }
+        };
+        self.notify("export", &message);
+    }
+
+    /// Send the quick-reply template in `slot` as user input
+    fn send_quick_reply(&mut self, slot: usize) {
+        self.ui.view = ViewMode::Chat;
+        if let Some(text) = self.quick_replies.get(slot) {
+            self.ui.input_text = String::from(text);
+            self.send_user_input();
+        }
+    }
+
+    /// `/reply` lists templates, `/reply <n> <text>` sets slot n, `/reply <n>` clears it
+    fn edit_quick_reply(&mut self, slot: Option<&str>, text: &str) {
+        let slot = match slot.and_then(|s| s.parse::<usize>().ok()) {
+            Some(n) if (1..=quick_reply::MAX_QUICK_REPLIES).contains(&n) => n - 1,
+            Some(_) => {
+                self.notify("reply", &format!("Slot must be 1-{}", quick_reply::MAX_QUICK_REPLIES));
+                return;
+            }
+            None => {
+                let mut list = String::from("Quick replies:");
+                for (i, template) in self.quick_replies.iter().enumerate() {
+                    list.push_str(&format!("\nF{} {}", i + 1, template));
+                }
+                self.notify("reply", &list);
+                return;
+            }
+        };
+
+        let changed = if text.is_empty() {
+            self.quick_replies.remove(slot)
+        } else {
+            self.quick_replies.set(slot, text)
+        };
+        if !changed {
+            self.notify("reply", "Quick reply unchanged");
             return;
         }
+        match self.prefs.set_ccr_quick_replies(self.quick_replies.to_settings()) {
+            Ok(()) => self.notify("reply", "Quick replies saved"),
+            Err(e) => self.notify("reply", &format!("Save failed: {:?}", e)),
+        }
+    }
 
-        let payload = format!(
-            r#"{{"session_id":"{}","text":"{}"}}"#,
-            self.ui.session_id,
-            text.replace('"', "\\\"")
-        );
+    /// Refresh and return whether the DnD window is active now
+    fn update_dnd(&mut self) -> bool {
+        self.ui.dnd_active = match (&self.dnd, self.localtime.get_local_time_ms()) {
+            (Some(dnd), Some(now)) => dnd.is_active_at(now),
+            _ => false,
+        };
+        self.ui.dnd_active
+    }
 
-        log::info!("CCR: Sending user input: {}", text);
+    /// Vibrate for an event that needs attention, unless DnD is active
+    fn alert(&mut self) {
+        if !self.update_dnd() {
+            self.llio.vibe(llio::VibePattern::Double).ok();
+        }
+    }
 
-        // Publish to MQTT
-        #[cfg(feature = "hosted")]
-        {
-            if let Ok(mut state) = self.mqtt_state.lock() {
-                let packet_id = state.next_packet_id();
-                if let Some(stream) = &mut state.stream {
-                    let publish = mqtt::build_publish_packet(
-                        "ccr/user_input",
-                        payload.as_bytes(),
-                        packet_id
-                    );
-                    use std::io::Write as IoWrite;
-                    let _ = stream.write_all(&publish);
-                    let _ = stream.flush();
+    /// `/dnd` shows the schedule, `/dnd HH:MM-HH:MM` sets it, `/dnd off` clears it
+    fn edit_dnd(&mut self, arg: Option<&str>) {
+        let settings = match arg {
+            None => {
+                let message = match &self.dnd {
+                    Some(dnd) => format!(
+                        "DnD {} ({})",
+                        dnd.to_settings(),
+                        if self.update_dnd() { "active" } else { "inactive" }
+                    ),
+                    None => String::from("DnD off"),
+                };
+                self.notify("dnd", &message);
+                return;
+            }
+            Some("off") => {
+                self.dnd = None;
+                String::new()
+            }
+            Some(window) => match DndSchedule::parse(window) {
+                Some(dnd) => {
+                    self.dnd = Some(dnd);
+                    dnd.to_settings()
+                }
+                None => {
+                    self.notify("dnd", "Usage: /dnd HH:MM-HH:MM | off");
+                    return;
                 }
+            },
+        };
+        self.update_dnd();
+        let message = if settings.is_empty() { String::from("DnD off") } else { format!("DnD {}", settings) };
+        match self.prefs.set_ccr_dnd(settings) {
+            Ok(()) => self.notify("dnd", &message),
+            Err(e) => self.notify("dnd", &format!("Save failed: {:?}", e)),
+        }
+    }
+
+    /// `/secure` shows the approval mode, `/secure on|off` switches chord-only approval
+    fn edit_chord_approval(&mut self, arg: Option<&str>) {
+        let enabled = match arg {
+            None => {
+                let mode = if self.chord_approval { "on: hold F1 + center to allow" } else { "off" };
+                self.notify("secure", &format!("Chord approval {}", mode));
+                return;
+            }
+            Some("on") => true,
+            Some("off") => false,
+            Some(_) => {
+                self.notify("secure", "Usage: /secure [on|off]");
+                return;
             }
+        };
+        match self.prefs.set_ccr_chord_approval(enabled) {
+            Ok(()) => {
+                self.chord_approval = enabled;
+                self.notify("secure", if enabled { "Chord approval on" } else { "Chord approval off" });
+            }
+            Err(e) => self.notify("secure", &format!("Save failed: {:?}", e)),
         }
+    }
 
-        // Add to event queue
-        self.events.push(CcrEvent::UserInput {
-            text,
+    /// Verify and install a policy pushed on `ccr/policy`
+    ///
+    /// The new rules only take effect once they are saved, so a failure at
+    /// any step leaves the previous policy in force.
+    fn apply_policy(&mut self, document: &str) {
+        let key = match &self.policy_key {
+            Some(key) => key,
+            None => {
+                log::warn!("CCR: Ignoring policy update, no signing key provisioned");
+                return;
+            }
+        };
+        let update =
+            match Policy::verify(document, key).and_then(|p| self.policy.check_upgrade(&p).map(|_| p)) {
+                Ok(update) => update,
+                // Retained copy of the policy already in force
+                Err(PolicyError::Stale { current, offered }) if current == offered => return,
+                Err(e) => {
+                    log::warn!("CCR: Policy update rejected: {:?}", e);
+                    self.notify("policy", &format!("Policy update rejected: {:?}", e));
+                    return;
+                }
+            };
+        if let Err(e) = self.prefs.set_ccr_policy(String::from(document)) {
+            log::error!("CCR: Couldn't save policy: {:?}", e);
+            self.notify("policy", "Policy update not saved; keeping current rules");
+            return;
+        }
+        let diff = self.policy.diff(&update);
+        self.policy = update;
+        self.notify("policy", &diff.summary(&self.policy));
+    }
+
+    /// `/policy` shows the rules in force, `/policy key <hex>` provisions the signing key
+    fn edit_policy(&mut self, arg: Option<&str>, value: Option<&str>) {
+        match (arg, value) {
+            (None, _) => {
+                let message = if self.policy_key.is_none() {
+                    String::from("No policy key")
+                } else {
+                    format!("Policy v{}, {} rules", self.policy.version(), self.policy.rules().len())
+                };
+                self.notify("policy", &message);
+            }
+            (Some("key"), Some(hex)) => match policy::parse_key(hex) {
+                Ok(_) => match self.prefs.set_ccr_policy_key(String::from(hex)) {
+                    Ok(()) => {
+                        // Rules signed by a previous key no longer apply
+                        let (policy_key, policy) = load_policy(&self.prefs);
+                        self.policy_key = policy_key;
+                        self.policy = policy;
+                        self.notify("policy", "Policy key saved");
+                    }
+                    Err(e) => self.notify("policy", &format!("Save failed: {:?}", e)),
+                },
+                Err(_) => self.notify("policy", "Key must be 64 hex digits"),
+            },
+            _ => self.notify("policy", "Usage: /policy [key <hex>]"),
+        }
+    }
+
+    /// `/e2e` shows the link state and device key, `/e2e key <hex>` provisions the bridge key
+    fn edit_e2e(&mut self, arg: Option<&str>, value: Option<&str>) {
+        match (arg, value) {
+            (None, _) => {
+                let device = hex::encode(self.e2e.device_public().as_bytes());
+                let state = if !self.e2e.is_enabled() {
+                    "off, no bridge key"
+                } else if self.e2e.is_secure() {
+                    "secure"
+                } else {
+                    "waiting for bridge"
+                };
+                log::info!("CCR: Device E2E key {}", device);
+                self.notify("e2e", &format!("E2E {}; device key {}", state, device));
+            }
+            (Some("key"), Some(hex)) => match e2e::parse_key(hex) {
+                Ok(key) => match self.prefs.set_ccr_e2e_bridge_key(String::from(hex.trim())) {
+                    Ok(()) => {
+                        self.e2e.set_bridge_key(Some(key));
+                        self.start_e2e();
+                        self.notify("e2e", "Bridge key saved");
+                    }
+                    Err(e) => self.notify("e2e", &format!("Save failed: {:?}", e)),
+                },
+                Err(_) => self.notify("e2e", "Key must be 64 hex digits"),
+            },
+            _ => self.notify("e2e", "Usage: /e2e [key <hex>]"),
+        }
+    }
+
+    /// Show a one-time pairing code and wait for broker credentials
+    fn start_pairing(&mut self) {
+        self.end_pairing();
+        let pairing = provision::Pairing::new(&mut rand_core::OsRng, self.ticktimer.elapsed_ms());
+        let topic = pairing.topic();
+        let qr = pairing.qr_text(&self.e2e.device_public());
+        self.subscribe(&topic);
+        self.provisioning = Some(pairing);
+        log::info!("CCR: Pairing on {}: {}", topic, qr);
+        self.notify("pair", "Scan the code with the provisioning tool");
+        if self.screen.is_some() {
+            // The modal blocks until dismissed, and the credentials can arrive before that
+            let minutes = provision::PAIRING_TIMEOUT_MS / 60_000;
+            let note = format!("Pair with broker\nValid for {} minutes", minutes);
+            std::thread::spawn(move || {
+                let xns = xous_names::XousNames::new().unwrap();
+                let modals = modals::Modals::new(&xns).expect("can't connect to modals");
+                modals.show_notification(&note, Some(&qr)).ok();
+            });
+        }
+    }
+
+    /// Forget the pairing code, if one is showing
+    fn end_pairing(&mut self) {
+        if let Some(pairing) = self.provisioning.take() {
+            self.unsubscribe(&pairing.topic());
+        }
+    }
+
+    /// Take broker credentials from the provisioning tool
+    fn handle_pairing_message(&mut self, topic: &str, payload: &str) {
+        let now_ms = self.ticktimer.elapsed_ms();
+        let result = match &self.provisioning {
+            Some(pairing) if pairing.topic() == topic => pairing.open(payload, now_ms),
+            _ => return,
+        };
+        match result {
+            Ok(credentials) => {
+                self.end_pairing();
+                self.apply_credentials(credentials);
+            }
+            Err(provision::ProvisionError::Expired) => {
+                self.end_pairing();
+                self.notify("pair", "Pairing code expired; run /pair again");
+            }
+            // Anyone can publish here; keep waiting for the real tool
+            Err(e) => log::warn!("CCR: Ignoring pairing message: {:?}", e),
+        }
+    }
+
+    /// Save provisioned broker settings and reconnect with them
+    fn apply_credentials(&mut self, credentials: provision::Credentials) {
+        if let Err(e) = self.save_credentials(&credentials) {
+            log::error!("CCR: Couldn't save provisioned settings: {}", e);
+            self.notify("pair", "Pairing failed: settings not saved");
+            return;
+        }
+
+        if let Some(bridge_key) = &credentials.bridge_key {
+            self.e2e.set_bridge_key(e2e::parse_key(bridge_key).ok());
+        }
+        log::info!("CCR: Provisioned for {}", credentials.broker);
+        self.notify("pair", &format!("Paired; connecting to {}", credentials.broker));
+
+        // The MQTT thread picks the new settings up when it reconnects
+        #[cfg(feature = "hosted")]
+        if let Ok(mut state) = self.mqtt_state.lock() {
+            state.broker = BrokerSettings {
+                address: credentials.broker,
+                username: credentials.username,
+                password: credentials.password,
+            };
+        }
+        self.reconnect();
+    }
+
+    /// Drop the broker connection; the MQTT thread connects again after its retry delay
+    fn reconnect(&mut self) {
+        #[cfg(feature = "hosted")]
+        if let Ok(state) = self.mqtt_state.lock() {
+            if let Some(stream) = &state.stream {
+                log::info!("CCR: Reconnecting to the broker");
+                stream.shutdown(std::net::Shutdown::Both).ok();
+            }
+        }
+    }
+
+    /// Broker connection state for the status service
+    fn mqtt_status(&self) -> ccr_api::MqttStatus {
+        #[cfg(feature = "hosted")]
+        let (broker, last_error) = match self.mqtt_state.lock() {
+            Ok(state) => (state.broker.address.clone(), state.last_error.clone()),
+            Err(_) => (String::new(), None),
+        };
+        #[cfg(not(feature = "hosted"))]
+        let (broker, last_error) = (String::new(), Some(String::from("No MQTT transport in this build")));
+        ccr_api::MqttStatus { broker, connected: self.ui.connected, last_error }
+    }
+
+    /// Persist provisioned settings; the username, password and namespace go
+    /// in a PDDB dict that other services on the same broker can read too
+    fn save_credentials(&self, credentials: &provision::Credentials) -> Result<(), String> {
+        self.prefs.set_ccr_broker(credentials.broker.clone()).map_err(|e| format!("{:?}", e))?;
+        for (key, value) in [
+            ("username", &credentials.username),
+            ("password", &credentials.password),
+            ("namespace", &credentials.namespace),
+        ] {
+            // An empty record clears a setting from an earlier pairing
+            let value = value.as_deref().unwrap_or("");
+            self.store
+                .write(storage::CCR_BROKER_DICT, key, value.as_bytes())
+                .map_err(|e| format!("{:?}", e))?;
+        }
+        if let Some(bridge_key) = &credentials.bridge_key {
+            self.prefs.set_ccr_e2e_bridge_key(bridge_key.clone()).map_err(|e| format!("{:?}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Track network availability so the MQTT thread only reconnects when it can succeed
+    fn set_connectivity(&mut self, connectivity: net_power::Connectivity) {
+        log::info!("CCR: Connectivity {:?} -> {:?}", self.connectivity, connectivity);
+        self.connectivity = connectivity;
+        #[cfg(feature = "hosted")]
+        self.net_available.store(connectivity == net_power::Connectivity::Online, Ordering::SeqCst);
+    }
+
+    /// Announce the pending permission count on the event bus when it changes
+    fn publish_pending(&mut self) {
+        let pending = self.events.pending_permissions();
+        if self.bus_pending == Some(pending) {
+            return;
+        }
+        match self.bus.publish(BUS_TOPIC_PENDING, pending.to_string().as_bytes()) {
+            Ok(()) => self.bus_pending = Some(pending),
+            Err(e) => log::warn!("CCR: Couldn't publish pending count: {:?}", e),
+        }
+    }
+
+    /// Re-read settings after another process changed them
+    fn reload_settings(&mut self) {
+        let (quick_replies, dnd) = load_settings(&self.prefs);
+        self.quick_replies = quick_replies;
+        self.dnd = dnd;
+        let (policy_key, policy) = load_policy(&self.prefs);
+        self.policy_key = policy_key;
+        self.policy = policy;
+        let bridge_key = load_bridge_key(&self.prefs);
+        if bridge_key != self.e2e.bridge_key() {
+            self.e2e.set_bridge_key(bridge_key);
+            self.start_e2e();
+        }
+        self.chord_approval = self.prefs.ccr_chord_approval_or_default().unwrap_or(false);
+        #[cfg(feature = "hosted")]
+        self.heartbeat_secs.store(load_heartbeat_secs(&self.prefs), Ordering::Relaxed);
+        self.update_dnd();
+        // Broker settings change through /pair, which reconnects by itself
+        log::info!("CCR: Settings reloaded");
+    }
+
+    /// Show a local notification bubble
+    fn notify(&mut self, notification_type: &str, message: &str) {
+        self.events.push(CcrEvent::Notification {
+            notification_type: String::from(notification_type),
+            message: String::from(message),
             session_id: self.ui.session_id.clone(),
         });
+        self.ui.auto_scroll(self.events.len());
+    }
+
+    /// Send user input via MQTT
+    fn send_user_input(&mut self) {
+        let text = self.ui.input_get().to_string();
+        if text.is_empty() {
+            return;
+        }
+
+        #[derive(serde::Serialize)]
+        struct UserInput<'a> {
+            session_id: &'a str,
+            text: &'a str,
+        }
+        let input = UserInput { session_id: &self.ui.session_id, text: &text };
+        // Strings always serialize, and to UTF-8
+        let payload = String::from_utf8(xous_mqtt::codec::to_json(&input).unwrap()).unwrap();
+
+        log::info!("CCR: Sending user input: {}", text);
+        self.publish(TOPIC_USER_INPUT, &payload);
+
+        // Add to event queue
+        self.events.push(CcrEvent::UserInput { text, session_id: self.ui.session_id.clone() });
 
         // Clear input
         self.ui.input_clear();
@@ -393,62 +1182,152 @@ impl CcrApp {
 
     /// Send permission response via MQTT
     fn send_permission_response(&mut self) {
-        if let Some(request_id) = &self.ui.pending_permission {
+        if let Some(request_id) = self.ui.pending_permission.clone() {
             let decision = if self.ui.permission_choice { "allow" } else { "deny" };
+            self.publish_permission_decision(&request_id, decision);
 
-            let payload = format!(
-                r#"{{"request_id":"{}","decision":"{}"}}"#,
-                request_id,
-                decision
-            );
+            // Clear pending and return to chat
+            self.ui.clear_pending_permission();
+            self.ui.view = ViewMode::Chat;
+            self.ui.auto_scroll(self.events.len());
+        }
+    }
 
-            log::info!("CCR: Sending permission response: {}", payload);
+    /// Publish a permission decision and record it in the event queue
+    fn publish_permission_decision(&mut self, request_id: &str, decision: &str) {
+        #[derive(serde::Serialize)]
+        struct Decision<'a> {
+            request_id: &'a str,
+            decision: &'a str,
+        }
+        // `request_id` comes off the network, so it must go through the codec's escaping.
+        // Strings always serialize, and to UTF-8.
+        let json = xous_mqtt::codec::to_json(&Decision { request_id, decision }).unwrap();
+        let payload = String::from_utf8(json).unwrap();
+
+        log::info!("CCR: Sending permission response: {}", payload);
+        self.publish(TOPIC_PERM_RESPONSE, &payload);
+
+        // Add resolved event to queue
+        self.events.push(CcrEvent::PermissionResolved {
+            request_id: String::from(request_id),
+            decision: String::from(decision),
+            session_id: self.ui.session_id.clone(),
+        });
+    }
 
-            // Actually publish to MQTT
-            #[cfg(feature = "hosted")]
-            {
-                if let Ok(mut state) = self.mqtt_state.lock() {
-                    let packet_id = state.next_packet_id();
-                    if let Some(stream) = &mut state.stream {
-                        let publish = mqtt::build_publish_packet(
-                            TOPIC_PERM_RESPONSE,
-                            payload.as_bytes(),
-                            packet_id
-                        );
-                        use std::io::Write as IoWrite;
-                        let _ = stream.write_all(&publish);
-                        let _ = stream.flush();
-                    }
+    /// Publish `payload` on `topic`, sealed if the topic is protected
+    ///
+    /// A protected message with no session to seal it is not sent at all.
+    fn publish(&mut self, topic: &str, payload: &str) {
+        let sealed;
+        let payload = if self.e2e.is_enabled() && e2e::protects(topic) {
+            match self.e2e.seal(topic, payload) {
+                Ok(message) => {
+                    sealed = message;
+                    sealed.as_str()
+                }
+                Err(e) => {
+                    log::warn!("CCR: Not sending on {}: {:?}", topic, e);
+                    self.notify("e2e", "No secure session with bridge; not sent");
+                    return;
                 }
             }
+        } else {
+            payload
+        };
 
-            // Add resolved event to queue
-            self.events.push(CcrEvent::PermissionResolved {
-                request_id: request_id.clone(),
-                decision: String::from(decision),
-                session_id: self.ui.session_id.clone(),
+        self.send_packet(|packet_id| mqtt::build_publish_packet(topic, payload.as_bytes(), packet_id));
+    }
+
+    /// Publish a retained heartbeat with the device's state on its status topic
+    fn publish_heartbeat(&mut self) {
+        #[cfg(feature = "hosted")]
+        let interval_secs = self.heartbeat_secs.load(Ordering::Relaxed);
+        #[cfg(not(feature = "hosted"))]
+        let interval_secs = heartbeat::DEFAULT_INTERVAL_SECS;
+        let firmware = self.ticktimer.get_version();
+        let status = heartbeat::Status {
+            uptime_ms: self.ticktimer.elapsed_ms(),
+            events: self.events_received,
+            pending_permissions: self.events.pending_permissions(),
+            firmware: firmware.lines().next().unwrap_or(""),
+            interval_secs,
+        };
+        if let (Ok(topic), Ok(payload)) = (heartbeat::status_topic(&self.device_id), status.to_json()) {
+            self.send_packet(|_| {
+                let qos = xous_mqtt::QoS::AtMostOnce;
+                xous_mqtt::packet::build_publish_with_id(topic.as_str(), &payload, qos, None, true)
             });
+        } else {
+            log::warn!("CCR: Could not build the heartbeat");
+        }
+        self.publish_latency_report();
+    }
 
-            // Clear pending and return to chat
-            self.ui.clear_pending_permission();
-            self.ui.view = ViewMode::Chat;
-            self.ui.auto_scroll(self.events.len());
+    /// Publish the latency window as a retained benchmark report, once there are samples
+    fn publish_latency_report(&mut self) {
+        use bench_report::unit;
+        let Some(p50) = self.latency.percentile(50) else { return };
+        let firmware = self.ticktimer.get_version();
+        let uptime_ms = self.ticktimer.elapsed_ms();
+        let mut report = bench_report::Report::new(&self.device_id, "ccr-latency", &firmware, uptime_ms);
+        report
+            .metric("p50", p50, unit::MILLISECONDS)
+            .metric("p95", self.latency.percentile(95).unwrap_or(p50), unit::MILLISECONDS)
+            .metric("samples", self.latency.total(), unit::COUNT)
+            .metric("slow", self.latency.slow(), unit::COUNT);
+        let (Ok(topic), Ok(payload)) = (report.topic(), report.to_json()) else {
+            log::warn!("CCR: Could not build the latency report");
+            return;
+        };
+        self.send_packet(|_| {
+            let qos = xous_mqtt::QoS::AtMostOnce;
+            xous_mqtt::packet::build_publish_with_id(topic.as_str(), &payload, qos, None, true)
+        });
+    }
+
+    /// Write a packet to the broker, if connected; `build` gets a fresh packet id
+    fn send_packet(&mut self, build: impl FnOnce(u16) -> Vec<u8>) {
+        #[cfg(feature = "hosted")]
+        {
+            if let Ok(mut state) = self.mqtt_state.lock() {
+                let packet_id = state.next_packet_id();
+                if let Some(stream) = &mut state.stream {
+                    let _ = stream.write_all(&build(packet_id));
+                    let _ = stream.flush();
+                }
+            }
         }
+        #[cfg(not(feature = "hosted"))]
+        let _ = build;
+    }
+
+    /// Subscribe to one more topic on the current connection
+    fn subscribe(&mut self, topic: &str) {
+        self.send_packet(|packet_id| mqtt::build_subscribe_packet(packet_id, &[topic]));
     }
 
+    /// Drop a subscription made by `subscribe`
+    fn unsubscribe(&mut self, topic: &str) {
+        self.send_packet(|packet_id| xous_mqtt::packet::build_unsubscribe(packet_id, topic));
+    }
+
+    /// GAM handle; only the drawing paths under `redraw` use it, and they need a display
+    fn gam(&self) -> &gam::Gam { &self.screen.as_ref().expect("drawing without a display").gam }
+
+    /// Content canvas
+    fn content(&self) -> Gid { self.screen.as_ref().expect("drawing without a display").content }
+
     /// Clear screen area
     fn clear_area(&self) {
-        self.gam
+        self.gam()
             .draw_rectangle(
-                self.content,
+                self.content(),
                 Rectangle::new_with_style(
                     Point::new(0, 0),
                     self.screensize,
-                    DrawStyle {
-                        fill_color: Some(PixelColor::Light),
-                        stroke_color: None,
-                        stroke_width: 0,
-                    },
+                    DrawStyle { fill_color: Some(PixelColor::Light), stroke_color: None, stroke_width: 0 },
                 ),
             )
             .expect("can't clear content area");
@@ -456,19 +1335,119 @@ impl CcrApp {
 
     /// Redraw the UI
     fn redraw(&mut self) {
+        if self.screen.is_none() {
+            self.redraw_console();
+            return;
+        }
         self.clear_area();
 
         match self.ui.view {
-            ViewMode::Chat => self.redraw_chat(),
+            ViewMode::Chat => {
+                self.redraw_chat();
+                self.redraw_pinned();
+            }
             ViewMode::Detail => self.redraw_detail(),
+            ViewMode::Stats => self.redraw_stats(),
             ViewMode::Permission => {
                 // Permissions shown inline in Chat view
                 self.ui.view = ViewMode::Chat;
                 self.redraw_chat();
             }
+            ViewMode::QuickReply => {
+                self.redraw_chat();
+                self.redraw_quick_replies();
+            }
+        }
+
+        self.gam().redraw().expect("Could not redraw screen");
+    }
+
+    /// Bubble contents for an event: (text, is_user_input, font_style)
+    fn bubble_text(&self, event: &CcrEvent) -> (String, bool, GlyphStyle) {
+        // Use Regular for most content, Bold only for short titles
+        match event {
+            CcrEvent::SessionStart { source, .. } => {
+                (format!("Session {}", source), false, GlyphStyle::Regular)
+            }
+            CcrEvent::SessionEnd { reason, .. } => (format!("End: {}", reason), false, GlyphStyle::Regular),
+            CcrEvent::Stop { .. } => (String::from("Stopped"), false, GlyphStyle::Regular),
+            CcrEvent::UserInput { text, .. } => {
+                (truncate_str(text, 40).to_string(), true, GlyphStyle::Regular)
+            }
+            CcrEvent::ToolCall { tool, args, .. } => {
+                // Tool name as title, args on next line in regular font
+                (format!("{}\n{}", tool, truncate_str(args, 30)), false, GlyphStyle::Regular)
+            }
+            CcrEvent::ToolResult { output, .. } => {
+                (truncate_str(output, 35).to_string(), false, GlyphStyle::Monospace)
+            }
+            CcrEvent::PermissionPending { request_id, tool, command, .. } => {
+                // Permission request - render like other events
+                let mut text = format!("PERMISSION: {}\n{}", tool, truncate_str(command, 30));
+                if self.chord_approval && self.ui.pending_permission.as_deref() == Some(request_id.as_str()) {
+                    text.push_str("\nHold F1 + center to allow");
+                }
+                (text, false, GlyphStyle::Regular)
+            }
+            CcrEvent::PermissionResolved { decision, .. } => {
+                (format!("Permission {}", decision), false, GlyphStyle::Regular)
+            }
+            CcrEvent::PermissionTimeout { .. } => {
+                (String::from("Permission timeout"), false, GlyphStyle::Regular)
+            }
+            CcrEvent::Notification { message, .. } => {
+                (truncate_str(message, 35).to_string(), false, GlyphStyle::Regular)
+            }
+            CcrEvent::Status { connected, message } => {
+                let status = if *connected { "Connected" } else { "Disconnected" };
+                (format!("{}: {}", status, truncate_str(message, 25)), false, GlyphStyle::Regular)
+            }
+        }
+    }
+
+    /// Write the current view to the log, for running without a display
+    fn redraw_console(&mut self) {
+        let no_selection = self.events.get(self.ui.selected).is_none();
+        if self.ui.view == ViewMode::Permission || (self.ui.view == ViewMode::Detail && no_selection) {
+            self.ui.view = ViewMode::Chat;
+        }
+
+        let mut view = String::new();
+        match self.ui.view {
+            ViewMode::Detail => {
+                if let Some(event) = self.events.get(self.ui.selected) {
+                    view.push_str(&ui_improved::render_event_detail(event));
+                }
+            }
+            ViewMode::Stats => view.push_str(&ui_improved::render_stats(&self.latency, self.events.len())),
+            ViewMode::QuickReply => {
+                view.push_str("Quick reply");
+                for (i, template) in self.quick_replies.iter().enumerate() {
+                    write!(view, "\nF{}  {}", i + 1, template).ok();
+                }
+            }
+            ViewMode::Chat | ViewMode::Permission => {
+                let status = if self.ui.connected { "connected" } else { "waiting" };
+                write!(view, "CCR: {}", status).ok();
+                if self.ui.dnd_active {
+                    view.push_str(" DnD");
+                }
+                for i in self.events.len().saturating_sub(CONSOLE_EVENTS)..self.events.len() {
+                    if let Some(event) = self.events.get(i) {
+                        let (text, is_user_input, _) = self.bubble_text(event);
+                        let marker = if self.ui.is_selected(i) { '*' } else { ' ' };
+                        let arrow = if is_user_input { "> " } else { "" };
+                        write!(view, "\n{} {}{}", marker, arrow, text.replace('\n', " | ")).ok();
+                    }
+                }
+            }
         }
 
-        self.gam.redraw().expect("Could not redraw screen");
+        // Redraws follow every message; only log what changed
+        if view != self.console_view {
+            log::info!("CCR view:\n{}", view);
+            self.console_view = view;
+        }
     }
 
     /// Redraw chat view with bubbles
@@ -476,8 +1455,8 @@ impl CcrApp {
         // Use clear_area on canvas to avoid dirty rendering
         self.clear_area();
 
-        // Start from bottom of content area, grow upward
-        let mut bubble_baseline = self.screensize.y - MARGIN_Y;
+        // Start from bottom of the chat area, grow upward
+        let mut bubble_baseline = self.chat_bottom() - MARGIN_Y;
 
         // Track if there are more events above (older) that aren't shown
         let mut has_more_above = false;
@@ -501,51 +1480,13 @@ impl CcrApp {
 
             first_shown_idx = Some(i);
 
-            // (text, is_user_input, border_width, font_style)
-            // Use Regular for most content, Bold only for short titles
-            let (text, is_user_input, border_width, font_style) = match event {
-                CcrEvent::SessionStart { source, .. } => {
-                    (format!("Session {}", source), false, 1, GlyphStyle::Regular)
-                }
-                CcrEvent::SessionEnd { reason, .. } => {
-                    (format!("End: {}", reason), false, 1, GlyphStyle::Regular)
-                }
-                CcrEvent::Stop { .. } => {
-                    (String::from("Stopped"), false, 1, GlyphStyle::Regular)
-                }
-                CcrEvent::UserInput { text, .. } => {
-                    (truncate_str(text, 40).to_string(), true, 1, GlyphStyle::Regular)
-                }
-                CcrEvent::ToolCall { tool, args, .. } => {
-                    // Tool name as title, args on next line in regular font
-                    (format!("{}\n{}", tool, truncate_str(args, 30)), false, 1, GlyphStyle::Regular)
-                }
-                CcrEvent::ToolResult { output, .. } => {
-                    (truncate_str(output, 35).to_string(), false, 1, GlyphStyle::Monospace)
-                }
-                CcrEvent::PermissionPending { tool, command, .. } => {
-                    // Permission request - render like other events
-                    (format!("PERMISSION: {}\n{}", tool, truncate_str(command, 30)), false, 1, GlyphStyle::Regular)
-                }
-                CcrEvent::PermissionResolved { decision, .. } => {
-                    (format!("Permission {}", decision), false, 1, GlyphStyle::Regular)
-                }
-                CcrEvent::PermissionTimeout { .. } => {
-                    (String::from("Permission timeout"), false, 1, GlyphStyle::Regular)
-                }
-                CcrEvent::Notification { message, .. } => {
-                    (truncate_str(message, 35).to_string(), false, 1, GlyphStyle::Regular)
-                }
-                CcrEvent::Status { connected, message } => {
-                    let status = if *connected { "Connected" } else { "Disconnected" };
-                    (format!("{}: {}", status, truncate_str(message, 25)), false, 1, GlyphStyle::Regular)
-                }
-            };
+            let (text, is_user_input, font_style) = self.bubble_text(event);
+            let border_width = 1;
 
             // Create bubble - right-align for user input, left-align for others
             let mut bubble_tv = if is_user_input {
                 TextView::new(
-                    self.content,
+                    self.content(),
                     TextBounds::GrowableFromBr(
                         Point::new(self.screensize.x - MARGIN_X, bubble_baseline),
                         self.bubble_width,
@@ -553,11 +1494,8 @@ impl CcrApp {
                 )
             } else {
                 TextView::new(
-                    self.content,
-                    TextBounds::GrowableFromBl(
-                        Point::new(MARGIN_X, bubble_baseline),
-                        self.bubble_width,
-                    ),
+                    self.content(),
+                    TextBounds::GrowableFromBl(Point::new(MARGIN_X, bubble_baseline), self.bubble_width),
                 )
             };
 
@@ -573,7 +1511,7 @@ impl CcrApp {
                 bubble_tv.border_width = 2;
             }
             write!(bubble_tv.text, "{}", text).ok();
-            self.gam.post_textview(&mut bubble_tv).expect("couldn't render bubble");
+            self.gam().post_textview(&mut bubble_tv).expect("couldn't render bubble");
 
             if let Some(bounds) = bubble_tv.bounds_computed {
                 bubble_baseline -= (bounds.br.y - bounds.tl.y) + BUBBLE_SPACE + self.bubble_margin.y;
@@ -583,7 +1521,7 @@ impl CcrApp {
         // Show "more" indicator at top if there are hidden events
         if has_more_above {
             let mut more_tv = TextView::new(
-                self.content,
+                self.content(),
                 TextBounds::GrowableFromTl(
                     Point::new(MARGIN_X, MARGIN_Y),
                     (self.screensize.x - MARGIN_X * 2) as u16,
@@ -593,13 +1531,37 @@ impl CcrApp {
             more_tv.draw_border = false;
             more_tv.clear_area = false;
             write!(more_tv.text, "> more").ok();
-            self.gam.post_textview(&mut more_tv).expect("couldn't render more indicator");
+            self.gam().post_textview(&mut more_tv).expect("couldn't render more indicator");
+        }
+
+        // Show DnD and latency indicators at top right
+        let mut indicators = String::new();
+        if self.ui.dnd_active {
+            indicators.push_str("DnD");
+        }
+        if self.latency.is_slow() {
+            let ms = self.latency.last().unwrap_or(0);
+            if !indicators.is_empty() {
+                indicators.push(' ');
+            }
+            write!(indicators, "LAG {}.{}s", ms / 1000, (ms % 1000) / 100).ok();
+        }
+        if !indicators.is_empty() {
+            let mut status_tv = TextView::new(
+                self.content(),
+                TextBounds::GrowableFromTr(Point::new(self.screensize.x - MARGIN_X, MARGIN_Y), 120),
+            );
+            status_tv.style = GlyphStyle::Small;
+            status_tv.draw_border = false;
+            status_tv.clear_area = true;
+            write!(status_tv.text, "{}", indicators).ok();
+            self.gam().post_textview(&mut status_tv).expect("couldn't render status indicators");
         }
 
         // If no events, show waiting message
         if self.events.is_empty() {
             let mut wait_tv = TextView::new(
-                self.content,
+                self.content(),
                 TextBounds::CenteredTop(Rectangle::new(
                     Point::new(0, self.screensize.y / 3),
                     Point::new(self.screensize.x, self.screensize.y / 3 + 40),
@@ -609,8 +1571,80 @@ impl CcrApp {
             wait_tv.draw_border = false;
             let status = if self.ui.connected { "connected" } else { "waiting" };
             write!(wait_tv.text, "CCR: {}", status).ok();
-            self.gam.post_textview(&mut wait_tv).expect("couldn't render wait text");
+            self.gam().post_textview(&mut wait_tv).expect("couldn't render wait text");
+        }
+    }
+
+    /// Bottom edge of the chat stream: the whole canvas, or the top pane in split view
+    fn chat_bottom(&self) -> isize {
+        if self.ui.split {
+            self.screensize.y - self.screensize.y * SPLIT_DETAIL_PERCENT / 100
+        } else {
+            self.screensize.y
+        }
+    }
+
+    /// Draw the pinned event's detail in the bottom pane of the split view
+    fn redraw_pinned(&mut self) {
+        if !self.ui.split {
+            return;
+        }
+        let top = self.chat_bottom();
+        self.gam()
+            .draw_line(
+                self.content(),
+                Line::new_with_style(
+                    Point::new(0, top),
+                    Point::new(self.screensize.x, top),
+                    DrawStyle::new(PixelColor::Dark, PixelColor::Dark, 1),
+                ),
+            )
+            .expect("can't draw split divider");
+
+        // Clipped to the pane; the full text is one Right press away in the detail view
+        let mut pane_tv = TextView::new(
+            self.content(),
+            TextBounds::BoundingBox(Rectangle::new(
+                Point::new(MARGIN_X, top + MARGIN_Y),
+                Point::new(self.screensize.x - MARGIN_X, self.screensize.y - MARGIN_Y),
+            )),
+        );
+        pane_tv.style = GlyphStyle::Regular;
+        pane_tv.draw_border = false;
+        pane_tv.clear_area = true;
+        pane_tv.ellipsis = true;
+        match &self.ui.pinned {
+            Some(event) => write!(pane_tv.text, "{}", ui_improved::render_event_detail(event)).ok(),
+            None => write!(pane_tv.text, "\u{2192} pins the selected event here").ok(),
+        };
+        self.gam().post_textview(&mut pane_tv).expect("couldn't render pinned detail");
+    }
+
+    /// Draw the quick-reply picker over the top of the chat view
+    fn redraw_quick_replies(&mut self) {
+        let mut picker_tv = TextView::new(
+            self.content(),
+            TextBounds::GrowableFromTl(
+                Point::new(MARGIN_X, MARGIN_Y),
+                (self.screensize.x - MARGIN_X * 2) as u16,
+            ),
+        );
+        picker_tv.style = GlyphStyle::Regular;
+        picker_tv.border_width = 2;
+        picker_tv.draw_border = true;
+        picker_tv.clear_area = true;
+        picker_tv.rounded_border = Some(BUBBLE_RADIUS);
+        picker_tv.margin = self.bubble_margin;
+
+        write!(picker_tv.text, "Quick reply").ok();
+        if self.quick_replies.is_empty() {
+            write!(picker_tv.text, "\n(none - use /reply <n> <text>)").ok();
+        }
+        for (i, template) in self.quick_replies.iter().enumerate() {
+            write!(picker_tv.text, "\nF{}  {}", i + 1, truncate_str(template, 35)).ok();
         }
+        write!(picker_tv.text, "\n\u{2190} cancel").ok();
+        self.gam().post_textview(&mut picker_tv).expect("couldn't render quick reply picker");
     }
 
     /// Redraw detail view
@@ -631,7 +1665,7 @@ impl CcrApp {
         let detail = ui_improved::render_event_detail(event);
 
         let mut text_view = TextView::new(
-            self.content,
+            self.content(),
             TextBounds::GrowableFromTl(
                 Point::new(MARGIN_X, MARGIN_Y),
                 (self.screensize.x - MARGIN_X * 2) as u16,
@@ -646,15 +1680,32 @@ impl CcrApp {
         text_view.margin = self.bubble_margin;
 
         write!(text_view.text, "{}", detail).ok();
-        self.gam.post_textview(&mut text_view).expect("Could not render detail view");
+        self.gam().post_textview(&mut text_view).expect("Could not render detail view");
+    }
+
+    /// Redraw stats view
+    fn redraw_stats(&mut self) {
+        let mut text_view = TextView::new(
+            self.content(),
+            TextBounds::GrowableFromTl(
+                Point::new(MARGIN_X, MARGIN_Y),
+                (self.screensize.x - MARGIN_X * 2) as u16,
+            ),
+        );
+        text_view.style = GlyphStyle::Monospace;
+        text_view.border_width = 1;
+        text_view.draw_border = true;
+        text_view.clear_area = true;
+        text_view.rounded_border = Some(BUBBLE_RADIUS);
+        text_view.margin = self.bubble_margin;
+
+        write!(text_view.text, "{}", ui_improved::render_stats(&self.latency, self.events.len())).ok();
+        self.gam().post_textview(&mut text_view).expect("Could not render stats view");
     }
 
     /// Add demo events for testing
     fn add_demo_events(&mut self) {
-        self.handle_event(CcrEvent::Status {
-            connected: true,
-            message: String::from("Demo mode - no MQTT"),
-        });
+        self.handle_event(CcrEvent::Status { connected: true, message: String::from("Demo mode - no MQTT") });
 
         self.handle_event(CcrEvent::SessionStart {
             session_id: String::from("demo-session-123"),
@@ -696,18 +1747,111 @@ impl CcrApp {
     }
 }
 
+/// Load quick replies and the DnD schedule from user preferences
+fn load_settings(prefs: &userprefs::Manager) -> (QuickReplies, Option<DndSchedule>) {
+    // A missing record falls back to the default templates; an empty one means the user removed them all
+    let quick_replies = prefs
+        .ccr_quick_replies_or_value(QuickReplies::default().to_settings())
+        .map(|text| QuickReplies::from_settings(&text))
+        .unwrap_or_default();
+    let dnd = prefs.ccr_dnd_or_default().ok().and_then(|text| DndSchedule::parse(&text));
+    (quick_replies, dnd)
+}
+
+/// Load the policy signing key and the stored policy, dropping the policy
+/// if it doesn't verify against the key
+fn load_policy(prefs: &userprefs::Manager) -> (Option<ed25519_dalek::VerifyingKey>, Policy) {
+    let key = match prefs.ccr_policy_key_or_default() {
+        Ok(text) if !text.is_empty() => policy::parse_key(&text).ok(),
+        _ => None,
+    };
+    let policy = match (&key, prefs.ccr_policy_or_default()) {
+        (Some(key), Ok(document)) if !document.is_empty() => {
+            Policy::verify(&document, key).unwrap_or_else(|e| {
+                log::warn!("CCR: Stored policy rejected: {:?}", e);
+                Policy::default()
+            })
+        }
+        _ => Policy::default(),
+    };
+    (key, policy)
+}
+
+/// Load the device's end-to-end key, making one on first run, and the bridge key
+fn load_e2e(store: &storage::Store, prefs: &userprefs::Manager) -> e2e::Link {
+    let device_key = match store.read(storage::CCR_KEY_DICT, E2E_DEVICE_KEY) {
+        Ok(bytes) if bytes.len() == 32 => {
+            let mut secret = [0u8; 32];
+            secret.copy_from_slice(&bytes);
+            ccr_e2e::StaticSecret::from(secret)
+        }
+        _ => {
+            let secret = ccr_e2e::StaticSecret::random_from_rng(rand_core::OsRng);
+            if let Err(e) = store.write(storage::CCR_KEY_DICT, E2E_DEVICE_KEY, secret.as_bytes()) {
+                log::error!("CCR: Couldn't save E2E device key: {:?}", e);
+            }
+            secret
+        }
+    };
+    let link = e2e::Link::new(device_key, load_bridge_key(prefs));
+    log::info!("CCR: Device E2E key {}", hex::encode(link.device_public().as_bytes()));
+    link
+}
+
+/// Broker address from user preferences, with any provisioned credentials
+#[cfg(feature = "hosted")]
+fn load_broker(prefs: &userprefs::Manager, store: &storage::Store) -> BrokerSettings {
+    let address = match prefs.ccr_broker_or_default() {
+        Ok(broker) if !broker.is_empty() => broker,
+        _ => String::from(MQTT_BROKER),
+    };
+    let read = |key: &str| {
+        store
+            .read(storage::CCR_BROKER_DICT, key)
+            .ok()
+            .and_then(|value| String::from_utf8(value).ok())
+            .filter(|value| !value.is_empty())
+    };
+    BrokerSettings { address, username: read("username"), password: read("password") }
+}
+
+/// Seconds between heartbeats
+#[cfg(feature = "hosted")]
+fn load_heartbeat_secs(prefs: &userprefs::Manager) -> u64 {
+    match prefs.ccr_heartbeat_secs_or_default() {
+        Ok(secs) if secs > 0 => secs,
+        _ => heartbeat::DEFAULT_INTERVAL_SECS,
+    }
+}
+
+/// Provisioned bridge key, if any
+fn load_bridge_key(prefs: &userprefs::Manager) -> Option<ccr_e2e::PublicKey> {
+    match prefs.ccr_e2e_bridge_key_or_default() {
+        Ok(text) if !text.is_empty() => e2e::parse_key(&text).ok(),
+        _ => None,
+    }
+}
+
 /// MQTT background thread (hosted mode only)
 #[cfg(feature = "hosted")]
 fn mqtt_thread_main(
-    broker: &str,
     running: Arc<AtomicBool>,
+    net_available: Arc<AtomicBool>,
     state: Arc<Mutex<MqttThreadState>>,
     main_cid: xous::CID,
 ) {
-    log::info!("CCR MQTT: Thread started, connecting to {}", broker);
+    log::info!("CCR MQTT: Thread started");
 
     while running.load(Ordering::SeqCst) {
-        // Try to connect
+        // Don't wake the radio for connection attempts while it's off or suspending
+        if !net_available.load(Ordering::SeqCst) {
+            std::thread::sleep(Duration::from_secs(1));
+            continue;
+        }
+
+        // Try to connect, with whatever settings are current
+        let settings = state.lock().unwrap().broker.clone();
+        let broker = settings.address.as_str();
         match TcpStream::connect(broker) {
             Ok(mut stream) => {
                 log::info!("CCR MQTT: TCP connected to {}", broker);
@@ -717,9 +1861,19 @@ fn mqtt_thread_main(
                 stream.set_write_timeout(Some(Duration::from_millis(5000))).ok();
 
                 // Send CONNECT packet
-                let connect_packet = mqtt::build_connect_packet("ccr-precursor");
+                let connect_packet = match &settings.username {
+                    Some(username) => xous_mqtt::packet::build_connect_with_options(
+                        "ccr-precursor",
+                        Some(username.as_str()),
+                        settings.password.as_ref().map(|p| p.as_bytes()),
+                        true,
+                        60,
+                    ),
+                    None => mqtt::build_connect_packet("ccr-precursor"),
+                };
                 if let Err(e) = stream.write_all(&connect_packet) {
                     log::error!("CCR MQTT: Failed to send CONNECT: {:?}", e);
+                    record_error(&state, format!("Failed to send CONNECT: {}", e));
                     std::thread::sleep(Duration::from_secs(5));
                     continue;
                 }
@@ -733,40 +1887,34 @@ fn mqtt_thread_main(
                             log::info!("CCR MQTT: CONNACK received, connected!");
                         } else {
                             log::error!("CCR MQTT: CONNACK rejected");
+                            record_error(&state, String::from("Broker refused the connection"));
                             std::thread::sleep(Duration::from_secs(5));
                             continue;
                         }
                     }
                     Err(e) => {
                         log::error!("CCR MQTT: Failed to read CONNACK: {:?}", e);
+                        record_error(&state, format!("No CONNACK: {}", e));
                         std::thread::sleep(Duration::from_secs(5));
                         continue;
                     }
                 }
 
-                // Subscribe to topics
+                // Subscribe to all topics in one round-trip
                 {
                     let mut st = state.lock().unwrap();
-
-                    // Subscribe to events topic
-                    let packet_id = st.next_packet_id();
-                    let sub_packet = mqtt::build_subscribe_packet(packet_id, TOPIC_EVENTS);
-                    if let Err(e) = stream.write_all(&sub_packet) {
-                        log::error!("CCR MQTT: Failed to send SUBSCRIBE: {:?}", e);
-                        continue;
-                    }
-                    stream.flush().ok();
-                    log::info!("CCR MQTT: Subscribed to {}", TOPIC_EVENTS);
-
-                    // Subscribe to permission requests topic
                     let packet_id = st.next_packet_id();
-                    let sub_packet = mqtt::build_subscribe_packet(packet_id, TOPIC_PERM_REQUEST);
+                    let sub_packet = mqtt::build_subscribe_packet(packet_id, &SUBSCRIBE_TOPICS);
                     if let Err(e) = stream.write_all(&sub_packet) {
                         log::error!("CCR MQTT: Failed to send SUBSCRIBE: {:?}", e);
+                        st.last_error = Some(format!("Failed to send SUBSCRIBE: {}", e));
+                        // Not holding the state lock while backing off
+                        drop(st);
+                        std::thread::sleep(Duration::from_secs(5));
                         continue;
                     }
                     stream.flush().ok();
-                    log::info!("CCR MQTT: Subscribed to {}", TOPIC_PERM_REQUEST);
+                    log::info!("CCR MQTT: Subscribing to {:?} (id={})", SUBSCRIBE_TOPICS, packet_id);
                 }
 
                 // Update state and notify main thread
@@ -774,6 +1922,7 @@ fn mqtt_thread_main(
                     let mut st = state.lock().unwrap();
                     st.stream = Some(stream.try_clone().expect("Failed to clone stream"));
                     st.connected = true;
+                    st.last_error = None;
                 }
                 notify_main_connected(main_cid, true);
 
@@ -782,11 +1931,12 @@ fn mqtt_thread_main(
                 let mut last_ping = std::time::Instant::now();
                 let ping_interval = Duration::from_secs(30);
 
-                while running.load(Ordering::SeqCst) {
+                while running.load(Ordering::SeqCst) && net_available.load(Ordering::SeqCst) {
                     // Try to read data
                     match stream.read(&mut read_buf) {
                         Ok(0) => {
                             log::info!("CCR MQTT: Connection closed by broker");
+                            record_error(&state, String::from("Connection closed by broker"));
                             break;
                         }
                         Ok(n) => {
@@ -797,8 +1947,19 @@ fn mqtt_thread_main(
                                 send_mqtt_message_to_main(main_cid, &topic, &payload_str);
                             } else if mqtt::is_pingresp(data) {
                                 log::debug!("CCR MQTT: PINGRESP received");
-                            } else if mqtt::is_suback(data) {
-                                log::debug!("CCR MQTT: SUBACK received");
+                            } else if let Some((packet_id, codes)) = mqtt::parse_suback_packet(data) {
+                                for (topic, &code) in SUBSCRIBE_TOPICS.iter().zip(codes) {
+                                    if code & 0x80 != 0 {
+                                        log::warn!("CCR MQTT: Broker refused subscription to {}", topic);
+                                    } else {
+                                        log::info!(
+                                            "CCR MQTT: Subscribed to {} (QoS {}, id={})",
+                                            topic,
+                                            code,
+                                            packet_id
+                                        );
+                                    }
+                                }
                             }
                         }
                         Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
@@ -809,6 +1970,7 @@ fn mqtt_thread_main(
                         }
                         Err(e) => {
                             log::error!("CCR MQTT: Read error: {:?}", e);
+                            record_error(&state, format!("Read error: {}", e));
                             break;
                         }
                     }
@@ -818,6 +1980,7 @@ fn mqtt_thread_main(
                         let ping_packet = mqtt::build_pingreq_packet();
                         if let Err(e) = stream.write_all(&ping_packet) {
                             log::error!("CCR MQTT: Failed to send PINGREQ: {:?}", e);
+                            record_error(&state, format!("Failed to send PINGREQ: {}", e));
                             break;
                         }
                         stream.flush().ok();
@@ -839,6 +2002,7 @@ fn mqtt_thread_main(
             }
             Err(e) => {
                 log::warn!("CCR MQTT: Connection failed: {:?}", e);
+                record_error(&state, format!("Can't reach {}: {}", broker, e));
             }
         }
 
@@ -851,6 +2015,14 @@ fn mqtt_thread_main(
     log::info!("CCR MQTT: Thread exiting");
 }
 
+/// Keep `error` for the status service to show
+#[cfg(feature = "hosted")]
+fn record_error(state: &Mutex<MqttThreadState>, error: String) {
+    if let Ok(mut st) = state.lock() {
+        st.last_error = Some(error);
+    }
+}
+
 /// Notify main thread of connection status change
 #[cfg(feature = "hosted")]
 fn notify_main_connected(main_cid: xous::CID, connected: bool) {
@@ -859,7 +2031,9 @@ fn notify_main_connected(main_cid: xous::CID, connected: bool) {
         xous::Message::new_scalar(
             CcrOp::MqttMessage.to_usize().unwrap(),
             if connected { 1 } else { 0 },
-            0, 0, 0,
+            0,
+            0,
+            0,
         ),
     );
 }
@@ -892,7 +2066,7 @@ fn main() -> ! {
     log::info!("CCR: Subscribe to {} and {}", TOPIC_EVENTS, TOPIC_PERM_REQUEST);
 
     loop {
-        let msg = xous::receive_message(sid).unwrap();
+        let mut msg = xous::receive_message(sid).unwrap();
 
         match FromPrimitive::from_usize(msg.body.id()) {
             Some(CcrOp::Redraw) => {
@@ -901,7 +2075,8 @@ fn main() -> ! {
             }
             Some(CcrOp::Line) => {
                 // A line of text from IME
-                let buffer = unsafe { xous_ipc::Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let buffer =
+                    unsafe { xous_ipc::Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
                 let line = buffer.as_flat::<String, _>().unwrap();
                 log::info!("CCR: Got input line: {}", line.as_str());
                 app.handle_line(line.as_str());
@@ -910,16 +2085,14 @@ fn main() -> ! {
             Some(CcrOp::RawKey) => {
                 // Raw key event for d-pad navigation
                 xous::msg_scalar_unpack!(msg, k1, k2, k3, k4, {
-                    let keys = [
-                        core::char::from_u32(k1 as u32),
-                        core::char::from_u32(k2 as u32),
-                        core::char::from_u32(k3 as u32),
-                        core::char::from_u32(k4 as u32),
-                    ];
-                    for key in keys.iter().flatten() {
+                    let mut keys = [' '; 4];
+                    let mut count = 0;
+                    for key in [k1, k2, k3, k4].iter().filter_map(|&k| core::char::from_u32(k as u32)) {
                         log::debug!("CCR: RawKey '{}'", key);
-                        app.handle_rawkey(*key);
+                        keys[count] = key;
+                        count += 1;
                     }
+                    app.handle_rawkeys(&keys[..count]);
                 });
                 app.redraw();
             }
@@ -928,7 +2101,11 @@ fn main() -> ! {
                     xous::Message::Scalar(scalar) => {
                         // Connection status change (arg1: 1=connected, 0=disconnected)
                         let connected = scalar.arg1 != 0;
-                        log::info!("CCR: MQTT connection status: {}", if connected { "connected" } else { "disconnected" });
+                        log::info!(
+                            "CCR: MQTT connection status: {}",
+                            if connected { "connected" } else { "disconnected" }
+                        );
+                        app.set_mqtt_connected(connected);
                         app.handle_event(CcrEvent::Status {
                             connected,
                             message: if connected {
@@ -956,8 +2133,39 @@ fn main() -> ! {
                 }
             }
             Some(CcrOp::Tick) => {
-                // Tick is handled by MQTT thread in hosted mode
+                app.release_orphans();
+                app.redraw();
+            }
+            Some(CcrOp::SettingsChanged) => {
+                app.reload_settings();
+                app.redraw();
+            }
+            Some(CcrOp::Connectivity) => xous::msg_scalar_unpack!(msg, state, _, _, _, {
+                if let Some(connectivity) = FromPrimitive::from_usize(state) {
+                    app.set_connectivity(connectivity);
+                }
+            }),
+            Some(CcrOp::PostNotification) => {
+                let buffer =
+                    unsafe { xous_ipc::Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                if let Ok(data) = buffer.to_original::<String, _>() {
+                    let (source, message) = data.split_once('\0').unwrap_or(("external", data.as_str()));
+                    log::info!("CCR: Notification from {}", source);
+                    app.notify(&sanitize_text(source), &sanitize_text(message));
+                    app.redraw();
+                }
+            }
+            Some(CcrOp::PendingPermissions) => xous::msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                xous::return_scalar(msg.sender, app.events.pending_permissions())
+                    .expect("couldn't return PendingPermissions");
+            }),
+            Some(CcrOp::Heartbeat) => app.publish_heartbeat(),
+            Some(CcrOp::MqttStatus) => {
+                let memory = msg.body.memory_message_mut().unwrap();
+                let mut buffer = unsafe { xous_ipc::Buffer::from_memory_message_mut(memory) };
+                buffer.replace(app.mqtt_status().encode()).expect("couldn't return MqttStatus");
             }
+            Some(CcrOp::Reconnect) => app.reconnect(),
             Some(CcrOp::Quit) => {
                 log::info!("CCR: Quitting");
                 break;
@@ -966,6 +2174,7 @@ fn main() -> ! {
                 log::debug!("CCR: Unknown message");
             }
         }
+        app.publish_pending();
     }
 
     xous::terminate_process(0)
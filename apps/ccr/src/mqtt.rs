@@ -50,8 +50,8 @@ pub fn build_connect_packet(client_id: &str) -> Vec<u8> {
     packet
 }
 
-/// Build MQTT SUBSCRIBE packet
-pub fn build_subscribe_packet(packet_id: u16, topic: &str) -> Vec<u8> {
+/// Build MQTT SUBSCRIBE packet for one or more topics, all at QoS 0
+pub fn build_subscribe_packet(packet_id: u16, topics: &[&str]) -> Vec<u8> {
     let mut packet = Vec::new();
 
     // Variable header (packet ID)
@@ -59,12 +59,14 @@ pub fn build_subscribe_packet(packet_id: u16, topic: &str) -> Vec<u8> {
     var_header.push((packet_id >> 8) as u8);
     var_header.push((packet_id & 0xFF) as u8);
 
-    // Payload (topic filter + QoS)
+    // Payload (topic filter + QoS for each topic)
     let mut payload = Vec::new();
-    payload.push((topic.len() >> 8) as u8);
-    payload.push((topic.len() & 0xFF) as u8);
-    payload.extend_from_slice(topic.as_bytes());
-    payload.push(0x00); // QoS 0
+    for topic in topics {
+        payload.push((topic.len() >> 8) as u8);
+        payload.push((topic.len() & 0xFF) as u8);
+        payload.extend_from_slice(topic.as_bytes());
+        payload.push(0x00); // QoS 0
+    }
 
     // Fixed header
     let remaining_len = var_header.len() + payload.len();
@@ -92,7 +94,7 @@ pub fn build_publish_packet(topic: &str, payload: &[u8], packet_id: u16) -> Vec<
 
     // Fixed header (PUBLISH with QoS 1)
     let remaining_len = var_header.len() + payload.len();
-    packet.push((PUBLISH << 4) | 0x02);  // QoS 1
+    packet.push((PUBLISH << 4) | 0x02); // QoS 1
     encode_remaining_length(&mut packet, remaining_len);
 
     packet.extend(var_header);
@@ -101,14 +103,10 @@ pub fn build_publish_packet(topic: &str, payload: &[u8], packet_id: u16) -> Vec<
 }
 
 /// Build MQTT PINGREQ packet
-pub fn build_pingreq_packet() -> Vec<u8> {
-    vec![PINGREQ << 4, 0x00]
-}
+pub fn build_pingreq_packet() -> Vec<u8> { vec![PINGREQ << 4, 0x00] }
 
 /// Parse MQTT packet type from first byte
-pub fn packet_type(byte: u8) -> u8 {
-    byte >> 4
-}
+pub fn packet_type(byte: u8) -> u8 { byte >> 4 }
 
 /// Parse MQTT PUBLISH packet, returns (topic, payload)
 pub fn parse_publish_packet(packet: &[u8]) -> Option<(String, Vec<u8>)> {
@@ -144,20 +142,28 @@ pub fn parse_publish_packet(packet: &[u8]) -> Option<(String, Vec<u8>)> {
 
 /// Check if packet is CONNACK with success
 pub fn is_connack_success(packet: &[u8]) -> bool {
-    packet.len() >= 4
-        && packet_type(packet[0]) == CONNACK
-        && packet[3] == 0x00 // Return code 0 = accepted
+    packet.len() >= 4 && packet_type(packet[0]) == CONNACK && packet[3] == 0x00 // Return code 0 = accepted
 }
 
-/// Check if packet is SUBACK
-pub fn is_suback(packet: &[u8]) -> bool {
-    packet.len() >= 2 && packet_type(packet[0]) == SUBACK
+/// Parse MQTT SUBACK packet, returns (packet ID, return code per topic)
+///
+/// Return codes are the granted QoS, or 0x80 where the broker refused the
+/// topic, in the order the topics were requested.
+pub fn parse_suback_packet(packet: &[u8]) -> Option<(u16, &[u8])> {
+    if packet.is_empty() || packet_type(packet[0]) != SUBACK {
+        return None;
+    }
+    let (remaining_len, len_bytes) = decode_remaining_length(&packet[1..])?;
+    let body = packet.get(1 + len_bytes..1 + len_bytes + remaining_len)?;
+    if body.len() < 3 {
+        return None;
+    }
+    let packet_id = ((body[0] as u16) << 8) | (body[1] as u16);
+    Some((packet_id, &body[2..]))
 }
 
 /// Check if packet is PINGRESP
-pub fn is_pingresp(packet: &[u8]) -> bool {
-    packet.len() >= 2 && packet_type(packet[0]) == PINGRESP
-}
+pub fn is_pingresp(packet: &[u8]) -> bool { packet.len() >= 2 && packet_type(packet[0]) == PINGRESP }
 
 /// Encode remaining length (MQTT variable length encoding)
 fn encode_remaining_length(packet: &mut Vec<u8>, mut len: usize) {
@@ -0,0 +1,135 @@
+//! CCR Tool Call/Result Pairing
+//!
+//! Events arrive at QoS 0, and a lossy bridge can deliver a tool's result
+//! before the call that produced it. Shown as they come, the output would
+//! sit above the command it belongs to. A result whose call hasn't been seen
+//! is held here for up to [`ORPHAN_HOLD_MS`]; if the call turns up it goes
+//! into the timeline straight after it, otherwise it is shown on its own.
+
+extern crate alloc;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::events::CcrEvent;
+
+/// How long a result waits for its call before it is shown without it
+pub const ORPHAN_HOLD_MS: u64 = 2000;
+
+/// Results held at once; the oldest is released to make room
+pub const MAX_ORPHANS: usize = 8;
+
+/// Call ids remembered, so their results pass straight through
+const RECENT_CALLS: usize = 16;
+
+/// Tool results held until their call arrives
+#[derive(Default)]
+pub struct ToolPairing {
+    /// Results waiting for a call, with the time they arrived
+    orphans: VecDeque<(CcrEvent, u64)>,
+    /// Ids of the most recent calls
+    calls: VecDeque<String>,
+}
+
+impl ToolPairing {
+    pub fn new() -> Self { Self::default() }
+
+    /// Note a tool call; returns its result if that arrived first
+    pub fn on_call(&mut self, id: &str) -> Option<CcrEvent> {
+        if id.is_empty() {
+            return None;
+        }
+        if self.calls.len() == RECENT_CALLS {
+            self.calls.pop_front();
+        }
+        self.calls.push_back(String::from(id));
+        let index = self.orphans.iter().position(|(event, _)| result_id(event) == Some(id))?;
+        self.orphans.remove(index).map(|(event, _)| event)
+    }
+
+    /// Offer a tool result that arrived at `now_ms`
+    ///
+    /// Returns the events to show now: the result itself if its call has been
+    /// seen, and any older result pushed out of a full hold.
+    pub fn on_result(&mut self, event: CcrEvent, now_ms: u64) -> Vec<CcrEvent> {
+        let known = match result_id(&event) {
+            Some(id) => id.is_empty() || self.calls.iter().any(|call| call == id),
+            None => true,
+        };
+        if known {
+            return alloc::vec![event];
+        }
+        let mut released = Vec::new();
+        if self.orphans.len() == MAX_ORPHANS {
+            released.extend(self.orphans.pop_front().map(|(event, _)| event));
+        }
+        self.orphans.push_back((event, now_ms));
+        released
+    }
+
+    /// Results that have waited [`ORPHAN_HOLD_MS`] by `now_ms`, oldest first
+    pub fn expired(&mut self, now_ms: u64) -> Vec<CcrEvent> {
+        let mut expired = Vec::new();
+        while let Some((_, held_ms)) = self.orphans.front() {
+            if now_ms.saturating_sub(*held_ms) < ORPHAN_HOLD_MS {
+                break;
+            }
+            expired.extend(self.orphans.pop_front().map(|(event, _)| event));
+        }
+        expired
+    }
+
+    /// Whether any result is waiting for its call
+    pub fn is_holding(&self) -> bool { !self.orphans.is_empty() }
+}
+
+fn result_id(event: &CcrEvent) -> Option<&str> {
+    match event {
+        CcrEvent::ToolResult { id, .. } => Some(id.as_str()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(id: &str) -> CcrEvent {
+        CcrEvent::ToolResult { id: String::from(id), output: String::from("ok"), session_id: String::new() }
+    }
+
+    #[test]
+    fn test_result_before_call() {
+        let mut pairing = ToolPairing::new();
+        assert!(pairing.on_result(result("t1"), 0).is_empty());
+        assert!(pairing.is_holding());
+        assert!(matches!(pairing.on_call("t1"), Some(CcrEvent::ToolResult { id, .. }) if id == "t1"));
+        assert!(!pairing.is_holding());
+
+        // Once the call is known its result isn't held
+        assert_eq!(pairing.on_result(result("t1"), 10).len(), 1);
+        assert_eq!(pairing.on_result(result(""), 10).len(), 1);
+    }
+
+    #[test]
+    fn test_orphan_expires() {
+        let mut pairing = ToolPairing::new();
+        pairing.on_result(result("lost"), 100);
+        pairing.on_result(result("late"), 1500);
+        assert!(pairing.expired(ORPHAN_HOLD_MS).is_empty());
+        let expired = pairing.expired(100 + ORPHAN_HOLD_MS);
+        assert!(matches!(&expired[..], [CcrEvent::ToolResult { id, .. }] if id == "lost"));
+        assert!(pairing.on_call("lost").is_none());
+        assert!(pairing.on_call("late").is_some());
+    }
+
+    #[test]
+    fn test_full_hold_releases_oldest() {
+        let mut pairing = ToolPairing::new();
+        for i in 0..MAX_ORPHANS {
+            assert!(pairing.on_result(result(&alloc::format!("t{}", i)), 0).is_empty());
+        }
+        let released = pairing.on_result(result("extra"), 0);
+        assert!(matches!(&released[..], [CcrEvent::ToolResult { id, .. }] if id == "t0"));
+    }
+}
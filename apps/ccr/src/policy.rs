@@ -0,0 +1,258 @@
+//! CCR Permission Policy
+//!
+//! Rules that answer permission requests without asking the user. The
+//! desktop pushes a signed policy document on `ccr/policy`; it is only
+//! applied if the Ed25519 signature checks out against the key held in the
+//! `ccr_policy_key` user preference and its version is newer than the one in
+//! force. The accepted document is kept in the `ccr_policy` preference and
+//! re-verified on every load.
+//!
+//! Document format: the first line is the hex signature over everything
+//! after that line's newline. The body holds a `version <n>` line followed by
+//! one rule per line, first match wins:
+//!
+//! ```text
+//! <128 hex digits>
+//! version 7
+//! # action tool command-pattern
+//! allow Read *
+//! allow Bash git status*
+//! deny * *rm -rf*
+//! ```
+//!
+//! The tool is matched exactly or with `*`; the command pattern is a glob
+//! where `*` matches any run of characters.
+
+extern crate alloc;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use ed25519_dalek::{PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH, Signature, Verifier, VerifyingKey};
+
+/// Upper bound on rules in one document
+pub const MAX_RULES: usize = 64;
+
+/// What a matching rule does with the request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+impl Action {
+    /// Decision string published on `ccr/permissions/response`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Allow => "allow",
+            Action::Deny => "deny",
+        }
+    }
+}
+
+/// One policy rule
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub action: Action,
+    pub tool: String,
+    pub pattern: String,
+}
+
+impl Rule {
+    fn matches(&self, tool: &str, command: &str) -> bool {
+        (self.tool == "*" || self.tool == tool) && glob_match(&self.pattern, command)
+    }
+}
+
+/// Why a policy document was refused
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PolicyError {
+    /// Malformed document or rule (line number, 1-based within the body)
+    Format(usize),
+    /// Too many rules
+    TooLarge,
+    /// Signature missing or doesn't verify
+    BadSignature,
+    /// Provisioned key isn't a valid Ed25519 public key
+    BadKey,
+    /// Version is not newer than the policy in force
+    Stale { current: u64, offered: u64 },
+}
+
+/// Rule set in force
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Policy {
+    version: u64,
+    rules: Vec<Rule>,
+}
+
+/// Rules added and removed by a policy update
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PolicyDiff {
+    pub added: usize,
+    pub removed: usize,
+}
+
+impl Policy {
+    /// Verify a signed document against `key` and parse its body
+    pub fn verify(document: &str, key: &VerifyingKey) -> Result<Self, PolicyError> {
+        let (signature, body) = document.split_once('\n').ok_or(PolicyError::BadSignature)?;
+        let mut bytes = [0u8; SIGNATURE_LENGTH];
+        hex::decode_to_slice(signature.trim(), &mut bytes).map_err(|_| PolicyError::BadSignature)?;
+        key.verify(body.as_bytes(), &Signature::from_bytes(&bytes)).map_err(|_| PolicyError::BadSignature)?;
+        Self::parse(body)
+    }
+
+    /// Parse an (already verified) document body
+    pub fn parse(body: &str) -> Result<Self, PolicyError> {
+        let mut version = None;
+        let mut rules = Vec::new();
+        for (index, line) in body.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (keyword, rest) = line.split_once(' ').ok_or(PolicyError::Format(index + 1))?;
+            let action = match keyword {
+                "version" if version.is_none() => {
+                    version = Some(rest.trim().parse().map_err(|_| PolicyError::Format(index + 1))?);
+                    continue;
+                }
+                "allow" => Action::Allow,
+                "deny" => Action::Deny,
+                _ => return Err(PolicyError::Format(index + 1)),
+            };
+            let (tool, pattern) = rest.trim().split_once(' ').ok_or(PolicyError::Format(index + 1))?;
+            if rules.len() == MAX_RULES {
+                return Err(PolicyError::TooLarge);
+            }
+            rules.push(Rule { action, tool: String::from(tool), pattern: String::from(pattern.trim()) });
+        }
+        Ok(Self { version: version.ok_or(PolicyError::Format(0))?, rules })
+    }
+
+    /// Decide a permission request, or `None` to ask the user
+    pub fn decide(&self, tool: &str, command: &str) -> Option<Action> {
+        self.rules.iter().find(|rule| rule.matches(tool, command)).map(|rule| rule.action)
+    }
+
+    /// Check that `newer` may replace this policy
+    pub fn check_upgrade(&self, newer: &Policy) -> Result<(), PolicyError> {
+        if newer.version <= self.version {
+            return Err(PolicyError::Stale { current: self.version, offered: newer.version });
+        }
+        Ok(())
+    }
+
+    /// Rules in `newer` that aren't in this policy, and vice versa
+    pub fn diff(&self, newer: &Policy) -> PolicyDiff {
+        PolicyDiff {
+            added: newer.rules.iter().filter(|r| !self.rules.contains(r)).count(),
+            removed: self.rules.iter().filter(|r| !newer.rules.contains(r)).count(),
+        }
+    }
+
+    pub fn version(&self) -> u64 { self.version }
+
+    pub fn rules(&self) -> &[Rule] { &self.rules }
+}
+
+impl PolicyDiff {
+    /// One-line description for the event list
+    pub fn summary(&self, policy: &Policy) -> String {
+        format!(
+            "Policy updated to v{}: +{} -{} ({} rules)",
+            policy.version(),
+            self.added,
+            self.removed,
+            policy.rules().len()
+        )
+    }
+}
+
+/// Parse a hex-encoded Ed25519 public key
+pub fn parse_key(text: &str) -> Result<VerifyingKey, PolicyError> {
+    let mut bytes = [0u8; PUBLIC_KEY_LENGTH];
+    hex::decode_to_slice(text.trim(), &mut bytes).map_err(|_| PolicyError::BadKey)?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| PolicyError::BadKey)
+}
+
+/// Match `text` against a glob where `*` matches any run of characters
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match text.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let last = match parts.pop() {
+        Some(last) => last,
+        // No `*` in the pattern: exact match
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use super::*;
+
+    fn sign(key: &SigningKey, body: &str) -> String {
+        format!("{}\n{}", hex::encode(key.sign(body.as_bytes()).to_bytes()), body)
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("git status*", "git status --short"));
+        assert!(glob_match("*rm -rf*", "cd /tmp && rm -rf x"));
+        assert!(glob_match("cargo * --release", "cargo build --release"));
+        assert!(!glob_match("git status", "git status --short"));
+        assert!(!glob_match("a*a", "a"));
+    }
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let policy = Policy::parse("version 1\ndeny * *rm -rf*\nallow Bash git *\nallow Read *\n").unwrap();
+        assert_eq!(policy.decide("Bash", "git rm -rf ."), Some(Action::Deny));
+        assert_eq!(policy.decide("Bash", "git status"), Some(Action::Allow));
+        assert_eq!(policy.decide("Read", "/etc/hosts"), Some(Action::Allow));
+        assert_eq!(policy.decide("Write", "notes.md"), None);
+        assert_eq!(Policy::parse("allow Read *").unwrap_err(), PolicyError::Format(0));
+        assert_eq!(Policy::parse("version 1\npermit Read *").unwrap_err(), PolicyError::Format(2));
+    }
+
+    #[test]
+    fn test_signed_update() {
+        let signer = SigningKey::from_bytes(&[7u8; 32]);
+        let key = parse_key(&hex::encode(signer.verifying_key().to_bytes())).unwrap();
+
+        let current = Policy::verify(&sign(&signer, "version 1\nallow Read *\n"), &key).unwrap();
+        let document = sign(&signer, "version 2\nallow Read *\nallow Bash ls*\ndeny * *\n");
+        let update = Policy::verify(&document, &key).unwrap();
+        current.check_upgrade(&update).unwrap();
+        assert_eq!(current.diff(&update), PolicyDiff { added: 2, removed: 0 });
+        assert_eq!(update.check_upgrade(&current), Err(PolicyError::Stale { current: 2, offered: 1 }));
+
+        let tampered = document.replace("deny * *", "allow * *");
+        assert_eq!(Policy::verify(&tampered, &key), Err(PolicyError::BadSignature));
+        let other = SigningKey::from_bytes(&[9u8; 32]);
+        assert_eq!(
+            Policy::verify(&sign(&other, "version 3\nallow * *\n"), &key),
+            Err(PolicyError::BadSignature)
+        );
+    }
+}
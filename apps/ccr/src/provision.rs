@@ -0,0 +1,210 @@
+//! CCR Broker Provisioning
+//!
+//! `/pair` sets CCR up for a broker without typing credentials on the
+//! device keyboard. CCR makes a one-time X25519 pairing key and shows it as
+//! a QR code, together with the device's end-to-end key:
+//!
+//! ```text
+//! ccr-pair:1:<pairing key hex>:<device key hex>
+//! ```
+//!
+//! It then listens on `ccr/pair/<first 16 hex digits of the pairing key>`
+//! on the broker it is currently connected to. The provisioning tool scans
+//! the code and publishes there, hex-encoded and sealed to the pairing key
+//! with `ccr_e2e::seal_to`, one setting per line:
+//!
+//! ```text
+//! broker mqtt.example.com:1883
+//! username precursor-7
+//! password correct horse battery staple
+//! namespace lab/ccr
+//! bridge <64 hex digits>
+//! ```
+//!
+//! Only `broker` is required; `bridge` provisions the bridge's end-to-end
+//! key. The first message that opens ends pairing and the pairing key is
+//! forgotten, as it is after [`PAIRING_TIMEOUT_MS`].
+
+extern crate alloc;
+use alloc::format;
+use alloc::string::String;
+
+use ccr_e2e::{E2eError, PublicKey, StaticSecret};
+use rand_core::{CryptoRng, RngCore};
+use xous_mqtt::Topic;
+
+/// How long a pairing code stays valid
+pub const PAIRING_TIMEOUT_MS: u64 = 5 * 60 * 1000;
+
+/// Start of the QR text, with the format version
+pub const QR_PREFIX: &str = "ccr-pair:1:";
+
+/// Parent of the one-time pairing topics
+pub const TOPIC_PAIR: &str = "ccr/pair";
+
+/// Whether `topic` is a pairing topic, `ccr/pair/<id>`
+pub fn is_pairing_topic(topic: &str) -> bool {
+    topic.rsplit_once('/').is_some_and(|(parent, _)| parent == TOPIC_PAIR)
+}
+
+/// Pairing topic for the first 16 hex digits of a pairing key
+fn pairing_topic(id: &str) -> String {
+    Topic::parse(TOPIC_PAIR)
+        .and_then(|topic| topic.join(id))
+        .expect("hex is a valid topic level")
+        .into_string()
+}
+
+/// Why a pairing message was refused
+#[derive(Debug, PartialEq)]
+pub enum ProvisionError {
+    /// The pairing code timed out
+    Expired,
+    /// Not valid hex
+    Hex,
+    /// Not sealed to this pairing key, or altered in transit
+    Sealed(E2eError),
+    /// Opened, but not a valid settings document
+    BadDocument(String),
+}
+
+impl From<E2eError> for ProvisionError {
+    fn from(err: E2eError) -> Self { Self::Sealed(err) }
+}
+
+/// Broker settings delivered by the provisioning tool
+#[derive(Debug, Default, PartialEq)]
+pub struct Credentials {
+    /// `host:port`
+    pub broker: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Topic prefix for services sharing the broker
+    pub namespace: Option<String>,
+    /// Hex X25519 public key of the bridge
+    pub bridge_key: Option<String>,
+}
+
+impl Credentials {
+    /// Parse a settings document; unknown names are ignored so newer tools still work
+    pub fn parse(text: &str) -> Result<Self, ProvisionError> {
+        let mut credentials = Self::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+            let value = String::from(value.trim());
+            match name {
+                "broker" => credentials.broker = value,
+                "username" => credentials.username = Some(value),
+                "password" => credentials.password = Some(value),
+                "namespace" => credentials.namespace = Some(value),
+                "bridge" => {
+                    if crate::e2e::parse_key(&value).is_err() {
+                        let reason = String::from("bridge key must be 64 hex digits");
+                        return Err(ProvisionError::BadDocument(reason));
+                    }
+                    credentials.bridge_key = Some(value);
+                }
+                _ => log::debug!("CCR: Ignoring provisioning setting {}", name),
+            }
+        }
+        if !credentials.broker.contains(':') {
+            return Err(ProvisionError::BadDocument(String::from("broker must be host:port")));
+        }
+        Ok(credentials)
+    }
+}
+
+/// A pairing code on display
+pub struct Pairing {
+    secret: StaticSecret,
+    started_ms: u64,
+}
+
+impl Pairing {
+    pub fn new(rng: &mut (impl RngCore + CryptoRng), now_ms: u64) -> Self {
+        Self { secret: StaticSecret::random_from_rng(rng), started_ms: now_ms }
+    }
+
+    /// Topic the provisioning tool publishes to
+    pub fn topic(&self) -> String {
+        let public = hex::encode(PublicKey::from(&self.secret).as_bytes());
+        pairing_topic(&public[..16])
+    }
+
+    /// Text for the QR code
+    pub fn qr_text(&self, device_key: &PublicKey) -> String {
+        format!(
+            "{}{}:{}",
+            QR_PREFIX,
+            hex::encode(PublicKey::from(&self.secret).as_bytes()),
+            hex::encode(device_key.as_bytes())
+        )
+    }
+
+    pub fn is_expired(&self, now_ms: u64) -> bool {
+        now_ms.saturating_sub(self.started_ms) >= PAIRING_TIMEOUT_MS
+    }
+
+    /// Open a hex-encoded message from the provisioning tool
+    pub fn open(&self, payload: &str, now_ms: u64) -> Result<Credentials, ProvisionError> {
+        if self.is_expired(now_ms) {
+            return Err(ProvisionError::Expired);
+        }
+        let sealed = hex::decode(payload.trim()).map_err(|_| ProvisionError::Hex)?;
+        let document = ccr_e2e::open_from(&self.secret, &self.topic(), &sealed)?;
+        let document = String::from_utf8(document)
+            .map_err(|_| ProvisionError::BadDocument(String::from("not UTF-8")))?;
+        Credentials::parse(&document)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::e2e::tests::TestRng;
+
+    /// What the provisioning tool does with a scanned code
+    fn provision(qr: &str, document: &str) -> (String, String) {
+        let (pairing, _device) = qr.strip_prefix(QR_PREFIX).unwrap().split_once(':').unwrap();
+        let key = crate::e2e::parse_key(pairing).unwrap();
+        let topic = pairing_topic(&pairing[..16]);
+        let sealed = ccr_e2e::seal_to(&key, &topic, document.as_bytes(), &mut TestRng(50));
+        (topic, hex::encode(sealed))
+    }
+
+    #[test]
+    fn test_pairing_round_trip() {
+        let pairing = Pairing::new(&mut TestRng(0), 1000);
+        let device = PublicKey::from(&StaticSecret::from([3u8; 32]));
+        let qr = pairing.qr_text(&device);
+        assert!(qr.ends_with(&hex::encode(device.as_bytes())));
+
+        let document =
+            "broker mqtt.lan:1883\nusername pc-7\npassword two words\nbridge ".to_string() + &"ab".repeat(32);
+        let (topic, payload) = provision(&qr, &document);
+        assert_eq!(topic, pairing.topic());
+        assert!(is_pairing_topic(&topic) && !is_pairing_topic(TOPIC_PAIR));
+        let credentials = pairing.open(&payload, 2000).unwrap();
+        assert_eq!(credentials.broker, "mqtt.lan:1883");
+        assert_eq!(credentials.password.as_deref(), Some("two words"));
+        assert_eq!(credentials.namespace, None);
+        assert!(credentials.bridge_key.is_some());
+
+        assert_eq!(pairing.open(&payload, 1000 + PAIRING_TIMEOUT_MS), Err(ProvisionError::Expired));
+        // Sealed to another pairing code
+        let other = Pairing::new(&mut TestRng(9), 1000);
+        assert!(matches!(other.open(&payload, 2000), Err(ProvisionError::Sealed(_))));
+    }
+
+    #[test]
+    fn test_parse_document() {
+        assert!(matches!(Credentials::parse("username x"), Err(ProvisionError::BadDocument(_))));
+        assert!(matches!(Credentials::parse("broker h:1\nbridge 12"), Err(ProvisionError::BadDocument(_))));
+        let credentials = Credentials::parse("# from the lab\nbroker h:1\nqos 1\n").unwrap();
+        assert_eq!(credentials, Credentials { broker: String::from("h:1"), ..Default::default() });
+    }
+}
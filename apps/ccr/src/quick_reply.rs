@@ -0,0 +1,131 @@
+//! CCR Quick-Reply Templates
+//!
+//! Canned replies sent with two keypresses from the chat view: F1 opens the
+//! picker, then F1-F4 sends the matching template. Templates are stored in
+//! the `ccr_quick_replies` user preference, one per line.
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// One template per function key
+pub const MAX_QUICK_REPLIES: usize = 4;
+
+/// Maximum template length (matches the input line limit)
+pub const MAX_QUICK_REPLY_LEN: usize = 200;
+
+/// Templates used until the user defines their own
+const DEFAULT_QUICK_REPLIES: [&str; 3] = ["continue", "explain before running", "abort"];
+
+/// Quick-reply template set
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuickReplies {
+    templates: Vec<String>,
+}
+
+impl QuickReplies {
+    /// Parse templates from the stored settings value
+    pub fn from_settings(text: &str) -> Self {
+        let templates = text
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .take(MAX_QUICK_REPLIES)
+            .map(|line| String::from(truncate(line, MAX_QUICK_REPLY_LEN)))
+            .collect();
+        Self { templates }
+    }
+
+    /// Serialize templates for storage
+    ///
+    /// An empty set serializes to an empty string, which is distinct from a
+    /// missing record (see [`QuickReplies::default`]).
+    pub fn to_settings(&self) -> String { self.templates.join("\n") }
+
+    /// Get template for slot (0-based)
+    pub fn get(&self, slot: usize) -> Option<&str> { self.templates.get(slot).map(|s| s.as_str()) }
+
+    /// Set template for slot (0-based), appending if the slot is past the end
+    pub fn set(&mut self, slot: usize, text: &str) -> bool {
+        let text = text.trim();
+        if slot >= MAX_QUICK_REPLIES || text.is_empty() || text.contains('\n') {
+            return false;
+        }
+        let text = String::from(truncate(text, MAX_QUICK_REPLY_LEN));
+        if slot < self.templates.len() {
+            self.templates[slot] = text;
+        } else {
+            self.templates.push(text);
+        }
+        true
+    }
+
+    /// Remove template for slot (0-based)
+    pub fn remove(&mut self, slot: usize) -> bool {
+        if slot < self.templates.len() {
+            self.templates.remove(slot);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Iterate over templates
+    pub fn iter(&self) -> impl Iterator<Item = &str> { self.templates.iter().map(|s| s.as_str()) }
+
+    pub fn len(&self) -> usize { self.templates.len() }
+
+    pub fn is_empty(&self) -> bool { self.templates.is_empty() }
+}
+
+impl Default for QuickReplies {
+    fn default() -> Self {
+        Self { templates: DEFAULT_QUICK_REPLIES.iter().map(|s| String::from(*s)).collect() }
+    }
+}
+
+/// Map a function key to its template slot
+pub fn function_key_slot(key: char) -> Option<usize> {
+    match key {
+        '\u{0011}' => Some(0), // F1
+        '\u{0012}' => Some(1), // F2
+        '\u{0013}' => Some(2), // F3
+        '\u{0014}' => Some(3), // F4
+        _ => None,
+    }
+}
+
+/// Truncate to at most `max_len` bytes on a char boundary
+fn truncate(s: &str, max_len: usize) -> &str {
+    if s.len() <= max_len {
+        return s;
+    }
+    let mut end = max_len;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings_roundtrip() {
+        let replies = QuickReplies::from_settings("continue\n\n  abort  \nyes\nno\nextra");
+        assert_eq!(replies.len(), MAX_QUICK_REPLIES);
+        assert_eq!(replies.get(1), Some("abort"));
+        assert_eq!(QuickReplies::from_settings(&replies.to_settings()), replies);
+    }
+
+    #[test]
+    fn test_set_and_remove() {
+        let mut replies = QuickReplies::default();
+        assert!(replies.set(3, "looks good"));
+        assert!(!replies.set(4, "too many"));
+        assert_eq!(replies.get(3), Some("looks good"));
+        assert!(replies.remove(0));
+        assert_eq!(replies.get(0), Some("explain before running"));
+    }
+}
@@ -0,0 +1,53 @@
+//! CCR Persistent Storage
+//!
+//! Thin wrapper over the PDDB for CCR's exported transcripts and its
+//! end-to-end device key. Settings live in `userprefs` so the system
+//! settings UI can manage them alongside the rest of the device preferences.
+
+extern crate alloc;
+use std::io::{Read, Write};
+
+/// Dictionary holding exported session transcripts
+pub const CCR_EXPORT_DICT: &str = "ccr.exports";
+
+/// Dictionary holding key material; kept out of `userprefs` so it isn't shown as a setting
+pub const CCR_KEY_DICT: &str = "ccr.keys";
+
+/// Provisioned broker credentials and topic namespace, shared with other services on the broker
+pub const CCR_BROKER_DICT: &str = "ccr.broker";
+
+/// PDDB-backed key/value store
+pub struct Store {
+    pddb: pddb::Pddb,
+}
+
+impl Store {
+    pub fn new() -> Self {
+        let pddb = pddb::Pddb::new();
+        pddb.try_mount();
+        Self { pddb }
+    }
+
+    /// Write `value` to `dict:key`, replacing any previous contents
+    pub fn write(&self, dict: &str, key: &str, value: &[u8]) -> Result<(), std::io::Error> {
+        // delete key first to ensure data in a prior longer key is gone
+        self.pddb.delete_key(dict, key, None).ok();
+        let mut pddb_key = self.pddb.get(dict, key, None, true, true, Some(value.len()), None::<fn()>)?;
+        pddb_key.write_all(value)?;
+        self.pddb.sync().ok();
+        log::debug!("CCR: Wrote {} bytes to {}:{}", value.len(), dict, key);
+        Ok(())
+    }
+
+    /// Read the whole of `dict:key`
+    pub fn read(&self, dict: &str, key: &str) -> Result<Vec<u8>, std::io::Error> {
+        let mut pddb_key = self.pddb.get(dict, key, None, false, false, None, None::<fn()>)?;
+        let mut value = Vec::new();
+        pddb_key.read_to_end(&mut value)?;
+        Ok(value)
+    }
+}
+
+impl Default for Store {
+    fn default() -> Self { Self::new() }
+}
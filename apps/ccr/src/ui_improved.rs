@@ -8,21 +8,22 @@ use alloc::string::String;
 use core::fmt::Write;
 
 use crate::events::{CcrEvent, EventQueue};
+use crate::latency::{LATENCY_WARN_MS, LATENCY_WINDOW, LatencyStats};
 
 /// Display dimensions (Precursor/Clipin)
 pub const DISPLAY_WIDTH: usize = 336;
 pub const DISPLAY_HEIGHT: usize = 536;
-pub const STATUS_BAR_HEIGHT: usize = 32;  // Managed by GAM
-pub const USABLE_HEIGHT: usize = 504;     // 536 - 32
+pub const STATUS_BAR_HEIGHT: usize = 32; // Managed by GAM
+pub const USABLE_HEIGHT: usize = 504; // 536 - 32
 
 /// Characters per line (8px monospace font)
 pub const CHARS_PER_LINE: usize = 42;
 
 /// Layout constants
-pub const HEADER_HEIGHT: usize = 3;  // lines
-pub const INPUT_HEIGHT: usize = 3;   // lines
-pub const PERM_HEIGHT: usize = 8;    // lines when shown
-pub const CHAT_LINES: usize = 22;    // lines for messages
+pub const HEADER_HEIGHT: usize = 3; // lines
+pub const INPUT_HEIGHT: usize = 3; // lines
+pub const PERM_HEIGHT: usize = 8; // lines when shown
+pub const CHAT_LINES: usize = 22; // lines for messages
 
 /// Current view mode
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -33,6 +34,10 @@ pub enum ViewMode {
     Detail,
     /// Permission dialog view
     Permission,
+    /// Quick-reply picker over the chat view
+    QuickReply,
+    /// Session and latency statistics
+    Stats,
 }
 
 /// UI State
@@ -55,6 +60,9 @@ pub struct UiState {
     /// Connection status
     pub connected: bool,
 
+    /// Do-not-disturb window currently active
+    pub dnd_active: bool,
+
     /// Current session ID
     pub session_id: String,
 
@@ -66,6 +74,13 @@ pub struct UiState {
 
     /// Input cursor position
     pub input_cursor: usize,
+
+    /// Split view: chat stream on top, pinned event detail below
+    pub split: bool,
+
+    /// Event shown in the bottom pane of the split view; a copy, so it stays
+    /// put while new events push older ones out of the queue
+    pub pinned: Option<CcrEvent>,
 }
 
 impl UiState {
@@ -77,10 +92,13 @@ impl UiState {
             pending_permission: None,
             permission_choice: true, // Default to allow
             connected: false,
+            dnd_active: false,
             session_id: String::new(),
             event_count: 0,
             input_text: String::new(),
             input_cursor: 0,
+            split: false,
+            pinned: None,
         }
     }
 
@@ -98,11 +116,7 @@ impl UiState {
     pub fn scroll_down(&mut self, queue_len: usize) {
         if queue_len > 0 && self.selected < queue_len - 1 {
             self.selected += 1;
-            let visible = if self.has_pending_permission() {
-                CHAT_LINES - PERM_HEIGHT
-            } else {
-                CHAT_LINES
-            };
+            let visible = if self.has_pending_permission() { CHAT_LINES - PERM_HEIGHT } else { CHAT_LINES };
             if self.selected >= self.scroll_pos + visible / 2 {
                 self.scroll_pos = self.selected - visible / 2 + 1;
             }
@@ -114,11 +128,7 @@ impl UiState {
         self.event_count = queue_len;
         if queue_len > 0 {
             self.selected = queue_len - 1;
-            let visible = if self.has_pending_permission() {
-                CHAT_LINES - PERM_HEIGHT
-            } else {
-                CHAT_LINES
-            };
+            let visible = if self.has_pending_permission() { CHAT_LINES - PERM_HEIGHT } else { CHAT_LINES };
             if queue_len > visible / 2 {
                 self.scroll_pos = queue_len - visible / 2;
             } else {
@@ -149,24 +159,16 @@ impl UiState {
     }
 
     /// Clear pending permission
-    pub fn clear_pending_permission(&mut self) {
-        self.pending_permission = None;
-    }
+    pub fn clear_pending_permission(&mut self) { self.pending_permission = None; }
 
     /// Toggle permission choice
-    pub fn toggle_permission_choice(&mut self) {
-        self.permission_choice = !self.permission_choice;
-    }
+    pub fn toggle_permission_choice(&mut self) { self.permission_choice = !self.permission_choice; }
 
     /// Check if there's a pending permission
-    pub fn has_pending_permission(&self) -> bool {
-        self.pending_permission.is_some()
-    }
+    pub fn has_pending_permission(&self) -> bool { self.pending_permission.is_some() }
 
     /// Check if there's a valid selection
-    pub fn has_selection(&self) -> bool {
-        self.selected < self.event_count
-    }
+    pub fn has_selection(&self) -> bool { self.selected < self.event_count }
 
     /// Clear selection (set to invalid index)
     pub fn clear_selection(&mut self) {
@@ -175,13 +177,30 @@ impl UiState {
     }
 
     /// Check if given index is selected
-    pub fn is_selected(&self, index: usize) -> bool {
-        self.selected == index && self.selected != usize::MAX
+    pub fn is_selected(&self, index: usize) -> bool { self.selected == index && self.selected != usize::MAX }
+
+    /// Toggle the split view, pinning the selected (or newest) event when turning it on
+    pub fn toggle_split(&mut self, queue: &EventQueue) {
+        self.split = !self.split;
+        if self.split {
+            let index = if self.has_selection() { self.selected } else { queue.len().saturating_sub(1) };
+            self.pinned = queue.get(index).cloned();
+        } else {
+            self.pinned = None;
+        }
+    }
+
+    /// Pin the selected event into the split view's detail pane
+    pub fn pin_selected(&mut self, queue: &EventQueue) {
+        if let Some(event) = queue.get(self.selected) {
+            self.pinned = Some(event.clone());
+        }
     }
 
     /// Add character to input
     pub fn input_add_char(&mut self, c: char) {
-        if self.input_text.len() < 200 {  // Max input length
+        if self.input_text.len() < 200 {
+            // Max input length
             self.input_text.push(c);
             self.input_cursor = self.input_text.len();
         }
@@ -202,15 +221,11 @@ impl UiState {
     }
 
     /// Get input text
-    pub fn input_get(&self) -> &str {
-        &self.input_text
-    }
+    pub fn input_get(&self) -> &str { &self.input_text }
 }
 
 impl Default for UiState {
-    fn default() -> Self {
-        Self::new()
-    }
+    fn default() -> Self { Self::new() }
 }
 
 /// Render header bar
@@ -225,25 +240,20 @@ pub fn render_header(state: &UiState) -> String {
     };
 
     // Reverse counting: last event shown = 1, first event = total
-    let current = if state.event_count > 0 {
-        state.event_count - state.selected
-    } else {
-        0
-    };
+    let current = if state.event_count > 0 { state.event_count - state.selected } else { 0 };
 
-    let perm_indicator = if state.has_pending_permission() {
-        " [!]"
-    } else {
-        ""
-    };
+    let perm_indicator = if state.has_pending_permission() { " [!]" } else { "" };
+
+    let dnd_indicator = if state.dnd_active { " [DnD]" } else { "" };
 
     alloc::format!(
-        "[{}:{}]  {}  {}{}",
+        "[{}:{}]  {}  {}{}{}",
         current,
         state.event_count,
         session_short,
         status_icon,
-        perm_indicator
+        perm_indicator,
+        dnd_indicator
     )
 }
 
@@ -279,11 +289,8 @@ pub fn render_permission_area(event: &CcrEvent, choice: bool) -> String {
         writeln!(output, "║   PERMISSION REQUIRED                 ║").ok();
         writeln!(output, "╚═══════════════════════════════════════╝").ok();
 
-        let cmd_short = if command.len() > 35 {
-            alloc::format!("{}...", &command[..32])
-        } else {
-            command.clone()
-        };
+        let cmd_short =
+            if command.len() > 35 { alloc::format!("{}...", &command[..32]) } else { command.clone() };
 
         writeln!(output, "{}: {}", tool, cmd_short).ok();
         writeln!(output).ok();
@@ -328,11 +335,7 @@ pub fn render_chat(queue: &EventQueue, state: &UiState) -> String {
     writeln!(output, "──────────────────────────────────────────").ok();
 
     // Calculate visible lines
-    let visible_lines = if state.has_pending_permission() {
-        CHAT_LINES - PERM_HEIGHT
-    } else {
-        CHAT_LINES
-    };
+    let visible_lines = if state.has_pending_permission() { CHAT_LINES - PERM_HEIGHT } else { CHAT_LINES };
 
     if queue.is_empty() {
         writeln!(output).ok();
@@ -373,6 +376,26 @@ pub fn render_chat(queue: &EventQueue, state: &UiState) -> String {
     output
 }
 
+/// Render the stats view
+pub fn render_stats(latency: &LatencyStats, event_count: usize) -> String {
+    let mut output = String::new();
+    let ms = |value: Option<u32>| value.map_or(String::from("-"), |v| alloc::format!("{} ms", v));
+
+    writeln!(output, "STATS").ok();
+    writeln!(output).ok();
+    writeln!(output, "Events:  {}", event_count).ok();
+    writeln!(output).ok();
+    writeln!(output, "Latency (last {} stamped events)", LATENCY_WINDOW).ok();
+    writeln!(output, "  last:  {}", ms(latency.last())).ok();
+    writeln!(output, "  p50:   {}", ms(latency.percentile(50))).ok();
+    writeln!(output, "  p95:   {}", ms(latency.percentile(95))).ok();
+    writeln!(output, "  over {} ms: {} of {}", LATENCY_WARN_MS, latency.slow(), latency.total()).ok();
+    writeln!(output).ok();
+    write!(output, "\u{2190} back").ok();
+
+    output
+}
+
 /// Render event detail view
 pub fn render_event_detail(event: &CcrEvent) -> String {
     let mut output = String::new();
@@ -451,13 +474,7 @@ pub fn render_event_detail(event: &CcrEvent) -> String {
 }
 
 /// Truncate session ID for display
-fn truncate_id(id: &str) -> &str {
-    if id.len() > 12 {
-        &id[..12]
-    } else {
-        id
-    }
-}
+fn truncate_id(id: &str) -> &str { if id.len() > 12 { &id[..12] } else { id } }
 
 /// Word wrap text to specified width
 fn word_wrap(text: &str, width: usize) -> alloc::vec::Vec<String> {
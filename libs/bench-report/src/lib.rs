@@ -0,0 +1,145 @@
+//! Benchmark Reports
+//!
+//! Every subsystem that measures itself publishes its results the same way,
+//! so one desktop dashboard can collect them from all devices and compare
+//! firmware versions. A report goes to
+//!
+//! ```text
+//! bench/<device>/<component>
+//! ```
+//!
+//! where `<device>` is the SoC DNA in hex, as in CCR's status topic, and
+//! `<component>` names what was measured, e.g. `ccr-latency`. The payload
+//! is JSON:
+//!
+//! ```text
+//! {"schema":1,"device":"0123456789abcdef","component":"ccr-latency",
+//!  "firmware":"v0.9.16","uptime_ms":812345,
+//!  "metrics":[{"name":"p50","value":68,"unit":"ms"},
+//!             {"name":"samples","value":64,"unit":"count"}]}
+//! ```
+//!
+//! `schema` is [`SCHEMA_VERSION`]; a dashboard should skip reports with a
+//! version it doesn't know. Fields are only ever added within a version.
+//! Metric names are unique within a report, and `unit` is one of the
+//! [`unit`] constants where one fits.
+
+#![no_std]
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use serde::{Serialize, Serializer};
+use xous_mqtt::codec::{self, CodecError};
+use xous_mqtt::topic::{Topic, TopicError};
+
+/// Version of the report layout
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// First level of every report topic
+pub const TOPIC_ROOT: &str = "bench";
+
+/// Units for [`Metric::unit`]
+pub mod unit {
+    pub const MILLISECONDS: &str = "ms";
+    pub const MICROSECONDS: &str = "us";
+    pub const COUNT: &str = "count";
+    pub const BYTES: &str = "bytes";
+    pub const PER_SECOND: &str = "per_s";
+    pub const PERCENT: &str = "percent";
+}
+
+/// Topic a component's reports are published on
+///
+/// `device` and `component` must each be a single topic level.
+pub fn topic(device: &str, component: &str) -> Result<Topic, TopicError> {
+    Topic::new(TOPIC_ROOT)?.join(device)?.join(component)
+}
+
+/// A measured value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Self::Int(v) => serializer.serialize_i64(v),
+            Self::Float(v) => serializer.serialize_f64(v),
+        }
+    }
+}
+
+impl From<u32> for Value {
+    fn from(v: u32) -> Self { Self::Int(v.into()) }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Self { Self::Int(v) }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Self { Self::Float(v) }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Metric<'a> {
+    pub name: &'a str,
+    pub value: Value,
+    pub unit: &'a str,
+}
+
+/// One component's results at one moment
+#[derive(Debug, Clone, Serialize)]
+pub struct Report<'a> {
+    schema: u32,
+    pub device: &'a str,
+    pub component: &'a str,
+    /// Firmware version string, first line only
+    pub firmware: &'a str,
+    pub uptime_ms: u64,
+    pub metrics: Vec<Metric<'a>>,
+}
+
+impl<'a> Report<'a> {
+    pub fn new(device: &'a str, component: &'a str, firmware: &'a str, uptime_ms: u64) -> Self {
+        let firmware = firmware.lines().next().unwrap_or("");
+        Self { schema: SCHEMA_VERSION, device, component, firmware, uptime_ms, metrics: Vec::new() }
+    }
+
+    /// Add a metric
+    pub fn metric(&mut self, name: &'a str, value: impl Into<Value>, unit: &'a str) -> &mut Self {
+        self.metrics.push(Metric { name, value: value.into(), unit });
+        self
+    }
+
+    /// Topic to publish this report on
+    pub fn topic(&self) -> Result<Topic, TopicError> { topic(self.device, self.component) }
+
+    /// The report as a JSON payload
+    pub fn to_json(&self) -> Result<Vec<u8>, CodecError> { codec::to_json(self) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let mut report = Report::new("00000000000000ab", "ccr-latency", "v0.9.16\nclean", 5000);
+        report.metric("p50", 68u32, unit::MILLISECONDS).metric("cpu", 12.5, unit::PERCENT);
+        assert_eq!(report.topic().unwrap().as_str(), "bench/00000000000000ab/ccr-latency");
+        assert_eq!(
+            report.to_json().unwrap(),
+            concat!(
+                r#"{"schema":1,"device":"00000000000000ab","component":"ccr-latency","firmware":"v0.9.16","#,
+                r#""uptime_ms":5000,"metrics":[{"name":"p50","value":68,"unit":"ms"},"#,
+                r#"{"name":"cpu","value":12.5,"unit":"percent"}]}"#
+            )
+            .as_bytes()
+        );
+        assert!(topic("dev", "ccr/latency").is_err());
+    }
+}
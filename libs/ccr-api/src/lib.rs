@@ -0,0 +1,151 @@
+//! Server name, opcodes and a small client for the Claude Code Remote app.
+//!
+//! CCR is an optional app, so [`Ccr::new`] fails instead of blocking when it
+//! isn't running.
+
+use num_traits::ToPrimitive;
+use xous::{CID, Message, send_message};
+use xous_ipc::Buffer;
+
+/// Server name for xous-names registration
+pub const SERVER_NAME_CCR: &str = "_Claude Code Remote_";
+
+/// Message opcodes
+#[derive(Debug, num_derive::FromPrimitive, num_derive::ToPrimitive)]
+pub enum CcrOp {
+    /// Redraw the UI
+    Redraw = 0,
+    /// A line of text has arrived from IME
+    Line,
+    /// Raw key event (for d-pad navigation)
+    RawKey,
+    /// MQTT message received (scalar: connection status, or memory: topic+payload)
+    MqttMessage,
+    /// Timer tick: shows tool results whose calls haven't arrived
+    Tick,
+    /// Quit the application
+    Quit,
+    /// User preferences changed; reload them from `userprefs` (scalar)
+    SettingsChanged,
+    /// Connectivity update from net-power (scalar arg1: `net_power::Connectivity`)
+    Connectivity,
+    /// Post a notification bubble from another process (memory: `String` "source\0message")
+    PostNotification,
+    /// Number of pending permission requests (blocking scalar, returns the count)
+    PendingPermissions,
+    /// Timer tick: publish a heartbeat on the device status topic
+    Heartbeat,
+    /// Broker connection state for the status service
+    /// (memory: `String`, replaced with [`MqttStatus::encode`])
+    MqttStatus,
+    /// Drop the broker connection and connect again (scalar)
+    Reconnect,
+}
+
+/// CCR's broker connection, as returned by [`Ccr::mqtt_status`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttStatus {
+    /// Broker address, `host:port`
+    pub broker: String,
+    pub connected: bool,
+    /// Why the last connection attempt or session failed, cleared on connect
+    pub last_error: Option<String>,
+}
+
+impl MqttStatus {
+    /// Wire form for [`CcrOp::MqttStatus`]: "broker\0connected\0last error"
+    pub fn encode(&self) -> String {
+        let connected = if self.connected { "1" } else { "0" };
+        format!("{}\0{}\0{}", self.broker, connected, self.last_error.as_deref().unwrap_or_default())
+    }
+
+    pub fn decode(text: &str) -> Self {
+        let mut fields = text.split('\0');
+        MqttStatus {
+            broker: fields.next().unwrap_or_default().to_string(),
+            connected: fields.next() == Some("1"),
+            last_error: fields.next().filter(|e| !e.is_empty()).map(|e| e.to_string()),
+        }
+    }
+}
+
+pub struct Ccr {
+    conn: CID,
+}
+impl Ccr {
+    /// Connect to CCR, failing if the app isn't running
+    pub fn new(xns: &xous_names::XousNames) -> Result<Self, xous::Error> {
+        let conn = xns.request_connection(SERVER_NAME_CCR)?;
+        REFCOUNT.fetch_add(1, Ordering::Relaxed);
+        Ok(Ccr { conn })
+    }
+
+    /// Tell CCR that its `ccr_*` user preferences were written by someone else
+    pub fn settings_changed(&self) -> Result<(), xous::Error> {
+        send_message(self.conn, Message::new_scalar(CcrOp::SettingsChanged.to_usize().unwrap(), 0, 0, 0, 0))
+            .map(|_| ())
+    }
+
+    /// Show a notification bubble in CCR's event list, attributed to `source`
+    pub fn post_notification(&self, source: &str, message: &str) -> Result<(), xous::Error> {
+        let buf = Buffer::into_buf(format!("{}\0{}", source, message)).or(Err(xous::Error::InternalError))?;
+        buf.send(self.conn, CcrOp::PostNotification.to_u32().unwrap()).map(|_| ())
+    }
+
+    /// Number of permission requests waiting for an answer on the device
+    pub fn pending_permissions(&self) -> Result<usize, xous::Error> {
+        let response = send_message(
+            self.conn,
+            Message::new_blocking_scalar(CcrOp::PendingPermissions.to_usize().unwrap(), 0, 0, 0, 0),
+        )?;
+        if let xous::Result::Scalar1(count) = response { Ok(count) } else { Err(xous::Error::InternalError) }
+    }
+
+    /// State of CCR's broker connection
+    pub fn mqtt_status(&self) -> Result<MqttStatus, xous::Error> {
+        let mut buf = Buffer::into_buf(String::new()).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(self.conn, CcrOp::MqttStatus.to_u32().unwrap())?;
+        let status = buf.to_original::<String, _>().or(Err(xous::Error::InternalError))?;
+        Ok(MqttStatus::decode(&status))
+    }
+
+    /// Drop the broker connection and connect again
+    pub fn reconnect(&self) -> Result<(), xous::Error> {
+        send_message(self.conn, Message::new_scalar(CcrOp::Reconnect.to_usize().unwrap(), 0, 0, 0, 0))
+            .map(|_| ())
+    }
+}
+
+use core::sync::atomic::{AtomicU32, Ordering};
+static REFCOUNT: AtomicU32 = AtomicU32::new(0);
+impl Drop for Ccr {
+    fn drop(&mut self) {
+        // the connection to the server side must be reference counted, so that multiple instances of this
+        // object within a single process do not end up de-allocating the CID on other threads before they
+        // go out of scope.
+        if REFCOUNT.fetch_sub(1, Ordering::Relaxed) == 1 {
+            unsafe {
+                xous::disconnect(self.conn).unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mqtt_status_round_trip() {
+        let mut status =
+            MqttStatus { broker: "mqtt.lan:1883".to_string(), connected: false, last_error: None };
+        assert_eq!(status.encode(), ["mqtt.lan:1883", "0", ""].join("\0"));
+        assert_eq!(MqttStatus::decode(&status.encode()), status);
+        status.last_error = Some("connection refused".to_string());
+        assert_eq!(MqttStatus::decode(&status.encode()), status);
+        assert_eq!(
+            MqttStatus::decode(""),
+            MqttStatus { broker: String::new(), connected: false, last_error: None }
+        );
+    }
+}
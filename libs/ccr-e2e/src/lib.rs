@@ -0,0 +1,455 @@
+//! CCR End-to-End Payload Encryption
+//!
+//! The MQTT broker between CCR and its bridge only relays messages, and it
+//! may be a public one. This crate keeps payloads away from it: the two ends
+//! agree on session keys with X25519 and seal each message with
+//! XChaCha20-Poly1305, so the broker sees topics and sizes but nothing else.
+//!
+//! # Handshake
+//!
+//! Each end has a long-term X25519 key, and each has been given the other's
+//! public key out of band. On every (re)connect both make a fresh ephemeral
+//! key and exchange its public half:
+//!
+//! ```text
+//! device -> bridge   E_device
+//! bridge -> device   E_bridge || seal(confirmation)
+//! ```
+//!
+//! The session keys come from all four Diffie-Hellman pairings of the static
+//! and ephemeral keys, so only holders of both static secrets can derive
+//! them, and recording the traffic doesn't help once the ephemeral keys are
+//! gone. The bridge's sealed confirmation shows the device it is talking to
+//! the real bridge; the device's first sealed message does the same the
+//! other way.
+//!
+//! # Messages
+//!
+//! ```text
+//! version (1) || sequence (8, big-endian) || ciphertext || tag (16)
+//! ```
+//!
+//! The nonce is built from the sequence number, and the topic is
+//! authenticated along with the header, so a message can't be replayed or
+//! moved to another topic. Each direction has its own key and sequence.
+//!
+//! # One-shot messages
+//!
+//! [`seal_to`] and [`open_from`] carry a single message to the holder of a
+//! key without a handshake, e.g. credentials sent to a device that is
+//! showing a one-time pairing key. The sender is anonymous: anyone who has
+//! the public key can produce one, so the contents must stand on their own.
+//!
+//! ```rust,ignore
+//! let mut handshake = Handshake::new(Role::Device, &device_secret, bridge_public, &mut OsRng);
+//! publish("ccr/e2e/device", handshake.public().as_bytes());
+//! // ... the bridge's ephemeral key arrives
+//! let mut session = handshake.finish(&bridge_ephemeral)?;
+//! let sealed = session.seal("ccr/permissions/response", b"{...}");
+//! ```
+
+#![no_std]
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use rand_core::{CryptoRng, RngCore};
+use sha2::Sha256;
+use x25519_dalek::SharedSecret;
+pub use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Format version carried in every sealed message
+pub const VERSION: u8 = 1;
+
+/// Bytes a sealed message adds to its plaintext
+pub const OVERHEAD: usize = HEADER_LEN + TAG_LEN;
+
+/// Length of an X25519 public key
+pub const KEY_LEN: usize = 32;
+
+const HEADER_LEN: usize = 1 + 8;
+const TAG_LEN: usize = 16;
+
+/// Messages a receiver will still accept behind the newest one it has seen
+const REPLAY_WINDOW: u64 = 64;
+
+/// Domain separation for the key schedule
+const PROTOCOL: &[u8] = b"ccr-e2e v1";
+
+/// Which end of the link this is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// The Precursor running CCR
+    Device,
+    /// The bridge on the development machine
+    Bridge,
+}
+
+/// Errors from the handshake or from opening a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum E2eError {
+    /// Too short to be a sealed message, or a key of the wrong length
+    Malformed,
+    /// Sealed with a format version this build doesn't know
+    Version(u8),
+    /// Sequence number already seen, or too far behind to tell
+    Replay,
+    /// Failed authentication: wrong key, wrong topic, or altered in transit
+    Forged,
+    /// A Diffie-Hellman result was all zeros, so the peer sent a low-order point
+    WeakKey,
+}
+
+/// Parse a 32-byte public key
+pub fn public_key(bytes: &[u8]) -> Result<PublicKey, E2eError> {
+    let bytes: [u8; KEY_LEN] = bytes.try_into().map_err(|_| E2eError::Malformed)?;
+    Ok(PublicKey::from(bytes))
+}
+
+/// One side of a key exchange in progress
+pub struct Handshake {
+    role: Role,
+    our_static: StaticSecret,
+    peer_static: PublicKey,
+    // Used twice, which `EphemeralSecret` doesn't allow; dropped with the handshake
+    ephemeral: StaticSecret,
+}
+
+impl Handshake {
+    /// Start a handshake with the peer whose long-term key is `peer_static`
+    pub fn new(
+        role: Role,
+        our_static: &StaticSecret,
+        peer_static: PublicKey,
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> Self {
+        let ephemeral = StaticSecret::random_from_rng(rng);
+        Self { role, our_static: our_static.clone(), peer_static, ephemeral }
+    }
+
+    /// Ephemeral public key to send to the peer
+    pub fn public(&self) -> PublicKey { PublicKey::from(&self.ephemeral) }
+
+    /// Complete the handshake with the peer's ephemeral key
+    ///
+    /// The handshake is kept, so an answer that turns out to be forged
+    /// doesn't stop the genuine one from completing it.
+    pub fn finish(&self, peer_ephemeral: &PublicKey) -> Result<Session, E2eError> {
+        let ss = self.our_static.diffie_hellman(&self.peer_static);
+        let es = self.ephemeral.diffie_hellman(&self.peer_static);
+        let se = self.our_static.diffie_hellman(peer_ephemeral);
+        let ee = self.ephemeral.diffie_hellman(peer_ephemeral);
+        if ![&ss, &es, &se, &ee].iter().all(|dh| dh.was_contributory()) {
+            return Err(E2eError::WeakKey);
+        }
+
+        // Both ends hash the same transcript, so put it in device-then-bridge order
+        let our_static = PublicKey::from(&self.our_static);
+        let our_ephemeral = self.public();
+        let (device, bridge, device_eph, bridge_eph, dev_eph_bridge, dev_bridge_eph) = match self.role {
+            Role::Device => (&our_static, &self.peer_static, &our_ephemeral, peer_ephemeral, &es, &se),
+            Role::Bridge => (&self.peer_static, &our_static, peer_ephemeral, &our_ephemeral, &se, &es),
+        };
+        let mut ikm = [0u8; 4 * KEY_LEN];
+        for (chunk, dh) in ikm.chunks_exact_mut(KEY_LEN).zip([&ss, dev_eph_bridge, dev_bridge_eph, &ee]) {
+            chunk.copy_from_slice(dh.as_bytes());
+        }
+        let mut salt = [0u8; 4 * KEY_LEN];
+        for (chunk, key) in salt.chunks_exact_mut(KEY_LEN).zip([device, bridge, device_eph, bridge_eph]) {
+            chunk.copy_from_slice(key.as_bytes());
+        }
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), &ikm);
+        let mut to_bridge = [0u8; 32];
+        let mut to_device = [0u8; 32];
+        // Can't fail: 32 bytes is well under HKDF-SHA256's output limit
+        hkdf.expand_multi_info(&[PROTOCOL, b" device->bridge"], &mut to_bridge).ok();
+        hkdf.expand_multi_info(&[PROTOCOL, b" bridge->device"], &mut to_device).ok();
+        let (send, recv) = match self.role {
+            Role::Device => (to_bridge, to_device),
+            Role::Bridge => (to_device, to_bridge),
+        };
+        Ok(Session {
+            send: XChaCha20Poly1305::new(&send.into()),
+            recv: XChaCha20Poly1305::new(&recv.into()),
+            next_seq: 1,
+            highest: 0,
+            seen: 0,
+        })
+    }
+}
+
+/// Keys and sequence state for one connection
+pub struct Session {
+    send: XChaCha20Poly1305,
+    recv: XChaCha20Poly1305,
+    /// Sequence number for the next message sealed
+    next_seq: u64,
+    /// Newest sequence number opened; 0 before the first
+    highest: u64,
+    /// Bit `n` set if `highest - n` has been opened
+    seen: u64,
+}
+
+impl Session {
+    /// Encrypt `plaintext` for publishing on `topic`
+    pub fn seal(&mut self, topic: &str, plaintext: &[u8]) -> Vec<u8> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let header = header(seq);
+        let mut sealed = Vec::with_capacity(plaintext.len() + OVERHEAD);
+        sealed.extend_from_slice(&header);
+        let aad = aad(topic, &header);
+        // Can't fail: XChaCha20-Poly1305 only errors on messages near 256 GiB
+        let ciphertext =
+            self.send.encrypt(&nonce(seq), Payload { msg: plaintext, aad: &aad }).unwrap_or_default();
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypt a message received on `topic`
+    ///
+    /// Messages may arrive out of order, up to 64 behind the
+    /// newest; each is accepted once.
+    pub fn open(&mut self, topic: &str, message: &[u8]) -> Result<Vec<u8>, E2eError> {
+        if message.len() < OVERHEAD {
+            return Err(E2eError::Malformed);
+        }
+        let (header, ciphertext) = message.split_at(HEADER_LEN);
+        if header[0] != VERSION {
+            return Err(E2eError::Version(header[0]));
+        }
+        let mut seq = [0u8; 8];
+        seq.copy_from_slice(&header[1..]);
+        let seq = u64::from_be_bytes(seq);
+        if seq == 0 || self.is_replay(seq) {
+            return Err(E2eError::Replay);
+        }
+        let aad = aad(topic, header);
+        let plaintext = self
+            .recv
+            .decrypt(&nonce(seq), Payload { msg: ciphertext, aad: &aad })
+            .map_err(|_| E2eError::Forged)?;
+        // Only authentic messages move the window
+        if seq > self.highest {
+            let shift = seq - self.highest;
+            self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+            self.highest = seq;
+        }
+        self.seen |= 1 << (self.highest - seq);
+        Ok(plaintext)
+    }
+
+    fn is_replay(&self, seq: u64) -> bool {
+        if seq > self.highest {
+            return false;
+        }
+        let behind = self.highest - seq;
+        behind >= REPLAY_WINDOW || self.seen & (1 << behind) != 0
+    }
+}
+
+/// Encrypt one message for the holder of `recipient`'s secret key
+///
+/// The message carries a fresh ephemeral public key ahead of the usual
+/// sealed format.
+pub fn seal_to(
+    recipient: &PublicKey,
+    topic: &str,
+    plaintext: &[u8],
+    rng: &mut (impl RngCore + CryptoRng),
+) -> Vec<u8> {
+    let ephemeral = StaticSecret::random_from_rng(rng);
+    let public = PublicKey::from(&ephemeral);
+    let cipher = one_shot_cipher(&ephemeral.diffie_hellman(recipient), &public, recipient);
+    let header = header(1);
+    let mut sealed = Vec::with_capacity(KEY_LEN + plaintext.len() + OVERHEAD);
+    sealed.extend_from_slice(public.as_bytes());
+    sealed.extend_from_slice(&header);
+    let aad = aad(topic, &header);
+    // Can't fail: XChaCha20-Poly1305 only errors on messages near 256 GiB
+    sealed.extend(cipher.encrypt(&nonce(1), Payload { msg: plaintext, aad: &aad }).unwrap_or_default());
+    sealed
+}
+
+/// Decrypt a message made by [`seal_to`] for our key `secret`
+pub fn open_from(secret: &StaticSecret, topic: &str, message: &[u8]) -> Result<Vec<u8>, E2eError> {
+    if message.len() < KEY_LEN + OVERHEAD {
+        return Err(E2eError::Malformed);
+    }
+    let (public, rest) = message.split_at(KEY_LEN);
+    let (header, ciphertext) = rest.split_at(HEADER_LEN);
+    if header[0] != VERSION {
+        return Err(E2eError::Version(header[0]));
+    }
+    let public = public_key(public)?;
+    let shared = secret.diffie_hellman(&public);
+    if !shared.was_contributory() {
+        return Err(E2eError::WeakKey);
+    }
+    let cipher = one_shot_cipher(&shared, &public, &PublicKey::from(secret));
+    let aad = aad(topic, header);
+    cipher.decrypt(&nonce(1), Payload { msg: ciphertext, aad: &aad }).map_err(|_| E2eError::Forged)
+}
+
+/// Key for a [`seal_to`] message; used once, so a fixed nonce is safe
+fn one_shot_cipher(shared: &SharedSecret, sender: &PublicKey, recipient: &PublicKey) -> XChaCha20Poly1305 {
+    let mut salt = [0u8; 2 * KEY_LEN];
+    salt[..KEY_LEN].copy_from_slice(sender.as_bytes());
+    salt[KEY_LEN..].copy_from_slice(recipient.as_bytes());
+    let mut key = [0u8; 32];
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+    // Can't fail: 32 bytes is well under HKDF-SHA256's output limit
+    hkdf.expand_multi_info(&[PROTOCOL, b" one-shot"], &mut key).ok();
+    XChaCha20Poly1305::new(&key.into())
+}
+
+fn header(seq: u64) -> [u8; HEADER_LEN] {
+    let mut header = [VERSION; HEADER_LEN];
+    header[1..].copy_from_slice(&seq.to_be_bytes());
+    header
+}
+
+fn nonce(seq: u64) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[16..].copy_from_slice(&seq.to_be_bytes());
+    nonce
+}
+
+fn aad(topic: &str, header: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(topic.len() + 1 + header.len());
+    aad.extend_from_slice(topic.as_bytes());
+    // Keeps the topic and header from running together
+    aad.push(0);
+    aad.extend_from_slice(header);
+    aad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic generator; fine for tests, never for keys
+    struct TestRng(u64);
+
+    impl RngCore for TestRng {
+        fn next_u32(&mut self) -> u32 { self.next_u64() as u32 }
+
+        fn next_u64(&mut self) -> u64 {
+            // xorshift64
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for chunk in dest.chunks_mut(8) {
+                chunk.copy_from_slice(&self.next_u64().to_le_bytes()[..chunk.len()]);
+            }
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for TestRng {}
+
+    fn pair(rng: &mut TestRng) -> (Session, Session) {
+        let device_key = StaticSecret::random_from_rng(&mut *rng);
+        let bridge_key = StaticSecret::random_from_rng(&mut *rng);
+        let device = Handshake::new(Role::Device, &device_key, PublicKey::from(&bridge_key), rng);
+        let bridge = Handshake::new(Role::Bridge, &bridge_key, PublicKey::from(&device_key), rng);
+        let (device_eph, bridge_eph) = (device.public(), bridge.public());
+        (device.finish(&bridge_eph).unwrap(), bridge.finish(&device_eph).unwrap())
+    }
+
+    #[test]
+    fn test_handshake_agrees() {
+        let (mut device, mut bridge) = pair(&mut TestRng(0x1234_5678));
+        let sealed = bridge.seal("ccr/events", b"{\"type\":\"status\"}");
+        assert_eq!(sealed.len(), 17 + OVERHEAD);
+        assert_eq!(device.open("ccr/events", &sealed).unwrap(), b"{\"type\":\"status\"}");
+        let sealed = device.seal("ccr/permissions/response", b"allow");
+        assert_eq!(bridge.open("ccr/permissions/response", &sealed).unwrap(), b"allow");
+
+        // Each direction has its own key
+        let sealed = device.seal("ccr/events", b"echo");
+        assert_eq!(device.open("ccr/events", &sealed), Err(E2eError::Forged));
+    }
+
+    #[test]
+    fn test_wrong_static_key() {
+        let mut rng = TestRng(99);
+        let device_key = StaticSecret::random_from_rng(&mut rng);
+        let bridge_key = StaticSecret::random_from_rng(&mut rng);
+        let impostor_key = StaticSecret::random_from_rng(&mut rng);
+        let device = Handshake::new(Role::Device, &device_key, PublicKey::from(&bridge_key), &mut rng);
+        let impostor = Handshake::new(Role::Bridge, &impostor_key, PublicKey::from(&device_key), &mut rng);
+        let (device_eph, impostor_eph) = (device.public(), impostor.public());
+        let mut device = device.finish(&impostor_eph).unwrap();
+        let mut impostor = impostor.finish(&device_eph).unwrap();
+        assert_eq!(device.open("t", &impostor.seal("t", b"hello")), Err(E2eError::Forged));
+    }
+
+    #[test]
+    fn test_rejects_tampering() {
+        let (mut device, mut bridge) = pair(&mut TestRng(7));
+        let sealed = bridge.seal("ccr/events", b"payload");
+        assert_eq!(device.open("ccr/permissions/request", &sealed), Err(E2eError::Forged));
+        let mut altered = sealed.clone();
+        *altered.last_mut().unwrap() ^= 1;
+        assert_eq!(device.open("ccr/events", &altered), Err(E2eError::Forged));
+        let mut future = sealed.clone();
+        future[0] = 2;
+        assert_eq!(device.open("ccr/events", &future), Err(E2eError::Version(2)));
+        assert_eq!(device.open("ccr/events", &sealed[..OVERHEAD - 1]), Err(E2eError::Malformed));
+        // None of those spent the sequence number
+        assert!(device.open("ccr/events", &sealed).is_ok());
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let (mut device, mut bridge) = pair(&mut TestRng(42));
+        let first = bridge.seal("t", b"1");
+        let second = bridge.seal("t", b"2");
+        assert!(device.open("t", &second).is_ok());
+        // Out of order is fine, twice is not
+        assert!(device.open("t", &first).is_ok());
+        assert_eq!(device.open("t", &first), Err(E2eError::Replay));
+        assert_eq!(device.open("t", &second), Err(E2eError::Replay));
+
+        let stale = bridge.seal("t", b"3");
+        for _ in 0..REPLAY_WINDOW {
+            device.open("t", &bridge.seal("t", b"n")).unwrap();
+        }
+        assert_eq!(device.open("t", &stale), Err(E2eError::Replay));
+    }
+
+    #[test]
+    fn test_one_shot() {
+        let mut rng = TestRng(11);
+        let key = StaticSecret::random_from_rng(&mut rng);
+        let other = StaticSecret::random_from_rng(&mut rng);
+        let sealed = seal_to(&PublicKey::from(&key), "ccr/pair/x", b"broker host:1883", &mut rng);
+        assert_eq!(open_from(&key, "ccr/pair/x", &sealed).unwrap(), b"broker host:1883");
+        assert_eq!(open_from(&key, "ccr/pair/y", &sealed), Err(E2eError::Forged));
+        assert_eq!(open_from(&other, "ccr/pair/x", &sealed), Err(E2eError::Forged));
+        let short = &sealed[..KEY_LEN + OVERHEAD - 1];
+        assert_eq!(open_from(&key, "ccr/pair/x", short), Err(E2eError::Malformed));
+    }
+
+    #[test]
+    fn test_low_order_key_refused() {
+        let mut rng = TestRng(5);
+        let device_key = StaticSecret::random_from_rng(&mut rng);
+        let bridge_key = StaticSecret::random_from_rng(&mut rng);
+        let device = Handshake::new(Role::Device, &device_key, PublicKey::from(&bridge_key), &mut rng);
+        assert_eq!(device.finish(&PublicKey::from([0u8; KEY_LEN])).err(), Some(E2eError::WeakKey));
+    }
+}
@@ -0,0 +1,15 @@
+//! Hosted codec benchmarks: the cases in `xous_mqtt::bench`, under criterion.
+//!
+//! Run with: cargo bench --features bench (from libs/mqtt)
+use criterion::{Criterion, black_box, criterion_group, criterion_main};
+use xous_mqtt::bench::{CASES, SCRATCH_LEN};
+
+fn codec(c: &mut Criterion) {
+    let mut scratch = [0u8; SCRATCH_LEN];
+    for case in CASES {
+        c.bench_function(case.name, |b| b.iter(|| (case.run)(black_box(&mut scratch))));
+    }
+}
+
+criterion_group!(benches, codec);
+criterion_main!(benches);
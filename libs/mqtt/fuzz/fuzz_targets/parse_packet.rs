@@ -0,0 +1,33 @@
+#![no_main]
+//! Feeds arbitrary broker input to every packet parser.
+//!
+//! Run with: cargo fuzz run parse_packet (from libs/mqtt)
+use libfuzzer_sys::fuzz_target;
+use xous_mqtt::packet::{self, Packet, ParseMode, v5};
+
+fuzz_target!(|data: &[u8]| {
+    // Consume the input the way the client drains its receive buffer
+    let mut rest = data;
+    while let Ok((parsed, consumed)) = packet::parse_packet(rest) {
+        let (parsed_ref, consumed_ref) = packet::parse_packet_ref(rest).unwrap();
+        assert_eq!(consumed, consumed_ref);
+        assert_eq!(parsed, Packet::from(parsed_ref));
+        assert!(consumed > 0 && consumed <= rest.len());
+        rest = &rest[consumed..];
+    }
+
+    // Strict mode only ever rejects more
+    if let Ok((parsed, consumed)) = packet::parse_packet_with(data, ParseMode::Strict) {
+        assert_eq!(packet::parse_packet(data), Ok((parsed, consumed)));
+    }
+
+    let mut rest = data;
+    while let Ok((_, consumed)) = v5::parse_packet(rest) {
+        assert!(consumed > 0 && consumed <= rest.len());
+        rest = &rest[consumed..];
+    }
+
+    let _ = packet::parse_publish_ref(data);
+    let _ = v5::parse_publish_ref(data);
+    let _ = v5::decode_properties(data);
+});
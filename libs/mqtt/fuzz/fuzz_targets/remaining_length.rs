@@ -0,0 +1,27 @@
+#![no_main]
+//! Feeds arbitrary fixed headers to the length decoding every parser shares.
+//!
+//! Run with: cargo fuzz run remaining_length (from libs/mqtt)
+use libfuzzer_sys::fuzz_target;
+use xous_mqtt::packet::{self, MAX_REMAINING_LEN, ParseError};
+
+fuzz_target!(|data: &[u8]| {
+    match packet::peek_packet_len(data) {
+        Ok(size) => {
+            let len_bytes = data[1..].iter().position(|&byte| byte & 0x80 == 0).unwrap() + 1;
+            assert!(len_bytes <= 4);
+            let remaining_len = size - 1 - len_bytes;
+            assert!(remaining_len <= MAX_REMAINING_LEN);
+            // Only the shortest encoding is accepted, the one the builders write
+            assert_eq!(packet::packet_len(remaining_len), size);
+
+            // Once the whole packet is there, parsers answer instead of waiting
+            if data.len() >= size {
+                assert_ne!(packet::parse_packet_ref(data).err(), Some(ParseError::Incomplete));
+            }
+        }
+        // Waiting is only for the rest of the length itself
+        Err(ParseError::Incomplete) => assert!(data.len() < 5),
+        Err(_) => {}
+    }
+});
@@ -0,0 +1,144 @@
+//! Client-side Topic Access Control
+//!
+//! A [`TopicAcl`] limits which topics a client (or one [`ChannelMux`]
+//! channel) may publish to and subscribe to. It is checked before any
+//! packet is built, so a misbehaving component sharing the connection can't
+//! reach outside its namespace even if the broker would let it.
+//!
+//! Each direction has an allowlist and a denylist of topic filters. An empty
+//! allowlist permits everything; the denylist always wins.
+//!
+//! ```rust
+//! use xous_mqtt::TopicFilter;
+//! use xous_mqtt::acl::TopicAcl;
+//!
+//! let acl = TopicAcl::new()
+//!     .allow_publish(TopicFilter::parse("ccr/plugin/#").unwrap())
+//!     .allow_subscribe(TopicFilter::parse("ccr/+/events").unwrap())
+//!     .deny_subscribe(TopicFilter::parse("ccr/admin/#").unwrap());
+//!
+//! assert!(acl.check_publish("ccr/plugin/status").is_ok());
+//! assert!(acl.check_publish("ccr/policy").is_err());
+//! assert!(acl.check_subscribe("ccr/s1/events").is_ok());
+//! assert!(acl.check_subscribe("ccr/#").is_err());
+//! ```
+//!
+//! [`ChannelMux`]: crate::channel::ChannelMux
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::topic::{TopicFilter, filter_covers, filters_overlap};
+
+/// Which operation an ACL refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclDenied {
+    Publish,
+    Subscribe,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Rules {
+    allow: Vec<TopicFilter>,
+    deny: Vec<TopicFilter>,
+}
+
+/// Publish and subscribe allowlists/denylists
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TopicAcl {
+    publish: Rules,
+    subscribe: Rules,
+}
+
+impl TopicAcl {
+    /// An ACL that permits everything
+    pub fn new() -> Self { Self::default() }
+
+    /// Permit publishing to topics matched by `filter`
+    pub fn allow_publish(mut self, filter: TopicFilter) -> Self {
+        self.publish.allow.push(filter);
+        self
+    }
+
+    /// Refuse publishing to topics matched by `filter`
+    pub fn deny_publish(mut self, filter: TopicFilter) -> Self {
+        self.publish.deny.push(filter);
+        self
+    }
+
+    /// Permit subscriptions that stay within `filter`
+    pub fn allow_subscribe(mut self, filter: TopicFilter) -> Self {
+        self.subscribe.allow.push(filter);
+        self
+    }
+
+    /// Refuse subscriptions that could receive any topic matched by `filter`
+    pub fn deny_subscribe(mut self, filter: TopicFilter) -> Self {
+        self.subscribe.deny.push(filter);
+        self
+    }
+
+    /// Whether the ACL places no restrictions at all
+    pub fn is_open(&self) -> bool { *self == Self::default() }
+
+    /// Check a PUBLISH to `topic`
+    pub fn check_publish(&self, topic: &str) -> Result<(), AclDenied> {
+        let rules = &self.publish;
+        let allowed = rules.allow.is_empty() || rules.allow.iter().any(|f| f.matches(topic));
+        if !allowed || rules.deny.iter().any(|f| f.matches(topic)) {
+            return Err(AclDenied::Publish);
+        }
+        Ok(())
+    }
+
+    /// Check a SUBSCRIBE to `filter`
+    ///
+    /// The subscription must be covered by one allowed filter in full, and
+    /// must not overlap any denied filter, so wildcards can't be used to
+    /// widen it past the permitted namespace.
+    pub fn check_subscribe(&self, filter: &str) -> Result<(), AclDenied> {
+        let rules = &self.subscribe;
+        let allowed = rules.allow.is_empty() || rules.allow.iter().any(|f| filter_covers(f.as_str(), filter));
+        if !allowed || rules.deny.iter().any(|f| filters_overlap(f.as_str(), filter)) {
+            return Err(AclDenied::Subscribe);
+        }
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(s: &str) -> TopicFilter { TopicFilter::parse(s).unwrap() }
+
+    #[test]
+    fn test_open_acl_permits_everything() {
+        let acl = TopicAcl::new();
+        assert!(acl.is_open());
+        assert!(acl.check_publish("any/topic").is_ok());
+        assert!(acl.check_subscribe("#").is_ok());
+    }
+
+    #[test]
+    fn test_deny_overrides_allow() {
+        let acl = TopicAcl::new()
+            .allow_publish(filter("ccr/#"))
+            .deny_publish(filter("ccr/policy"))
+            .allow_subscribe(filter("ccr/#"))
+            .deny_subscribe(filter("ccr/+/secrets"));
+
+        assert!(acl.check_publish("ccr/s1/events").is_ok());
+        assert_eq!(acl.check_publish("ccr/policy"), Err(AclDenied::Publish));
+        assert_eq!(acl.check_publish("other"), Err(AclDenied::Publish));
+
+        assert!(acl.check_subscribe("ccr/s1/events").is_ok());
+        // Would also receive ccr/<id>/secrets
+        assert_eq!(acl.check_subscribe("ccr/s1/#"), Err(AclDenied::Subscribe));
+        assert_eq!(acl.check_subscribe("#"), Err(AclDenied::Subscribe));
+    }
+}
@@ -0,0 +1,163 @@
+//! Codec Benchmarks
+//!
+//! The packet work a client does per message, as a fixed set of [`CASES`]:
+//! encoding CONNECT, PUBLISH and SUBSCRIBE, both into a fresh `Vec` and into
+//! a reused buffer, and parsing what comes back, a PUBLISH and a SUBACK.
+//! The client never parses CONNECT or SUBSCRIBE, so those aren't measured.
+//!
+//! [`run`] times every case against a [`Clock`], so on a device it runs with
+//! the ticktimer:
+//!
+//! ```rust,ignore
+//! use xous_mqtt::{bench, clock::TicktimerClock};
+//!
+//! for m in bench::run(&TicktimerClock::new(), 10_000) {
+//!     log::info!("{}: {} ns", m.name, m.ns_per_iter());
+//! }
+//! ```
+//!
+//! The clock only counts milliseconds, so `iterations` should make each case
+//! take a few tens of them. Hosted, `cargo bench --features bench` runs the
+//! same cases under criterion.
+
+extern crate alloc;
+use alloc::vec::Vec;
+use core::hint::black_box;
+
+use crate::clock::Clock;
+use crate::packet::{self, QoS};
+
+/// Buffer every case can encode into
+pub const SCRATCH_LEN: usize = 512;
+
+const CLIENT_ID: &str = "precursor-0123456789abcdef";
+const TOPIC: &str = "ccr/permission/request";
+/// About what CCR's bridge sends for a permission prompt
+const PAYLOAD: &[u8] = concat!(
+    r#"{"id":"7f3c2a9e-41d0-4b8e-9c55-0e6a1b2c3d4e","tool":"Bash","#,
+    r#""command":"cargo test --workspace","cwd":"/home/dev/xous-core","sent_ms":1700000000000}"#
+)
+.as_bytes();
+const FILTERS: &[(&str, QoS)] = &[
+    ("ccr/permission/#", QoS::AtLeastOnce),
+    ("ccr/status", QoS::AtMostOnce),
+    ("bench/+/ccr", QoS::AtMostOnce),
+];
+
+static PUBLISH: &[u8] = crate::prebuilt_publish!(TOPIC, PAYLOAD, false);
+static SUBACK: &[u8] = &[0x90, 0x05, 0x00, 0x07, 0x01, 0x00, 0x00];
+
+/// One benchmarked operation
+pub struct Case {
+    pub name: &'static str,
+    /// Do the operation once, using `scratch` as the output buffer if it
+    /// needs one; returns a size so the work can't be optimized away
+    pub run: fn(scratch: &mut [u8]) -> usize,
+}
+
+pub const CASES: &[Case] = &[
+    Case { name: "connect_encode", run: connect_encode },
+    Case { name: "connect_encode_into", run: connect_encode_into },
+    Case { name: "publish_encode", run: publish_encode },
+    Case { name: "publish_encode_into", run: publish_encode_into },
+    Case { name: "subscribe_encode", run: subscribe_encode },
+    Case { name: "subscribe_encode_into", run: subscribe_encode_into },
+    Case { name: "publish_parse", run: publish_parse },
+    Case { name: "publish_parse_ref", run: publish_parse_ref },
+    Case { name: "suback_parse", run: suback_parse },
+];
+
+/// Time taken by one case
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Measurement {
+    pub name: &'static str,
+    pub iterations: u32,
+    pub elapsed_ms: u64,
+}
+
+impl Measurement {
+    /// Average time per iteration
+    pub fn ns_per_iter(&self) -> u64 { self.elapsed_ms * 1_000_000 / u64::from(self.iterations.max(1)) }
+}
+
+/// Run every case `iterations` times, timed by `clock`
+pub fn run(clock: &dyn Clock, iterations: u32) -> Vec<Measurement> {
+    let mut scratch = [0u8; SCRATCH_LEN];
+    CASES
+        .iter()
+        .map(|case| {
+            let start = clock.now_ms();
+            for _ in 0..iterations {
+                black_box((case.run)(black_box(&mut scratch)));
+            }
+            Measurement { name: case.name, iterations, elapsed_ms: clock.now_ms() - start }
+        })
+        .collect()
+}
+
+fn connect_encode(_: &mut [u8]) -> usize {
+    let password = Some(b"secret".as_slice());
+    packet::build_connect_with_options(black_box(CLIENT_ID), Some("ccr"), password, true, 60).len()
+}
+
+fn connect_encode_into(scratch: &mut [u8]) -> usize {
+    let password = Some(b"secret".as_slice());
+    packet::build_connect_with_options_into(scratch, black_box(CLIENT_ID), Some("ccr"), password, true, 60)
+        .unwrap_or(0)
+}
+
+fn publish_encode(_: &mut [u8]) -> usize {
+    packet::build_publish_with_id(black_box(TOPIC), black_box(PAYLOAD), QoS::AtLeastOnce, Some(42), false)
+        .len()
+}
+
+fn publish_encode_into(scratch: &mut [u8]) -> usize {
+    let (topic, payload) = (black_box(TOPIC), black_box(PAYLOAD));
+    packet::build_publish_with_id_into(scratch, topic, payload, QoS::AtLeastOnce, Some(42), false)
+        .unwrap_or(0)
+}
+
+fn subscribe_encode(_: &mut [u8]) -> usize { packet::build_subscribe_many(7, black_box(FILTERS)).len() }
+
+fn subscribe_encode_into(scratch: &mut [u8]) -> usize {
+    packet::build_subscribe_many_into(scratch, 7, black_box(FILTERS)).unwrap_or(0)
+}
+
+fn publish_parse(_: &mut [u8]) -> usize {
+    match packet::parse_packet(black_box(PUBLISH)) {
+        Ok((packet::Packet::Publish { payload, .. }, _)) => payload.len(),
+        _ => 0,
+    }
+}
+
+fn publish_parse_ref(_: &mut [u8]) -> usize {
+    packet::parse_publish_ref(black_box(PUBLISH)).map_or(0, |(publish, _)| publish.payload.len())
+}
+
+fn suback_parse(_: &mut [u8]) -> usize {
+    match packet::parse_packet(black_box(SUBACK)) {
+        Ok((packet::Packet::Suback { return_codes, .. }, _)) => return_codes.len(),
+        _ => 0,
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_cases_do_the_work() {
+        let mut scratch = [0u8; SCRATCH_LEN];
+        for case in CASES {
+            assert!((case.run)(&mut scratch) > 0, "{} did nothing", case.name);
+        }
+        let measured = run(&ManualClock::new(0), 1);
+        assert_eq!(measured.len(), CASES.len());
+        assert_eq!(measured[0].ns_per_iter(), 0);
+    }
+}
@@ -0,0 +1,506 @@
+//! Logical Channels over one Broker Session
+//!
+//! [`ChannelMux`] lets several consumers in one process share a single
+//! [`MqttClient`]. Each [`Channel`] has its own subscription set and a
+//! bounded event queue. Incoming messages are routed by topic filter, so a
+//! consumer that falls behind only loses its own messages.
+//!
+//! A subscription can also cap how many of its messages sit in the channel
+//! queue ([`ChannelMux::subscribe_limited`]), so a chatty topic such as
+//! verbose tool output can't crowd out rarer, important ones such as
+//! permission requests.
+//!
+//! A channel can be confined to a topic namespace with
+//! [`ChannelMux::restrict`], e.g. when it is handed to a less trusted
+//! component. The client's own ACL still applies on top.
+//!
+//! The mux reads events with [`MqttClient::poll`], so `borrow_publish` must
+//! be left off in the client configuration.
+
+extern crate alloc;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use crate::acl::TopicAcl;
+use crate::client::{MqttClient, MqttError, MqttEvent};
+use crate::packet::QoS;
+use crate::topic::TopicFilter;
+
+/// Handle to a logical channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Channel(usize);
+
+/// What to do with a new event when a channel's queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Discard the oldest queued event to make room
+    DropOldest,
+    /// Discard the new event
+    DropNewest,
+}
+
+/// A channel's subscription to one filter
+struct Subscription {
+    id: u32,
+    filter: TopicFilter,
+    /// Maximum messages from this subscription queued at once
+    limit: Option<usize>,
+}
+
+/// Queued event, tagged with the subscription that matched it
+struct Queued {
+    subscription: Option<u32>,
+    event: MqttEvent,
+}
+
+struct ChannelState {
+    subscriptions: Vec<Subscription>,
+    next_subscription: u32,
+    queue: VecDeque<Queued>,
+    capacity: usize,
+    overflow: Overflow,
+    dropped: u32,
+    acl: TopicAcl,
+}
+
+impl ChannelState {
+    fn push(&mut self, event: MqttEvent) { self.push_tagged(None, event); }
+
+    /// Queue a message matched by subscription `id`, enforcing its limit first
+    fn push_message(&mut self, id: u32, limit: Option<usize>, event: MqttEvent) {
+        if let Some(limit) = limit {
+            let tag = Some(id);
+            if self.queue.iter().filter(|q| q.subscription == tag).count() >= limit {
+                self.dropped = self.dropped.saturating_add(1);
+                match self.overflow {
+                    Overflow::DropOldest => {
+                        if let Some(pos) = self.queue.iter().position(|q| q.subscription == tag) {
+                            self.queue.remove(pos);
+                        }
+                    }
+                    Overflow::DropNewest => return,
+                }
+            }
+        }
+        self.push_tagged(Some(id), event);
+    }
+
+    fn push_tagged(&mut self, subscription: Option<u32>, event: MqttEvent) {
+        if self.queue.len() >= self.capacity {
+            self.dropped = self.dropped.saturating_add(1);
+            match self.overflow {
+                Overflow::DropOldest => {
+                    self.queue.pop_front();
+                }
+                Overflow::DropNewest => return,
+            }
+        }
+        self.queue.push_back(Queued { subscription, event });
+    }
+}
+
+/// Broker subscription shared by one or more channels
+struct Route {
+    filter: TopicFilter,
+    qos: QoS,
+    /// Number of channels subscribed with this filter
+    refs: usize,
+}
+
+/// Shares one [`MqttClient`] between several [`Channel`]s
+pub struct ChannelMux {
+    client: MqttClient,
+    channels: Vec<Option<ChannelState>>,
+    routes: Vec<Route>,
+    /// Packet ids awaiting SUBACK/PUBACK/PUBCOMP, with the channel that sent them
+    pending: Vec<(u16, usize)>,
+}
+
+impl ChannelMux {
+    /// Take ownership of `client`
+    pub fn new(client: MqttClient) -> Self {
+        Self { client, channels: Vec::new(), routes: Vec::new(), pending: Vec::new() }
+    }
+
+    /// Get the shared client
+    pub fn client(&self) -> &MqttClient { &self.client }
+
+    /// Get the shared client mutably, e.g. to feed it received data
+    pub fn client_mut(&mut self) -> &mut MqttClient { &mut self.client }
+
+    /// Open a channel queueing at most `capacity` events
+    pub fn open(&mut self, capacity: usize, overflow: Overflow) -> Channel {
+        let state = ChannelState {
+            subscriptions: Vec::new(),
+            next_subscription: 0,
+            queue: VecDeque::new(),
+            capacity: capacity.max(1),
+            overflow,
+            dropped: 0,
+            acl: TopicAcl::new(),
+        };
+        match self.channels.iter().position(|c| c.is_none()) {
+            Some(index) => {
+                self.channels[index] = Some(state);
+                Channel(index)
+            }
+            None => {
+                self.channels.push(Some(state));
+                Channel(self.channels.len() - 1)
+            }
+        }
+    }
+
+    /// Close a channel, dropping its queue and any broker subscriptions no
+    /// other channel needs
+    pub fn close(&mut self, channel: Channel) {
+        let state = match self.channels.get_mut(channel.0).and_then(|c| c.take()) {
+            Some(state) => state,
+            None => return,
+        };
+        for subscription in state.subscriptions.iter() {
+            if let Err(e) = self.release_route(&subscription.filter) {
+                log::warn!("MQTT: Unsubscribe for closed channel failed: {:?}", e);
+            }
+        }
+        self.pending.retain(|&(_, index)| index != channel.0);
+    }
+
+    /// Limit what `channel` may publish and subscribe to from now on
+    ///
+    /// Existing subscriptions are left in place.
+    pub fn restrict(&mut self, channel: Channel, acl: TopicAcl) -> Result<(), MqttError> {
+        self.state_mut(channel)?.acl = acl;
+        Ok(())
+    }
+
+    /// Subscribe `channel` to `filter`
+    ///
+    /// The broker is only sent a SUBSCRIBE when no other channel holds the
+    /// filter at `qos` or higher; otherwise `Ok(None)` is returned.
+    pub fn subscribe(
+        &mut self,
+        channel: Channel,
+        filter: TopicFilter,
+        qos: QoS,
+    ) -> Result<Option<u16>, MqttError> {
+        self.add_subscription(channel, filter, qos, None)
+    }
+
+    /// Subscribe `channel` to `filter`, queueing at most `max_queued` of its
+    /// messages at once
+    ///
+    /// Messages beyond the limit are dropped according to the channel's
+    /// [`Overflow`] policy, applied to this subscription's messages only.
+    /// Calling this for a filter the channel already holds updates the limit.
+    pub fn subscribe_limited(
+        &mut self,
+        channel: Channel,
+        filter: TopicFilter,
+        qos: QoS,
+        max_queued: usize,
+    ) -> Result<Option<u16>, MqttError> {
+        self.add_subscription(channel, filter, qos, Some(max_queued.max(1)))
+    }
+
+    fn add_subscription(
+        &mut self,
+        channel: Channel,
+        filter: TopicFilter,
+        qos: QoS,
+        limit: Option<usize>,
+    ) -> Result<Option<u16>, MqttError> {
+        let state = self.state_mut(channel)?;
+        state.acl.check_subscribe(filter.as_str()).map_err(MqttError::NotPermitted)?;
+        if let Some(existing) = state.subscriptions.iter_mut().find(|s| s.filter == filter) {
+            existing.limit = limit;
+            return Ok(None);
+        }
+
+        let packet_id = match self.routes.iter().position(|r| r.filter == filter) {
+            Some(index) if self.routes[index].qos as u8 >= qos as u8 => {
+                self.routes[index].refs += 1;
+                None
+            }
+            Some(index) => {
+                // Upgrade the shared subscription to the higher QoS
+                let packet_id = self.client.subscribe(filter.as_str(), qos)?;
+                let route = &mut self.routes[index];
+                route.qos = qos;
+                route.refs += 1;
+                Some(packet_id)
+            }
+            None => {
+                let packet_id = self.client.subscribe(filter.as_str(), qos)?;
+                self.routes.push(Route { filter: filter.clone(), qos, refs: 1 });
+                Some(packet_id)
+            }
+        };
+
+        if let Some(id) = packet_id {
+            self.pending.push((id, channel.0));
+        }
+        let state = self.state_mut(channel)?;
+        let id = state.next_subscription;
+        state.next_subscription = state.next_subscription.wrapping_add(1);
+        state.subscriptions.push(Subscription { id, filter, limit });
+        Ok(packet_id)
+    }
+
+    /// Unsubscribe `channel` from `filter`
+    ///
+    /// The broker is only sent an UNSUBSCRIBE once no channel holds the filter.
+    pub fn unsubscribe(&mut self, channel: Channel, filter: &TopicFilter) -> Result<Option<u16>, MqttError> {
+        let state = self.state_mut(channel)?;
+        match state.subscriptions.iter().position(|s| &s.filter == filter) {
+            Some(index) => {
+                state.subscriptions.remove(index);
+            }
+            None => return Ok(None),
+        }
+        self.release_route(filter)
+    }
+
+    /// Publish on behalf of `channel`; its acknowledgement is queued on `channel`
+    pub fn publish(
+        &mut self,
+        channel: Channel,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+    ) -> Result<Option<u16>, MqttError> {
+        self.state_mut(channel)?.acl.check_publish(topic).map_err(MqttError::NotPermitted)?;
+        let packet_id = self.client.publish(topic, payload, qos)?;
+        if let Some(id) = packet_id {
+            self.pending.push((id, channel.0));
+        }
+        Ok(packet_id)
+    }
+
+    /// Poll the next event for `channel` (non-blocking)
+    pub fn poll(&mut self, channel: Channel) -> Option<MqttEvent> {
+        self.pump();
+        self.state_mut(channel).ok()?.queue.pop_front().map(|q| q.event)
+    }
+
+    /// Number of events discarded because `channel`'s queue was full
+    pub fn dropped(&self, channel: Channel) -> u32 {
+        self.channels.get(channel.0).and_then(|c| c.as_ref()).map_or(0, |state| state.dropped)
+    }
+
+    /// Move all pending client events into channel queues
+    pub fn pump(&mut self) {
+        while let Some(event) = self.client.poll() {
+            self.route(event);
+        }
+    }
+
+    fn route(&mut self, event: MqttEvent) {
+        match event {
+            MqttEvent::Message { .. } => self.deliver(event),
+            #[cfg(feature = "codec")]
+            MqttEvent::TypedMessage { .. } => self.deliver(event),
+            MqttEvent::Subscribed { packet_id, .. }
+            | MqttEvent::PublishAcked { packet_id }
+            | MqttEvent::PublishComplete { packet_id } => {
+                if let Some(pos) = self.pending.iter().position(|&(id, _)| id == packet_id) {
+                    let (_, index) = self.pending.remove(pos);
+                    if let Some(Some(state)) = self.channels.get_mut(index) {
+                        state.push(event);
+                    }
+                }
+            }
+            MqttEvent::Connected => {
+                // A clean session starts with no subscriptions on the broker
+                if self.client.config().clean_session {
+                    for route in self.routes.iter() {
+                        if let Err(e) = self.client.subscribe(route.filter.as_str(), route.qos) {
+                            log::warn!("MQTT: Resubscribe to {} failed: {:?}", route.filter, e);
+                        }
+                    }
+                }
+                self.broadcast(event);
+            }
+            MqttEvent::Disconnected { .. } => {
+                if self.client.config().clean_session {
+                    self.pending.clear();
+                }
+                self.broadcast(event);
+            }
+            MqttEvent::Error(_) => self.broadcast(event),
+        }
+    }
+
+    /// Queue a message on each channel with a matching subscription
+    fn deliver(&mut self, event: MqttEvent) {
+        let Some(topic) = event.topic() else { return };
+        for state in self.channels.iter_mut().flatten() {
+            // The first matching subscription owns the message for limit accounting
+            let matched = state.subscriptions.iter().find(|s| s.filter.matches(topic));
+            if let Some(&Subscription { id, limit, .. }) = matched {
+                state.push_message(id, limit, event.clone());
+            }
+        }
+    }
+
+    fn broadcast(&mut self, event: MqttEvent) {
+        for state in self.channels.iter_mut().flatten() {
+            state.push(event.clone());
+        }
+    }
+
+    /// Drop one channel's reference to `filter`, unsubscribing at the broker
+    /// when it was the last
+    fn release_route(&mut self, filter: &TopicFilter) -> Result<Option<u16>, MqttError> {
+        let index = match self.routes.iter().position(|r| &r.filter == filter) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        self.routes[index].refs -= 1;
+        if self.routes[index].refs > 0 {
+            return Ok(None);
+        }
+        self.routes.remove(index);
+        match self.client.unsubscribe(filter.as_str()) {
+            Ok(packet_id) => Ok(Some(packet_id)),
+            // Nothing to undo on the broker
+            Err(MqttError::NotConnected) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn state_mut(&mut self, channel: Channel) -> Result<&mut ChannelState, MqttError> {
+        self.channels.get_mut(channel.0).and_then(|c| c.as_mut()).ok_or(MqttError::ChannelClosed)
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::AclDenied;
+    use crate::client::MqttConfig;
+    use crate::packet;
+    use crate::transport::mock;
+
+    fn connected_mux() -> ChannelMux {
+        let (mut client, _, broker) = mock::client(MqttConfig::default());
+        mock::accept(&mut client, &broker);
+        ChannelMux::new(client)
+    }
+
+    fn filter(s: &str) -> TopicFilter { TopicFilter::parse(s).unwrap() }
+
+    #[test]
+    fn test_messages_routed_by_filter() {
+        let mut mux = connected_mux();
+        let ui = mux.open(8, Overflow::DropOldest);
+        let log = mux.open(8, Overflow::DropOldest);
+
+        assert!(mux.subscribe(ui, filter("ccr/+/events"), QoS::AtMostOnce).unwrap().is_some());
+        assert!(mux.subscribe(log, filter("ccr/#"), QoS::AtMostOnce).unwrap().is_some());
+        // Already held at a higher or equal QoS: no second SUBSCRIBE
+        assert!(mux.subscribe(log, filter("ccr/+/events"), QoS::AtMostOnce).unwrap().is_none());
+
+        let events = packet::build_publish("ccr/s1/events", b"e", QoS::AtMostOnce);
+        mux.client_mut().process_data(&events).unwrap();
+        mux.client_mut().process_data(&packet::build_publish("ccr/status", b"s", QoS::AtMostOnce)).unwrap();
+
+        assert!(
+            matches!(mux.poll(ui), Some(MqttEvent::Message { ref topic, .. }) if topic == "ccr/s1/events")
+        );
+        assert!(mux.poll(ui).is_none());
+        assert!(
+            matches!(mux.poll(log), Some(MqttEvent::Message { ref topic, .. }) if topic == "ccr/s1/events")
+        );
+        assert!(matches!(mux.poll(log), Some(MqttEvent::Message { ref topic, .. }) if topic == "ccr/status"));
+
+        // The shared filter stays subscribed until the last channel lets go
+        assert!(mux.unsubscribe(ui, &filter("ccr/+/events")).unwrap().is_none());
+        assert!(mux.unsubscribe(log, &filter("ccr/+/events")).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_backpressure_is_per_channel() {
+        let mut mux = connected_mux();
+        let slow = mux.open(2, Overflow::DropOldest);
+        let fast = mux.open(8, Overflow::DropNewest);
+        mux.subscribe(slow, filter("t"), QoS::AtMostOnce).unwrap();
+        mux.subscribe(fast, filter("t"), QoS::AtMostOnce).unwrap();
+
+        for payload in [b"1", b"2", b"3"] {
+            mux.client_mut().process_data(&packet::build_publish("t", payload, QoS::AtMostOnce)).unwrap();
+        }
+        mux.pump();
+
+        assert_eq!(mux.dropped(slow), 1);
+        assert_eq!(mux.dropped(fast), 0);
+        assert!(matches!(mux.poll(slow), Some(MqttEvent::Message { ref payload, .. }) if payload == b"2"));
+        assert!(matches!(mux.poll(fast), Some(MqttEvent::Message { ref payload, .. }) if payload == b"1"));
+    }
+
+    #[test]
+    fn test_acks_go_to_sender() {
+        let mut mux = connected_mux();
+        let a = mux.open(4, Overflow::DropOldest);
+        let b = mux.open(4, Overflow::DropOldest);
+        let id = mux.subscribe(b, filter("x"), QoS::AtLeastOnce).unwrap().unwrap();
+
+        // SUBACK granting QoS 1
+        mux.client_mut().process_data(&[0x90, 0x03, (id >> 8) as u8, id as u8, 0x01]).unwrap();
+        assert!(mux.poll(a).is_none());
+        assert!(matches!(mux.poll(b), Some(MqttEvent::Subscribed { packet_id, ref results })
+            if packet_id == id && results[0].granted == Some(QoS::AtLeastOnce)));
+
+        mux.close(b);
+        assert_eq!(mux.poll(b).map(|_| ()), None);
+        assert!(matches!(mux.subscribe(b, filter("y"), QoS::AtMostOnce), Err(MqttError::ChannelClosed)));
+    }
+
+    #[test]
+    fn test_subscription_limit_protects_other_topics() {
+        let mut mux = connected_mux();
+        let ch = mux.open(4, Overflow::DropOldest);
+        mux.subscribe_limited(ch, filter("ccr/tool/#"), QoS::AtMostOnce, 2).unwrap();
+        mux.subscribe(ch, filter("ccr/perm/#"), QoS::AtMostOnce).unwrap();
+
+        mux.client_mut().process_data(&packet::build_publish("ccr/perm/req", b"p", QoS::AtMostOnce)).unwrap();
+        for payload in [b"1", b"2", b"3", b"4", b"5"] {
+            let output = packet::build_publish("ccr/tool/out", payload, QoS::AtMostOnce);
+            mux.client_mut().process_data(&output).unwrap();
+        }
+        mux.pump();
+
+        assert_eq!(mux.dropped(ch), 3);
+        assert!(
+            matches!(mux.poll(ch), Some(MqttEvent::Message { ref topic, .. }) if topic == "ccr/perm/req")
+        );
+        assert!(matches!(mux.poll(ch), Some(MqttEvent::Message { ref payload, .. }) if payload == b"4"));
+        assert!(matches!(mux.poll(ch), Some(MqttEvent::Message { ref payload, .. }) if payload == b"5"));
+        assert!(mux.poll(ch).is_none());
+    }
+
+    #[test]
+    fn test_restricted_channel_stays_in_namespace() {
+        let mut mux = connected_mux();
+        let guest = mux.open(4, Overflow::DropOldest);
+        let host = mux.open(4, Overflow::DropOldest);
+        let acl = TopicAcl::new().allow_publish(filter("ccr/guest/#")).allow_subscribe(filter("ccr/guest/#"));
+        mux.restrict(guest, acl).unwrap();
+
+        assert!(mux.publish(guest, "ccr/guest/out", b"x", QoS::AtMostOnce).is_ok());
+        assert!(matches!(
+            mux.publish(guest, "ccr/policy", b"x", QoS::AtMostOnce),
+            Err(MqttError::NotPermitted(AclDenied::Publish))
+        ));
+        assert!(matches!(
+            mux.subscribe(guest, filter("ccr/#"), QoS::AtMostOnce),
+            Err(MqttError::NotPermitted(AclDenied::Subscribe))
+        ));
+        // Other channels are unaffected
+        assert!(mux.publish(host, "ccr/policy", b"x", QoS::AtMostOnce).is_ok());
+    }
+}
@@ -1,13 +1,42 @@
 //! MQTT Client for Xous OS
 //!
 //! Full-featured MQTT client using Xous Net service for TCP.
+//!
+//! The client never blocks: [`MqttClient::connect`] opens the socket and
+//! sends CONNECT, and [`MqttClient::poll`] reads whatever the broker has
+//! sent, turning CONNACK into `MqttEvent::Connected`.
 
 extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
 use alloc::string::String;
 use alloc::vec::Vec;
-use alloc::collections::VecDeque;
+use core::fmt;
+
+use crate::acl::{AclDenied, TopicAcl};
+use crate::clock::{Clock, TicktimerClock, Timestamp};
+#[cfg(feature = "codec")]
+use crate::codec::{self, Codec, CodecError};
+use crate::ordering::{Held, TopicSequencer};
+#[cfg(feature = "mqtt5")]
+use crate::packet::v5::{self, ReasonCode};
+use crate::packet::{self, Packet, PacketType, ParseError, ProtocolVersion, PublishRef, QoS, Will};
+use crate::packet_id::PacketIdAllocator;
+use crate::qos1::InflightStore;
+use crate::qos2::{Eviction, Inbound, Qos2Receiver, Qos2Sender};
+use crate::refusal::{DefaultRefusalPolicy, RefusalAction, RefusalPolicy, RefusedReason};
+use crate::resolve::{AddressFamily, ResolveError};
+use crate::session::{MemoryStore, SavedSubscription, SessionStore};
+use crate::socks::Socks5Proxy;
+use crate::subscriptions::{HandlerId, Subscriptions};
+use crate::topic::{TopicError, TopicFilter};
+use crate::transport::{Connector, OpenError, Recv, TcpConnector, Transport};
 
-use crate::packet::{self, Packet, QoS, ParseError};
+/// Upper bound on socket reads per `poll`, so a flood can't starve the caller
+const MAX_READS_PER_POLL: usize = 16;
+
+/// Default limit on events queued for `poll`
+pub const DEFAULT_EVENT_QUEUE_CAPACITY: usize = 256;
 
 /// MQTT client configuration
 #[derive(Debug, Clone)]
@@ -28,6 +57,57 @@ pub struct MqttConfig {
     pub auto_reconnect: bool,
     /// Reconnect delay in milliseconds
     pub reconnect_delay_ms: u64,
+    /// Resend an unacknowledged QoS 1/2 packet after this many milliseconds
+    pub retry_interval_ms: u64,
+    /// Give up opening the connection to the broker after this many milliseconds
+    pub connect_timeout_ms: u64,
+    /// Give up on a connection attempt if no CONNACK arrives within this many milliseconds
+    pub connack_timeout_ms: u64,
+    /// Abandon a SUBSCRIBE, UNSUBSCRIBE or QoS 1/2 PUBLISH still unacknowledged
+    /// this many milliseconds after it was first sent; 0 retries indefinitely
+    pub ack_timeout_ms: u64,
+    /// Leave PUBLISH packets in the receive buffer for [`MqttClient::poll_ref`]
+    /// instead of copying them into `MqttEvent::Message`
+    pub borrow_publish: bool,
+    /// Topics this client may publish and subscribe to; open by default
+    pub acl: TopicAcl,
+    /// Message the broker publishes if the connection drops without a DISCONNECT
+    pub will: Option<LastWill>,
+    /// Protocol level to request; an MQTT 5 client falls back to 3.1.1 for
+    /// good if the broker refuses it. `V31` is for legacy brokers that
+    /// refuse 3.1.1, and needs a `client_id` of 1 to 23 characters.
+    pub protocol: ProtocolVersion,
+    /// Largest packet sent or accepted, in bytes; a bigger PUBLISH is refused,
+    /// and a bigger incoming packet is never buffered
+    pub max_packet_size: usize,
+    /// What happens to an incoming packet over `max_packet_size`
+    pub oversized_packet: OversizedPacket,
+    /// Which of the broker's addresses to try, when its name has IPv4 and IPv6 ones
+    pub address_family: AddressFamily,
+    /// SOCKS5 proxy to reach the broker through, for networks that only
+    /// let traffic out via a bastion
+    pub proxy: Option<Socks5Proxy>,
+    /// Most received QoS 2 messages awaiting PUBREL, whose ids are kept to
+    /// recognise redeliveries
+    pub max_inbound_qos2: usize,
+    /// What gives when a QoS 2 message arrives with `max_inbound_qos2` awaiting PUBREL
+    pub inbound_qos2_eviction: Eviction,
+    /// Keep each topic's messages in publish order through retries and
+    /// reconnects, by sending one QoS 1/2 PUBLISH per topic at a time
+    pub ordered_delivery: bool,
+    /// Most events queued for `poll` at once, so a stalled consumer can't
+    /// exhaust memory
+    pub event_queue_capacity: usize,
+    /// What gives when an event arrives with `event_queue_capacity` queued
+    pub event_queue_overflow: QueueOverflow,
+    /// Most QoS 1/2 PUBLISHes sent and not yet acknowledged; 0 for no limit
+    pub max_inflight: usize,
+    /// What `publish` does with a QoS 1/2 message when `max_inflight` are
+    /// unacknowledged
+    pub inflight_full: InflightFull,
+    /// Key/value pairs sent as user properties in an MQTT 5 CONNECT
+    #[cfg(feature = "mqtt5")]
+    pub user_properties: Vec<(String, String)>,
 }
 
 impl Default for MqttConfig {
@@ -41,53 +121,306 @@ impl Default for MqttConfig {
             clean_session: true,
             auto_reconnect: true,
             reconnect_delay_ms: 5000,
+            retry_interval_ms: 10000,
+            connect_timeout_ms: 10000,
+            connack_timeout_ms: 10000,
+            ack_timeout_ms: 60000,
+            borrow_publish: false,
+            acl: TopicAcl::new(),
+            will: None,
+            protocol: ProtocolVersion::V311,
+            max_packet_size: crate::DEFAULT_MAX_PACKET_SIZE,
+            oversized_packet: OversizedPacket::default(),
+            address_family: AddressFamily::default(),
+            proxy: None,
+            max_inbound_qos2: crate::qos2::DEFAULT_MAX_PENDING,
+            inbound_qos2_eviction: Eviction::default(),
+            ordered_delivery: false,
+            event_queue_capacity: DEFAULT_EVENT_QUEUE_CAPACITY,
+            event_queue_overflow: QueueOverflow::default(),
+            max_inflight: 0,
+            inflight_full: InflightFull::default(),
+            #[cfg(feature = "mqtt5")]
+            user_properties: Vec::new(),
         }
     }
 }
 
+/// What happens to an event that arrives with the event queue full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueueOverflow {
+    /// Stop parsing received packets, and reading the connection, until
+    /// `poll` makes room. Nothing is lost; the broker just sees a slow
+    /// reader. Connection changes and errors are still queued.
+    #[default]
+    Backpressure,
+    /// Discard the oldest queued event to make room
+    DropOldest,
+    /// Discard the new event
+    DropNewest,
+}
+
+/// What happens to an incoming packet larger than `max_packet_size`
+///
+/// The size is known from the fixed header, so either way the packet's body
+/// never reaches the receive buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OversizedPacket {
+    /// End the connection, telling an MQTT 5 broker why
+    #[default]
+    Disconnect,
+    /// Discard the packet's bytes as they arrive and report
+    /// [`MqttError::PacketTooLarge`]; the connection carries on. A skipped
+    /// QoS 1/2 PUBLISH goes unacknowledged, so the broker may send it again
+    /// after a reconnect.
+    Skip,
+}
+
+/// What happens to a QoS 1/2 PUBLISH when the in-flight window is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InflightFull {
+    /// Hold it, unsent, until an acknowledgement makes room. At most
+    /// `max_inflight` are held; `publish` fails with
+    /// [`MqttError::WouldBlock`] beyond that.
+    #[default]
+    Queue,
+    /// Fail with [`MqttError::WouldBlock`]; nothing is sent
+    WouldBlock,
+}
+
+/// Last Will and Testament registered with the broker on connect
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LastWill {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+impl LastWill {
+    fn as_packet(&self) -> Will<'_> {
+        Will { topic: &self.topic, payload: &self.payload, qos: self.qos, retain: self.retain }
+    }
+}
+
+/// Why the connection to the broker ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// Application called `disconnect()`
+    Requested,
+    /// Broker closed the socket
+    BrokerClosed,
+    /// No PINGRESP within 1.5x the keep-alive interval
+    KeepAliveTimeout,
+    /// Broker sent data that violates the protocol
+    ProtocolError {
+        /// Type of the offending packet, if it could be identified
+        packet_type: Option<PacketType>,
+        error: ParseError,
+    },
+    /// Network I/O failed
+    TransportError(String),
+    /// Broker sent DISCONNECT with this MQTT 5 reason code
+    #[cfg(feature = "mqtt5")]
+    ServerDisconnect { reason: u8 },
+    /// Broker started a packet larger than `max_packet_size`
+    PacketTooLarge { size: usize },
+    /// Broker refused the connection in its CONNACK
+    Refused(RefusedReason),
+}
+
+/// What the broker made of one topic filter in a SUBSCRIBE
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscribeResult {
+    pub filter: String,
+    /// QoS asked for
+    pub requested: QoS,
+    /// QoS granted, or `None` where the broker refused the filter
+    pub granted: Option<QoS>,
+    /// Return code as sent: the granted QoS, `0x80`, or with MQTT 5 a
+    /// reason code saying why the filter was refused
+    pub code: u8,
+}
+
+impl SubscribeResult {
+    pub fn is_refused(&self) -> bool { self.granted.is_none() }
+
+    /// Granted, but at a lower QoS than asked for
+    pub fn is_downgraded(&self) -> bool {
+        self.granted.is_some_and(|granted| (granted as u8) < (self.requested as u8))
+    }
+}
+
 /// MQTT client events
 #[derive(Debug, Clone)]
 pub enum MqttEvent {
     /// Connected to broker
     Connected,
     /// Disconnected from broker
-    Disconnected,
+    Disconnected { reason: DisconnectReason },
     /// Received message
     Message {
         topic: String,
         payload: Vec<u8>,
+        /// The broker's stored value for the topic, sent because of a new
+        /// subscription, rather than a message published just now
+        retain: bool,
+        /// When the message was read from the connection
+        received: Timestamp,
+        /// MQTT 5 user properties, in the order sent
+        #[cfg(feature = "mqtt5")]
+        user_properties: Vec<(String, String)>,
+    },
+    /// Received message on a filter subscribed with [`MqttClient::subscribe_typed`]
+    #[cfg(feature = "codec")]
+    TypedMessage {
+        topic: String,
+        /// Encoding the subscriber expects; the payload is as received
+        codec: Codec,
+        payload: Vec<u8>,
+        retain: bool,
+        received: Timestamp,
+        #[cfg(feature = "mqtt5")]
+        user_properties: Vec<(String, String)>,
     },
     /// Subscription confirmed
     Subscribed {
         packet_id: u16,
+        /// Outcome for each requested filter, in request order
+        results: Vec<SubscribeResult>,
     },
     /// Publish acknowledged (QoS 1)
-    PublishAcked {
-        packet_id: u16,
-    },
+    PublishAcked { packet_id: u16 },
     /// Publish complete (QoS 2)
-    PublishComplete {
-        packet_id: u16,
-    },
+    PublishComplete { packet_id: u16 },
     /// Error occurred
     Error(MqttError),
 }
 
+impl MqttEvent {
+    /// Topic of a received message
+    pub fn topic(&self) -> Option<&str> {
+        match self {
+            Self::Message { topic, .. } => Some(topic),
+            #[cfg(feature = "codec")]
+            Self::TypedMessage { topic, .. } => Some(topic),
+            _ => None,
+        }
+    }
+}
+
+/// Received message borrowed from the client's receive buffer
+///
+/// Returned by [`MqttClient::poll_ref`]; valid until the next call on the client.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageRef<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub qos: QoS,
+    pub retain: bool,
+}
+
 /// MQTT client errors
 #[derive(Debug, Clone)]
 pub enum MqttError {
-    /// Connection failed
-    ConnectionFailed(String),
-    /// Connection refused by broker
-    ConnectionRefused(u8),
-    /// Network I/O error
-    IoError(String),
-    /// Protocol error
-    ProtocolError(String),
-    /// Timeout
-    Timeout,
+    /// Couldn't open a connection to `broker`
+    ConnectionFailed {
+        broker: String,
+        /// What the transport reported
+        cause: String,
+    },
+    /// `broker` couldn't be resolved to an address
+    Resolve { broker: String, error: ResolveError },
+    /// `broker` refused the connection in its CONNACK
+    ConnectionRefused { broker: String, reason: RefusedReason },
+    /// The connection to `broker` was lost, e.g. to an I/O error or a
+    /// malformed packet; `reason` has the details
+    Disconnected { broker: String, reason: DisconnectReason },
+    /// The request can't be made into a valid packet of this type; nothing was sent
+    ProtocolError { packet_type: PacketType, reason: &'static str },
+    /// `broker` didn't answer in time
+    Timeout {
+        broker: String,
+        /// Packet that never came, or `None` if the socket couldn't be opened
+        packet_type: Option<PacketType>,
+    },
     /// Not connected
     NotConnected,
+    /// Every packet id is held by an unacknowledged exchange
+    NoPacketIds,
+    /// Channel handle refers to a closed channel
+    ChannelClosed,
+    /// Connection handle refers to a connection no longer managed
+    UnknownConnection,
+    /// The event queue is full; data fed to `process_data` is held until
+    /// `poll` makes room
+    QueueFull,
+    /// `max_inflight` QoS 1/2 PUBLISHes are unacknowledged; publish again
+    /// once one is
+    WouldBlock,
+    /// No acknowledgement for this packet within `ack_timeout_ms`; the
+    /// exchange has been abandoned
+    AckTimeout { packet_id: u16 },
+    /// Topic refused by the client-side ACL
+    NotPermitted(AclDenied),
+    /// Malformed topic name or filter; nothing was sent
+    InvalidTopic(TopicError),
+    /// Payload couldn't be serialized; nothing was sent
+    #[cfg(feature = "codec")]
+    Encode(CodecError),
+    /// Packet over the size limit in force: an outgoing one wasn't sent,
+    /// an incoming one was skipped
+    PacketTooLarge { size: usize, limit: usize },
+    /// Broker refused a QoS 1/2 PUBLISH with this MQTT 5 reason code
+    #[cfg(feature = "mqtt5")]
+    Rejected { packet_id: u16, reason: u8 },
+    /// The broker lost the session while this QoS 2 PUBLISH awaited PUBCOMP;
+    /// whether it was delivered is unknown
+    SessionLost { packet_id: u16 },
+    /// This PUBLISH expired before the broker acknowledged it and won't be sent again
+    Expired { packet_id: u16 },
+}
+
+impl fmt::Display for MqttError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ConnectionFailed { broker, cause } => write!(f, "can't connect to {}: {}", broker, cause),
+            Self::Resolve { broker, error } => write!(f, "can't resolve {}: {:?}", broker, error),
+            Self::ConnectionRefused { broker, reason } => {
+                write!(f, "{} refused the connection: {:?}", broker, reason)
+            }
+            Self::Disconnected { broker, reason } => write!(f, "lost connection to {}: {:?}", broker, reason),
+            Self::ProtocolError { packet_type, reason } => write!(f, "invalid {:?}: {}", packet_type, reason),
+            Self::Timeout { broker, packet_type: Some(packet_type) } => {
+                write!(f, "no {:?} from {}", packet_type, broker)
+            }
+            Self::Timeout { broker, packet_type: None } => write!(f, "no answer from {}", broker),
+            Self::NotConnected => write!(f, "not connected"),
+            Self::NoPacketIds => write!(f, "no free packet ids"),
+            Self::ChannelClosed => write!(f, "channel closed"),
+            Self::UnknownConnection => write!(f, "unknown connection"),
+            Self::QueueFull => write!(f, "event queue full"),
+            Self::WouldBlock => write!(f, "too many publishes awaiting acknowledgement"),
+            Self::AckTimeout { packet_id } => write!(f, "no acknowledgement for packet {}", packet_id),
+            Self::NotPermitted(denied) => write!(f, "{:?} not permitted on this topic", denied),
+            Self::InvalidTopic(error) => write!(f, "invalid topic: {:?}", error),
+            #[cfg(feature = "codec")]
+            Self::Encode(error) => write!(f, "can't encode payload: {}", error),
+            Self::PacketTooLarge { size, limit } => {
+                write!(f, "packet of {} bytes exceeds the limit of {}", size, limit)
+            }
+            #[cfg(feature = "mqtt5")]
+            Self::Rejected { packet_id, reason } => {
+                write!(f, "broker rejected packet {} with reason 0x{:02x}", packet_id, reason)
+            }
+            Self::SessionLost { packet_id } => {
+                write!(f, "broker lost the session; delivery of packet {} is unknown", packet_id)
+            }
+            Self::Expired { packet_id } => {
+                write!(f, "packet {} expired before it was acknowledged", packet_id)
+            }
+        }
+    }
 }
 
 /// MQTT connection state
@@ -104,250 +437,2492 @@ pub enum ConnectionState {
 /// Requires `xous-client` feature.
 pub struct MqttClient {
     config: MqttConfig,
+    /// Protocol level in use, starting at `config.protocol`
+    protocol: ProtocolVersion,
+    /// Keep-alive in force; an MQTT 5 broker may override the configured one
+    keep_alive_secs: u16,
+    /// Largest packet we may send; an MQTT 5 broker may lower the configured one
+    max_outgoing: usize,
     state: ConnectionState,
-    packet_id: u16,
+    /// The broker resumed a persistent session on the current connection
+    session_present: bool,
+    /// Ids of unacknowledged SUBSCRIBE, UNSUBSCRIBE and QoS 1/2 PUBLISH packets
+    packet_ids: PacketIdAllocator,
+    /// When each outstanding packet id was first sent; entries for released ids are dropped lazily
+    ack_started: Vec<(u16, u64)>,
+    /// When QoS 1/2 PUBLISHes sent with an expiry interval expire, likewise
+    expiry: Vec<(u16, u64)>,
     rx_buffer: Vec<u8>,
+    /// Length of the PUBLISH at the front of `rx_buffer` lent out by `poll_ref`
+    rx_lent: usize,
+    connector: Box<dyn Connector>,
+    transport: Option<Box<dyn Transport>>,
+    /// PUBLISHes held for room in the in-flight window
+    window: VecDeque<Held>,
     event_queue: VecDeque<MqttEvent>,
-    last_ping_time: u64,
-    // TCP stream would be stored here when connected
-    // stream: Option<TcpStream>,
+    /// Events discarded by the overflow policy
+    events_dropped: u32,
+    /// Returned by `last_error`
+    last_error: Option<MqttError>,
+    /// Parsing stopped with packets left in `rx_buffer` because the event queue is full
+    rx_held: bool,
+    /// Bytes still to discard of an oversized packet being skipped
+    rx_skip: usize,
+    clock: Box<dyn Clock>,
+    /// Time the current connection attempt started
+    connect_started_ms: u64,
+    /// Time the last packet was sent
+    last_tx_ms: u64,
+    /// Time of the outstanding PINGREQ, if any
+    ping_sent_ms: Option<u64>,
+    /// When to attempt the next automatic reconnect
+    reconnect_at_ms: Option<u64>,
+    /// Outbound QoS 1 PUBLISHes awaiting PUBACK
+    inflight: InflightStore,
+    /// Outbound QoS 2 exchanges
+    qos2_out: Qos2Sender,
+    /// PUBLISHes held behind another on their topic, with `ordered_delivery`
+    sequencer: TopicSequencer,
+    /// Inbound QoS 2 messages delivered to the app but not yet released
+    qos2_in: Qos2Receiver,
+    session_store: Box<dyn SessionStore>,
+    /// Subscriptions of a persistent session, restored if the broker lost it
+    subscriptions: Vec<SavedSubscription>,
+    /// SUBSCRIBEs awaiting SUBACK, with the filters and QoS requested
+    pending_subscribe: Vec<(u16, Vec<SavedSubscription>)>,
+    /// UNSUBSCRIBEs of a persistent session awaiting UNSUBACK
+    pending_unsubscribe: Vec<(u16, Vec<String>)>,
+    /// Per-topic message handlers
+    handlers: Subscriptions,
+    /// Filters whose messages are queued as [`MqttEvent::TypedMessage`]
+    #[cfg(feature = "codec")]
+    typed: Vec<(String, Codec)>,
+    /// User properties of the MQTT 5 PUBLISH being handled
+    #[cfg(feature = "mqtt5")]
+    rx_user_properties: Vec<(String, String)>,
+    /// Decides what follows a refused CONNACK
+    refusal_policy: Box<dyn RefusalPolicy>,
+    /// Connections refused since the last one accepted
+    refusals: u32,
 }
 
 impl MqttClient {
-    /// Create a new MQTT client
-    pub fn new(config: MqttConfig) -> Self {
+    /// Create a new MQTT client timed by the Xous ticktimer
+    pub fn new(config: MqttConfig) -> Self { Self::with_session_store(config, Box::new(MemoryStore::new())) }
+
+    /// Create a new MQTT client whose session state is written through to `store`
+    ///
+    /// State saved by a previous run is restored, so a QoS 2 message delivered
+    /// before a reboot is not delivered again when the broker resends it. With
+    /// `clean_session` false the saved client id is used when the config
+    /// leaves it empty, and the saved subscriptions are re-sent if the broker
+    /// no longer holds the session. A config naming a different client id
+    /// starts without them.
+    pub fn with_session_store(config: MqttConfig, store: Box<dyn SessionStore>) -> Self {
+        Self::with_store_and_clock(config, store, Box::new(TicktimerClock::new()))
+    }
+
+    /// Create a new MQTT client timed by `clock`
+    pub fn with_clock(config: MqttConfig, clock: Box<dyn Clock>) -> Self {
+        Self::with_store_and_clock(config, Box::new(MemoryStore::new()), clock)
+    }
+
+    /// Create a new MQTT client with both the session store and clock supplied
+    pub fn with_store_and_clock(
+        mut config: MqttConfig,
+        mut store: Box<dyn SessionStore>,
+        clock: Box<dyn Clock>,
+    ) -> Self {
+        let qos2_in = Qos2Receiver::with_pending(&store.load_incoming_qos2(), clock.now_ms())
+            .with_limit(config.max_inbound_qos2, config.inbound_qos2_eviction);
+        let mut subscriptions = Vec::new();
+        if !config.clean_session {
+            let saved_id = store.load_client_id();
+            if config.client_id.is_empty() {
+                config.client_id = saved_id.unwrap_or_default();
+                subscriptions = store.load_subscriptions();
+            } else if saved_id.as_deref() != Some(config.client_id.as_str()) {
+                // The saved subscriptions belong to the old identity's session, not this one
+                store.save_client_id(&config.client_id);
+                store.save_subscriptions(&[]);
+            } else {
+                subscriptions = store.load_subscriptions();
+            }
+        } else if !store.load_subscriptions().is_empty() {
+            // Left over from a persistent session; the broker drops them on a clean connect
+            store.save_subscriptions(&[]);
+        }
         Self {
+            protocol: config.protocol,
+            keep_alive_secs: config.keep_alive_secs,
+            max_outgoing: config.max_packet_size,
+            connector: Box::new(TcpConnector::new(config.address_family).with_proxy(config.proxy.clone())),
             config,
             state: ConnectionState::Disconnected,
-            packet_id: 1,
+            session_present: false,
+            packet_ids: PacketIdAllocator::new(),
+            ack_started: Vec::new(),
+            expiry: Vec::new(),
             rx_buffer: Vec::with_capacity(4096),
+            rx_lent: 0,
+            transport: None,
+            window: VecDeque::new(),
             event_queue: VecDeque::new(),
-            last_ping_time: 0,
+            events_dropped: 0,
+            last_error: None,
+            rx_held: false,
+            rx_skip: 0,
+            connect_started_ms: 0,
+            last_tx_ms: clock.now_ms(),
+            clock,
+            ping_sent_ms: None,
+            reconnect_at_ms: None,
+            inflight: InflightStore::new(),
+            qos2_out: Qos2Sender::new(),
+            sequencer: TopicSequencer::new(),
+            qos2_in,
+            session_store: store,
+            subscriptions,
+            pending_subscribe: Vec::new(),
+            pending_unsubscribe: Vec::new(),
+            handlers: Subscriptions::new(),
+            #[cfg(feature = "codec")]
+            typed: Vec::new(),
+            #[cfg(feature = "mqtt5")]
+            rx_user_properties: Vec::new(),
+            refusal_policy: Box::new(DefaultRefusalPolicy),
+            refusals: 0,
         }
     }
 
-    /// Get current connection state
-    pub fn state(&self) -> ConnectionState {
-        self.state
+    /// Open broker connections with `connector` instead of plain TCP
+    pub fn with_connector(mut self, connector: Box<dyn Connector>) -> Self {
+        self.connector = connector;
+        self
+    }
+
+    /// Decide what to do about refused connections with `policy` instead of
+    /// [`DefaultRefusalPolicy`]
+    pub fn with_refusal_policy(mut self, policy: Box<dyn RefusalPolicy>) -> Self {
+        self.refusal_policy = policy;
+        self
     }
 
+    /// Get the client configuration
+    pub fn config(&self) -> &MqttConfig { &self.config }
+
+    /// Get current connection state
+    pub fn state(&self) -> ConnectionState { self.state }
+
     /// Check if connected
-    pub fn is_connected(&self) -> bool {
-        self.state == ConnectionState::Connected
+    pub fn is_connected(&self) -> bool { self.state == ConnectionState::Connected }
+
+    /// Whether the broker resumed the persistent session on connecting
+    ///
+    /// Always false with `clean_session`.
+    pub fn session_resumed(&self) -> bool { self.session_present }
+
+    /// Get next packet ID, skipping ids still awaiting acknowledgement
+    ///
+    /// The id is assumed sent straight away, which starts its `ack_timeout_ms`.
+    fn next_packet_id(&mut self) -> Result<u16, MqttError> {
+        let id = self.packet_ids.allocate().ok_or(MqttError::NoPacketIds)?;
+        self.start_ack_timer(id);
+        Ok(id)
+    }
+
+    /// Start (or restart) the `ack_timeout_ms` of packet id `id`
+    fn start_ack_timer(&mut self, id: u16) {
+        self.ack_started.retain(|&(started, _)| started != id);
+        self.ack_started.push((id, self.clock.now_ms()));
+    }
+
+    /// Encode a PUBLISH for the protocol level in use
+    fn build_publish(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        packet_id: Option<u16>,
+        options: &PublishOptions,
+    ) -> Vec<u8> {
+        match self.protocol {
+            ProtocolVersion::V31 | ProtocolVersion::V311 => {
+                packet::build_publish_with_id(topic, payload, qos, packet_id, options.retain)
+            }
+            #[cfg(feature = "mqtt5")]
+            ProtocolVersion::V5 => {
+                v5::build_publish(topic, payload, qos, packet_id, options.retain, &options.properties())
+            }
+        }
+    }
+
+    /// Size of the PUBLISH `build_publish` would encode
+    fn publish_len(&self, topic: &str, payload_len: usize, qos: QoS, options: &PublishOptions) -> usize {
+        match self.protocol {
+            ProtocolVersion::V31 | ProtocolVersion::V311 => {
+                // Nothing in the options takes space at 3.1.1
+                let _ = options;
+                packet::publish_len(topic, payload_len, qos)
+            }
+            #[cfg(feature = "mqtt5")]
+            ProtocolVersion::V5 => v5::publish_len(topic, payload_len, qos, &options.properties()),
+        }
+    }
+
+    /// Encode a SUBSCRIBE for the protocol level in use
+    fn build_subscribe(&self, packet_id: u16, topics: &[(&str, QoS)]) -> Vec<u8> {
+        match self.protocol {
+            ProtocolVersion::V31 | ProtocolVersion::V311 => packet::build_subscribe_many(packet_id, topics),
+            #[cfg(feature = "mqtt5")]
+            ProtocolVersion::V5 => v5::build_subscribe(packet_id, topics, &[]),
+        }
     }
 
-    /// Get next packet ID
-    fn next_packet_id(&mut self) -> u16 {
-        let id = self.packet_id;
-        self.packet_id = self.packet_id.wrapping_add(1);
-        if self.packet_id == 0 {
-            self.packet_id = 1;
+    /// Encode an UNSUBSCRIBE for the protocol level in use
+    fn build_unsubscribe(&self, packet_id: u16, topics: &[&str]) -> Vec<u8> {
+        match self.protocol {
+            ProtocolVersion::V31 | ProtocolVersion::V311 => packet::build_unsubscribe_many(packet_id, topics),
+            #[cfg(feature = "mqtt5")]
+            ProtocolVersion::V5 => v5::build_unsubscribe(packet_id, topics, &[]),
         }
-        id
     }
 
     /// Connect to broker
     ///
-    /// This is a placeholder - actual implementation requires TCP stream.
+    /// Opens the socket and sends CONNECT. The connection is usable once
+    /// `poll` has returned `MqttEvent::Connected`; if the broker doesn't answer
+    /// within `connack_timeout_ms` the attempt ends with `Disconnected`. Fails
+    /// with `MqttError::Timeout` if the socket can't be opened within
+    /// `connect_timeout_ms`.
     pub fn connect(&mut self) -> Result<(), MqttError> {
         if self.state != ConnectionState::Disconnected {
             return Ok(());
         }
+        if let Some(will) = self.config.will.as_ref() {
+            packet::validate_topic_name(&will.topic).map_err(MqttError::InvalidTopic)?;
+        }
+        if self.config.protocol == ProtocolVersion::V31 && !(1..=23).contains(&self.config.client_id.len()) {
+            let reason = "MQTT 3.1 needs a client id of 1 to 23 characters";
+            return Err(MqttError::ProtocolError { packet_type: PacketType::Connect, reason });
+        }
 
-        self.state = ConnectionState::Connecting;
-
-        // TODO: Implement TCP connection via Xous Net service
-        // 1. Parse broker address
-        // 2. Create TcpStream::connect()
-        // 3. Send CONNECT packet
-        // 4. Wait for CONNACK
-        // 5. Set state to Connected
-
+        self.reconnect_at_ms = None;
         log::info!("MQTT: Connecting to {}", self.config.broker);
+        let transport = match self.connector.open(&self.config.broker, self.config.connect_timeout_ms) {
+            Ok(transport) => transport,
+            Err(OpenError::Timeout) => {
+                log::warn!("MQTT: No answer from {}", self.config.broker);
+                self.schedule_reconnect();
+                let broker = self.config.broker.clone();
+                return Err(self.failed(MqttError::Timeout { broker, packet_type: None }));
+            }
+            Err(OpenError::Resolve(e)) => {
+                log::warn!("MQTT: Can't resolve {}: {:?}", self.config.broker, e);
+                self.schedule_reconnect();
+                let broker = self.config.broker.clone();
+                return Err(self.failed(MqttError::Resolve { broker, error: e }));
+            }
+            Err(OpenError::Failed(e)) => {
+                log::warn!("MQTT: Connection failed: {}", e);
+                self.schedule_reconnect();
+                let broker = self.config.broker.clone();
+                return Err(self.failed(MqttError::ConnectionFailed { broker, cause: e }));
+            }
+        };
+        self.transport = Some(transport);
+        self.state = ConnectionState::Connecting;
+        self.connect_started_ms = self.clock.now_ms();
 
-        // Build CONNECT packet
-        let _connect_packet = packet::build_connect_with_options(
-            &self.config.client_id,
-            self.config.username.as_deref(),
-            self.config.password.as_deref(),
-            self.config.clean_session,
-            self.config.keep_alive_secs,
-        );
+        // A clean session discards any QoS 2 exchange the broker had pending
+        if self.config.clean_session && !self.qos2_in.is_empty() {
+            self.qos2_in.clear();
+            self.session_store.save_incoming_qos2(&[]);
+        }
 
-        // Placeholder: would send packet and wait for CONNACK
-        self.state = ConnectionState::Connected;
-        self.event_queue.push_back(MqttEvent::Connected);
+        self.keep_alive_secs = self.config.keep_alive_secs;
+        self.max_outgoing = self.config.max_packet_size;
+        let will = self.config.will.as_ref().map(|will| will.as_packet());
+        let connect_packet = match self.protocol {
+            ProtocolVersion::V31 => packet::build_connect_v31(
+                &self.config.client_id,
+                self.config.username.as_deref(),
+                self.config.password.as_deref(),
+                self.config.clean_session,
+                self.config.keep_alive_secs,
+                will.as_ref(),
+            ),
+            ProtocolVersion::V311 => packet::build_connect_with_will(
+                &self.config.client_id,
+                self.config.username.as_deref(),
+                self.config.password.as_deref(),
+                self.config.clean_session,
+                self.config.keep_alive_secs,
+                will.as_ref(),
+            ),
+            #[cfg(feature = "mqtt5")]
+            ProtocolVersion::V5 => v5::build_connect(
+                &self.config.client_id,
+                self.config.username.as_deref(),
+                self.config.password.as_deref(),
+                self.config.clean_session,
+                self.config.keep_alive_secs,
+                will.as_ref(),
+                &self.connect_properties(),
+            ),
+        };
+        self.send(connect_packet);
 
         Ok(())
     }
 
+    /// Properties of an MQTT 5 CONNECT
+    #[cfg(feature = "mqtt5")]
+    fn connect_properties(&self) -> Vec<v5::Property> {
+        let mut properties = Vec::from([
+            // A 3.1.1 persistent session never expires
+            v5::Property::SessionExpiryInterval(if self.config.clean_session { 0 } else { u32::MAX }),
+            v5::Property::MaximumPacketSize(self.config.max_packet_size.min(u32::MAX as usize) as u32),
+        ]);
+        for (key, value) in &self.config.user_properties {
+            properties.push(v5::Property::UserProperty(key.clone(), value.clone()));
+        }
+        properties
+    }
+
     /// Disconnect from broker
     pub fn disconnect(&mut self) -> Result<(), MqttError> {
-        if self.state != ConnectionState::Connected {
+        if self.state == ConnectionState::Disconnected {
+            self.reconnect_at_ms = None;
             return Ok(());
         }
 
-        let _disconnect_packet = packet::build_disconnect();
-        // TODO: Send packet, close stream
+        if self.state == ConnectionState::Connected {
+            self.send(packet::build_disconnect());
+        }
+        self.connection_closed(DisconnectReason::Requested);
+
+        Ok(())
+    }
 
+    /// Close the socket and emit `Disconnected`
+    fn connection_closed(&mut self, reason: DisconnectReason) {
+        if self.state == ConnectionState::Disconnected {
+            return;
+        }
+        log::info!("MQTT: Disconnected ({:?})", reason);
         self.state = ConnectionState::Disconnected;
-        self.event_queue.push_back(MqttEvent::Disconnected);
+        self.session_present = false;
+        self.transport = None;
+        self.rx_buffer.clear();
+        self.rx_lent = 0;
+        self.rx_skip = 0;
+        self.ping_sent_ms = None;
+        self.pending_subscribe.clear();
+        self.pending_unsubscribe.clear();
+        // SUBACK and UNSUBACK won't come now; QoS 1/2 exchanges carry over to a resumed session
+        self.packet_ids.retain(|&id| {
+            self.inflight.contains(id) || self.qos2_out.contains(id) || holds(&self.window, id)
+        });
+        match reason {
+            DisconnectReason::Requested => self.reconnect_at_ms = None,
+            // Already reported as `ConnectionRefused`
+            DisconnectReason::Refused(_) => self.schedule_reconnect(),
+            _ => {
+                let broker = self.config.broker.clone();
+                self.last_error = Some(MqttError::Disconnected { broker, reason: reason.clone() });
+                self.schedule_reconnect();
+            }
+        }
+        self.push_event(MqttEvent::Disconnected { reason });
+    }
 
-        Ok(())
+    /// Arm the automatic reconnect timer if enabled
+    fn schedule_reconnect(&mut self) {
+        self.reconnect_at_ms = if self.config.auto_reconnect {
+            Some(self.clock.now_ms() + self.config.reconnect_delay_ms)
+        } else {
+            None
+        };
+    }
+
+    /// Drop the connection after unparseable data from the broker
+    fn protocol_error(&mut self, error: ParseError) {
+        log::error!("MQTT: Parse error: {:?}", error);
+        let packet_type = self.rx_buffer.first().and_then(|&b| PacketType::from_byte(b));
+        self.rx_buffer.clear();
+        self.rx_lent = 0;
+        self.connection_closed(DisconnectReason::ProtocolError { packet_type, error });
+    }
+
+    /// Skip or drop the connection, as configured, rather than buffer a packet over `max_packet_size`
+    fn packet_too_large(&mut self, size: usize) {
+        let limit = self.config.max_packet_size;
+        if self.config.oversized_packet == OversizedPacket::Skip {
+            log::warn!("MQTT: Skipping incoming packet of {} bytes, over limit of {}", size, limit);
+            let buffered = size.min(self.rx_buffer.len());
+            self.rx_buffer.drain(..buffered);
+            self.rx_skip = size - buffered;
+            self.push_event(MqttEvent::Error(MqttError::PacketTooLarge { size, limit }));
+            return;
+        }
+        log::error!("MQTT: Incoming packet of {} bytes exceeds limit of {}", size, limit);
+        #[cfg(feature = "mqtt5")]
+        if self.protocol == ProtocolVersion::V5 && self.state == ConnectionState::Connected {
+            self.send(v5::build_disconnect(ReasonCode::PACKET_TOO_LARGE, &[]));
+        }
+        self.rx_buffer.clear();
+        self.rx_lent = 0;
+        self.connection_closed(DisconnectReason::PacketTooLarge { size });
     }
 
     /// Subscribe to a topic
     pub fn subscribe(&mut self, topic: &str, qos: QoS) -> Result<u16, MqttError> {
+        packet::validate_topic_filter(topic).map_err(MqttError::InvalidTopic)?;
+        if let Err(denied) = self.config.acl.check_subscribe(topic) {
+            log::warn!("MQTT: Subscription to {} refused by ACL", topic);
+            return Err(MqttError::NotPermitted(denied));
+        }
         if self.state != ConnectionState::Connected {
             return Err(MqttError::NotConnected);
         }
 
-        let packet_id = self.next_packet_id();
-        let _subscribe_packet = packet::build_subscribe(packet_id, topic, qos);
-
-        // TODO: Send packet
+        let packet_id = self.next_packet_id()?;
+        self.send(self.build_subscribe(packet_id, &[(topic, qos)]));
+        self.track_subscribe(packet_id, &[(topic, qos)]);
         log::info!("MQTT: Subscribing to {} (id={})", topic, packet_id);
 
         Ok(packet_id)
     }
 
+    /// Subscribe to several topics with a single SUBSCRIBE
+    ///
+    /// The resulting [`MqttEvent::Subscribed`] reports the outcome for each
+    /// filter in the order given here.
+    pub fn subscribe_many(&mut self, topics: &[(&str, QoS)]) -> Result<u16, MqttError> {
+        if topics.is_empty() {
+            let reason = "SUBSCRIBE needs at least one topic";
+            return Err(MqttError::ProtocolError { packet_type: PacketType::Subscribe, reason });
+        }
+        for &(topic, _) in topics {
+            packet::validate_topic_filter(topic).map_err(MqttError::InvalidTopic)?;
+            if let Err(denied) = self.config.acl.check_subscribe(topic) {
+                log::warn!("MQTT: Subscription to {} refused by ACL", topic);
+                return Err(MqttError::NotPermitted(denied));
+            }
+        }
+        if self.state != ConnectionState::Connected {
+            return Err(MqttError::NotConnected);
+        }
+
+        let packet_id = self.next_packet_id()?;
+        self.send(self.build_subscribe(packet_id, topics));
+        self.track_subscribe(packet_id, topics);
+        log::info!("MQTT: Subscribing to {} topics (id={})", topics.len(), packet_id);
+
+        Ok(packet_id)
+    }
+
+    /// Subscribe to a topic whose payloads are in `codec`
+    ///
+    /// Its messages are queued as [`MqttEvent::TypedMessage`] rather than
+    /// [`MqttEvent::Message`], until the filter is unsubscribed.
+    #[cfg(feature = "codec")]
+    pub fn subscribe_typed(&mut self, topic: &str, qos: QoS, codec: Codec) -> Result<u16, MqttError> {
+        let packet_id = self.subscribe(topic, qos)?;
+        self.typed.retain(|(filter, _)| filter != topic);
+        self.typed.push((String::from(topic), codec));
+        Ok(packet_id)
+    }
+
     /// Unsubscribe from a topic
     pub fn unsubscribe(&mut self, topic: &str) -> Result<u16, MqttError> {
+        packet::validate_topic_filter(topic).map_err(MqttError::InvalidTopic)?;
         if self.state != ConnectionState::Connected {
             return Err(MqttError::NotConnected);
         }
 
-        let packet_id = self.next_packet_id();
-        let _unsubscribe_packet = packet::build_unsubscribe(packet_id, topic);
-
-        // TODO: Send packet
+        let packet_id = self.next_packet_id()?;
+        self.send(self.build_unsubscribe(packet_id, &[topic]));
+        self.track_unsubscribe(packet_id, &[topic]);
+        #[cfg(feature = "codec")]
+        self.typed.retain(|(filter, _)| filter != topic);
         log::info!("MQTT: Unsubscribing from {} (id={})", topic, packet_id);
 
         Ok(packet_id)
     }
 
+    /// Unsubscribe from several topics with a single UNSUBSCRIBE
+    pub fn unsubscribe_many(&mut self, topics: &[&str]) -> Result<u16, MqttError> {
+        if topics.is_empty() {
+            let reason = "UNSUBSCRIBE needs at least one topic";
+            return Err(MqttError::ProtocolError { packet_type: PacketType::Unsubscribe, reason });
+        }
+        for topic in topics {
+            packet::validate_topic_filter(topic).map_err(MqttError::InvalidTopic)?;
+        }
+        if self.state != ConnectionState::Connected {
+            return Err(MqttError::NotConnected);
+        }
+
+        let packet_id = self.next_packet_id()?;
+        self.send(self.build_unsubscribe(packet_id, topics));
+        self.track_unsubscribe(packet_id, topics);
+        #[cfg(feature = "codec")]
+        self.typed.retain(|(filter, _)| !topics.contains(&filter.as_str()));
+        log::info!("MQTT: Unsubscribing from {} topics (id={})", topics.len(), packet_id);
+
+        Ok(packet_id)
+    }
+
+    /// Subscribe to `filter` and pass its messages to `handler` instead of
+    /// queueing them as [`MqttEvent::Message`]
+    ///
+    /// Messages no handler matches are still queued as events. A handler
+    /// registered while disconnected is subscribed once the broker accepts the
+    /// connection; the SUBSCRIBE is skipped when another handler already holds
+    /// `filter` at `qos` or higher.
+    pub fn subscribe_with(
+        &mut self,
+        filter: TopicFilter,
+        qos: QoS,
+        handler: impl FnMut(&str, &[u8]) + 'static,
+    ) -> Result<HandlerId, MqttError> {
+        if let Err(denied) = self.config.acl.check_subscribe(filter.as_str()) {
+            log::warn!("MQTT: Subscription to {} refused by ACL", filter);
+            return Err(MqttError::NotPermitted(denied));
+        }
+        let held = self.handlers.qos_for(&filter).is_some_and(|held| held as u8 >= qos as u8);
+        if !held && self.is_connected() {
+            self.subscribe(filter.as_str(), qos)?;
+        }
+        Ok(self.handlers.add(filter, qos, Box::new(handler)))
+    }
+
+    /// Remove a handler added with [`MqttClient::subscribe_with`]
+    ///
+    /// The broker is sent an UNSUBSCRIBE once no handler holds the filter.
+    pub fn remove_handler(&mut self, id: HandlerId) -> Result<(), MqttError> {
+        let Some(filter) = self.handlers.remove(id) else {
+            return Ok(());
+        };
+        if self.handlers.qos_for(&filter).is_none() && self.is_connected() {
+            self.unsubscribe(filter.as_str())?;
+        }
+        Ok(())
+    }
+
+    /// Subscribe the handlers' filters the broker doesn't already hold for us
+    fn subscribe_handlers(&mut self) {
+        let filters: Vec<(TopicFilter, QoS)> = self
+            .handlers
+            .filters()
+            .into_iter()
+            .filter(|(filter, _)| !self.subscriptions.iter().any(|saved| saved.filter == filter.as_str()))
+            .collect();
+        if filters.is_empty() {
+            return;
+        }
+        let topics: Vec<(&str, QoS)> = filters.iter().map(|(filter, qos)| (filter.as_str(), *qos)).collect();
+        if let Err(e) = self.subscribe_many(&topics) {
+            log::warn!("MQTT: Subscribing handlers failed: {:?}", e);
+        }
+    }
+
+    /// Subscriptions of the persistent session, as granted by the broker
+    ///
+    /// Always empty with `clean_session`, where the broker forgets them on
+    /// disconnect anyway.
+    pub fn subscriptions(&self) -> &[SavedSubscription] { &self.subscriptions }
+
+    /// Remember a SUBSCRIBE so its SUBACK can be matched to the filters
+    fn track_subscribe(&mut self, packet_id: u16, topics: &[(&str, QoS)]) {
+        let requested = topics
+            .iter()
+            .map(|&(filter, qos)| SavedSubscription { filter: String::from(filter), qos })
+            .collect();
+        self.pending_subscribe.push((packet_id, requested));
+    }
+
+    /// Remember an UNSUBSCRIBE so its UNSUBACK can update the persistent session
+    fn track_unsubscribe(&mut self, packet_id: u16, topics: &[&str]) {
+        if self.config.clean_session {
+            return;
+        }
+        let filters = topics.iter().map(|&filter| String::from(filter)).collect();
+        self.pending_unsubscribe.push((packet_id, filters));
+    }
+
+    /// Pair a SUBACK's return codes with the filters requested, and record
+    /// the subscriptions granted in a persistent session
+    fn subscribe_acked(&mut self, packet_id: u16, return_codes: &[u8]) -> Option<Vec<SubscribeResult>> {
+        let index = self.pending_subscribe.iter().position(|(id, _)| *id == packet_id)?;
+        let (_, requested) = self.pending_subscribe.swap_remove(index);
+        if requested.len() != return_codes.len() {
+            let (codes, filters) = (return_codes.len(), requested.len());
+            log::warn!("MQTT: SUBACK {} has {} codes for {} filters", packet_id, codes, filters);
+        }
+        let results: Vec<SubscribeResult> = requested
+            .into_iter()
+            .zip(return_codes)
+            .map(|(sub, &code)| SubscribeResult {
+                filter: sub.filter,
+                requested: sub.qos,
+                granted: QoS::from_suback(code),
+                code,
+            })
+            .collect();
+        if !self.config.clean_session {
+            for result in results.iter() {
+                self.subscriptions.retain(|saved| saved.filter != result.filter);
+                if let Some(qos) = result.granted {
+                    self.subscriptions.push(SavedSubscription { filter: result.filter.clone(), qos });
+                }
+            }
+            self.session_store.save_subscriptions(&self.subscriptions);
+        }
+        Some(results)
+    }
+
+    /// Forget the subscriptions an UNSUBACK removed
+    fn unsubscribe_acked(&mut self, packet_id: u16) {
+        let Some(index) = self.pending_unsubscribe.iter().position(|(id, _)| *id == packet_id) else {
+            return;
+        };
+        let (_, filters) = self.pending_unsubscribe.swap_remove(index);
+        self.subscriptions.retain(|saved| !filters.contains(&saved.filter));
+        self.session_store.save_subscriptions(&self.subscriptions);
+    }
+
+    /// Re-send the saved subscriptions after the broker lost the session
+    fn restore_subscriptions(&mut self) {
+        if self.subscriptions.is_empty() {
+            return;
+        }
+        let packet_id = match self.next_packet_id() {
+            Ok(id) => id,
+            Err(e) => {
+                log::warn!("MQTT: Can't restore subscriptions: {:?}", e);
+                return;
+            }
+        };
+        let topics: Vec<(&str, QoS)> =
+            self.subscriptions.iter().map(|sub| (sub.filter.as_str(), sub.qos)).collect();
+        let subscribe = self.build_subscribe(packet_id, &topics);
+        self.send(subscribe);
+        self.pending_subscribe.push((packet_id, self.subscriptions.clone()));
+        log::info!("MQTT: Restoring {} subscriptions (id={})", self.subscriptions.len(), packet_id);
+    }
+
     /// Publish a message
     pub fn publish(&mut self, topic: &str, payload: &[u8], qos: QoS) -> Result<Option<u16>, MqttError> {
+        self.publish_with_options(topic, payload, qos, PublishOptions::default())
+    }
+
+    /// Publish a message the broker keeps as the topic's current value
+    ///
+    /// Later subscribers receive it straight away, with `retain` set in
+    /// [`MqttEvent::Message`]. An empty payload clears the stored value.
+    pub fn publish_retained(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+    ) -> Result<Option<u16>, MqttError> {
+        self.publish_with_options(topic, payload, qos, PublishOptions { retain: true, ..Default::default() })
+    }
+
+    /// Publish a message that is only worth delivering for `expiry_secs`
+    ///
+    /// A QoS 1/2 message still unacknowledged when it expires is dropped
+    /// instead of being sent again, e.g. after a reconnect, and reported as
+    /// [`MqttError::Expired`]. At MQTT 5 the broker is told the interval too,
+    /// so it won't deliver the message late either.
+    pub fn publish_expiring(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        expiry_secs: u32,
+    ) -> Result<Option<u16>, MqttError> {
+        let options = PublishOptions { expiry_secs: Some(expiry_secs), ..Default::default() };
+        self.publish_with_options(topic, payload, qos, options)
+    }
+
+    /// Publish a message carrying MQTT 5 user properties, e.g. a payload schema version
+    ///
+    /// The properties are dropped on a 3.1.1 connection, including one the
+    /// client fell back to.
+    #[cfg(feature = "mqtt5")]
+    pub fn publish_with_properties(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        user_properties: &[(&str, &str)],
+    ) -> Result<Option<u16>, MqttError> {
+        let options = PublishOptions { user_properties, ..Default::default() };
+        self.publish_with_options(topic, payload, qos, options)
+    }
+
+    /// Publish a request the responder should answer on `reply_topic`
+    ///
+    /// At MQTT 5 the reply topic and `correlation` go in the PUBLISH; at
+    /// 3.1.1 they are dropped and the payload has to carry them.
+    pub(crate) fn publish_request(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        reply_topic: &str,
+        correlation: &[u8],
+    ) -> Result<Option<u16>, MqttError> {
+        let options = PublishOptions { response: Some((reply_topic, correlation)), ..Default::default() };
+        self.publish_with_options(topic, payload, qos, options)
+    }
+
+    /// Publish `value` serialized as JSON
+    #[cfg(feature = "codec")]
+    pub fn publish_json<T: serde::Serialize + ?Sized>(
+        &mut self,
+        topic: &str,
+        value: &T,
+        qos: QoS,
+    ) -> Result<Option<u16>, MqttError> {
+        let payload = codec::to_json(value).map_err(MqttError::Encode)?;
+        self.publish(topic, &payload, qos)
+    }
+
+    /// Publish `value` serialized as CBOR
+    #[cfg(feature = "codec")]
+    pub fn publish_cbor<T: serde::Serialize + ?Sized>(
+        &mut self,
+        topic: &str,
+        value: &T,
+        qos: QoS,
+    ) -> Result<Option<u16>, MqttError> {
+        let payload = codec::to_cbor(value).map_err(MqttError::Encode)?;
+        self.publish(topic, &payload, qos)
+    }
+
+    fn publish_with_options(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        options: PublishOptions,
+    ) -> Result<Option<u16>, MqttError> {
+        packet::validate_topic_name(topic).map_err(MqttError::InvalidTopic)?;
+        if let Err(denied) = self.config.acl.check_publish(topic) {
+            log::warn!("MQTT: Publish to {} refused by ACL", topic);
+            return Err(MqttError::NotPermitted(denied));
+        }
         if self.state != ConnectionState::Connected {
             return Err(MqttError::NotConnected);
         }
+        let size = self.publish_len(topic, payload.len(), qos, &options);
+        if size > self.max_outgoing {
+            log::warn!("MQTT: Publish to {} of {} bytes exceeds limit of {}", topic, size, self.max_outgoing);
+            return Err(MqttError::PacketTooLarge { size, limit: self.max_outgoing });
+        }
+        if qos != QoS::AtMostOnce && self.window_full() {
+            let queue = self.config.inflight_full == InflightFull::Queue;
+            if !queue || self.window.len() >= self.config.max_inflight {
+                return Err(MqttError::WouldBlock);
+            }
+        }
 
-        let packet_id = if qos != QoS::AtMostOnce {
-            Some(self.next_packet_id())
-        } else {
-            None
-        };
+        let packet_id = if qos != QoS::AtMostOnce { Some(self.next_packet_id()?) } else { None };
 
-        let _publish_packet = packet::build_publish_with_id(
-            topic,
-            payload,
-            qos,
-            packet_id,
-            false, // retain
-        );
+        if let Some(id) = packet_id {
+            self.expiry.retain(|&(expiring, _)| expiring != id);
+            if let Some(secs) = options.expiry_secs {
+                self.expiry.push((id, self.clock.now_ms() + secs as u64 * 1000));
+            }
+        }
 
-        // TODO: Send packet
+        let packet = self.build_publish(topic, payload, qos, packet_id, &options);
+        let publish = Held { packet_id, qos, packet };
+        if !self.config.ordered_delivery {
+            self.start_publish(publish);
+        } else if let Some(publish) = self.sequencer.submit(topic, publish) {
+            self.start_publish(publish);
+        } else {
+            // Its acknowledgement timeout starts when it is sent
+            if let Some(id) = packet_id {
+                self.ack_started.retain(|&(started, _)| started != id);
+            }
+            log::debug!("MQTT: Holding publish to {} behind the one in flight", topic);
+            return Ok(packet_id);
+        }
         log::debug!("MQTT: Publishing to {} ({} bytes)", topic, payload.len());
 
         Ok(packet_id)
     }
 
+    /// Send a PUBLISH, keeping it for retries until acknowledged if QoS > 0
+    fn start_publish(&mut self, publish: Held) {
+        if let Some(id) = publish.packet_id {
+            let now = self.clock.now_ms();
+            if self.expiry.iter().any(|&(expiring, at)| expiring == id && now >= at) {
+                // Expired while held behind another on its topic, or for the window
+                self.message_expired(id);
+                return;
+            }
+            if self.window_full() {
+                // Its acknowledgement timeout starts when it is sent
+                self.ack_started.retain(|&(started, _)| started != id);
+                log::debug!("MQTT: Holding publish {} until the in-flight window has room", id);
+                self.window.push_back(publish);
+                return;
+            }
+            self.start_ack_timer(id);
+            if publish.qos == QoS::ExactlyOnce {
+                self.qos2_out.publish(id, publish.packet.clone(), now);
+            } else {
+                self.inflight.insert(id, publish.packet.clone(), now);
+            }
+        }
+        self.send(publish.packet);
+    }
+
+    /// The exchange for `packet_id` is over; send what was held behind it
+    fn publish_ended(&mut self, packet_id: u16) {
+        for publish in self.sequencer.complete(packet_id) {
+            self.start_publish(publish);
+        }
+        self.release_window();
+    }
+
+    /// Send PUBLISHes held for the in-flight window while it has room
+    fn release_window(&mut self) {
+        while !self.window_full() {
+            let Some(publish) = self.window.pop_front() else { break };
+            self.start_publish(publish);
+        }
+    }
+
+    /// Number of QoS 1/2 PUBLISHes sent and not yet acknowledged
+    pub fn in_flight(&self) -> usize { self.inflight.len() + self.qos2_out.len() }
+
+    fn window_full(&self) -> bool {
+        self.config.max_inflight != 0 && self.in_flight() >= self.config.max_inflight
+    }
+
     /// Send ping to keep connection alive
     pub fn ping(&mut self) -> Result<(), MqttError> {
         if self.state != ConnectionState::Connected {
             return Err(MqttError::NotConnected);
         }
 
-        let _ping_packet = packet::build_pingreq();
-        // TODO: Send packet
+        self.send(packet::build_pingreq());
+        self.ping_sent_ms = Some(self.last_tx_ms);
 
         Ok(())
     }
 
     /// Poll for events (non-blocking)
+    ///
+    /// Reads and handles whatever the broker has sent, then drives the
+    /// timers: sends PINGREQ when the link has been idle for the keep-alive
+    /// interval, resends unacknowledged packets, and reconnects after
+    /// `reconnect_delay_ms` when `auto_reconnect` is set.
     pub fn poll(&mut self) -> Option<MqttEvent> {
-        // TODO: Read from TCP stream, parse packets, generate events
+        self.receive();
+        self.check_timers();
 
         // Return queued events
         self.event_queue.pop_front()
     }
 
-    /// Process received data
-    pub fn process_data(&mut self, data: &[u8]) {
-        self.rx_buffer.extend_from_slice(data);
+    /// Number of events discarded because the event queue was full
+    pub fn dropped_events(&self) -> u32 { self.events_dropped }
 
-        // Try to parse complete packets
-        loop {
-            match packet::parse_packet(&self.rx_buffer) {
-                Ok((packet, consumed)) => {
-                    self.handle_packet(packet);
-                    self.rx_buffer.drain(..consumed);
+    /// Most recent failure, kept after the connection recovers
+    ///
+    /// Covers failed connection attempts, lost connections and every error
+    /// `poll` has reported, so the application can show why it is offline
+    /// rather than just that it is. Errors returned by calls such as
+    /// `publish` are left to the caller.
+    pub fn last_error(&self) -> Option<&MqttError> { self.last_error.as_ref() }
+
+    /// Current time on the client's clock
+    pub(crate) fn now_ms(&self) -> u64 { self.clock.now_ms() }
+
+    /// Record `error` as the last one and hand it back
+    fn failed(&mut self, error: MqttError) -> MqttError {
+        self.last_error = Some(error.clone());
+        error
+    }
+
+    /// Queue an event for `poll`, applying the overflow policy
+    fn push_event(&mut self, event: MqttEvent) {
+        if let MqttEvent::Error(error) = &event {
+            self.last_error = Some(error.clone());
+        }
+        if self.event_queue.len() >= self.config.event_queue_capacity {
+            match self.config.event_queue_overflow {
+                // Packets aren't parsed while the queue is full, so this is a state change or error
+                QueueOverflow::Backpressure => {}
+                QueueOverflow::DropOldest => {
+                    self.event_queue.pop_front();
+                    self.events_dropped = self.events_dropped.saturating_add(1);
                 }
-                Err(ParseError::Incomplete) => break,
-                Err(e) => {
-                    log::error!("MQTT: Parse error: {:?}", e);
-                    self.rx_buffer.clear();
-                    break;
+                QueueOverflow::DropNewest => {
+                    self.events_dropped = self.events_dropped.saturating_add(1);
+                    return;
                 }
             }
         }
+        self.event_queue.push_back(event);
     }
 
-    /// Handle a parsed packet
-    fn handle_packet(&mut self, packet: Packet) {
-        match packet {
-            Packet::Connack { code, .. } => {
-                if code == packet::ConnackCode::Accepted {
-                    self.state = ConnectionState::Connected;
-                    self.event_queue.push_back(MqttEvent::Connected);
-                } else {
-                    self.state = ConnectionState::Disconnected;
-                    self.event_queue.push_back(MqttEvent::Error(
-                        MqttError::ConnectionRefused(code as u8)
-                    ));
-                }
-            }
-            Packet::Publish { topic, payload, qos, packet_id, .. } => {
-                // Send acknowledgment for QoS > 0
-                if qos == QoS::AtLeastOnce {
-                    if let Some(id) = packet_id {
-                        let _puback = packet::build_puback(id);
-                        // TODO: Send PUBACK
-                    }
-                } else if qos == QoS::ExactlyOnce {
-                    if let Some(id) = packet_id {
-                        let _pubrec = packet::build_pubrec(id);
-                        // TODO: Send PUBREC, track state
+    /// Whether received packets must wait for room in the event queue
+    fn queue_full(&self) -> bool {
+        self.config.event_queue_overflow == QueueOverflow::Backpressure
+            && self.event_queue.len() >= self.config.event_queue_capacity
+    }
+
+    /// Write an encoded packet to the broker
+    ///
+    /// Dropped when there is no connection; QoS 1/2 packets are kept in the
+    /// in-flight list and resent after reconnecting.
+    fn send(&mut self, packet: Vec<u8>) {
+        let result = match self.transport.as_mut() {
+            Some(transport) => transport.send(&packet),
+            None => return,
+        };
+        match result {
+            Ok(()) => self.last_tx_ms = self.clock.now_ms(),
+            Err(e) => self.connection_closed(DisconnectReason::TransportError(e)),
+        }
+    }
+
+    /// Read from the socket into the receive buffer
+    fn receive(&mut self) {
+        // Packets held back by a full queue go first, and the socket waits until they are through
+        if self.rx_held {
+            self.process_data(&[]).ok();
+        }
+        let mut buf = [0u8; 1024];
+        for _ in 0..MAX_READS_PER_POLL {
+            if self.rx_held {
+                return;
+            }
+            let result = match self.transport.as_mut() {
+                Some(transport) => transport.recv(&mut buf),
+                None => return,
+            };
+            match result {
+                Ok(Recv::Data(n)) => {
+                    self.process_data(&buf[..n]).ok();
+                }
+                Ok(Recv::Idle) => return,
+                Ok(Recv::Closed) => self.connection_closed(DisconnectReason::BrokerClosed),
+                Err(e) => self.connection_closed(DisconnectReason::TransportError(e)),
+            }
+        }
+    }
+
+    /// Act on keep-alive, retransmit and reconnect deadlines
+    fn check_timers(&mut self) {
+        let now = self.clock.now_ms();
+        self.expire_acks(now);
+        match self.state {
+            ConnectionState::Connected => {
+                let keep_alive_ms = self.keep_alive_secs as u64 * 1000;
+                if keep_alive_ms > 0 {
+                    match self.ping_sent_ms {
+                        // Same grace the broker allows us: 1.5x the keep-alive interval
+                        Some(sent) if now.saturating_sub(sent) >= keep_alive_ms * 3 / 2 => {
+                            self.connection_closed(DisconnectReason::KeepAliveTimeout);
+                            return;
+                        }
+                        None if now.saturating_sub(self.last_tx_ms) >= keep_alive_ms => {
+                            self.ping().ok();
+                        }
+                        _ => {}
                     }
                 }
+                self.retransmit(now, false);
+            }
+            ConnectionState::Connecting
+                if now.saturating_sub(self.connect_started_ms) >= self.config.connack_timeout_ms =>
+            {
+                log::warn!("MQTT: No CONNACK from {}", self.config.broker);
+                let broker = self.config.broker.clone();
+                let error = MqttError::Timeout { broker, packet_type: Some(PacketType::Connack) };
+                self.push_event(MqttEvent::Error(error.clone()));
+                self.connection_closed(DisconnectReason::TransportError(String::from("CONNACK timeout")));
+                // The timeout says more than the disconnect it caused
+                self.last_error = Some(error);
+            }
+            ConnectionState::Disconnected if self.reconnect_at_ms.is_some_and(|at| now >= at) => {
+                log::info!("MQTT: Reconnecting");
+                if let Err(e) = self.connect() {
+                    // `connect` has already armed the next attempt
+                    self.push_event(MqttEvent::Error(e));
+                }
+            }
+            _ => {}
+        }
+    }
 
-                self.event_queue.push_back(MqttEvent::Message { topic, payload });
+    /// Abandon exchanges unacknowledged for `ack_timeout_ms`, connected or not
+    fn expire_acks(&mut self, now: u64) {
+        let timeout_ms = self.config.ack_timeout_ms;
+        if timeout_ms == 0 {
+            return;
+        }
+        let packet_ids = &self.packet_ids;
+        self.ack_started.retain(|&(id, _)| packet_ids.is_in_use(id));
+        let expired: Vec<u16> = self
+            .ack_started
+            .iter()
+            .filter(|&&(_, started)| now.saturating_sub(started) >= timeout_ms)
+            .map(|&(id, _)| id)
+            .collect();
+        for packet_id in expired {
+            log::warn!("MQTT: No acknowledgement for packet {} in {} ms", packet_id, timeout_ms);
+            self.packet_ids.release(packet_id);
+            self.inflight.ack(packet_id);
+            self.qos2_out.forget(packet_id);
+            self.pending_subscribe.retain(|(id, _)| *id != packet_id);
+            self.pending_unsubscribe.retain(|(id, _)| *id != packet_id);
+            self.push_event(MqttEvent::Error(MqttError::AckTimeout { packet_id }));
+            self.publish_ended(packet_id);
+        }
+    }
+
+    /// Drop the exchanges a broker without our persistent session can't finish
+    ///
+    /// PUBLISHes it never acknowledged are still resent. Inbound QoS 2 ids
+    /// awaiting PUBREL are forgotten, as the new session may reuse them, and
+    /// outbound QoS 2 exchanges past PUBREC are reported as lost.
+    fn session_lost(&mut self) {
+        log::warn!("MQTT: Broker has no session for {}, starting a new one", self.config.client_id);
+        if !self.qos2_in.is_empty() {
+            self.qos2_in.clear();
+            self.session_store.save_incoming_qos2(&[]);
+        }
+        for packet_id in self.qos2_out.forget_released() {
+            self.packet_ids.release(packet_id);
+            self.push_event(MqttEvent::Error(MqttError::SessionLost { packet_id }));
+            self.publish_ended(packet_id);
+        }
+    }
+
+    /// Drop QoS 1/2 PUBLISHes whose expiry interval has passed before they were acknowledged
+    fn expire_messages(&mut self, now: u64) {
+        let packet_ids = &self.packet_ids;
+        self.expiry.retain(|&(id, _)| packet_ids.is_in_use(id));
+        let expired: Vec<u16> = self.expiry.iter().filter(|&&(_, at)| now >= at).map(|&(id, _)| id).collect();
+        for packet_id in expired {
+            // Once PUBREC has arrived the broker owns the message; only PUBREL is left to send
+            if self.inflight.ack(packet_id) || self.qos2_out.abandon(packet_id) {
+                self.message_expired(packet_id);
+            } else if self.qos2_out.contains(packet_id) {
+                self.expiry.retain(|&(id, _)| id != packet_id);
+            }
+        }
+    }
+
+    /// Report a PUBLISH dropped unsent or unacknowledged because it expired
+    fn message_expired(&mut self, packet_id: u16) {
+        log::info!("MQTT: Publish {} expired", packet_id);
+        self.expiry.retain(|&(id, _)| id != packet_id);
+        self.packet_ids.release(packet_id);
+        self.push_event(MqttEvent::Error(MqttError::Expired { packet_id }));
+        self.publish_ended(packet_id);
+    }
+
+    /// Resend unacknowledged packets that are due, or all of them if `all`
+    fn retransmit(&mut self, now: u64, all: bool) {
+        self.expire_messages(now);
+        let retry_ms = self.config.retry_interval_ms;
+        let mut due = self.inflight.due(now, retry_ms, all);
+        due.extend(self.qos2_out.due(now, retry_ms, all));
+        due.extend(self.qos2_in.due(now, retry_ms, all));
+        for packet in due {
+            log::debug!("MQTT: Retransmitting {:?}", PacketType::from_byte(packet[0]));
+            self.send(packet);
+        }
+    }
+
+    /// Poll for the next received message without copying it (non-blocking)
+    ///
+    /// Only yields messages when `borrow_publish` is set in the config; the
+    /// returned topic and payload point directly into the receive buffer.
+    pub fn poll_ref(&mut self) -> Option<MessageRef<'_>> {
+        self.release_lent();
+        self.receive();
+        self.check_timers();
+        loop {
+            self.release_lent();
+            self.parse_rx_buffer();
+            if self.rx_held {
+                return None;
+            }
+
+            let (qos, packet_id, consumed) = match parse_publish_ref(self.protocol, &self.rx_buffer) {
+                Ok((publish, consumed)) => (publish.qos, publish.packet_id, consumed),
+                Err(ParseError::Incomplete) => return None,
+                Err(e) => {
+                    self.protocol_error(e);
+                    return None;
+                }
+            };
+            self.rx_lent = consumed;
+            if self.accept_publish(qos, packet_id) {
+                let (publish, _) = parse_publish_ref(self.protocol, &self.rx_buffer).ok()?;
+                if !self.handlers.dispatch(publish.topic, publish.payload) {
+                    break;
+                }
+            }
+        }
+
+        let (publish, _) = parse_publish_ref(self.protocol, &self.rx_buffer).ok()?;
+        Some(MessageRef {
+            topic: publish.topic,
+            payload: publish.payload,
+            qos: publish.qos,
+            retain: publish.retain,
+        })
+    }
+
+    /// Drop the PUBLISH previously handed out by `poll_ref`
+    fn release_lent(&mut self) {
+        if self.rx_lent > 0 {
+            self.rx_buffer.drain(..self.rx_lent);
+            self.rx_lent = 0;
+        }
+    }
+
+    /// Process received data
+    ///
+    /// With [`QueueOverflow::Backpressure`] and the event queue full, the
+    /// data is kept but not parsed yet, and [`MqttError::QueueFull`] returned.
+    pub fn process_data(&mut self, data: &[u8]) -> Result<(), MqttError> {
+        self.release_lent();
+        // The rest of a packet being skipped never reaches the buffer
+        let skipped = self.rx_skip.min(data.len());
+        self.rx_skip -= skipped;
+        self.rx_buffer.extend_from_slice(&data[skipped..]);
+        self.parse_rx_buffer();
+        if self.rx_held { Err(MqttError::QueueFull) } else { Ok(()) }
+    }
+
+    /// Parse complete packets out of the receive buffer
+    ///
+    /// With `borrow_publish` set, stops at the first PUBLISH so it can be
+    /// handed out by `poll_ref`.
+    fn parse_rx_buffer(&mut self) {
+        self.rx_held = false;
+        loop {
+            if self.queue_full() {
+                self.rx_held = !self.rx_buffer.is_empty();
+                break;
+            }
+            // Known from the fixed header, before the body arrives
+            if let Ok(size) = packet::peek_packet_len(&self.rx_buffer) {
+                if size > self.config.max_packet_size {
+                    self.packet_too_large(size);
+                    if self.rx_skip > 0 || self.state == ConnectionState::Disconnected {
+                        break;
+                    }
+                    continue;
+                }
+            }
+            if self.config.borrow_publish
+                && matches!(packet::parse_fixed_header(&self.rx_buffer), Ok((PacketType::Publish, _, _)))
+            {
+                break;
+            }
+            let parsed = match self.protocol {
+                ProtocolVersion::V31 | ProtocolVersion::V311 => {
+                    packet::parse_packet(&self.rx_buffer).map(|(packet, n)| (Received::V311(packet), n))
+                }
+                #[cfg(feature = "mqtt5")]
+                ProtocolVersion::V5 => {
+                    v5::parse_packet(&self.rx_buffer).map(|(packet, n)| (Received::V5(packet), n))
+                }
+            };
+            match parsed {
+                // Consume before handling: handling may close the connection and clear the buffer
+                Ok((received, consumed)) => {
+                    self.rx_buffer.drain(..consumed);
+                    match received {
+                        Received::V311(packet) => self.handle_packet(packet),
+                        #[cfg(feature = "mqtt5")]
+                        Received::V5(packet) => self.handle_packet_v5(packet),
+                    }
+                }
+                Err(ParseError::Incomplete) => break,
+                Err(e) => {
+                    self.protocol_error(e);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Handle a parsed packet
+    fn handle_packet(&mut self, packet: Packet) {
+        match packet {
+            Packet::Connack { session_present, code } => {
+                if self.state != ConnectionState::Connecting {
+                    log::warn!("MQTT: Unexpected CONNACK");
+                } else if code == packet::ConnackCode::Accepted {
+                    self.state = ConnectionState::Connected;
+                    self.refusals = 0;
+                    self.session_present = session_present && !self.config.clean_session;
+                    self.push_event(MqttEvent::Connected);
+
+                    // Unacknowledged exchanges continue in a resumed session and are void in a clean one
+                    if self.config.clean_session {
+                        self.inflight.clear();
+                        self.qos2_out.clear();
+                        // Held PUBLISHes haven't been sent, so they keep their ids
+                        let (sequencer, window) = (&self.sequencer, &self.window);
+                        self.packet_ids.retain(|&id| sequencer.holds(id) || holds(window, id));
+                        self.release_window();
+                        for publish in self.sequencer.reset() {
+                            self.start_publish(publish);
+                        }
+                    } else {
+                        if !session_present {
+                            self.session_lost();
+                        }
+                        let now = self.clock.now_ms();
+                        self.retransmit(now, true);
+                        if !session_present {
+                            self.restore_subscriptions();
+                        }
+                    }
+                    self.subscribe_handlers();
+                } else {
+                    self.connect_refused(RefusedReason::from_connack(code as u8));
+                }
+            }
+            Packet::Publish { topic, payload, qos, packet_id, retain, .. } => {
+                if self.accept_publish(qos, packet_id) && !self.handlers.dispatch(&topic, &payload) {
+                    let event = self.message_event(topic, payload, retain);
+                    self.push_event(event);
+                }
+                #[cfg(feature = "mqtt5")]
+                self.rx_user_properties.clear();
             }
             Packet::Puback { packet_id } => {
-                self.event_queue.push_back(MqttEvent::PublishAcked { packet_id });
+                if self.inflight.ack(packet_id) {
+                    self.packet_ids.release(packet_id);
+                    self.push_event(MqttEvent::PublishAcked { packet_id });
+                    self.publish_ended(packet_id);
+                } else {
+                    log::warn!("MQTT: PUBACK for unknown packet id {}", packet_id);
+                }
             }
             Packet::Pubrec { packet_id } => {
-                // QoS 2: Send PUBREL
-                let _pubrel = packet::build_pubrel(packet_id);
-                // TODO: Send PUBREL
+                // QoS 2: PUBREL replaces the PUBLISH as the packet to retry
+                let now = self.clock.now_ms();
+                let pubrel = self.qos2_out.on_pubrec(packet_id, now);
+                self.send(pubrel);
             }
             Packet::Pubrel { packet_id } => {
-                // QoS 2: Send PUBCOMP
-                let _pubcomp = packet::build_pubcomp(packet_id);
-                // TODO: Send PUBCOMP
+                // QoS 2: Release the id, then send PUBCOMP
+                if self.qos2_in.on_pubrel(packet_id) {
+                    self.session_store.save_incoming_qos2(&self.qos2_in.packet_ids());
+                }
+                self.send(packet::build_pubcomp(packet_id));
             }
             Packet::Pubcomp { packet_id } => {
-                self.event_queue.push_back(MqttEvent::PublishComplete { packet_id });
+                if self.qos2_out.on_pubcomp(packet_id) {
+                    self.packet_ids.release(packet_id);
+                    self.push_event(MqttEvent::PublishComplete { packet_id });
+                    self.publish_ended(packet_id);
+                } else {
+                    log::warn!("MQTT: PUBCOMP for unknown packet id {}", packet_id);
+                }
             }
-            Packet::Suback { packet_id, .. } => {
-                self.event_queue.push_back(MqttEvent::Subscribed { packet_id });
+            Packet::Suback { packet_id, return_codes } => {
+                let Some(results) = self.subscribe_acked(packet_id, &return_codes) else {
+                    log::warn!("MQTT: SUBACK for unknown packet id {}", packet_id);
+                    return;
+                };
+                self.packet_ids.release(packet_id);
+                for result in results.iter() {
+                    if result.is_refused() {
+                        log::warn!("MQTT: Broker refused {} (code 0x{:02x})", result.filter, result.code);
+                    } else if result.is_downgraded() {
+                        log::warn!("MQTT: Broker granted {} at {:?}", result.filter, result.granted);
+                    }
+                }
+                self.push_event(MqttEvent::Subscribed { packet_id, results });
             }
-            Packet::Unsuback { .. } => {
-                // Unsubscribe confirmed
+            Packet::Unsuback { packet_id } => {
+                self.packet_ids.release(packet_id);
+                self.unsubscribe_acked(packet_id);
             }
             Packet::Pingresp => {
                 // Connection is alive
+                self.ping_sent_ms = None;
+            }
+        }
+    }
+
+    /// Handle a refused CONNACK as the refusal policy directs
+    fn connect_refused(&mut self, reason: RefusedReason) {
+        log::warn!("MQTT: {} refused the connection: {:?}", self.config.broker, reason);
+        self.refusals = self.refusals.saturating_add(1);
+        let broker = self.config.broker.clone();
+        self.push_event(MqttEvent::Error(MqttError::ConnectionRefused { broker, reason }));
+        self.connection_closed(DisconnectReason::Refused(reason));
+
+        let now = self.clock.now_ms();
+        match self.refusal_policy.on_refused(reason, self.refusals) {
+            // `connection_closed` has armed the usual reconnect
+            RefusalAction::Retry => {}
+            RefusalAction::RetryAfter(delay_ms) => self.reconnect_at_ms = Some(now + delay_ms),
+            RefusalAction::UpdateCredentials { username, password } => {
+                self.config.username = username;
+                self.config.password = password;
+                self.reconnect_at_ms = Some(now);
+            }
+            RefusalAction::GiveUp => {
+                log::warn!("MQTT: Not reconnecting to {}", self.config.broker);
+                self.reconnect_at_ms = None;
+            }
+        }
+    }
+
+    /// Handle a parsed MQTT 5 packet
+    ///
+    /// Reason codes and properties are acted on here; what is left maps onto
+    /// the 3.1.1 packet and goes through the same state machine.
+    #[cfg(feature = "mqtt5")]
+    fn handle_packet_v5(&mut self, packet: v5::Packet) {
+        let packet = match packet {
+            v5::Packet::Connack { session_present, reason, properties } => {
+                if self.state == ConnectionState::Connecting
+                    && (reason == ReasonCode::UNSUPPORTED_PROTOCOL_VERSION
+                        || reason.0 == packet::ConnackCode::UnacceptableProtocol as u8)
+                {
+                    // Retry right away at 3.1.1
+                    log::warn!("MQTT: {} refused MQTT 5, falling back to 3.1.1", self.config.broker);
+                    self.protocol = ProtocolVersion::V311;
+                    self.state = ConnectionState::Disconnected;
+                    self.transport = None;
+                    self.reconnect_at_ms = Some(self.clock.now_ms());
+                    return;
+                }
+                if reason.is_error() {
+                    self.connect_refused(RefusedReason::from_reason(reason));
+                    return;
+                }
+                for property in properties {
+                    match property {
+                        v5::Property::ServerKeepAlive(secs) => self.keep_alive_secs = secs,
+                        v5::Property::MaximumPacketSize(size) => {
+                            self.max_outgoing = self.max_outgoing.min(size as usize);
+                        }
+                        v5::Property::AssignedClientIdentifier(id) => {
+                            log::info!("MQTT: Assigned client id {}", id);
+                            // Needed to resume the session after a reboot
+                            if !self.config.clean_session {
+                                self.session_store.save_client_id(&id);
+                            }
+                            self.config.client_id = id;
+                        }
+                        _ => {}
+                    }
+                }
+                Packet::Connack { session_present, code: packet::ConnackCode::Accepted }
+            }
+            v5::Packet::Publish { topic, payload, qos, packet_id, retain, dup, properties } => {
+                self.rx_user_properties = properties
+                    .into_iter()
+                    .filter_map(|property| match property {
+                        v5::Property::UserProperty(key, value) => Some((key, value)),
+                        _ => None,
+                    })
+                    .collect();
+                Packet::Publish { topic, payload, qos, packet_id, retain, dup }
+            }
+            v5::Packet::Puback { packet_id, reason, .. } if reason.is_error() => {
+                // The exchange is over either way
+                if self.inflight.ack(packet_id) {
+                    self.publish_rejected(packet_id, reason);
+                }
+                return;
+            }
+            v5::Packet::Puback { packet_id, .. } => Packet::Puback { packet_id },
+            v5::Packet::Pubrec { packet_id, reason, .. } if reason.is_error() => {
+                if self.qos2_out.abandon(packet_id) {
+                    self.publish_rejected(packet_id, reason);
+                }
+                return;
+            }
+            v5::Packet::Pubrec { packet_id, .. } => Packet::Pubrec { packet_id },
+            v5::Packet::Pubrel { packet_id, .. } => Packet::Pubrel { packet_id },
+            v5::Packet::Pubcomp { packet_id, .. } => Packet::Pubcomp { packet_id },
+            v5::Packet::Suback { packet_id, reasons, .. } => {
+                // Granted QoS and failures share the 3.1.1 encoding
+                Packet::Suback { packet_id, return_codes: reasons.iter().map(|r| r.0).collect() }
+            }
+            v5::Packet::Unsuback { packet_id, .. } => Packet::Unsuback { packet_id },
+            v5::Packet::Pingresp => Packet::Pingresp,
+            v5::Packet::Disconnect { reason, properties } => {
+                for property in properties {
+                    if let v5::Property::ReasonString(text) = property {
+                        log::warn!("MQTT: Broker disconnecting: {}", text);
+                    }
+                }
+                self.connection_closed(DisconnectReason::ServerDisconnect { reason: reason.0 });
+                return;
+            }
+            v5::Packet::Auth { .. } => {
+                // Enhanced authentication is never requested
+                log::warn!("MQTT: Ignoring unsolicited AUTH");
+                return;
             }
+        };
+        self.handle_packet(packet);
+    }
+
+    /// Report a PUBLISH the broker refused with an MQTT 5 error reason
+    #[cfg(feature = "mqtt5")]
+    fn publish_rejected(&mut self, packet_id: u16, reason: ReasonCode) {
+        self.packet_ids.release(packet_id);
+        log::warn!("MQTT: Publish {} rejected with reason {:#04x}", packet_id, reason.0);
+        self.push_event(MqttEvent::Error(MqttError::Rejected { packet_id, reason: reason.0 }));
+        self.publish_ended(packet_id);
+    }
+
+    /// Event for a received message no handler took
+    fn message_event(&mut self, topic: String, payload: Vec<u8>, retain: bool) -> MqttEvent {
+        let received = self.clock.stamp();
+        #[cfg(feature = "mqtt5")]
+        let user_properties = core::mem::take(&mut self.rx_user_properties);
+        #[cfg(feature = "codec")]
+        {
+            let typed = self.typed.iter().find(|(filter, _)| crate::topic::filter_matches(filter, &topic));
+            if let Some(&(_, codec)) = typed {
+                return MqttEvent::TypedMessage {
+                    topic,
+                    codec,
+                    payload,
+                    retain,
+                    received,
+                    #[cfg(feature = "mqtt5")]
+                    user_properties,
+                };
+            }
+        }
+        MqttEvent::Message {
+            topic,
+            payload,
+            retain,
+            received,
+            #[cfg(feature = "mqtt5")]
+            user_properties,
+        }
+    }
+
+    /// Send acknowledgment for a received QoS > 0 PUBLISH
+    ///
+    /// Returns false if the message is a redelivery of a QoS 2 message that
+    /// was already handed to the application and must not be surfaced again.
+    fn accept_publish(&mut self, qos: QoS, packet_id: Option<u16>) -> bool {
+        if qos == QoS::AtLeastOnce {
+            if let Some(id) = packet_id {
+                self.send(packet::build_puback(id));
+            }
+        } else if qos == QoS::ExactlyOnce {
+            if let Some(id) = packet_id {
+                let now = self.clock.now_ms();
+                let inbound = self.qos2_in.on_publish(id, now);
+                match inbound {
+                    // Persist before delivering so a reboot can't cause a second delivery
+                    Inbound::Deliver => self.session_store.save_incoming_qos2(&self.qos2_in.packet_ids()),
+                    Inbound::Duplicate => {}
+                    // Unacknowledged, so the broker sends it again
+                    Inbound::Refused => return false,
+                }
+                self.send(packet::build_pubrec(id));
+                return inbound == Inbound::Deliver;
+            }
+        }
+        true
+    }
+}
+
+/// Packet parsed at whichever protocol level is in use
+enum Received {
+    V311(Packet),
+    #[cfg(feature = "mqtt5")]
+    V5(v5::Packet),
+}
+
+/// Whether `packet_id` belongs to one of the `held` PUBLISHes
+fn holds(held: &VecDeque<Held>, packet_id: u16) -> bool {
+    held.iter().any(|publish| publish.packet_id == Some(packet_id))
+}
+
+/// Parse a PUBLISH without copying it, at protocol level `protocol`
+fn parse_publish_ref(protocol: ProtocolVersion, data: &[u8]) -> Result<(PublishRef<'_>, usize), ParseError> {
+    match protocol {
+        ProtocolVersion::V31 | ProtocolVersion::V311 => packet::parse_publish_ref(data),
+        #[cfg(feature = "mqtt5")]
+        ProtocolVersion::V5 => v5::parse_publish_ref(data),
+    }
+}
+
+/// Optional parts of an outgoing PUBLISH
+#[derive(Default)]
+struct PublishOptions<'a> {
+    retain: bool,
+    /// Seconds the message is worth delivering for
+    expiry_secs: Option<u32>,
+    /// Only sent at MQTT 5; 3.1.1 has nowhere to put them
+    #[cfg_attr(not(feature = "mqtt5"), allow(dead_code))]
+    user_properties: &'a [(&'a str, &'a str)],
+    /// Reply topic and correlation data of a request; MQTT 5 only too
+    #[cfg_attr(not(feature = "mqtt5"), allow(dead_code))]
+    response: Option<(&'a str, &'a [u8])>,
+}
+
+#[cfg(feature = "mqtt5")]
+impl PublishOptions<'_> {
+    fn properties(&self) -> Vec<v5::Property> {
+        let mut properties = Vec::new();
+        if let Some(secs) = self.expiry_secs {
+            properties.push(v5::Property::MessageExpiryInterval(secs));
+        }
+        if let Some((topic, correlation)) = self.response {
+            properties.push(v5::Property::ResponseTopic(String::from(topic)));
+            properties.push(v5::Property::CorrelationData(correlation.to_vec()));
         }
+        for &(key, value) in self.user_properties {
+            properties.push(v5::Property::UserProperty(String::from(key), String::from(value)));
+        }
+        properties
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::transport::mock;
+
+    #[test]
+    fn test_protocol_error_disconnects() {
+        let (mut client, _, broker) = mock::client(MqttConfig::default());
+        mock::accept(&mut client, &broker);
+
+        // CONNACK with an undefined return code
+        broker.borrow_mut().rx.extend([0x20, 0x02, 0x00, 0x09]);
+        match client.poll() {
+            Some(MqttEvent::Disconnected { reason }) => assert_eq!(
+                reason,
+                DisconnectReason::ProtocolError {
+                    packet_type: Some(PacketType::Connack),
+                    error: ParseError::InvalidFormat,
+                }
+            ),
+            other => panic!("Expected Disconnected, got {:?}", other),
+        }
+        assert!(!client.is_connected());
+    }
+
+    #[test]
+    fn test_connect_handshake() {
+        let (mut client, clock, broker) = mock::client(MqttConfig::default());
+        client.connect().unwrap();
+        assert_eq!(client.state(), ConnectionState::Connecting);
+        assert_eq!(mock::sent(&broker)[0][0] >> 4, PacketType::Connect as u8);
+        assert!(client.subscribe("a", QoS::AtMostOnce).is_err());
+
+        // Refused: not authorized
+        broker.borrow_mut().rx.extend([0x20, 0x02, 0x00, 0x05]);
+        assert!(matches!(
+            client.poll(),
+            Some(MqttEvent::Error(MqttError::ConnectionRefused { reason: RefusedReason::NotAuthorized, .. }))
+        ));
+        assert!(matches!(
+            client.poll(),
+            Some(MqttEvent::Disconnected { reason: DisconnectReason::Refused(RefusedReason::NotAuthorized) })
+        ));
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+
+        // No answer at all
+        client.connect().unwrap();
+        clock.advance(10_000);
+        assert!(matches!(
+            client.poll(),
+            Some(MqttEvent::Error(MqttError::Timeout { packet_type: Some(PacketType::Connack), .. }))
+        ));
+        assert!(matches!(client.poll(), Some(MqttEvent::Disconnected { .. })));
+
+        // Broker hangs up after accepting
+        client.disconnect().unwrap();
+        client.poll();
+        mock::accept(&mut client, &broker);
+        broker.borrow_mut().closed = true;
+        match client.poll() {
+            Some(MqttEvent::Disconnected { reason }) => assert_eq!(reason, DisconnectReason::BrokerClosed),
+            other => panic!("Expected Disconnected, got {:?}", other),
+        }
+    }
+
+    /// Session store that outlives the client, standing in for the PDDB across a reboot
+    struct Shared(alloc::rc::Rc<core::cell::RefCell<MemoryStore>>);
+    impl SessionStore for Shared {
+        fn load_incoming_qos2(&mut self) -> Vec<u16> { self.0.borrow_mut().load_incoming_qos2() }
+
+        fn save_incoming_qos2(&mut self, ids: &[u16]) { self.0.borrow_mut().save_incoming_qos2(ids) }
+
+        fn load_client_id(&mut self) -> Option<String> { self.0.borrow_mut().load_client_id() }
+
+        fn save_client_id(&mut self, id: &str) { self.0.borrow_mut().save_client_id(id) }
+
+        fn load_subscriptions(&mut self) -> Vec<SavedSubscription> {
+            self.0.borrow_mut().load_subscriptions()
+        }
+
+        fn save_subscriptions(&mut self, subs: &[SavedSubscription]) {
+            self.0.borrow_mut().save_subscriptions(subs)
+        }
+    }
+
+    #[test]
+    fn test_qos2_dedup_survives_restart() {
+        let store = alloc::rc::Rc::new(core::cell::RefCell::new(MemoryStore::new()));
+        let config = MqttConfig { clean_session: false, ..Default::default() };
+        let publish = packet::build_publish_with_id("perm", b"allow", QoS::ExactlyOnce, Some(9), false);
+
+        let clock = Box::new(ManualClock::new(0));
+        let mut client =
+            MqttClient::with_store_and_clock(config.clone(), Box::new(Shared(store.clone())), clock);
+        client.process_data(&publish).unwrap();
+        assert!(matches!(client.poll(), Some(MqttEvent::Message { .. })));
+
+        // Reboot before PUBREL: the broker resumes the session and resends with DUP set
+        let broker = mock::Shared::default();
+        let clock = Box::new(ManualClock::new(0));
+        let mut client = MqttClient::with_store_and_clock(config, Box::new(Shared(store.clone())), clock)
+            .with_connector(mock::connector(&broker));
+        client.connect().unwrap();
+        broker.borrow_mut().rx.extend([0x20, 0x02, 0x01, 0x00]);
+        assert!(matches!(client.poll(), Some(MqttEvent::Connected)));
+        broker.borrow_mut().rx.extend(publish);
+        assert!(client.poll().is_none());
+
+        broker.borrow_mut().rx.extend(packet::build_pubrel(9));
+        client.poll();
+        assert!(store.borrow_mut().load_incoming_qos2().is_empty());
+        assert_eq!(mock::sent(&broker).last().unwrap(), &packet::build_pubcomp(9));
+    }
+
+    #[test]
+    fn test_ordered_delivery() {
+        let config = MqttConfig { ordered_delivery: true, ..Default::default() };
+        let (mut client, _, broker) = mock::client(config);
+        mock::accept(&mut client, &broker);
+        mock::sent(&broker);
+        let first = client.publish("log", b"1", QoS::AtLeastOnce).unwrap();
+        let second = client.publish("log", b"2", QoS::AtLeastOnce).unwrap();
+        client.publish("log", b"3", QoS::AtMostOnce).unwrap();
+        client.publish("other", b"x", QoS::AtMostOnce).unwrap();
+        let publish = |payload: &[u8], qos, id| packet::build_publish_with_id("log", payload, qos, id, false);
+        // Other topics don't wait
+        assert_eq!(
+            mock::sent(&broker),
+            [publish(b"1", QoS::AtLeastOnce, first), packet::build_publish("other", b"x", QoS::AtMostOnce)]
+        );
+
+        broker.borrow_mut().rx.extend(packet::build_puback(first.unwrap()));
+        assert!(matches!(client.poll(), Some(MqttEvent::PublishAcked { .. })));
+        assert_eq!(mock::sent(&broker), [publish(b"2", QoS::AtLeastOnce, second)]);
+        broker.borrow_mut().rx.extend(packet::build_puback(second.unwrap()));
+        client.poll();
+        assert_eq!(mock::sent(&broker), [publish(b"3", QoS::AtMostOnce, None)]);
+    }
+
+    #[test]
+    fn test_inbound_qos2_limit() {
+        let config =
+            MqttConfig { max_inbound_qos2: 1, inbound_qos2_eviction: Eviction::Refuse, ..Default::default() };
+        let (mut client, _, broker) = mock::client(config);
+        mock::accept(&mut client, &broker);
+        for id in [1, 2] {
+            let publish = packet::build_publish_with_id("perm", b"allow", QoS::ExactlyOnce, Some(id), false);
+            broker.borrow_mut().rx.extend(publish);
+        }
+        assert!(matches!(client.poll(), Some(MqttEvent::Message { .. })));
+        assert!(client.poll().is_none());
+        // Only the first was acknowledged; the second waits for the broker to send it again
+        assert_eq!(mock::sent(&broker).last().unwrap(), &packet::build_pubrec(1));
+    }
+
+    #[test]
+    fn test_session_resume_on_reconnect() {
+        let config =
+            MqttConfig { client_id: String::from("precursor-1"), clean_session: false, ..Default::default() };
+        let (mut client, _, broker) = mock::client(config);
+        let inbound =
+            |payload| packet::build_publish_with_id("ccr/c", payload, QoS::ExactlyOnce, Some(7), false);
+        let reconnect = |client: &mut MqttClient, session_present: u8| {
+            broker.borrow_mut().closed = true;
+            assert!(matches!(client.poll(), Some(MqttEvent::Disconnected { .. })));
+            client.connect().unwrap();
+            broker.borrow_mut().rx.extend([0x20, 0x02, session_present, 0x00]);
+            assert!(matches!(client.poll(), Some(MqttEvent::Connected)));
+        };
+        mock::accept(&mut client, &broker);
+        let qos1 = client.publish("ccr/a", b"1", QoS::AtLeastOnce).unwrap().unwrap();
+        let qos2 = client.publish("ccr/b", b"2", QoS::ExactlyOnce).unwrap().unwrap();
+        broker.borrow_mut().rx.extend(packet::build_pubrec(qos2));
+        broker.borrow_mut().rx.extend(inbound(b"3"));
+        assert!(matches!(client.poll(), Some(MqttEvent::Message { .. })));
+        let publish = mock::sent(&broker).remove(1);
+
+        // A resumed session picks every exchange up where it stopped, PUBLISH with DUP set
+        reconnect(&mut client, 0x01);
+        assert!(client.session_resumed());
+        let mut dup = publish.clone();
+        dup[0] |= 0x08;
+        let resent = mock::sent(&broker);
+        assert_eq!(resent[1..], [dup.clone(), packet::build_pubrel(qos2), packet::build_pubrec(7)]);
+
+        // A lost one only gets the PUBLISH again; the QoS 2 exchanges are void
+        reconnect(&mut client, 0x00);
+        assert!(!client.session_resumed());
+        let Some(MqttEvent::Error(MqttError::SessionLost { packet_id })) = client.poll() else { panic!() };
+        assert_eq!(packet_id, qos2);
+        assert_eq!(mock::sent(&broker)[1..], [dup]);
+        broker.borrow_mut().rx.extend(inbound(b"4"));
+        assert!(matches!(client.poll(), Some(MqttEvent::Message { .. })));
+        assert!(client.packet_ids.is_in_use(qos1) && !client.packet_ids.is_in_use(qos2));
+    }
+
+    #[test]
+    fn test_session_restored_after_reboot() {
+        let store = alloc::rc::Rc::new(core::cell::RefCell::new(MemoryStore::new()));
+        let config =
+            MqttConfig { client_id: String::from("precursor-1"), clean_session: false, ..Default::default() };
+
+        let broker = mock::Shared::default();
+        let clock = Box::new(ManualClock::new(0));
+        let mut client = MqttClient::with_store_and_clock(config, Box::new(Shared(store.clone())), clock)
+            .with_connector(mock::connector(&broker));
+        mock::accept(&mut client, &broker);
+        let id = client.subscribe_many(&[("ccr/#", QoS::ExactlyOnce), ("denied", QoS::AtMostOnce)]).unwrap();
+        let [hi, lo] = id.to_be_bytes();
+        broker.borrow_mut().rx.extend([0x90, 0x04, hi, lo, 0x01, 0x80]);
+        assert!(matches!(client.poll(), Some(MqttEvent::Subscribed { .. })));
+        let granted = [SavedSubscription { filter: String::from("ccr/#"), qos: QoS::AtLeastOnce }];
+        assert_eq!(client.subscriptions(), granted);
+
+        // After a reboot the id comes from the store, and a broker that lost the session gets the
+        // subscriptions again
+        let broker = mock::Shared::default();
+        let clock = Box::new(ManualClock::new(0));
+        let config = MqttConfig { client_id: String::new(), clean_session: false, ..Default::default() };
+        let mut client =
+            MqttClient::with_store_and_clock(config.clone(), Box::new(Shared(store.clone())), clock)
+                .with_connector(mock::connector(&broker));
+        assert_eq!(client.config().client_id, "precursor-1");
+        mock::accept(&mut client, &broker);
+        let sent = mock::sent(&broker);
+        let resubscribe = sent.last().unwrap();
+        assert_eq!(resubscribe[0] >> 4, PacketType::Subscribe as u8);
+        let id = u16::from_be_bytes([resubscribe[2], resubscribe[3]]);
+        assert_eq!(resubscribe, &packet::build_subscribe_many(id, &[("ccr/#", QoS::AtLeastOnce)]));
+
+        // Nothing to restore when the broker kept the session
+        client.disconnect().unwrap();
+        client.poll();
+        client.connect().unwrap();
+        broker.borrow_mut().rx.extend([0x20, 0x02, 0x01, 0x00]);
+        assert!(matches!(client.poll(), Some(MqttEvent::Connected)));
+        assert!(mock::sent(&broker).iter().all(|p| p[0] >> 4 != PacketType::Subscribe as u8));
+
+        let id = client.unsubscribe("ccr/#").unwrap();
+        let [hi, lo] = id.to_be_bytes();
+        broker.borrow_mut().rx.extend([0xB0, 0x02, hi, lo]);
+        client.poll();
+        assert!(store.borrow_mut().load_subscriptions().is_empty());
+
+        // A clean session starts from nothing
+        let clock = Box::new(ManualClock::new(0));
+        store.borrow_mut().save_subscriptions(&granted);
+        let config = MqttConfig { clean_session: true, ..config };
+        let client = MqttClient::with_store_and_clock(config, Box::new(Shared(store.clone())), clock);
+        assert!(client.subscriptions().is_empty());
+        assert!(store.borrow_mut().load_subscriptions().is_empty());
+    }
+
+    #[test]
+    fn test_new_client_id_drops_saved_subscriptions() {
+        let store = alloc::rc::Rc::new(core::cell::RefCell::new(MemoryStore::new()));
+        let granted = [SavedSubscription { filter: String::from("ccr/#"), qos: QoS::AtLeastOnce }];
+        store.borrow_mut().save_client_id("precursor-1");
+        store.borrow_mut().save_subscriptions(&granted);
+
+        let config =
+            MqttConfig { client_id: String::from("precursor-1"), clean_session: false, ..Default::default() };
+        let clock = Box::new(ManualClock::new(0));
+        let client = MqttClient::with_store_and_clock(config, Box::new(Shared(store.clone())), clock);
+        assert_eq!(client.subscriptions(), granted);
+
+        // Another identity's broker session never had them, so they aren't re-sent for it
+        let config =
+            MqttConfig { client_id: String::from("precursor-2"), clean_session: false, ..Default::default() };
+        let broker = mock::Shared::default();
+        let clock = Box::new(ManualClock::new(0));
+        let mut client = MqttClient::with_store_and_clock(config, Box::new(Shared(store.clone())), clock)
+            .with_connector(mock::connector(&broker));
+        assert!(client.subscriptions().is_empty());
+        assert!(store.borrow_mut().load_subscriptions().is_empty());
+        assert_eq!(store.borrow_mut().load_client_id().as_deref(), Some("precursor-2"));
+        mock::accept(&mut client, &broker);
+        assert!(mock::sent(&broker).iter().all(|p| p[0] >> 4 != PacketType::Subscribe as u8));
+    }
+
+    #[test]
+    fn test_handlers_take_matching_messages() {
+        let (mut client, _, broker) = mock::client(MqttConfig::default());
+        let seen = alloc::rc::Rc::new(core::cell::RefCell::new(Vec::new()));
+        let log = seen.clone();
+        let filter = TopicFilter::parse("ccr/+").unwrap();
+        let id = client
+            .subscribe_with(filter.clone(), QoS::AtLeastOnce, move |topic, payload| {
+                log.borrow_mut().push((String::from(topic), payload.to_vec()))
+            })
+            .unwrap();
+        // Held already, so no second SUBSCRIBE
+        let quiet = client.subscribe_with(filter, QoS::AtMostOnce, |_, _| {}).unwrap();
+
+        // Subscribed once the broker accepts us
+        mock::accept(&mut client, &broker);
+        let sent = mock::sent(&broker);
+        let subscribe = sent.last().unwrap();
+        let packet_id = u16::from_be_bytes([subscribe[2], subscribe[3]]);
+        assert_eq!(subscribe, &packet::build_subscribe_many(packet_id, &[("ccr/+", QoS::AtLeastOnce)]));
+
+        let mut data = packet::build_publish("ccr/events", b"{}", QoS::AtMostOnce);
+        data.extend(packet::build_publish("other", b"x", QoS::AtMostOnce));
+        broker.borrow_mut().rx.extend(data);
+        match client.poll() {
+            Some(MqttEvent::Message { topic, .. }) => assert_eq!(topic, "other"),
+            other => panic!("Expected Message, got {:?}", other),
+        }
+        assert_eq!(*seen.borrow(), [(String::from("ccr/events"), b"{}".to_vec())]);
+
+        // The UNSUBSCRIBE waits for the last handler on the filter
+        client.remove_handler(id).unwrap();
+        assert!(mock::sent(&broker).is_empty());
+        client.remove_handler(quiet).unwrap();
+        let sent = mock::sent(&broker);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0][0] >> 4, PacketType::Unsubscribe as u8);
+    }
+
+    #[test]
+    fn test_retained_messages() {
+        let (mut client, _, broker) = mock::client(MqttConfig::default());
+        mock::accept(&mut client, &broker);
+
+        client.publish_retained("dev/config", b"{}", QoS::AtMostOnce).unwrap();
+        let retained = packet::build_publish_with_id("dev/config", b"{}", QoS::AtMostOnce, None, true);
+        assert_eq!(mock::sent(&broker).last(), Some(&retained));
+
+        // Stored value on subscribe, then a live update
+        let mut data = packet::build_publish_with_id("dev/config", b"old", QoS::AtMostOnce, None, true);
+        data.extend(packet::build_publish("dev/config", b"new", QoS::AtMostOnce));
+        broker.borrow_mut().rx.extend(data);
+        assert!(matches!(client.poll(), Some(MqttEvent::Message { retain: true, .. })));
+        assert!(matches!(client.poll(), Some(MqttEvent::Message { retain: false, .. })));
+    }
+
+    #[test]
+    fn test_event_queue_bounded() {
+        let payload = |event: Option<MqttEvent>| match event {
+            Some(MqttEvent::Message { payload, .. }) => payload,
+            other => panic!("Expected Message, got {:?}", other),
+        };
+        let config = MqttConfig { event_queue_capacity: 2, ..Default::default() };
+        let (mut client, _, broker) = mock::client(config);
+        mock::accept(&mut client, &broker);
+
+        // A full queue holds the rest back until there is room, losing nothing
+        client.process_data(&packet::build_publish("t", b"1", QoS::AtMostOnce)).unwrap();
+        client.process_data(&packet::build_publish("t", b"2", QoS::AtMostOnce)).unwrap();
+        let result = client.process_data(&packet::build_publish("t", b"3", QoS::AtMostOnce));
+        assert!(matches!(result, Err(MqttError::QueueFull)));
+        broker.borrow_mut().rx.extend(packet::build_publish("t", b"4", QoS::AtMostOnce));
+        assert_eq!(payload(client.poll()), b"1");
+        // Nor is the connection read meanwhile
+        assert!(!broker.borrow().rx.is_empty());
+        for expected in [b"2", b"3", b"4"] {
+            assert_eq!(payload(client.poll()), expected);
+        }
+        assert!(client.poll().is_none());
+        assert_eq!(client.dropped_events(), 0);
+
+        let config = MqttConfig {
+            event_queue_capacity: 2,
+            event_queue_overflow: QueueOverflow::DropOldest,
+            ..Default::default()
+        };
+        let (mut client, _, broker) = mock::client(config);
+        mock::accept(&mut client, &broker);
+        for data in [b"1", b"2", b"3"] {
+            client.process_data(&packet::build_publish("t", data, QoS::AtMostOnce)).unwrap();
+        }
+        assert_eq!(payload(client.poll()), b"2");
+        assert_eq!(payload(client.poll()), b"3");
+        assert_eq!(client.dropped_events(), 1);
+    }
+
+    #[test]
+    fn test_inflight_window() {
+        let config = MqttConfig { max_inflight: 2, ..Default::default() };
+        let (mut client, _, broker) = mock::client(config);
+        mock::accept(&mut client, &broker);
+        mock::sent(&broker);
+
+        // Two go out and two are held, after which publishing would block
+        let ids: Vec<u16> =
+            (0..4).map(|_| client.publish("t", b"x", QoS::AtLeastOnce).unwrap().unwrap()).collect();
+        assert_eq!(mock::sent(&broker).len(), 2);
+        assert_eq!(client.in_flight(), 2);
+        assert!(matches!(client.publish("t", b"x", QoS::AtLeastOnce), Err(MqttError::WouldBlock)));
+        // QoS 0 doesn't wait
+        client.publish("t", b"0", QoS::AtMostOnce).unwrap();
+        assert_eq!(mock::sent(&broker).len(), 1);
+
+        // Each acknowledgement lets a held one go
+        broker.borrow_mut().rx.extend(packet::build_puback(ids[0]));
+        assert!(matches!(client.poll(), Some(MqttEvent::PublishAcked { .. })));
+        let next = packet::build_publish_with_id("t", b"x", QoS::AtLeastOnce, Some(ids[2]), false);
+        assert_eq!(mock::sent(&broker), [next]);
+        assert_eq!(client.in_flight(), 2);
+
+        let config =
+            MqttConfig { max_inflight: 1, inflight_full: InflightFull::WouldBlock, ..Default::default() };
+        let (mut client, _, broker) = mock::client(config);
+        mock::accept(&mut client, &broker);
+        client.publish("t", b"x", QoS::ExactlyOnce).unwrap();
+        assert!(matches!(client.publish("t", b"x", QoS::AtLeastOnce), Err(MqttError::WouldBlock)));
+    }
+
+    #[test]
+    fn test_poll_ref_borrows_publish() {
+        let (mut client, _, broker) = mock::client(MqttConfig { borrow_publish: true, ..Default::default() });
+        mock::accept(&mut client, &broker);
+        let mut data = packet::build_publish("a/b", b"first", QoS::AtMostOnce);
+        data.extend(packet::build_publish("a/c", b"second", QoS::AtMostOnce));
+        broker.borrow_mut().rx.extend(data);
+
+        assert!(client.poll().is_none());
+        let msg = client.poll_ref().unwrap();
+        assert_eq!((msg.topic, msg.payload), ("a/b", &b"first"[..]));
+        let msg = client.poll_ref().unwrap();
+        assert_eq!((msg.topic, msg.payload), ("a/c", &b"second"[..]));
+        assert!(client.poll_ref().is_none());
+    }
+
+    #[test]
+    fn test_keep_alive_ping_and_timeout() {
+        let (mut client, clock, broker) =
+            mock::client(MqttConfig { keep_alive_secs: 10, ..Default::default() });
+        mock::accept(&mut client, &broker);
+        mock::sent(&broker);
+
+        clock.advance(9_999);
+        assert!(client.poll().is_none());
+        assert!(mock::sent(&broker).is_empty());
+
+        clock.advance(1);
+        client.poll();
+        assert_eq!(mock::sent(&broker), [packet::build_pingreq()]);
+
+        // Answered: the next ping goes out one interval later
+        broker.borrow_mut().rx.extend([0xD0, 0x00]);
+        clock.advance(10_000);
+        client.poll();
+        assert_eq!(mock::sent(&broker), [packet::build_pingreq()]);
+
+        // Unanswered: the connection is declared dead after 1.5x keep-alive
+        clock.advance(14_999);
+        assert!(client.poll().is_none());
+        clock.advance(1);
+        match client.poll() {
+            Some(MqttEvent::Disconnected { reason }) => {
+                assert_eq!(reason, DisconnectReason::KeepAliveTimeout)
+            }
+            other => panic!("Expected Disconnected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_message_expiry() {
+        let config = MqttConfig { ordered_delivery: true, ..Default::default() };
+        let (mut client, clock, broker) = mock::client(config);
+        mock::accept(&mut client, &broker);
+        let first = client.publish_expiring("perm/a", b"1", QoS::AtLeastOnce, 15).unwrap().unwrap();
+        let held = client.publish_expiring("perm/a", b"2", QoS::AtLeastOnce, 5).unwrap().unwrap();
+        let released = client.publish_expiring("perm/b", b"3", QoS::ExactlyOnce, 5).unwrap().unwrap();
+        broker.borrow_mut().rx.extend(packet::build_pubrec(released));
+        assert!(client.poll().is_none());
+        let mut dup = mock::sent(&broker).remove(1);
+        dup[0] |= 0x08;
+
+        // Retried until it expires; the broker owns a QoS 2 message after PUBREC
+        clock.advance(10_000);
+        assert!(client.poll().is_none());
+        assert_eq!(mock::sent(&broker), [dup, packet::build_pubrel(released)]);
+
+        // The held message expired waiting and is dropped rather than sent
+        clock.advance(5_000);
+        let mut expired = Vec::new();
+        while let Some(MqttEvent::Error(MqttError::Expired { packet_id })) = client.poll() {
+            expired.push(packet_id);
+        }
+        assert_eq!(expired, [first, held]);
+        assert!(mock::sent(&broker).is_empty());
+        assert!(client.packet_ids.is_in_use(released) && !client.packet_ids.is_in_use(held));
+    }
+
+    #[test]
+    fn test_retransmit_until_acked() {
+        let (mut client, clock, broker) = mock::client(MqttConfig::default());
+        mock::accept(&mut client, &broker);
+        let id = client.publish("perm", b"allow", QoS::AtLeastOnce).unwrap().unwrap();
+        let sent = mock::sent(&broker).pop().unwrap();
+        assert_eq!(sent[0] & 0x08, 0);
+
+        clock.advance(10_000);
+        client.poll();
+        let resent = mock::sent(&broker).pop().unwrap();
+        assert_eq!(resent[0], sent[0] | 0x08);
+        assert_eq!(resent[1..], sent[1..]);
+
+        broker.borrow_mut().rx.extend([0x40, 0x02, (id >> 8) as u8, id as u8]);
+        assert!(matches!(client.poll(), Some(MqttEvent::PublishAcked { packet_id }) if packet_id == id));
+        clock.advance(10_000);
+        client.poll();
+        assert!(mock::sent(&broker).iter().all(|p| p[0] >> 4 != PacketType::Publish as u8));
+    }
+
+    #[test]
+    fn test_invalid_topics_rejected() {
+        let (mut client, _, broker) = mock::client(MqttConfig::default());
+        mock::accept(&mut client, &broker);
+        mock::sent(&broker);
+
+        let invalid = |result: Result<_, MqttError>, expected| {
+            assert!(matches!(result, Err(MqttError::InvalidTopic(e)) if e == expected));
+        };
+        invalid(client.publish("ccr/+", b"x", QoS::AtMostOnce).map(|_| ()), TopicError::Wildcard);
+        invalid(client.publish("", b"x", QoS::AtLeastOnce).map(|_| ()), TopicError::Empty);
+        invalid(client.subscribe("ccr/#/x", QoS::AtMostOnce).map(|_| ()), TopicError::MisplacedWildcard);
+        let many = [("ccr/ok", QoS::AtMostOnce), ("a\0b", QoS::AtMostOnce)];
+        invalid(client.subscribe_many(&many).map(|_| ()), TopicError::Nul);
+        invalid(client.unsubscribe_many(&["ccr/ok", "ccr/x+"]).map(|_| ()), TopicError::MisplacedWildcard);
+        assert!(mock::sent(&broker).is_empty());
+        assert!(client.packet_ids.is_empty());
+    }
+
+    #[test]
+    fn test_packet_ids_held_until_acked() {
+        let (mut client, _, broker) = mock::client(MqttConfig::default());
+        mock::accept(&mut client, &broker);
+        let sub = client.subscribe("ccr/#", QoS::AtLeastOnce).unwrap();
+        let unsub = client.unsubscribe("old/#").unwrap();
+        let publish = client.publish("perm", b"allow", QoS::AtLeastOnce).unwrap().unwrap();
+        assert!(sub != unsub && unsub != publish && publish != sub);
+        assert_eq!(client.packet_ids.len(), 3);
+
+        broker.borrow_mut().rx.extend([0x90, 0x03, (sub >> 8) as u8, sub as u8, 0x01]);
+        assert!(matches!(client.poll(), Some(MqttEvent::Subscribed { packet_id, .. }) if packet_id == sub));
+        assert!(!client.packet_ids.is_in_use(sub));
+
+        // The UNSUBACK is lost with the connection; the PUBLISH is still owed a PUBACK
+        broker.borrow_mut().closed = true;
+        assert!(matches!(client.poll(), Some(MqttEvent::Disconnected { .. })));
+        assert!(!client.packet_ids.is_in_use(unsub));
+        assert!(client.packet_ids.is_in_use(publish));
+    }
+
+    #[test]
+    fn test_suback_results() {
+        let (mut client, _, broker) = mock::client(MqttConfig::default());
+        mock::accept(&mut client, &broker);
+        let topics = [("a", QoS::ExactlyOnce), ("b", QoS::AtLeastOnce), ("c", QoS::AtMostOnce)];
+        let id = client.subscribe_many(&topics).unwrap();
+        let [hi, lo] = id.to_be_bytes();
+        broker.borrow_mut().rx.extend([0x90, 0x05, hi, lo, 0x01, 0x80, 0x00]);
+        let Some(MqttEvent::Subscribed { packet_id, results }) = client.poll() else {
+            panic!("Expected Subscribed");
+        };
+        assert_eq!(packet_id, id);
+        let summary: Vec<_> =
+            results.iter().map(|r| (r.filter.as_str(), r.is_downgraded(), r.is_refused())).collect();
+        assert_eq!(summary, [("a", true, false), ("b", false, true), ("c", false, false)]);
+        assert_eq!(results[1].code, 0x80);
+
+        // Nothing was asked for under this id
+        broker.borrow_mut().rx.extend([0x90, 0x03, 0x7F, 0x7F, 0x00]);
+        assert!(client.poll().is_none());
+    }
+
+    #[cfg(feature = "codec")]
+    #[test]
+    fn test_typed_payloads() {
+        let (mut client, _, broker) = mock::client(MqttConfig::default());
+        mock::accept(&mut client, &broker);
+        client.publish_json("ccr/user_input", &[("text", "hi")], QoS::AtMostOnce).unwrap();
+        let expected = packet::build_publish("ccr/user_input", br#"[["text","hi"]]"#, QoS::AtMostOnce);
+        assert_eq!(mock::sent(&broker).last().unwrap(), &expected);
+
+        client.subscribe_typed("sensors/+", QoS::AtMostOnce, Codec::Cbor).unwrap();
+        broker.borrow_mut().rx.extend(packet::build_publish("sensors/t", &[0x18, 0x2a], QoS::AtMostOnce));
+        broker.borrow_mut().rx.extend(packet::build_publish("other", b"x", QoS::AtMostOnce));
+        assert!(matches!(client.poll(), Some(MqttEvent::TypedMessage { codec: Codec::Cbor, .. })));
+        assert!(matches!(client.poll(), Some(MqttEvent::Message { .. })));
+
+        client.unsubscribe("sensors/+").unwrap();
+        broker.borrow_mut().rx.extend(packet::build_publish("sensors/t", &[0x00], QoS::AtMostOnce));
+        assert!(matches!(client.poll(), Some(MqttEvent::Message { .. })));
+    }
+
+    #[test]
+    fn test_message_timestamp() {
+        let (mut client, clock, broker) = mock::client(MqttConfig::default());
+        mock::accept(&mut client, &broker);
+        clock.advance(1500);
+        broker.borrow_mut().rx.extend(packet::build_publish("ccr/a", b"1", QoS::AtMostOnce));
+        let Some(MqttEvent::Message { received, .. }) = client.poll() else { panic!("no message") };
+        assert_eq!(received, Timestamp { monotonic_ms: 1500, unix_ms: None });
+
+        clock.set_unix_ms(Some(1_700_000_000_000));
+        broker.borrow_mut().rx.extend(packet::build_publish("ccr/a", b"2", QoS::AtMostOnce));
+        let Some(MqttEvent::Message { received, .. }) = client.poll() else { panic!("no message") };
+        assert_eq!(received.unix_ms, Some(1_700_000_000_000));
+    }
+
+    #[test]
+    fn test_packet_size_limit() {
+        let config = MqttConfig { max_packet_size: 64, ..Default::default() };
+        let (mut client, _, broker) = mock::client(config);
+        mock::accept(&mut client, &broker);
+        mock::sent(&broker);
+
+        // 2 + 5 bytes of topic and 55 of payload fill exactly 64 with the fixed header
+        assert!(client.publish("ccr/x", &[0; 55], QoS::AtMostOnce).is_ok());
+        assert!(matches!(
+            client.publish("ccr/x", &[0; 54], QoS::AtLeastOnce),
+            Err(MqttError::PacketTooLarge { size: 65, limit: 64 })
+        ));
+        assert_eq!(mock::sent(&broker).len(), 1);
+        assert!(client.packet_ids.is_empty());
+
+        // The header alone is enough to refuse a packet
+        broker.borrow_mut().rx.extend([0x30, 0x80, 0x01]);
+        assert!(matches!(
+            client.poll(),
+            Some(MqttEvent::Disconnected { reason: DisconnectReason::PacketTooLarge { size: 131 } })
+        ));
+        assert!(client.rx_buffer.is_empty());
+
+        // Nor is a length that never ends waited on
+        mock::accept(&mut client, &broker);
+        broker.borrow_mut().rx.extend([0x30, 0xFF, 0xFF, 0xFF, 0xFF]);
+        let Some(MqttEvent::Disconnected { reason }) = client.poll() else { panic!("still waiting") };
+        assert!(matches!(reason, DisconnectReason::ProtocolError { error: ParseError::InvalidLength, .. }));
+    }
+
+    #[test]
+    fn test_oversized_packet_skipped() {
+        let config =
+            MqttConfig { max_packet_size: 64, oversized_packet: OversizedPacket::Skip, ..Default::default() };
+        let (mut client, _, broker) = mock::client(config);
+        mock::accept(&mut client, &broker);
+        let small = packet::build_publish("ccr/x", b"ok", QoS::AtMostOnce);
+
+        // Arriving in pieces, none of the 131 bytes are kept
+        client.process_data(&[0x30, 0x80, 0x01]).unwrap();
+        client.process_data(&[0; 100]).unwrap();
+        assert!(client.rx_buffer.is_empty());
+        client.process_data(&[[0; 28].as_slice(), &small].concat()).unwrap();
+        assert!(matches!(
+            client.poll(),
+            Some(MqttEvent::Error(MqttError::PacketTooLarge { size: 131, limit: 64 }))
+        ));
+        assert!(matches!(client.poll(), Some(MqttEvent::Message { payload, .. }) if payload == b"ok"));
+
+        // Or all at once, with the next packet behind it
+        let mut data = alloc::vec![0x30, 0x80, 0x01];
+        data.extend([0; 128]);
+        data.extend(&small);
+        broker.borrow_mut().rx.extend(data);
+        assert!(matches!(client.poll(), Some(MqttEvent::Error(MqttError::PacketTooLarge { .. }))));
+        assert!(matches!(client.poll(), Some(MqttEvent::Message { .. })));
+        assert!(client.is_connected());
+    }
+
+    #[test]
+    fn test_operations_time_out() {
+        let (mut client, clock, broker) = mock::client(MqttConfig::default());
+        broker.borrow_mut().unreachable = true;
+        assert!(matches!(client.connect(), Err(MqttError::Timeout { packet_type: None, .. })));
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+        broker.borrow_mut().unreachable = false;
+
+        mock::accept(&mut client, &broker);
+        let sub = client.subscribe("ccr/#", QoS::AtLeastOnce).unwrap();
+        let qos1 = client.publish("a", b"1", QoS::AtLeastOnce).unwrap().unwrap();
+        clock.advance(30_000);
+        let qos2 = client.publish("a", b"2", QoS::ExactlyOnce).unwrap().unwrap();
+        broker.borrow_mut().rx.extend(packet::build_pubrec(qos2));
+        client.poll();
+
+        // The first two give up; the QoS 2 exchange still has time after its PUBREC
+        clock.advance(30_000);
+        let mut timed_out = Vec::new();
+        while let Some(event) = client.poll() {
+            if let MqttEvent::Error(MqttError::AckTimeout { packet_id }) = event {
+                timed_out.push(packet_id);
+            }
+        }
+        assert_eq!(timed_out, [sub, qos1]);
+        assert_eq!(client.packet_ids.len(), 1);
+        assert!(client.inflight.is_empty());
+
+        clock.advance(30_000);
+        assert!(matches!(
+            client.poll(),
+            Some(MqttEvent::Error(MqttError::AckTimeout { packet_id })) if packet_id == qos2
+        ));
+        assert!(client.qos2_out.state(qos2).is_none());
+        mock::sent(&broker);
+        clock.advance(60_000);
+        client.poll();
+        assert!(mock::sent(&broker).iter().all(|packet| packet[0] >> 4 == PacketType::Pingreq as u8));
+    }
+
+    #[test]
+    fn test_last_error() {
+        let config = MqttConfig { broker: String::from("broker.local:1883"), ..Default::default() };
+        let (mut client, _, broker) = mock::client(config);
+        assert!(client.last_error().is_none());
+        broker.borrow_mut().unreachable = true;
+        client.connect().unwrap_err();
+        assert_eq!(alloc::format!("{}", client.last_error().unwrap()), "no answer from broker.local:1883");
+        broker.borrow_mut().unreachable = false;
+
+        // Kept after connecting, until something else fails
+        mock::accept(&mut client, &broker);
+        assert!(matches!(client.last_error(), Some(MqttError::Timeout { .. })));
+        broker.borrow_mut().rx.extend([0x00, 0x00]);
+        assert!(matches!(client.poll(), Some(MqttEvent::Disconnected { .. })));
+        match client.last_error() {
+            Some(MqttError::Disconnected { broker, reason: DisconnectReason::ProtocolError { .. } }) => {
+                assert_eq!(broker, "broker.local:1883")
+            }
+            other => panic!("Expected a protocol error, got {:?}", other),
+        }
+
+        // Errors handed straight back aren't recorded, nor is a requested disconnect
+        assert!(matches!(client.publish("t", b"x", QoS::AtMostOnce), Err(MqttError::NotConnected)));
+        mock::accept(&mut client, &broker);
+        client.disconnect().unwrap();
+        assert!(matches!(client.last_error(), Some(MqttError::Disconnected { .. })));
+    }
+
+    #[test]
+    fn test_qos2_publish_completes() {
+        let (mut client, clock, broker) = mock::client(MqttConfig::default());
+        mock::accept(&mut client, &broker);
+        let id = client.publish("perm", b"allow", QoS::ExactlyOnce).unwrap().unwrap();
+        mock::sent(&broker);
+
+        broker.borrow_mut().rx.extend(packet::build_pubrec(id));
+        assert!(client.poll().is_none());
+        assert_eq!(mock::sent(&broker), [packet::build_pubrel(id)]);
+
+        // PUBCOMP lost: the PUBREL is retried, not the PUBLISH
+        clock.advance(10_000);
+        client.poll();
+        assert_eq!(mock::sent(&broker), [packet::build_pubrel(id)]);
+
+        broker.borrow_mut().rx.extend(packet::build_pubcomp(id));
+        assert!(matches!(client.poll(), Some(MqttEvent::PublishComplete { packet_id }) if packet_id == id));
+        clock.advance(10_000);
+        client.poll();
+        assert!(mock::sent(&broker).is_empty());
+    }
+
+    #[test]
+    fn test_mqtt31_compatibility() {
+        let config = MqttConfig { protocol: ProtocolVersion::V31, ..Default::default() };
+        let (mut client, _, broker) = mock::client(config);
+        mock::accept(&mut client, &broker);
+        let connect = packet::build_connect_v31("xous-mqtt-client", None, None, true, 60, None);
+        assert_eq!(mock::sent(&broker), [connect]);
+
+        // Everything after CONNECT is as at 3.1.1
+        let id = client.subscribe("a/b", QoS::AtLeastOnce).unwrap();
+        assert_eq!(mock::sent(&broker), [packet::build_subscribe(id, "a/b", QoS::AtLeastOnce)]);
+        broker.borrow_mut().rx.extend(packet::build_publish("a/b", b"x", QoS::AtMostOnce));
+        assert!(matches!(client.poll(), Some(MqttEvent::Message { .. })));
+
+        let client_id = String::from("longer-than-twenty-three");
+        let config = MqttConfig { protocol: ProtocolVersion::V31, client_id, ..Default::default() };
+        let (mut client, _, _) = mock::client(config);
+        let result = client.connect();
+        assert!(matches!(result, Err(MqttError::ProtocolError { packet_type: PacketType::Connect, .. })));
+    }
+
+    #[cfg(feature = "mqtt5")]
+    #[test]
+    fn test_mqtt5_fallback_and_reason_codes() {
+        let config = MqttConfig { protocol: ProtocolVersion::V5, ..Default::default() };
+        let (mut client, _, broker) = mock::client(config.clone());
+
+        // A 3.1.1 broker refuses protocol level 5; the client retries at level 4
+        client.connect().unwrap();
+        assert_eq!(mock::sent(&broker)[0][8], 5);
+        broker.borrow_mut().rx.extend([0x20, 0x02, 0x00, 0x01]);
+        assert!(client.poll().is_none());
+        assert_eq!(mock::sent(&broker)[0][8], 4);
+
+        let (mut client, _, broker) = mock::client(config);
+        mock::accept(&mut client, &broker);
+        let id = client.publish("t", b"x", QoS::AtLeastOnce).unwrap().unwrap();
+        assert_eq!(
+            mock::sent(&broker).last().unwrap(),
+            &v5::build_publish("t", b"x", QoS::AtLeastOnce, Some(id), false, &[])
+        );
+
+        broker.borrow_mut().rx.extend(v5::build_ack(PacketType::Puback, id, ReasonCode::NOT_AUTHORIZED, &[]));
+        assert!(matches!(
+            client.poll(),
+            Some(MqttEvent::Error(MqttError::Rejected { packet_id, reason: 0x87 })) if packet_id == id
+        ));
+
+        broker.borrow_mut().rx.extend(v5::build_disconnect(ReasonCode::SESSION_TAKEN_OVER, &[]));
+        assert!(matches!(
+            client.poll(),
+            Some(MqttEvent::Disconnected { reason: DisconnectReason::ServerDisconnect { reason: 0x8E } })
+        ));
+    }
+
+    #[cfg(feature = "mqtt5")]
+    #[test]
+    fn test_mqtt5_user_properties() {
+        let user_properties = Vec::from([(String::from("fw"), String::from("0.9.16"))]);
+        let config = MqttConfig { protocol: ProtocolVersion::V5, user_properties, ..Default::default() };
+        let (mut client, _, broker) = mock::client(config);
+        mock::accept(&mut client, &broker);
+        let properties = client.connect_properties();
+        assert!(properties.contains(&v5::Property::UserProperty("fw".into(), "0.9.16".into())));
+        let connect = v5::build_connect("xous-mqtt-client", None, None, true, 60, None, &properties);
+        assert_eq!(mock::sent(&broker)[0], connect);
+
+        let schema = |version: &str| v5::Property::UserProperty("schema".into(), version.into());
+        client.publish_with_properties("t", b"x", QoS::AtMostOnce, &[("schema", "2")]).unwrap();
+        let expected = v5::build_publish("t", b"x", QoS::AtMostOnce, None, false, &[schema("2")]);
+        assert_eq!(mock::sent(&broker), [expected]);
+
+        let incoming = v5::build_publish("t", b"y", QoS::AtMostOnce, None, false, &[schema("3")]);
+        broker.borrow_mut().rx.extend(incoming);
+        let Some(MqttEvent::Message { user_properties, .. }) = client.poll() else { panic!("no message") };
+        assert_eq!(user_properties, [(String::from("schema"), String::from("3"))]);
+    }
+
+    /// Tries a second password once, then stops
+    struct RotateOnce;
+    impl RefusalPolicy for RotateOnce {
+        fn on_refused(&mut self, reason: RefusedReason, attempts: u32) -> RefusalAction {
+            match (reason, attempts) {
+                (RefusedReason::BadCredentials, 1) => RefusalAction::UpdateCredentials {
+                    username: Some(String::from("device")),
+                    password: Some(b"rotated".to_vec()),
+                },
+                (RefusedReason::ServerUnavailable, _) => RefusalAction::RetryAfter(500),
+                _ => RefusalAction::GiveUp,
+            }
+        }
+    }
+
+    #[test]
+    fn test_refusal_policy() {
+        let (client, clock, broker) = mock::client(MqttConfig::default());
+        let mut client = client.with_refusal_policy(Box::new(RotateOnce));
+        let refuse = |code: u8| broker.borrow_mut().rx.extend([0x20, 0x02, 0x00, code]);
+
+        // Bad credentials: the next attempt goes out at once with the new ones
+        client.connect().unwrap();
+        mock::sent(&broker);
+        refuse(4);
+        while client.poll().is_some() {}
+        assert_eq!(client.state(), ConnectionState::Connecting);
+        assert_eq!(client.config().password.as_deref(), Some(&b"rotated"[..]));
+        assert!(mock::sent(&broker)[0].ends_with(b"rotated"));
+
+        // Rejected again: given up, even after the reconnect delay
+        refuse(4);
+        while client.poll().is_some() {}
+        clock.advance(60_000);
+        assert!(client.poll().is_none());
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+
+        // Busy broker: retried on the policy's schedule rather than the config's
+        client.connect().unwrap();
+        refuse(3);
+        while client.poll().is_some() {}
+        clock.advance(500);
+        client.poll();
+        assert_eq!(client.state(), ConnectionState::Connecting);
+
+        // The default policy only retries what may clear up by itself
+        let (mut client, clock, broker) = mock::client(MqttConfig::default());
+        client.connect().unwrap();
+        broker.borrow_mut().rx.extend([0x20, 0x02, 0x00, 0x02]);
+        while client.poll().is_some() {}
+        clock.advance(60_000);
+        client.poll();
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+    }
+
+    #[test]
+    fn test_auto_reconnect_after_delay() {
+        let (mut client, clock, broker) = mock::client(MqttConfig::default());
+        mock::accept(&mut client, &broker);
+        broker.borrow_mut().closed = true;
+        assert!(matches!(client.poll(), Some(MqttEvent::Disconnected { .. })));
+
+        clock.advance(4_999);
+        assert!(client.poll().is_none());
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+        clock.advance(1);
+        assert!(client.poll().is_none());
+        assert_eq!(client.state(), ConnectionState::Connecting);
+        broker.borrow_mut().rx.extend([0x20, 0x02, 0x00, 0x00]);
+        assert!(matches!(client.poll(), Some(MqttEvent::Connected)));
+
+        // A requested disconnect stays down
+        client.disconnect().unwrap();
+        client.poll();
+        clock.advance(60_000);
+        assert!(client.poll().is_none());
+        assert!(!client.is_connected());
     }
 }
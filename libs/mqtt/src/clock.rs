@@ -0,0 +1,93 @@
+//! Monotonic Time Source
+//!
+//! The client never reads a wall clock. Keep-alive, retransmit and reconnect
+//! deadlines are computed from a [`Clock`] supplied by the caller, so the same
+//! timing logic runs on hardware and under deterministic unit tests.
+//!
+//! A clock may also know the time of day, e.g. once the RTC has been set.
+//! That is only used to stamp received messages, never for deadlines.
+
+use core::cell::Cell;
+
+/// Monotonic millisecond clock
+pub trait Clock {
+    /// Milliseconds since an arbitrary fixed origin; must never go backwards
+    fn now_ms(&self) -> u64;
+
+    /// Milliseconds since the Unix epoch, if the time of day is known
+    fn unix_ms(&self) -> Option<u64> { None }
+
+    /// The current time, for stamping an event
+    fn stamp(&self) -> Timestamp { Timestamp { monotonic_ms: self.now_ms(), unix_ms: self.unix_ms() } }
+}
+
+/// When something happened, as read from a [`Clock`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp {
+    /// [`Clock::now_ms`]; comparable with other stamps from the same clock
+    pub monotonic_ms: u64,
+    /// [`Clock::unix_ms`], if the clock knew the time of day
+    pub unix_ms: Option<u64>,
+}
+
+#[cfg(feature = "alloc")]
+impl<C: Clock + ?Sized> Clock for alloc::rc::Rc<C> {
+    fn now_ms(&self) -> u64 { (**self).now_ms() }
+
+    fn unix_ms(&self) -> Option<u64> { (**self).unix_ms() }
+}
+
+/// Earliest time of day believed, 2020-01-01; an RTC that hasn't been set reads earlier
+#[cfg(feature = "xous-client")]
+const RTC_FLOOR_MS: u64 = 1_577_836_800_000;
+
+/// Clock backed by the Xous ticktimer, with the time of day from the RTC
+#[cfg(feature = "xous-client")]
+pub struct TicktimerClock {
+    ticktimer: ticktimer_server::Ticktimer,
+}
+
+#[cfg(feature = "xous-client")]
+impl TicktimerClock {
+    pub fn new() -> Self {
+        Self { ticktimer: ticktimer_server::Ticktimer::new().expect("Couldn't connect to Ticktimer") }
+    }
+}
+
+#[cfg(feature = "xous-client")]
+impl Default for TicktimerClock {
+    fn default() -> Self { Self::new() }
+}
+
+#[cfg(feature = "xous-client")]
+impl Clock for TicktimerClock {
+    fn now_ms(&self) -> u64 { self.ticktimer.elapsed_ms() }
+
+    fn unix_ms(&self) -> Option<u64> {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).ok()?;
+        Some(now.as_millis() as u64).filter(|&ms| ms >= RTC_FLOOR_MS)
+    }
+}
+
+/// Clock that only moves when told to, for tests
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: Cell<u64>,
+    unix: Cell<Option<u64>>,
+}
+
+impl ManualClock {
+    pub fn new(start_ms: u64) -> Self { Self { now: Cell::new(start_ms), unix: Cell::new(None) } }
+
+    /// Set the time of day the clock reports; it doesn't move with [`Self::advance`]
+    pub fn set_unix_ms(&self, unix_ms: Option<u64>) { self.unix.set(unix_ms); }
+
+    /// Move the clock forward by `ms`
+    pub fn advance(&self, ms: u64) { self.now.set(self.now.get() + ms); }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 { self.now.get() }
+
+    fn unix_ms(&self) -> Option<u64> { self.unix.get() }
+}
@@ -0,0 +1,139 @@
+//! CBOR encoder
+//!
+//! Integers take the shortest head that holds them. Sequences and maps of
+//! unknown length use the indefinite-length encoding, closed by a break.
+
+use alloc::vec::Vec;
+
+use super::{Format, Result};
+
+const UNSIGNED: u8 = 0;
+const NEGATIVE: u8 = 1;
+const BYTES: u8 = 2;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+/// Simple values and floats
+const SIMPLE: u8 = 7;
+
+/// Additional information for an indefinite length
+const INDEFINITE: u8 = 31;
+const BREAK: u8 = 0xff;
+
+#[derive(Default)]
+pub(super) struct Cbor {
+    pub(super) out: Vec<u8>,
+}
+
+impl Cbor {
+    /// Major type and argument
+    fn head(&mut self, major: u8, arg: u64) {
+        let major = major << 5;
+        match arg {
+            0..=23 => self.out.push(major | arg as u8),
+            24..=0xff => self.out.extend_from_slice(&[major | 24, arg as u8]),
+            0x100..=0xffff => {
+                self.out.push(major | 25);
+                self.out.extend_from_slice(&(arg as u16).to_be_bytes());
+            }
+            0x1_0000..=0xffff_ffff => {
+                self.out.push(major | 26);
+                self.out.extend_from_slice(&(arg as u32).to_be_bytes());
+            }
+            _ => {
+                self.out.push(major | 27);
+                self.out.extend_from_slice(&arg.to_be_bytes());
+            }
+        }
+    }
+
+    fn begin(&mut self, major: u8, len: Option<usize>) {
+        match len {
+            Some(len) => self.head(major, len as u64),
+            None => self.out.push((major << 5) | INDEFINITE),
+        }
+    }
+
+    fn end(&mut self, len: Option<usize>) {
+        if len.is_none() {
+            self.out.push(BREAK);
+        }
+    }
+}
+
+impl Format for Cbor {
+    fn null(&mut self) -> Result {
+        self.head(SIMPLE, 22);
+        Ok(())
+    }
+
+    fn bool(&mut self, v: bool) -> Result {
+        self.head(SIMPLE, if v { 21 } else { 20 });
+        Ok(())
+    }
+
+    fn int(&mut self, v: i64) -> Result {
+        if v < 0 {
+            // -1 - n, computed without overflow at i64::MIN
+            self.head(NEGATIVE, !(v as u64));
+        } else {
+            self.head(UNSIGNED, v as u64);
+        }
+        Ok(())
+    }
+
+    fn uint(&mut self, v: u64) -> Result {
+        self.head(UNSIGNED, v);
+        Ok(())
+    }
+
+    fn f32(&mut self, v: f32) -> Result {
+        self.out.push((SIMPLE << 5) | 26);
+        self.out.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn f64(&mut self, v: f64) -> Result {
+        self.out.push((SIMPLE << 5) | 27);
+        self.out.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn str(&mut self, v: &str) -> Result {
+        self.head(TEXT, v.len() as u64);
+        self.out.extend_from_slice(v.as_bytes());
+        Ok(())
+    }
+
+    fn bytes(&mut self, v: &[u8]) -> Result {
+        self.head(BYTES, v.len() as u64);
+        self.out.extend_from_slice(v);
+        Ok(())
+    }
+
+    fn begin_seq(&mut self, len: Option<usize>) -> Result {
+        self.begin(ARRAY, len);
+        Ok(())
+    }
+
+    fn element(&mut self, _first: bool) -> Result { Ok(()) }
+
+    fn end_seq(&mut self, len: Option<usize>) -> Result {
+        self.end(len);
+        Ok(())
+    }
+
+    fn begin_map(&mut self, len: Option<usize>) -> Result {
+        self.begin(MAP, len);
+        Ok(())
+    }
+
+    fn key(&mut self, _first: bool) -> Result { Ok(()) }
+
+    fn value(&mut self) -> Result { Ok(()) }
+
+    fn end_map(&mut self, len: Option<usize>) -> Result {
+        self.end(len);
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+//! JSON encoder
+
+use alloc::format;
+use alloc::vec::Vec;
+
+use super::{CodecError, Format, Result};
+
+#[derive(Default)]
+pub(super) struct Json {
+    pub(super) out: Vec<u8>,
+    /// Writing a map key: only strings are allowed, and integers are quoted
+    in_key: bool,
+}
+
+impl Json {
+    fn scalar(&mut self, text: &[u8]) -> Result {
+        if self.in_key {
+            return Err(CodecError::KeyMustBeString);
+        }
+        self.out.extend_from_slice(text);
+        Ok(())
+    }
+
+    fn number(&mut self, text: &str) -> Result {
+        if self.in_key {
+            self.out.push(b'"');
+            self.out.extend_from_slice(text.as_bytes());
+            self.out.push(b'"');
+        } else {
+            self.out.extend_from_slice(text.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+impl Format for Json {
+    fn null(&mut self) -> Result { self.scalar(b"null") }
+
+    fn bool(&mut self, v: bool) -> Result { self.scalar(if v { b"true" } else { b"false" }) }
+
+    fn int(&mut self, v: i64) -> Result { self.number(&format!("{}", v)) }
+
+    fn uint(&mut self, v: u64) -> Result { self.number(&format!("{}", v)) }
+
+    fn f32(&mut self, v: f32) -> Result {
+        // Widening to f64 would print 0.1 as 0.10000000149011612
+        if !v.is_finite() {
+            return self.scalar(b"null");
+        }
+        self.scalar(format!("{:?}", v).as_bytes())
+    }
+
+    fn f64(&mut self, v: f64) -> Result {
+        if !v.is_finite() {
+            return self.scalar(b"null");
+        }
+        // `Debug` always has a decimal point or exponent, so the value reads back as a float
+        self.scalar(format!("{:?}", v).as_bytes())
+    }
+
+    fn str(&mut self, v: &str) -> Result {
+        self.out.push(b'"');
+        for c in v.chars() {
+            match c {
+                '"' => self.out.extend_from_slice(b"\\\""),
+                '\\' => self.out.extend_from_slice(b"\\\\"),
+                '\n' => self.out.extend_from_slice(b"\\n"),
+                '\r' => self.out.extend_from_slice(b"\\r"),
+                '\t' => self.out.extend_from_slice(b"\\t"),
+                c if (c as u32) < 0x20 => {
+                    self.out.extend_from_slice(format!("\\u{:04x}", c as u32).as_bytes())
+                }
+                c => self.out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+        self.out.push(b'"');
+        Ok(())
+    }
+
+    /// An array of numbers, as JSON has no byte strings
+    fn bytes(&mut self, v: &[u8]) -> Result {
+        self.begin_seq(Some(v.len()))?;
+        for (i, &byte) in v.iter().enumerate() {
+            self.element(i == 0)?;
+            self.uint(byte.into())?;
+        }
+        self.end_seq(Some(v.len()))
+    }
+
+    fn begin_seq(&mut self, _len: Option<usize>) -> Result { self.scalar(b"[") }
+
+    fn element(&mut self, first: bool) -> Result {
+        if !first {
+            self.out.push(b',');
+        }
+        Ok(())
+    }
+
+    fn end_seq(&mut self, _len: Option<usize>) -> Result {
+        self.out.push(b']');
+        Ok(())
+    }
+
+    fn begin_map(&mut self, _len: Option<usize>) -> Result { self.scalar(b"{") }
+
+    fn key(&mut self, first: bool) -> Result {
+        self.element(first)?;
+        self.in_key = true;
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result {
+        self.in_key = false;
+        self.out.push(b':');
+        Ok(())
+    }
+
+    fn end_map(&mut self, _len: Option<usize>) -> Result {
+        self.out.push(b'}');
+        Ok(())
+    }
+}
@@ -0,0 +1,455 @@
+//! Typed Payloads
+//!
+//! Serializes any `serde::Serialize` value as a JSON or CBOR payload, so
+//! applications publish structs instead of formatting strings by hand:
+//!
+//! ```rust
+//! use serde::Serialize;
+//! use xous_mqtt::codec;
+//!
+//! #[derive(Serialize)]
+//! struct UserInput<'a> {
+//!     session_id: &'a str,
+//!     text: &'a str,
+//! }
+//!
+//! let input = UserInput { session_id: "s1", text: "say \"hi\"" };
+//! let payload = codec::to_json(&input).unwrap();
+//! assert_eq!(payload, br#"{"session_id":"s1","text":"say \"hi\""}"#);
+//! ```
+//!
+//! With `xous-client`, `MqttClient::publish_json` and `publish_cbor` do the
+//! same in one call. Both encoders are `no_std` and write into a `Vec`; they
+//! follow serde's usual data model, so enum variants become their name, or
+//! a one-entry map from the name to the contents. JSON map keys must be
+//! strings or integers, and non-finite floats are written as `null`. There
+//! are no decoders: messages on filters subscribed with `subscribe_typed`
+//! arrive tagged with their [`Codec`], for the application to decode.
+
+mod cbor;
+mod json;
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use serde::ser::{self, Serialize};
+
+/// Payload encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// JSON text (RFC 8259)
+    Json,
+    /// Concise Binary Object Representation (RFC 8949)
+    Cbor,
+}
+
+impl Codec {
+    /// MIME type, as used for the MQTT 5 content type property
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Cbor => "application/cbor",
+        }
+    }
+
+    /// Serialize `value` in this encoding
+    pub fn encode<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Self::Json => to_json(value),
+            Self::Cbor => to_cbor(value),
+        }
+    }
+}
+
+/// Why a value couldn't be serialized
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+    /// JSON map key that isn't a string or an integer
+    KeyMustBeString,
+    /// Integer wider than 64 bits
+    Unsupported(&'static str),
+    /// Raised by the value's `Serialize` implementation
+    Custom(String),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::KeyMustBeString => write!(f, "JSON map keys must be strings or integers"),
+            Self::Unsupported(what) => write!(f, "{} can't be encoded", what),
+            Self::Custom(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl ser::StdError for CodecError {}
+
+impl ser::Error for CodecError {
+    fn custom<T: fmt::Display>(msg: T) -> Self { Self::Custom(msg.to_string()) }
+}
+
+/// Serialize `value` as JSON
+pub fn to_json<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, CodecError> {
+    let mut serializer = Serializer(json::Json::default());
+    value.serialize(&mut serializer)?;
+    Ok(serializer.0.out)
+}
+
+/// Serialize `value` as CBOR
+pub fn to_cbor<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, CodecError> {
+    let mut serializer = Serializer(cbor::Cbor::default());
+    value.serialize(&mut serializer)?;
+    Ok(serializer.0.out)
+}
+
+type Result<T = (), E = CodecError> = core::result::Result<T, E>;
+
+/// What an encoder writes for each part of serde's data model
+///
+/// Sequences and maps are given their length when serde knows it; the
+/// separators between items are the encoder's business.
+trait Format {
+    fn null(&mut self) -> Result;
+    fn bool(&mut self, v: bool) -> Result;
+    fn int(&mut self, v: i64) -> Result;
+    fn uint(&mut self, v: u64) -> Result;
+    fn f32(&mut self, v: f32) -> Result;
+    fn f64(&mut self, v: f64) -> Result;
+    fn str(&mut self, v: &str) -> Result;
+    fn bytes(&mut self, v: &[u8]) -> Result;
+    fn begin_seq(&mut self, len: Option<usize>) -> Result;
+    /// Before each element; `first` for the first one
+    fn element(&mut self, first: bool) -> Result;
+    fn end_seq(&mut self, len: Option<usize>) -> Result;
+    fn begin_map(&mut self, len: Option<usize>) -> Result;
+    /// Before each key; `first` for the first one
+    fn key(&mut self, first: bool) -> Result;
+    /// Between a key and its value
+    fn value(&mut self) -> Result;
+    fn end_map(&mut self, len: Option<usize>) -> Result;
+}
+
+/// serde `Serializer` driving a [`Format`]
+struct Serializer<F>(F);
+
+impl<F: Format> Serializer<F> {
+    /// Open the one-entry map `{variant: ...}` an enum variant with contents is written as
+    fn begin_variant(&mut self, variant: &str) -> Result {
+        self.0.begin_map(Some(1))?;
+        self.0.key(true)?;
+        self.0.str(variant)?;
+        self.0.value()
+    }
+
+    fn compound(&mut self, len: Option<usize>, variant: bool) -> Compound<'_, F> {
+        Compound { ser: self, len, first: true, variant }
+    }
+}
+
+/// A sequence, map or struct being written, and the variant map around it, if any
+struct Compound<'a, F> {
+    ser: &'a mut Serializer<F>,
+    len: Option<usize>,
+    first: bool,
+    variant: bool,
+}
+
+impl<F: Format> Compound<'_, F> {
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result {
+        self.ser.0.element(self.first)?;
+        self.first = false;
+        value.serialize(&mut *self.ser)
+    }
+
+    fn field<T: Serialize + ?Sized>(&mut self, key: &str, value: &T) -> Result {
+        self.ser.0.key(self.first)?;
+        self.first = false;
+        self.ser.0.str(key)?;
+        self.ser.0.value()?;
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end_seq(self) -> Result {
+        self.ser.0.end_seq(self.len)?;
+        if self.variant { self.ser.0.end_map(Some(1)) } else { Ok(()) }
+    }
+
+    fn end_map(self) -> Result {
+        self.ser.0.end_map(self.len)?;
+        if self.variant { self.ser.0.end_map(Some(1)) } else { Ok(()) }
+    }
+}
+
+impl<'a, F: Format> ser::Serializer for &'a mut Serializer<F> {
+    type Error = CodecError;
+    type Ok = ();
+    type SerializeMap = Compound<'a, F>;
+    type SerializeSeq = Compound<'a, F>;
+    type SerializeStruct = Compound<'a, F>;
+    type SerializeStructVariant = Compound<'a, F>;
+    type SerializeTuple = Compound<'a, F>;
+    type SerializeTupleStruct = Compound<'a, F>;
+    type SerializeTupleVariant = Compound<'a, F>;
+
+    fn serialize_bool(self, v: bool) -> Result { self.0.bool(v) }
+
+    fn serialize_i8(self, v: i8) -> Result { self.0.int(v.into()) }
+
+    fn serialize_i16(self, v: i16) -> Result { self.0.int(v.into()) }
+
+    fn serialize_i32(self, v: i32) -> Result { self.0.int(v.into()) }
+
+    fn serialize_i64(self, v: i64) -> Result { self.0.int(v) }
+
+    fn serialize_i128(self, v: i128) -> Result {
+        let v = i64::try_from(v).map_err(|_| CodecError::Unsupported("i128"))?;
+        self.0.int(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result { self.0.uint(v.into()) }
+
+    fn serialize_u16(self, v: u16) -> Result { self.0.uint(v.into()) }
+
+    fn serialize_u32(self, v: u32) -> Result { self.0.uint(v.into()) }
+
+    fn serialize_u64(self, v: u64) -> Result { self.0.uint(v) }
+
+    fn serialize_u128(self, v: u128) -> Result {
+        let v = u64::try_from(v).map_err(|_| CodecError::Unsupported("u128"))?;
+        self.0.uint(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result { self.0.f32(v) }
+
+    fn serialize_f64(self, v: f64) -> Result { self.0.f64(v) }
+
+    fn serialize_char(self, v: char) -> Result { self.0.str(v.encode_utf8(&mut [0; 4])) }
+
+    fn serialize_str(self, v: &str) -> Result { self.0.str(v) }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result { self.0.bytes(v) }
+
+    fn serialize_none(self) -> Result { self.0.null() }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result { value.serialize(self) }
+
+    fn serialize_unit(self) -> Result { self.0.null() }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result { self.0.null() }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result {
+        self.0.str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result {
+        self.begin_variant(variant)?;
+        value.serialize(&mut *self)?;
+        self.0.end_map(Some(1))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq> {
+        self.0.begin_seq(len)?;
+        Ok(self.compound(len, false))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple> { self.serialize_seq(Some(len)) }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Self::SerializeTupleStruct> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        self.begin_variant(variant)?;
+        self.0.begin_seq(Some(len))?;
+        Ok(self.compound(Some(len), true))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap> {
+        self.0.begin_map(len)?;
+        Ok(self.compound(len, false))
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Self::SerializeStruct> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        self.begin_variant(variant)?;
+        self.0.begin_map(Some(len))?;
+        Ok(self.compound(Some(len), true))
+    }
+}
+
+impl<F: Format> ser::SerializeSeq for Compound<'_, F> {
+    type Error = CodecError;
+    type Ok = ();
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result { self.element(value) }
+
+    fn end(self) -> Result { self.end_seq() }
+}
+
+impl<F: Format> ser::SerializeTuple for Compound<'_, F> {
+    type Error = CodecError;
+    type Ok = ();
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result { self.element(value) }
+
+    fn end(self) -> Result { self.end_seq() }
+}
+
+impl<F: Format> ser::SerializeTupleStruct for Compound<'_, F> {
+    type Error = CodecError;
+    type Ok = ();
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result { self.element(value) }
+
+    fn end(self) -> Result { self.end_seq() }
+}
+
+impl<F: Format> ser::SerializeTupleVariant for Compound<'_, F> {
+    type Error = CodecError;
+    type Ok = ();
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result { self.element(value) }
+
+    fn end(self) -> Result { self.end_seq() }
+}
+
+impl<F: Format> ser::SerializeMap for Compound<'_, F> {
+    type Error = CodecError;
+    type Ok = ();
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result {
+        self.ser.0.key(self.first)?;
+        self.first = false;
+        key.serialize(&mut *self.ser)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result {
+        self.ser.0.value()?;
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result { self.end_map() }
+}
+
+impl<F: Format> ser::SerializeStruct for Compound<'_, F> {
+    type Error = CodecError;
+    type Ok = ();
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result { self.end_map() }
+}
+
+impl<F: Format> ser::SerializeStructVariant for Compound<'_, F> {
+    type Error = CodecError;
+    type Ok = ();
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result { self.end_map() }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::BTreeMap;
+    use alloc::vec;
+
+    use serde::Serialize;
+
+    use super::*;
+
+    #[derive(Serialize)]
+    enum Decision {
+        Allow,
+        Deny { reason: &'static str },
+    }
+
+    #[derive(Serialize)]
+    struct Response {
+        id: u32,
+        decision: Decision,
+        scores: (i8, f32),
+        note: Option<&'static str>,
+    }
+
+    #[test]
+    fn test_json() {
+        let response =
+            Response { id: 7, decision: Decision::Deny { reason: "a\\b\n" }, scores: (-1, 0.5), note: None };
+        assert_eq!(
+            to_json(&response).unwrap(),
+            br#"{"id":7,"decision":{"Deny":{"reason":"a\\b\n"}},"scores":[-1,0.5],"note":null}"#
+        );
+        assert_eq!(to_json(&Decision::Allow).unwrap(), br#""Allow""#);
+        assert_eq!(to_json(&[f64::NAN, 1.0, 1e-7]).unwrap(), b"[null,1.0,1e-7]");
+        assert_eq!(to_json("\u{1}é").unwrap(), "\"\\u0001é\"".as_bytes());
+
+        let mut map = BTreeMap::new();
+        map.insert(3u8, true);
+        assert_eq!(to_json(&map).unwrap(), br#"{"3":true}"#);
+        let mut map = BTreeMap::new();
+        map.insert((1, 2), true);
+        assert_eq!(to_json(&map), Err(CodecError::KeyMustBeString));
+    }
+
+    #[test]
+    fn test_cbor() {
+        // RFC 8949 appendix A
+        assert_eq!(to_cbor(&1_000_000u32).unwrap(), [0x1a, 0x00, 0x0f, 0x42, 0x40]);
+        assert_eq!(to_cbor(&-1000i16).unwrap(), [0x39, 0x03, 0xe7]);
+        assert_eq!(to_cbor(&1.1f64).unwrap(), [0xfb, 0x3f, 0xf1, 0x99, 0x99, 0x99, 0x99, 0x99, 0x9a]);
+        assert_eq!(to_cbor(&(1, [2, 3], [4, 5])).unwrap(), [0x83, 0x01, 0x82, 0x02, 0x03, 0x82, 0x04, 0x05]);
+        assert_eq!(to_cbor(&Option::<bool>::None).unwrap(), [0xf6]);
+        assert_eq!(to_cbor(&serde_bytes(b"\x01")).unwrap(), [0x41, 0x01]);
+
+        let response = Response { id: 1, decision: Decision::Allow, scores: (0, 1.5), note: Some("ok") };
+        let mut expected = vec![0xa4, 0x62, b'i', b'd', 0x01];
+        expected.extend_from_slice(&[0x68, b'd', b'e', b'c', b'i', b's', b'i', b'o', b'n']);
+        expected.extend_from_slice(&[0x65, b'A', b'l', b'l', b'o', b'w']);
+        expected.extend_from_slice(&[0x66, b's', b'c', b'o', b'r', b'e', b's', 0x82, 0x00]);
+        expected.extend_from_slice(&[0xfa, 0x3f, 0xc0, 0x00, 0x00]);
+        expected.extend_from_slice(&[0x64, b'n', b'o', b't', b'e', 0x62, b'o', b'k']);
+        assert_eq!(to_cbor(&response).unwrap(), expected);
+    }
+
+    /// A byte string, which `&[u8]` alone serializes as a sequence
+    fn serde_bytes(bytes: &[u8]) -> impl Serialize + '_ {
+        struct Bytes<'a>(&'a [u8]);
+        impl Serialize for Bytes<'_> {
+            fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_bytes(self.0)
+            }
+        }
+        Bytes(bytes)
+    }
+}
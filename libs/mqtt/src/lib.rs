@@ -5,10 +5,19 @@
 //! # Features
 //!
 //! - `default` - Packet encoding/decoding only (no networking)
-//! - `xous-client` - Full client with TCP networking via Xous Net service
-//! - `tls-support` - MQTT over TLS (port 8883)
-//! - `qos1` - At-least-once delivery
-//! - `qos2` - Exactly-once delivery
+//! - `encode` - Packet builders
+//! - `decode` - Packet parsers (borrowed parsing works without `alloc`)
+//! - `heapless` - Builders in `packet::fixed` writing into caller-sized `heapless::Vec`s, without `alloc`
+//! - `alloc` - Owned packet types and `Topic`/`TopicFilter` builders
+//! - `codec` - JSON and CBOR payloads from `serde::Serialize` values; with `xous-client`, `publish_json` and
+//!   friends
+//! - `xous-client` - Full client with TCP networking via Xous Net service, broker names resolved via Xous DNS
+//! - `tls-support` - MQTT over TLS (port 8883) with `TlsConnector`, including mutual TLS client certificates
+//! - `pddb-session` - `session::PddbStore`, keeping a persistent session in the PDDB across reboots
+//! - `qos1` - At-least-once delivery: in-flight store with retransmission (implied by `xous-client`)
+//! - `qos2` - Exactly-once delivery: sender and receiver state machines (implied by `xous-client`)
+//! - `mqtt5` - MQTT 5.0 packets in `packet::v5`; with `xous-client`, set `MqttConfig::protocol` to use it
+//! - `bench` - Codec benchmark cases in `bench`, for the device (any `Clock`) and `cargo bench`
 //!
 //! # Example (packet-only mode)
 //!
@@ -31,28 +40,49 @@
 //!     ..Default::default()
 //! };
 //!
-//! let mut client = MqttClient::new(config)?;
+//! let mut client = MqttClient::new(config);
 //! client.connect()?;
-//! client.subscribe("events/#", QoS::AtLeastOnce)?;
 //!
 //! loop {
-//!     match client.poll()? {
-//!         MqttEvent::Message { topic, payload } => {
-//!             // Handle message
+//!     while let Some(event) = client.poll() {
+//!         match event {
+//!             MqttEvent::Connected => {
+//!                 client.subscribe("events/#", QoS::AtLeastOnce)?;
+//!             }
+//!             MqttEvent::Message { topic, payload, .. } => {
+//!                 // Handle message
+//!             }
+//!             MqttEvent::Disconnected { .. } => {
+//!                 // Reconnects automatically after `reconnect_delay_ms`
+//!             }
+//!             _ => {}
 //!         }
-//!         MqttEvent::Disconnected => {
-//!             client.reconnect()?;
-//!         }
-//!         _ => {}
 //!     }
+//!     ticktimer.sleep_ms(50)?;
 //! }
 //! ```
 
 #![no_std]
 
+#[cfg(feature = "alloc")]
 extern crate alloc;
 
+// The client uses `std::net`, which on Xous targets is provided by the Net service
+#[cfg(feature = "xous-client")]
+extern crate std;
+
+#[cfg(feature = "alloc")]
+pub mod acl;
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod clock;
+#[cfg(feature = "codec")]
+pub mod codec;
 pub mod packet;
+pub mod topic;
+
+#[cfg(feature = "alloc")]
+pub mod packet_id;
 
 #[cfg(feature = "qos1")]
 pub mod qos1;
@@ -60,13 +90,70 @@ pub mod qos1;
 #[cfg(feature = "qos2")]
 pub mod qos2;
 
+#[cfg(feature = "qos1")]
+pub mod ordering;
+
 #[cfg(feature = "xous-client")]
 pub mod client;
 
 #[cfg(feature = "xous-client")]
-pub use client::{MqttClient, MqttConfig, MqttEvent, MqttError};
+pub mod channel;
+
+#[cfg(feature = "xous-client")]
+pub mod manager;
+
+#[cfg(feature = "xous-client")]
+pub mod refusal;
+
+#[cfg(feature = "xous-client")]
+pub mod request;
+
+#[cfg(feature = "xous-client")]
+pub mod resolve;
+
+#[cfg(feature = "xous-client")]
+pub mod session;
 
+#[cfg(feature = "xous-client")]
+pub mod socks;
+
+#[cfg(feature = "xous-client")]
+pub mod subscriptions;
+
+#[cfg(feature = "xous-client")]
+pub mod transport;
+
+#[cfg(feature = "alloc")]
+pub use acl::TopicAcl;
+#[cfg(feature = "xous-client")]
+pub use channel::{Channel, ChannelMux, Overflow};
+#[cfg(feature = "xous-client")]
+pub use client::{
+    DisconnectReason, InflightFull, LastWill, MessageRef, MqttClient, MqttConfig, MqttError, MqttEvent,
+    OversizedPacket, QueueOverflow, SubscribeResult,
+};
+pub use clock::{Clock, Timestamp};
+#[cfg(feature = "codec")]
+pub use codec::{Codec, CodecError};
+#[cfg(feature = "xous-client")]
+pub use manager::{ConnectionId, MqttConnectionManager};
 pub use packet::QoS;
+#[cfg(feature = "alloc")]
+pub use packet_id::PacketIdAllocator;
+#[cfg(feature = "xous-client")]
+pub use refusal::{RefusalAction, RefusalPolicy, RefusedReason};
+#[cfg(feature = "xous-client")]
+pub use request::{RequestToken, Requester, Response};
+#[cfg(feature = "xous-client")]
+pub use resolve::{AddressFamily, ResolveError};
+#[cfg(feature = "xous-client")]
+pub use socks::Socks5Proxy;
+#[cfg(feature = "xous-client")]
+pub use subscriptions::{HandlerId, Subscriptions};
+#[cfg(feature = "alloc")]
+pub use topic::{Topic, TopicFilter};
+#[cfg(feature = "tls-support")]
+pub use transport::{ALPN_MQTT, ClientIdentity, ServerTrust, TlsConfig, TlsConnector};
 
 /// MQTT protocol version
 pub const MQTT_VERSION: u8 = 4; // MQTT 3.1.1
@@ -74,6 +161,9 @@ pub const MQTT_VERSION: u8 = 4; // MQTT 3.1.1
 /// Default keep-alive interval in seconds
 pub const DEFAULT_KEEP_ALIVE: u16 = 60;
 
+/// Default limit on the size of a packet sent or received, in bytes
+pub const DEFAULT_MAX_PACKET_SIZE: usize = 256 * 1024;
+
 /// Default MQTT port (unencrypted)
 pub const MQTT_PORT: u16 = 1883;
 
@@ -0,0 +1,165 @@
+//! Several Broker Connections
+//!
+//! [`MqttConnectionManager`] owns one [`MqttClient`] per broker, e.g. a
+//! broker on the local network and one in the cloud, so an application can
+//! drive them all from one poll loop. Each client is added under a
+//! [`ConnectionId`]; its events come back tagged with that id, and
+//! publishes and subscriptions go to the connection they name.
+//!
+//! Connections are polled in turn, taking at most one event from each
+//! before coming back around, so a busy broker can't starve the others.
+//! Each client keeps its own configuration, session and reconnect timer.
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::client::{MqttClient, MqttError, MqttEvent};
+use crate::packet::QoS;
+
+/// Handle to a connection held by a [`MqttConnectionManager`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionId(usize);
+
+/// Owns several [`MqttClient`]s and multiplexes their events
+#[derive(Default)]
+pub struct MqttConnectionManager {
+    clients: Vec<Option<MqttClient>>,
+    /// Connection to poll first next time
+    next: usize,
+}
+
+impl MqttConnectionManager {
+    pub fn new() -> Self { Self::default() }
+
+    /// Take ownership of `client`
+    pub fn add(&mut self, client: MqttClient) -> ConnectionId {
+        match self.clients.iter().position(|c| c.is_none()) {
+            Some(index) => {
+                self.clients[index] = Some(client);
+                ConnectionId(index)
+            }
+            None => {
+                self.clients.push(Some(client));
+                ConnectionId(self.clients.len() - 1)
+            }
+        }
+    }
+
+    /// Give back the client for `id`, without disconnecting it
+    pub fn remove(&mut self, id: ConnectionId) -> Option<MqttClient> {
+        self.clients.get_mut(id.0).and_then(|c| c.take())
+    }
+
+    /// Connections currently held
+    pub fn connections(&self) -> impl Iterator<Item = ConnectionId> + '_ {
+        self.clients.iter().enumerate().filter(|(_, c)| c.is_some()).map(|(index, _)| ConnectionId(index))
+    }
+
+    /// Get the client for `id`
+    pub fn client(&self, id: ConnectionId) -> Option<&MqttClient> {
+        self.clients.get(id.0).and_then(|c| c.as_ref())
+    }
+
+    /// Get a client mutably, e.g. to feed it received data
+    pub fn client_mut(&mut self, id: ConnectionId) -> Option<&mut MqttClient> {
+        self.clients.get_mut(id.0).and_then(|c| c.as_mut())
+    }
+
+    /// Start connecting every client, returning the ones that failed
+    ///
+    /// A failed client still reconnects on its own if `auto_reconnect` is set.
+    pub fn connect_all(&mut self) -> Vec<(ConnectionId, MqttError)> {
+        let mut failed = Vec::new();
+        for (index, client) in self.clients.iter_mut().enumerate() {
+            if let Some(client) = client.as_mut() {
+                if let Err(e) = client.connect() {
+                    failed.push((ConnectionId(index), e));
+                }
+            }
+        }
+        failed
+    }
+
+    /// Publish through connection `id`
+    pub fn publish(
+        &mut self,
+        id: ConnectionId,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+    ) -> Result<Option<u16>, MqttError> {
+        self.connection(id)?.publish(topic, payload, qos)
+    }
+
+    /// Subscribe on connection `id`
+    pub fn subscribe(&mut self, id: ConnectionId, topic: &str, qos: QoS) -> Result<u16, MqttError> {
+        self.connection(id)?.subscribe(topic, qos)
+    }
+
+    /// Poll the next event from any connection (non-blocking)
+    pub fn poll(&mut self) -> Option<(ConnectionId, MqttEvent)> {
+        let count = self.clients.len();
+        for offset in 0..count {
+            let index = (self.next + offset) % count;
+            if let Some(event) = self.clients[index].as_mut().and_then(|client| client.poll()) {
+                self.next = (index + 1) % count;
+                return Some((ConnectionId(index), event));
+            }
+        }
+        None
+    }
+
+    fn connection(&mut self, id: ConnectionId) -> Result<&mut MqttClient, MqttError> {
+        self.client_mut(id).ok_or(MqttError::UnknownConnection)
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::MqttConfig;
+    use crate::packet;
+    use crate::transport::mock;
+
+    #[test]
+    fn test_connections_multiplexed() {
+        let mut manager = MqttConnectionManager::new();
+        let (local, _, local_broker) = mock::client(MqttConfig::default());
+        let (cloud, _, cloud_broker) = mock::client(MqttConfig::default());
+        let local = manager.add(local);
+        let cloud = manager.add(cloud);
+        assert!(manager.connect_all().is_empty());
+        local_broker.borrow_mut().rx.extend([0x20, 0x02, 0x00, 0x00]);
+        cloud_broker.borrow_mut().rx.extend([0x20, 0x02, 0x00, 0x00]);
+        let mut connected = [manager.poll().unwrap().0, manager.poll().unwrap().0];
+        connected.sort_by_key(|id| id.0);
+        assert_eq!(connected, [local, cloud]);
+
+        // Publishes go only to the connection named
+        mock::sent(&local_broker);
+        mock::sent(&cloud_broker);
+        manager.publish(cloud, "up", b"1", QoS::AtMostOnce).unwrap();
+        assert!(mock::sent(&local_broker).is_empty());
+        assert_eq!(mock::sent(&cloud_broker), [packet::build_publish("up", b"1", QoS::AtMostOnce)]);
+
+        // A busy connection doesn't hold up the other
+        for _ in 0..3 {
+            let message = packet::build_publish("local", b"x", QoS::AtMostOnce);
+            manager.client_mut(local).unwrap().process_data(&message).unwrap();
+        }
+        let message = packet::build_publish("cloud", b"y", QoS::AtMostOnce);
+        manager.client_mut(cloud).unwrap().process_data(&message).unwrap();
+        let order: Vec<ConnectionId> = core::iter::from_fn(|| manager.poll().map(|(id, _)| id)).collect();
+        assert_eq!(order[..2].iter().filter(|id| **id == cloud).count(), 1);
+        assert_eq!(order.len(), 4);
+
+        assert!(manager.remove(local).is_some());
+        assert_eq!(manager.connections().collect::<Vec<_>>(), [cloud]);
+        let result = manager.publish(local, "t", b"", QoS::AtMostOnce);
+        assert!(matches!(result, Err(MqttError::UnknownConnection)));
+    }
+}
@@ -0,0 +1,158 @@
+//! Per-Topic Ordering
+//!
+//! A QoS 1 or 2 PUBLISH that is lost and retried reaches the broker after
+//! any PUBLISH sent behind it, so subscribers see the topic's messages out
+//! of order. With `MqttConfig::ordered_delivery` the client sends at most
+//! one acknowledged PUBLISH per topic at a time and holds the rest here,
+//! QoS 0 ones included, until the exchange ahead of them ends:
+//!
+//! ```text
+//! topic a:  [id 4 in flight]  <- id 5 <- QoS 0 <- id 7
+//! topic b:  [id 6 in flight]
+//! ```
+//!
+//! Topics don't wait on each other, so a slow exchange only delays its own
+//! topic. Held packets have not been sent, so a reconnect leaves them held.
+
+extern crate alloc;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::packet::QoS;
+
+/// A PUBLISH waiting for the exchange ahead of it on its topic
+#[derive(Debug, PartialEq, Eq)]
+pub struct Held {
+    /// `None` for QoS 0
+    pub packet_id: Option<u16>,
+    pub qos: QoS,
+    pub packet: Vec<u8>,
+}
+
+struct TopicQueue {
+    topic: String,
+    /// Acknowledged exchange under way
+    in_flight: Option<u16>,
+    waiting: VecDeque<Held>,
+}
+
+impl TopicQueue {
+    /// Release what may go now: QoS 0 packets up to and including the next acknowledged one
+    fn release(&mut self, released: &mut Vec<Held>) {
+        while self.in_flight.is_none() {
+            let Some(held) = self.waiting.pop_front() else { break };
+            self.in_flight = held.packet_id;
+            released.push(held);
+        }
+    }
+}
+
+/// Outbound PUBLISHes sequenced per topic
+#[derive(Default)]
+pub struct TopicSequencer {
+    topics: Vec<TopicQueue>,
+}
+
+impl TopicSequencer {
+    pub fn new() -> Self { Self::default() }
+
+    /// Offer a PUBLISH to `topic`, returning it if it may be sent now
+    ///
+    /// A packet that may go is recorded as the topic's exchange under way
+    /// if it has a packet id; otherwise it is held until [`Self::complete`]
+    /// hands it back.
+    pub fn submit(&mut self, topic: &str, held: Held) -> Option<Held> {
+        // A topic is only listed while an exchange is under way on it
+        match self.topics.iter_mut().find(|q| q.topic == topic) {
+            Some(queue) => {
+                queue.waiting.push_back(held);
+                None
+            }
+            None => {
+                if held.packet_id.is_some() {
+                    let topic = String::from(topic);
+                    self.topics.push(TopicQueue {
+                        topic,
+                        in_flight: held.packet_id,
+                        waiting: VecDeque::new(),
+                    });
+                }
+                Some(held)
+            }
+        }
+    }
+
+    /// End the exchange for `packet_id`, however it ended, returning the packets now due, in order
+    pub fn complete(&mut self, packet_id: u16) -> Vec<Held> {
+        let mut released = Vec::new();
+        if let Some(queue) = self.topics.iter_mut().find(|q| q.in_flight == Some(packet_id)) {
+            queue.in_flight = None;
+            queue.release(&mut released);
+        }
+        self.topics.retain(|q| q.in_flight.is_some() || !q.waiting.is_empty());
+        released
+    }
+
+    /// End every exchange under way, e.g. when a clean session voids them,
+    /// returning the packets now due
+    pub fn reset(&mut self) -> Vec<Held> {
+        let mut released = Vec::new();
+        for queue in self.topics.iter_mut() {
+            queue.in_flight = None;
+            queue.release(&mut released);
+        }
+        self.topics.retain(|q| q.in_flight.is_some() || !q.waiting.is_empty());
+        released
+    }
+
+    /// Whether a packet held back has id `packet_id`
+    pub fn holds(&self, packet_id: u16) -> bool {
+        self.topics.iter().flat_map(|q| q.waiting.iter()).any(|held| held.packet_id == Some(packet_id))
+    }
+
+    /// Packets held back
+    pub fn held(&self) -> usize { self.topics.iter().map(|q| q.waiting.len()).sum() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn publish(packet_id: Option<u16>) -> Held {
+        let qos = if packet_id.is_some() { QoS::AtLeastOnce } else { QoS::AtMostOnce };
+        Held { packet_id, qos, packet: alloc::vec![packet_id.unwrap_or(0) as u8] }
+    }
+
+    fn ids(held: &[Held]) -> Vec<Option<u16>> { held.iter().map(|h| h.packet_id).collect() }
+
+    #[test]
+    fn test_one_exchange_per_topic() {
+        let mut sequencer = TopicSequencer::new();
+        assert!(sequencer.submit("a", publish(Some(4))).is_some());
+        assert!(sequencer.submit("b", publish(Some(6))).is_some());
+        assert!(sequencer.submit("a", publish(Some(5))).is_none());
+        assert!(sequencer.submit("a", publish(None)).is_none());
+        assert!(sequencer.submit("a", publish(Some(7))).is_none());
+        assert_eq!(sequencer.held(), 3);
+        assert!(sequencer.holds(7) && !sequencer.holds(4));
+
+        assert!(sequencer.complete(6).is_empty());
+        assert_eq!(ids(&sequencer.complete(4)), [Some(5)]);
+        // The QoS 0 packet goes with the next acknowledged one behind it
+        assert_eq!(ids(&sequencer.complete(5)), [None, Some(7)]);
+        assert!(sequencer.complete(7).is_empty());
+        assert_eq!(sequencer.held(), 0);
+        assert!(sequencer.submit("a", publish(None)).is_some());
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut sequencer = TopicSequencer::new();
+        sequencer.submit("a", publish(Some(1)));
+        sequencer.submit("a", publish(Some(2)));
+        sequencer.submit("a", publish(Some(3)));
+        assert_eq!(ids(&sequencer.reset()), [Some(2)]);
+        assert_eq!(ids(&sequencer.complete(2)), [Some(3)]);
+    }
+}
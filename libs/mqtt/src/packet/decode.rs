@@ -0,0 +1,381 @@
+//! MQTT packet parsers
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+#[cfg(feature = "alloc")]
+use alloc::string::String;
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use super::{ConnackCode, PacketType, QoS, SUBACK_FAILURE, validate_topic_name};
+use crate::topic::TopicError;
+
+/// Parsed MQTT packet
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    Connack { session_present: bool, code: ConnackCode },
+    Publish { topic: String, payload: Vec<u8>, qos: QoS, packet_id: Option<u16>, retain: bool, dup: bool },
+    Puback { packet_id: u16 },
+    Pubrec { packet_id: u16 },
+    Pubrel { packet_id: u16 },
+    Pubcomp { packet_id: u16 },
+    Suback { packet_id: u16, return_codes: Vec<u8> },
+    Unsuback { packet_id: u16 },
+    Pingresp,
+}
+
+/// Parse error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// Not enough data
+    Incomplete,
+    /// Invalid packet format
+    InvalidFormat,
+    /// Unknown packet type
+    UnknownType,
+    /// Invalid UTF-8 string
+    InvalidUtf8,
+    /// Fixed-header flags or CONNACK reserved bits not as the spec requires (strict)
+    ReservedBits,
+    /// PUBLISH with QoS 3, or a SUBACK return code that is neither a QoS nor 0x80 (strict)
+    InvalidQoS,
+    /// Remaining length running past four bytes or padded with needless bytes;
+    /// or, strict only, too short or too long for the packet type
+    InvalidLength,
+    /// Packet id of zero (strict)
+    ZeroPacketId,
+    /// PUBLISH topic name that isn't valid for publishing (strict)
+    InvalidTopic(TopicError),
+}
+
+/// How closely a parser holds the broker to the specification
+///
+/// The client parses leniently: anything it can make sense of is accepted,
+/// so a slightly off broker still works. `Strict` turns every MQTT 3.1.1
+/// violation it can detect into its own [`ParseError`], for interop testing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParseMode {
+    #[default]
+    Lenient,
+    Strict,
+}
+
+/// Borrowed view of a PUBLISH packet
+///
+/// Topic and payload point into the buffer the packet was parsed from.
+#[derive(Debug, Clone, Copy)]
+pub struct PublishRef<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub qos: QoS,
+    pub packet_id: Option<u16>,
+    pub retain: bool,
+    pub dup: bool,
+}
+
+/// Parsed MQTT packet borrowing from the buffer it was parsed from
+///
+/// Returned by [`parse_packet_ref`]; nothing is copied, so it is available
+/// without `alloc`.
+#[derive(Debug, Clone, Copy)]
+pub enum PacketRef<'a> {
+    Connack { session_present: bool, code: ConnackCode },
+    Publish(PublishRef<'a>),
+    Puback { packet_id: u16 },
+    Pubrec { packet_id: u16 },
+    Pubrel { packet_id: u16 },
+    Pubcomp { packet_id: u16 },
+    Suback { packet_id: u16, return_codes: &'a [u8] },
+    Unsuback { packet_id: u16 },
+    Pingresp,
+}
+
+#[cfg(feature = "alloc")]
+impl From<PacketRef<'_>> for Packet {
+    fn from(packet: PacketRef<'_>) -> Self {
+        match packet {
+            PacketRef::Connack { session_present, code } => Packet::Connack { session_present, code },
+            PacketRef::Publish(publish) => Packet::Publish {
+                topic: String::from(publish.topic),
+                payload: publish.payload.to_vec(),
+                qos: publish.qos,
+                packet_id: publish.packet_id,
+                retain: publish.retain,
+                dup: publish.dup,
+            },
+            PacketRef::Puback { packet_id } => Packet::Puback { packet_id },
+            PacketRef::Pubrec { packet_id } => Packet::Pubrec { packet_id },
+            PacketRef::Pubrel { packet_id } => Packet::Pubrel { packet_id },
+            PacketRef::Pubcomp { packet_id } => Packet::Pubcomp { packet_id },
+            PacketRef::Suback { packet_id, return_codes } => {
+                Packet::Suback { packet_id, return_codes: return_codes.to_vec() }
+            }
+            PacketRef::Unsuback { packet_id } => Packet::Unsuback { packet_id },
+            PacketRef::Pingresp => Packet::Pingresp,
+        }
+    }
+}
+
+/// Size of the packet at the start of `data`, known as soon as its fixed
+/// header has arrived, before the rest of it
+pub fn peek_packet_len(data: &[u8]) -> Result<usize, ParseError> {
+    let (remaining_len, len_bytes) = decode_remaining_length(data.get(1..).unwrap_or_default())?;
+    Ok(1 + len_bytes + remaining_len)
+}
+
+/// Decode the fixed header of the packet at the start of `data`
+/// Returns (packet_type, header_len, total_len) once the whole packet is buffered
+pub fn parse_fixed_header(data: &[u8]) -> Result<(PacketType, usize, usize), ParseError> {
+    if data.is_empty() {
+        return Err(ParseError::Incomplete);
+    }
+
+    let packet_type = PacketType::from_byte(data[0]).ok_or(ParseError::UnknownType)?;
+
+    // Decode remaining length
+    let (remaining_len, len_bytes) = decode_remaining_length(&data[1..])?;
+
+    let header_len = 1 + len_bytes;
+    let total_len = header_len + remaining_len;
+
+    if data.len() < total_len {
+        return Err(ParseError::Incomplete);
+    }
+
+    Ok((packet_type, header_len, total_len))
+}
+
+/// Parse a PUBLISH packet from buffer without copying topic or payload
+/// Returns (publish, bytes_consumed) or error
+pub fn parse_publish_ref(data: &[u8]) -> Result<(PublishRef<'_>, usize), ParseError> {
+    let (packet_type, header_len, total_len) = parse_fixed_header(data)?;
+    if packet_type != PacketType::Publish {
+        return Err(ParseError::InvalidFormat);
+    }
+    let publish = parse_publish_borrowed(data[0], &data[header_len..total_len]).map_err(truncated)?;
+    Ok((publish, total_len))
+}
+
+/// Parse a complete MQTT packet from buffer without copying
+/// Returns (packet, bytes_consumed) or error
+pub fn parse_packet_ref(data: &[u8]) -> Result<(PacketRef<'_>, usize), ParseError> {
+    parse_packet_ref_with(data, ParseMode::Lenient)
+}
+
+/// Parse a complete MQTT packet from buffer without copying, in `mode`
+pub fn parse_packet_ref_with(data: &[u8], mode: ParseMode) -> Result<(PacketRef<'_>, usize), ParseError> {
+    let (packet_type, header_len, total_len) = parse_fixed_header(data)?;
+    let first_byte = data[0];
+
+    let payload = &data[header_len..total_len];
+    if mode == ParseMode::Strict {
+        check_strict(first_byte, packet_type, payload)?;
+    }
+
+    let packet = match packet_type {
+        PacketType::Connack => parse_connack(payload)?,
+        PacketType::Publish => {
+            PacketRef::Publish(parse_publish_borrowed(first_byte, payload).map_err(truncated)?)
+        }
+        PacketType::Puback => PacketRef::Puback { packet_id: parse_packet_id(payload)? },
+        PacketType::Pubrec => PacketRef::Pubrec { packet_id: parse_packet_id(payload)? },
+        PacketType::Pubrel => PacketRef::Pubrel { packet_id: parse_packet_id(payload)? },
+        PacketType::Pubcomp => PacketRef::Pubcomp { packet_id: parse_packet_id(payload)? },
+        PacketType::Suback => parse_suback(payload)?,
+        PacketType::Unsuback => PacketRef::Unsuback { packet_id: parse_packet_id(payload)? },
+        PacketType::Pingresp => PacketRef::Pingresp,
+        _ => return Err(ParseError::UnknownType),
+    };
+
+    Ok((packet, total_len))
+}
+
+/// Parse a complete MQTT packet from buffer
+/// Returns (packet, bytes_consumed) or error
+#[cfg(feature = "alloc")]
+pub fn parse_packet(data: &[u8]) -> Result<(Packet, usize), ParseError> {
+    parse_packet_with(data, ParseMode::Lenient)
+}
+
+/// Parse a complete MQTT packet from buffer in `mode`
+#[cfg(feature = "alloc")]
+pub fn parse_packet_with(data: &[u8], mode: ParseMode) -> Result<(Packet, usize), ParseError> {
+    let (packet, consumed) = parse_packet_ref_with(data, mode)?;
+    Ok((packet.into(), consumed))
+}
+
+/// Spec checks the lenient parser skips, on a complete packet's body
+fn check_strict(first_byte: u8, packet_type: PacketType, body: &[u8]) -> Result<(), ParseError> {
+    let flags = first_byte & 0x0F;
+    let expected_flags = match packet_type {
+        PacketType::Publish => return check_publish(flags, body),
+        PacketType::Pubrel | PacketType::Subscribe | PacketType::Unsubscribe => 0b0010,
+        _ => 0,
+    };
+    if flags != expected_flags {
+        return Err(ParseError::ReservedBits);
+    }
+    match packet_type {
+        PacketType::Connack => {
+            if body.len() != 2 {
+                return Err(ParseError::InvalidLength);
+            }
+            if body[0] & 0xFE != 0 {
+                return Err(ParseError::ReservedBits);
+            }
+        }
+        PacketType::Puback
+        | PacketType::Pubrec
+        | PacketType::Pubrel
+        | PacketType::Pubcomp
+        | PacketType::Unsuback => {
+            if body.len() != 2 {
+                return Err(ParseError::InvalidLength);
+            }
+            check_packet_id(body)?;
+        }
+        PacketType::Suback => {
+            if body.len() < 3 {
+                return Err(ParseError::InvalidLength);
+            }
+            check_packet_id(body)?;
+            if body[2..].iter().any(|&code| code > 2 && code != SUBACK_FAILURE) {
+                return Err(ParseError::InvalidQoS);
+            }
+        }
+        PacketType::Pingresp if !body.is_empty() => return Err(ParseError::InvalidLength),
+        _ => {}
+    }
+    Ok(())
+}
+
+fn check_publish(flags: u8, body: &[u8]) -> Result<(), ParseError> {
+    let qos = (flags >> 1) & 0x03;
+    if qos == 3 {
+        return Err(ParseError::InvalidQoS);
+    }
+    // DUP is only meaningful, and only allowed, on a QoS 1/2 PUBLISH
+    if qos == 0 && flags & 0x08 != 0 {
+        return Err(ParseError::ReservedBits);
+    }
+    let (topic, topic_len) = match decode_str(body) {
+        Ok(topic) => topic,
+        Err(ParseError::Incomplete) => return Err(ParseError::InvalidLength),
+        Err(e) => return Err(e),
+    };
+    validate_topic_name(topic).map_err(ParseError::InvalidTopic)?;
+    if qos != 0 {
+        let id = &body[topic_len..];
+        if id.len() < 2 {
+            return Err(ParseError::InvalidLength);
+        }
+        check_packet_id(id)?;
+    }
+    Ok(())
+}
+
+fn check_packet_id(data: &[u8]) -> Result<(), ParseError> {
+    if data[0] == 0 && data[1] == 0 { Err(ParseError::ZeroPacketId) } else { Ok(()) }
+}
+
+fn parse_connack(data: &[u8]) -> Result<PacketRef<'_>, ParseError> {
+    if data.len() < 2 {
+        return Err(ParseError::InvalidFormat);
+    }
+    let session_present = (data[0] & 0x01) != 0;
+    let code = ConnackCode::from_byte(data[1]).ok_or(ParseError::InvalidFormat)?;
+    Ok(PacketRef::Connack { session_present, code })
+}
+
+fn parse_publish_borrowed(first_byte: u8, data: &[u8]) -> Result<PublishRef<'_>, ParseError> {
+    let dup = (first_byte & 0x08) != 0;
+    let qos = QoS::from_byte((first_byte >> 1) & 0x03).ok_or(ParseError::InvalidFormat)?;
+    let retain = (first_byte & 0x01) != 0;
+
+    let mut offset = 0;
+
+    // Topic
+    let (topic, topic_len) = decode_str(&data[offset..])?;
+    offset += topic_len;
+
+    // Packet ID (only for QoS > 0)
+    let packet_id = if qos != QoS::AtMostOnce {
+        if data.len() < offset + 2 {
+            return Err(ParseError::Incomplete);
+        }
+        let id = ((data[offset] as u16) << 8) | (data[offset + 1] as u16);
+        offset += 2;
+        Some(id)
+    } else {
+        None
+    };
+
+    // Payload
+    let payload = &data[offset..];
+
+    Ok(PublishRef { topic, payload, qos, packet_id, retain, dup })
+}
+
+/// Parse the packet id that starts an acknowledgement's variable header
+fn parse_packet_id(data: &[u8]) -> Result<u16, ParseError> {
+    if data.len() < 2 {
+        return Err(ParseError::InvalidFormat);
+    }
+    Ok(((data[0] as u16) << 8) | (data[1] as u16))
+}
+
+fn parse_suback(data: &[u8]) -> Result<PacketRef<'_>, ParseError> {
+    if data.len() < 3 {
+        return Err(ParseError::InvalidFormat);
+    }
+    let packet_id = parse_packet_id(data)?;
+    Ok(PacketRef::Suback { packet_id, return_codes: &data[2..] })
+}
+
+// ============================================================================
+// Helper Functions
+// ============================================================================
+
+/// Decode MQTT remaining length, returns (length, bytes_consumed)
+///
+/// A valid length is at most four bytes, so at most `MAX_REMAINING_LEN`,
+/// encoded in as few bytes as it needs. Anything else is `InvalidLength` as soon
+/// as it can be told apart from a length that hasn't all arrived yet, so a
+/// malformed header can't keep a reader waiting for more.
+pub(super) fn decode_remaining_length(data: &[u8]) -> Result<(usize, usize), ParseError> {
+    let mut len = 0usize;
+    for (index, &byte) in data.iter().take(4).enumerate() {
+        let digit = usize::from(byte & 0x7F) << (7 * index);
+        len = len.checked_add(digit).ok_or(ParseError::InvalidLength)?;
+        if byte & 0x80 == 0 {
+            // A zero after the first byte adds nothing but length
+            if byte == 0 && index > 0 {
+                return Err(ParseError::InvalidLength);
+            }
+            return Ok((len, index + 1));
+        }
+    }
+    if data.len() >= 4 { Err(ParseError::InvalidLength) } else { Err(ParseError::Incomplete) }
+}
+
+/// Inside a complete packet, running out of data means the packet is malformed
+pub(super) fn truncated(error: ParseError) -> ParseError {
+    match error {
+        ParseError::Incomplete => ParseError::InvalidFormat,
+        other => other,
+    }
+}
+
+/// Decode a UTF-8 string with length prefix, returns (str, bytes_consumed)
+pub(super) fn decode_str(data: &[u8]) -> Result<(&str, usize), ParseError> {
+    if data.len() < 2 {
+        return Err(ParseError::Incomplete);
+    }
+    let len = ((data[0] as usize) << 8) | (data[1] as usize);
+    if data.len() < 2 + len {
+        return Err(ParseError::Incomplete);
+    }
+    let s = core::str::from_utf8(&data[2..2 + len]).map_err(|_| ParseError::InvalidUtf8)?;
+    Ok((s, 2 + len))
+}
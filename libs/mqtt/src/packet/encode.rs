@@ -0,0 +1,232 @@
+//! MQTT packet builders
+
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::write::{connect_flags, publish_flags};
+use super::{PacketType, ProtocolVersion, QoS, Will, packet_len};
+
+/// Build MQTT CONNECT packet
+pub fn build_connect(client_id: &str) -> Vec<u8> {
+    build_connect_with_options(client_id, None, None, true, 60)
+}
+
+/// Build MQTT CONNECT packet with full options
+pub fn build_connect_with_options(
+    client_id: &str,
+    username: Option<&str>,
+    password: Option<&[u8]>,
+    clean_session: bool,
+    keep_alive_secs: u16,
+) -> Vec<u8> {
+    build_connect_with_will(client_id, username, password, clean_session, keep_alive_secs, None)
+}
+
+/// Build MQTT CONNECT packet with full options and an optional Last Will
+pub fn build_connect_with_will(
+    client_id: &str,
+    username: Option<&str>,
+    password: Option<&[u8]>,
+    clean_session: bool,
+    keep_alive_secs: u16,
+    will: Option<&Will<'_>>,
+) -> Vec<u8> {
+    let protocol = ProtocolVersion::V311;
+    build_connect_at(protocol, client_id, username, password, clean_session, keep_alive_secs, will)
+}
+
+/// Build an MQTT 3.1 CONNECT (`MQIsdp`, level 3), for brokers that refuse 3.1.1
+///
+/// The client id must be 1 to 23 characters; 3.1 brokers don't assign one.
+pub fn build_connect_v31(
+    client_id: &str,
+    username: Option<&str>,
+    password: Option<&[u8]>,
+    clean_session: bool,
+    keep_alive_secs: u16,
+    will: Option<&Will<'_>>,
+) -> Vec<u8> {
+    let protocol = ProtocolVersion::V31;
+    build_connect_at(protocol, client_id, username, password, clean_session, keep_alive_secs, will)
+}
+
+/// CONNECT naming `protocol`, which must be 3.1 or 3.1.1
+fn build_connect_at(
+    protocol: ProtocolVersion,
+    client_id: &str,
+    username: Option<&str>,
+    password: Option<&[u8]>,
+    clean_session: bool,
+    keep_alive_secs: u16,
+    will: Option<&Will<'_>>,
+) -> Vec<u8> {
+    // Protocol name, level, flags and keep-alive, then the client id
+    let name = protocol.name();
+    let mut remaining_len = 2 + name.len() + 4 + 2 + client_id.len();
+    if let Some(will) = will {
+        remaining_len += 2 + will.topic.len() + 2 + will.payload.len();
+    }
+    if let Some(user) = username {
+        remaining_len += 2 + user.len();
+    }
+    if let Some(pass) = password {
+        remaining_len += 2 + pass.len();
+    }
+
+    let mut packet = start((PacketType::Connect as u8) << 4, remaining_len);
+    encode_string(&mut packet, name);
+    packet.extend_from_slice(&[protocol as u8, connect_flags(username, password, clean_session, will)]);
+    packet.extend_from_slice(&keep_alive_secs.to_be_bytes());
+    encode_string(&mut packet, client_id);
+    if let Some(will) = will {
+        encode_string(&mut packet, will.topic);
+        encode_bytes(&mut packet, will.payload);
+    }
+    if let Some(user) = username {
+        encode_string(&mut packet, user);
+    }
+    if let Some(pass) = password {
+        encode_bytes(&mut packet, pass);
+    }
+    packet
+}
+
+/// Build MQTT SUBSCRIBE packet
+pub fn build_subscribe(packet_id: u16, topic: &str, qos: QoS) -> Vec<u8> {
+    build_subscribe_many(packet_id, &[(topic, qos)])
+}
+
+/// Build MQTT SUBSCRIBE packet requesting several topic filters at once
+///
+/// The SUBACK carries one return code per filter, in the same order.
+pub fn build_subscribe_many(packet_id: u16, topics: &[(&str, QoS)]) -> Vec<u8> {
+    // Packet id, then each topic filter and its QoS
+    let remaining_len = 2 + topics.iter().map(|(topic, _)| 2 + topic.len() + 1).sum::<usize>();
+
+    // SUBSCRIBE has reserved bits 0010
+    let mut packet = start(((PacketType::Subscribe as u8) << 4) | 0x02, remaining_len);
+    packet.extend_from_slice(&packet_id.to_be_bytes());
+    for &(topic, qos) in topics {
+        encode_string(&mut packet, topic);
+        packet.push(qos as u8);
+    }
+    packet
+}
+
+/// Build MQTT UNSUBSCRIBE packet
+pub fn build_unsubscribe(packet_id: u16, topic: &str) -> Vec<u8> {
+    build_unsubscribe_many(packet_id, &[topic])
+}
+
+/// Build MQTT UNSUBSCRIBE packet removing several topic filters at once
+pub fn build_unsubscribe_many(packet_id: u16, topics: &[&str]) -> Vec<u8> {
+    // Packet id, then the topic filters
+    let remaining_len = 2 + topics.iter().map(|topic| 2 + topic.len()).sum::<usize>();
+
+    // UNSUBSCRIBE has reserved bits 0010
+    let mut packet = start(((PacketType::Unsubscribe as u8) << 4) | 0x02, remaining_len);
+    packet.extend_from_slice(&packet_id.to_be_bytes());
+    for topic in topics {
+        encode_string(&mut packet, topic);
+    }
+    packet
+}
+
+/// Build MQTT PUBLISH packet (QoS 0)
+pub fn build_publish(topic: &str, payload: &[u8], qos: QoS) -> Vec<u8> {
+    build_publish_with_id(topic, payload, qos, None, false)
+}
+
+/// Size of the PUBLISH [`build_publish_with_id`] would build, without building it
+pub fn publish_len(topic: &str, payload_len: usize, qos: QoS) -> usize {
+    let packet_id_len = if qos == QoS::AtMostOnce { 0 } else { 2 };
+    packet_len(2 + topic.len() + packet_id_len + payload_len)
+}
+
+/// Build MQTT PUBLISH packet with packet ID (for QoS 1/2)
+pub fn build_publish_with_id(
+    topic: &str,
+    payload: &[u8],
+    qos: QoS,
+    packet_id: Option<u16>,
+    retain: bool,
+) -> Vec<u8> {
+    // Topic, packet id (required for QoS > 0) and payload
+    let remaining_len = 2 + topic.len() + if packet_id.is_some() { 2 } else { 0 } + payload.len();
+
+    let mut packet = start(publish_flags(qos, retain), remaining_len);
+    encode_string(&mut packet, topic);
+    if let Some(id) = packet_id {
+        packet.extend_from_slice(&id.to_be_bytes());
+    }
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Build MQTT PUBACK packet (QoS 1 acknowledgment)
+pub fn build_puback(packet_id: u16) -> Vec<u8> {
+    vec![(PacketType::Puback as u8) << 4, 0x02, (packet_id >> 8) as u8, (packet_id & 0xFF) as u8]
+}
+
+/// Build MQTT PUBREC packet (QoS 2 step 1)
+pub fn build_pubrec(packet_id: u16) -> Vec<u8> {
+    vec![(PacketType::Pubrec as u8) << 4, 0x02, (packet_id >> 8) as u8, (packet_id & 0xFF) as u8]
+}
+
+/// Build MQTT PUBREL packet (QoS 2 step 2)
+pub fn build_pubrel(packet_id: u16) -> Vec<u8> {
+    vec![
+        ((PacketType::Pubrel as u8) << 4) | 0x02, // Reserved bits
+        0x02,
+        (packet_id >> 8) as u8,
+        (packet_id & 0xFF) as u8,
+    ]
+}
+
+/// Build MQTT PUBCOMP packet (QoS 2 step 3)
+pub fn build_pubcomp(packet_id: u16) -> Vec<u8> {
+    vec![(PacketType::Pubcomp as u8) << 4, 0x02, (packet_id >> 8) as u8, (packet_id & 0xFF) as u8]
+}
+
+/// Build MQTT PINGREQ packet
+pub fn build_pingreq() -> Vec<u8> { vec![(PacketType::Pingreq as u8) << 4, 0x00] }
+
+/// Build MQTT DISCONNECT packet
+pub fn build_disconnect() -> Vec<u8> { vec![(PacketType::Disconnect as u8) << 4, 0x00] }
+
+// ============================================================================
+// Helper Functions
+// ============================================================================
+
+/// Vec with room for the whole packet, holding its fixed header
+fn start(first_byte: u8, remaining_len: usize) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(packet_len(remaining_len));
+    packet.push(first_byte);
+    encode_remaining_length(&mut packet, remaining_len);
+    packet
+}
+
+/// Encode MQTT remaining length (variable length encoding)
+pub(super) fn encode_remaining_length(packet: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let mut byte = (len & 0x7F) as u8;
+        len >>= 7;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+/// Encode a UTF-8 string with length prefix
+pub(super) fn encode_string(buf: &mut Vec<u8>, s: &str) { encode_bytes(buf, s.as_bytes()); }
+
+/// Encode binary data with length prefix
+pub(super) fn encode_bytes(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+}
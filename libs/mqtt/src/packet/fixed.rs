@@ -0,0 +1,215 @@
+//! Fixed-Capacity Packet Builders
+//!
+//! The other `packet` builders return a `Vec` and need `alloc`. These write
+//! the same bytes into a [`PacketBuf`] whose capacity the caller picks, so a
+//! service with no heap, or one running before its heap is set up, can still
+//! build MQTT 3.1.1 packets. With the borrowed `parse_packet_ref` and
+//! `parse_publish_ref` from `decode`, that is a whole packet layer without
+//! `alloc`.
+//!
+//! Each builder works out the packet's size before writing anything and
+//! returns [`EncodeError`] if it won't fit, rather than a truncated packet.
+//!
+//! ```rust,ignore
+//! use xous_mqtt::packet::{fixed, QoS};
+//!
+//! let publish: fixed::PacketBuf<128> = fixed::build_publish("status", b"online", QoS::AtMostOnce)?;
+//! ```
+
+use heapless::Vec;
+
+use super::write::*;
+use super::{EncodeError, QoS, Will};
+
+/// Packet built into a buffer of at most `N` bytes
+pub type PacketBuf<const N: usize> = Vec<u8, N>;
+
+/// Build MQTT CONNECT packet
+pub fn build_connect<const N: usize>(client_id: &str) -> Result<PacketBuf<N>, EncodeError> {
+    fill(|buf| build_connect_into(buf, client_id))
+}
+
+/// Build MQTT CONNECT packet with full options
+pub fn build_connect_with_options<const N: usize>(
+    client_id: &str,
+    username: Option<&str>,
+    password: Option<&[u8]>,
+    clean_session: bool,
+    keep_alive_secs: u16,
+) -> Result<PacketBuf<N>, EncodeError> {
+    fill(|buf| {
+        build_connect_with_options_into(buf, client_id, username, password, clean_session, keep_alive_secs)
+    })
+}
+
+/// Build MQTT CONNECT packet with full options and an optional Last Will
+pub fn build_connect_with_will<const N: usize>(
+    client_id: &str,
+    username: Option<&str>,
+    password: Option<&[u8]>,
+    clean_session: bool,
+    keep_alive_secs: u16,
+    will: Option<&Will<'_>>,
+) -> Result<PacketBuf<N>, EncodeError> {
+    fill(|buf| {
+        build_connect_with_will_into(buf, client_id, username, password, clean_session, keep_alive_secs, will)
+    })
+}
+
+/// Build an MQTT 3.1 CONNECT (`MQIsdp`, level 3)
+pub fn build_connect_v31<const N: usize>(
+    client_id: &str,
+    username: Option<&str>,
+    password: Option<&[u8]>,
+    clean_session: bool,
+    keep_alive_secs: u16,
+    will: Option<&Will<'_>>,
+) -> Result<PacketBuf<N>, EncodeError> {
+    fill(|buf| {
+        build_connect_v31_into(buf, client_id, username, password, clean_session, keep_alive_secs, will)
+    })
+}
+
+/// Build MQTT SUBSCRIBE packet
+pub fn build_subscribe<const N: usize>(
+    packet_id: u16,
+    topic: &str,
+    qos: QoS,
+) -> Result<PacketBuf<N>, EncodeError> {
+    fill(|buf| build_subscribe_into(buf, packet_id, topic, qos))
+}
+
+/// Build MQTT SUBSCRIBE packet requesting several topic filters at once
+pub fn build_subscribe_many<const N: usize>(
+    packet_id: u16,
+    topics: &[(&str, QoS)],
+) -> Result<PacketBuf<N>, EncodeError> {
+    fill(|buf| build_subscribe_many_into(buf, packet_id, topics))
+}
+
+/// Build MQTT UNSUBSCRIBE packet
+pub fn build_unsubscribe<const N: usize>(packet_id: u16, topic: &str) -> Result<PacketBuf<N>, EncodeError> {
+    fill(|buf| build_unsubscribe_into(buf, packet_id, topic))
+}
+
+/// Build MQTT UNSUBSCRIBE packet removing several topic filters at once
+pub fn build_unsubscribe_many<const N: usize>(
+    packet_id: u16,
+    topics: &[&str],
+) -> Result<PacketBuf<N>, EncodeError> {
+    fill(|buf| build_unsubscribe_many_into(buf, packet_id, topics))
+}
+
+/// Build MQTT PUBLISH packet (QoS 0)
+pub fn build_publish<const N: usize>(
+    topic: &str,
+    payload: &[u8],
+    qos: QoS,
+) -> Result<PacketBuf<N>, EncodeError> {
+    fill(|buf| build_publish_into(buf, topic, payload, qos))
+}
+
+/// Build MQTT PUBLISH packet with packet ID (for QoS 1/2)
+pub fn build_publish_with_id<const N: usize>(
+    topic: &str,
+    payload: &[u8],
+    qos: QoS,
+    packet_id: Option<u16>,
+    retain: bool,
+) -> Result<PacketBuf<N>, EncodeError> {
+    fill(|buf| build_publish_with_id_into(buf, topic, payload, qos, packet_id, retain))
+}
+
+/// Build MQTT PUBACK packet (QoS 1 acknowledgment)
+pub fn build_puback<const N: usize>(packet_id: u16) -> Result<PacketBuf<N>, EncodeError> {
+    fill(|buf| build_puback_into(buf, packet_id))
+}
+
+/// Build MQTT PUBREC packet (QoS 2 step 1)
+pub fn build_pubrec<const N: usize>(packet_id: u16) -> Result<PacketBuf<N>, EncodeError> {
+    fill(|buf| build_pubrec_into(buf, packet_id))
+}
+
+/// Build MQTT PUBREL packet (QoS 2 step 2)
+pub fn build_pubrel<const N: usize>(packet_id: u16) -> Result<PacketBuf<N>, EncodeError> {
+    fill(|buf| build_pubrel_into(buf, packet_id))
+}
+
+/// Build MQTT PUBCOMP packet (QoS 2 step 3)
+pub fn build_pubcomp<const N: usize>(packet_id: u16) -> Result<PacketBuf<N>, EncodeError> {
+    fill(|buf| build_pubcomp_into(buf, packet_id))
+}
+
+/// Build MQTT PINGREQ packet
+pub fn build_pingreq<const N: usize>() -> Result<PacketBuf<N>, EncodeError> { fill(build_pingreq_into) }
+
+/// Build MQTT DISCONNECT packet
+pub fn build_disconnect<const N: usize>() -> Result<PacketBuf<N>, EncodeError> { fill(build_disconnect_into) }
+
+/// Run an `_into` builder over the whole capacity and keep what it wrote
+fn fill<const N: usize>(
+    build: impl FnOnce(&mut [u8]) -> Result<usize, EncodeError>,
+) -> Result<PacketBuf<N>, EncodeError> {
+    let mut packet = PacketBuf::new();
+    // Can't fail: the new length is the capacity
+    packet.resize_default(N).ok();
+    let len = build(&mut packet)?;
+    packet.truncate(len);
+    Ok(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "encode")]
+    #[test]
+    fn test_matches_vec_builders() {
+        use crate::packet;
+
+        let will = Will { topic: "ccr/status", payload: b"offline", qos: QoS::AtLeastOnce, retain: true };
+        let connect: PacketBuf<128> =
+            build_connect_with_will("dev", Some("user"), Some(b"pass"), false, 30, Some(&will)).unwrap();
+        assert_eq!(
+            connect[..],
+            packet::build_connect_with_will("dev", Some("user"), Some(b"pass"), false, 30, Some(&will))[..]
+        );
+        let connect: PacketBuf<128> = build_connect_v31("dev", None, None, true, 30, Some(&will)).unwrap();
+        assert_eq!(connect[..], packet::build_connect_v31("dev", None, None, true, 30, Some(&will))[..]);
+
+        let topics = [("ccr/#", QoS::AtLeastOnce), ("a/+", QoS::ExactlyOnce)];
+        let subscribe: PacketBuf<64> = build_subscribe_many(7, &topics).unwrap();
+        assert_eq!(subscribe[..], packet::build_subscribe_many(7, &topics)[..]);
+        let unsubscribe: PacketBuf<64> = build_unsubscribe_many(8, &["ccr/#", "a/+"]).unwrap();
+        assert_eq!(unsubscribe[..], packet::build_unsubscribe_many(8, &["ccr/#", "a/+"])[..]);
+
+        // Long enough for a two-byte remaining length
+        let payload = [0x5a; 200];
+        let publish: PacketBuf<256> =
+            build_publish_with_id("ccr/x", &payload, QoS::ExactlyOnce, Some(9), true).unwrap();
+        assert_eq!(
+            publish[..],
+            packet::build_publish_with_id("ccr/x", &payload, QoS::ExactlyOnce, Some(9), true)[..]
+        );
+
+        let pubrel: PacketBuf<4> = build_pubrel(0x1234).unwrap();
+        assert_eq!(pubrel[..], packet::build_pubrel(0x1234)[..]);
+        let pingreq: PacketBuf<2> = build_pingreq().unwrap();
+        assert_eq!(pingreq[..], packet::build_pingreq()[..]);
+    }
+
+    #[test]
+    fn test_capacity_checked_first() {
+        // 2 + 6 bytes of topic and 6 of payload, behind a two-byte fixed header
+        assert_eq!(build_publish::<16>("status", b"online", QoS::AtMostOnce).map(|p| p.len()), Ok(16));
+        assert_eq!(
+            build_publish::<15>("status", b"online", QoS::AtMostOnce),
+            Err(EncodeError::BufferTooSmall { needed: 16 })
+        );
+        assert_eq!(build_puback::<3>(1), Err(EncodeError::BufferTooSmall { needed: 4 }));
+
+        let long = [b'a'; 65_536];
+        let topic = core::str::from_utf8(&long).unwrap();
+        assert_eq!(build_subscribe::<16>(1, topic, QoS::AtMostOnce), Err(EncodeError::FieldTooLong));
+    }
+}
@@ -0,0 +1,478 @@
+//! MQTT Packet Encoding/Decoding
+//!
+//! Pure Rust implementation of MQTT 3.1.1 packet format.
+//! No external dependencies, no_std compatible.
+//!
+//! Builders live behind the `encode` feature and parsers behind `decode`, so
+//! a publish-only service can leave the parser out of its image entirely.
+//! Every builder has a `build_*_into` variant that writes into a caller's
+//! buffer instead of allocating a `Vec`.
+//! The fixed-header parser and the borrowed `parse_packet_ref`/`parse_publish_ref`
+//! work without `alloc`.
+//! MQTT 5.0 builders and parsers are in `v5`, behind the `mqtt5` feature.
+//! Builders writing into fixed-capacity buffers, for use without `alloc`, are
+//! in `fixed`, behind the `heapless` feature.
+//! Packets fixed at build time, such as PINGREQ or a service's own
+//! subscriptions, are encoded by `const fn`s in `prebuilt`, with no feature.
+
+#[cfg(feature = "decode")]
+mod decode;
+#[cfg(feature = "encode")]
+mod encode;
+#[cfg(feature = "heapless")]
+pub mod fixed;
+pub mod prebuilt;
+#[cfg(feature = "mqtt5")]
+pub mod v5;
+#[cfg(any(feature = "encode", feature = "heapless"))]
+mod write;
+
+#[cfg(feature = "decode")]
+pub use decode::*;
+#[cfg(feature = "encode")]
+pub use encode::*;
+#[cfg(any(feature = "encode", feature = "heapless"))]
+pub use write::*;
+
+use crate::topic::{MAX_TOPIC_LEN, SEPARATOR, TopicError, WILDCARD_MULTI, WILDCARD_SINGLE};
+
+/// MQTT packet types
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Connect = 1,
+    Connack = 2,
+    Publish = 3,
+    Puback = 4,
+    Pubrec = 5,
+    Pubrel = 6,
+    Pubcomp = 7,
+    Subscribe = 8,
+    Suback = 9,
+    Unsubscribe = 10,
+    Unsuback = 11,
+    Pingreq = 12,
+    Pingresp = 13,
+    Disconnect = 14,
+    /// MQTT 5 only
+    Auth = 15,
+}
+
+impl PacketType {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte >> 4 {
+            1 => Some(Self::Connect),
+            2 => Some(Self::Connack),
+            3 => Some(Self::Publish),
+            4 => Some(Self::Puback),
+            5 => Some(Self::Pubrec),
+            6 => Some(Self::Pubrel),
+            7 => Some(Self::Pubcomp),
+            8 => Some(Self::Subscribe),
+            9 => Some(Self::Suback),
+            10 => Some(Self::Unsubscribe),
+            11 => Some(Self::Unsuback),
+            12 => Some(Self::Pingreq),
+            13 => Some(Self::Pingresp),
+            14 => Some(Self::Disconnect),
+            15 => Some(Self::Auth),
+            _ => None,
+        }
+    }
+}
+
+/// Protocol level requested in CONNECT
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProtocolVersion {
+    /// MQTT 3.1, for legacy brokers and gateways that refuse 3.1.1 clients.
+    /// Only CONNECT differs; every other packet is as at 3.1.1.
+    V31 = 3,
+    /// MQTT 3.1.1
+    #[default]
+    V311 = 4,
+    /// MQTT 5.0 (`mqtt5` feature)
+    #[cfg(feature = "mqtt5")]
+    V5 = 5,
+}
+
+impl ProtocolVersion {
+    /// Protocol name sent in CONNECT
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::V31 => "MQIsdp",
+            _ => "MQTT",
+        }
+    }
+}
+
+/// Quality of Service levels
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QoS {
+    /// At most once (fire and forget)
+    #[default]
+    AtMostOnce = 0,
+    /// At least once (acknowledged delivery)
+    AtLeastOnce = 1,
+    /// Exactly once (assured delivery)
+    ExactlyOnce = 2,
+}
+
+impl QoS {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte & 0x03 {
+            0 => Some(Self::AtMostOnce),
+            1 => Some(Self::AtLeastOnce),
+            2 => Some(Self::ExactlyOnce),
+            _ => None,
+        }
+    }
+
+    /// QoS granted by a SUBACK return code, or `None` if the broker refused
+    /// the subscription (`0x80`)
+    pub fn from_suback(code: u8) -> Option<Self> {
+        if code & SUBACK_FAILURE != 0 { None } else { Self::from_byte(code) }
+    }
+}
+
+/// SUBACK return code for a refused subscription
+pub const SUBACK_FAILURE: u8 = 0x80;
+
+/// Why a packet couldn't be encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeError {
+    /// The packet needs `needed` bytes and the buffer holds fewer
+    BufferTooSmall { needed: usize },
+    /// A string or binary field is longer than its 16-bit length prefix allows
+    FieldTooLong,
+    /// The body is longer than [`MAX_REMAINING_LEN`]
+    PacketTooLarge,
+}
+
+/// Largest remaining length the four-byte encoding can express
+pub const MAX_REMAINING_LEN: usize = 268_435_455;
+
+/// Size of a whole packet whose body (variable header and payload) is `remaining_len` bytes
+pub const fn packet_len(remaining_len: usize) -> usize {
+    let len_bytes = match remaining_len {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    };
+    1 + len_bytes + remaining_len
+}
+
+/// Last Will and Testament carried in CONNECT
+///
+/// The broker publishes it on the client's behalf when the connection drops
+/// without a DISCONNECT, e.g. so others can see the device went offline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Will<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+/// CONNACK return codes
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnackCode {
+    Accepted = 0,
+    UnacceptableProtocol = 1,
+    IdentifierRejected = 2,
+    ServerUnavailable = 3,
+    BadCredentials = 4,
+    NotAuthorized = 5,
+}
+
+impl ConnackCode {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::Accepted),
+            1 => Some(Self::UnacceptableProtocol),
+            2 => Some(Self::IdentifierRejected),
+            3 => Some(Self::ServerUnavailable),
+            4 => Some(Self::BadCredentials),
+            5 => Some(Self::NotAuthorized),
+            _ => None,
+        }
+    }
+}
+
+/// Check a topic name for PUBLISH (or a Will): non-empty, at most 65535
+/// bytes, with no wildcards and no NUL
+pub const fn validate_topic_name(topic: &str) -> Result<(), TopicError> {
+    if let Err(e) = check_topic_length(topic) {
+        return Err(e);
+    }
+    // Wildcards and NUL are ASCII, so no byte of a longer UTF-8 character matches them
+    let bytes = topic.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] as char {
+            WILDCARD_SINGLE | WILDCARD_MULTI => return Err(TopicError::Wildcard),
+            '\0' => return Err(TopicError::Nul),
+            _ => {}
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+/// Check a topic filter for SUBSCRIBE/UNSUBSCRIBE: as a topic name, except
+/// that `+` may stand for any whole level and `#` for the last one
+pub const fn validate_topic_filter(filter: &str) -> Result<(), TopicError> {
+    if let Err(e) = check_topic_length(filter) {
+        return Err(e);
+    }
+    let bytes = filter.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == 0 {
+            return Err(TopicError::Nul);
+        }
+        i += 1;
+    }
+    i = 0;
+    while i < bytes.len() {
+        let level_start = i == 0 || bytes[i - 1] as char == SEPARATOR;
+        let last = i + 1 == bytes.len();
+        let level_end = last || bytes[i + 1] as char == SEPARATOR;
+        match bytes[i] as char {
+            WILDCARD_MULTI if !(level_start && last) => return Err(TopicError::MisplacedWildcard),
+            WILDCARD_SINGLE if !(level_start && level_end) => return Err(TopicError::MisplacedWildcard),
+            _ => {}
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+const fn check_topic_length(topic: &str) -> Result<(), TopicError> {
+    if topic.is_empty() {
+        Err(TopicError::Empty)
+    } else if topic.len() > MAX_TOPIC_LEN {
+        Err(TopicError::TooLong)
+    } else {
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(all(test, feature = "encode", feature = "decode"))]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::decode::decode_remaining_length;
+    use super::encode::encode_remaining_length;
+    use super::*;
+
+    #[test]
+    fn test_connect_packet() {
+        let packet = build_connect("test-client");
+        assert_eq!(packet[0] >> 4, PacketType::Connect as u8);
+    }
+
+    #[test]
+    fn test_connect_with_will() {
+        let will = Will { topic: "dev/status", payload: b"offline", qos: QoS::AtLeastOnce, retain: true };
+        let packet = build_connect_with_will("c", Some("u"), None, true, 60, Some(&will));
+        // Fixed header (2) + protocol name (6) + level (1), then connect flags
+        assert_eq!(packet[9], 0x80 | 0x20 | (1 << 3) | 0x04 | 0x02);
+        // Client id, will topic, will message, username
+        let payload = &packet[12..];
+        assert_eq!(payload[..3], [0x00, 0x01, b'c']);
+        assert_eq!(&payload[3..15], b"\x00\x0adev/status");
+        assert_eq!(&payload[15..24], b"\x00\x07offline");
+        assert_eq!(&payload[24..], b"\x00\x01u");
+    }
+
+    #[test]
+    fn test_connect_v31() {
+        let packet = build_connect_v31("c", None, None, true, 60, None);
+        assert_eq!(packet[..11], [0x10, 0x0f, 0, 6, b'M', b'Q', b'I', b's', b'd', b'p', 3]);
+        // The same packet otherwise
+        assert_eq!(packet[11..], build_connect("c")[9..]);
+    }
+
+    #[test]
+    fn test_publish_roundtrip() {
+        let original = build_publish("test/topic", b"hello world", QoS::AtMostOnce);
+        let (parsed, len) = parse_packet(&original).unwrap();
+        assert_eq!(len, original.len());
+
+        if let Packet::Publish { topic, payload, qos, .. } = parsed {
+            assert_eq!(topic, "test/topic");
+            assert_eq!(payload, b"hello world");
+            assert_eq!(qos, QoS::AtMostOnce);
+        } else {
+            panic!("Expected Publish packet");
+        }
+    }
+
+    #[test]
+    fn test_publish_ref_borrows_buffer() {
+        let original = build_publish_with_id("test/topic", b"payload", QoS::AtLeastOnce, Some(7), true);
+        let (publish, len) = parse_publish_ref(&original).unwrap();
+        assert_eq!(len, original.len());
+        assert_eq!(publish.topic, "test/topic");
+        assert_eq!(publish.payload, b"payload");
+        assert_eq!(publish.packet_id, Some(7));
+        assert!(publish.retain);
+        assert_eq!(publish.payload.as_ptr(), original[original.len() - 7..].as_ptr());
+    }
+
+    #[test]
+    fn test_packet_ref_borrows_buffer() {
+        let data = build_publish("a/b", b"xyz", QoS::AtMostOnce);
+        match parse_packet_ref(&data).unwrap() {
+            (PacketRef::Publish(publish), len) => {
+                assert_eq!(len, data.len());
+                assert_eq!(publish.topic, "a/b");
+                assert_eq!(publish.payload.as_ptr(), data[data.len() - 3..].as_ptr());
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let suback = [0x90, 0x04, 0x00, 0x02, 0x01, 0x80];
+        assert!(matches!(
+            parse_packet_ref(&suback).unwrap().0,
+            PacketRef::Suback { packet_id: 2, return_codes: [0x01, 0x80] }
+        ));
+    }
+
+    #[test]
+    fn test_subscribe_packet() {
+        let packet = build_subscribe(1, "events/#", QoS::AtLeastOnce);
+        assert_eq!(packet[0] >> 4, PacketType::Subscribe as u8);
+    }
+
+    #[test]
+    fn test_subscribe_many_and_suback() {
+        let packet =
+            build_subscribe_many(9, &[("ccr/events", QoS::AtMostOnce), ("ccr/perm", QoS::AtLeastOnce)]);
+        assert_eq!(&packet[2..4], &[0, 9]);
+        assert_eq!(&packet[4..16], b"\x00\x0accr/events");
+        assert_eq!(packet[16], 0);
+        assert_eq!(&packet[17..], b"\x00\x08ccr/perm\x01");
+        assert_eq!(packet[1] as usize, packet.len() - 2);
+
+        // One return code per filter: QoS 1 granted, second refused
+        match parse_packet(&[0x90, 0x04, 0x00, 0x09, 0x01, 0x80]).unwrap() {
+            (Packet::Suback { packet_id, return_codes }, 6) => {
+                assert_eq!(packet_id, 9);
+                let granted: Vec<_> = return_codes.iter().map(|&c| QoS::from_suback(c)).collect();
+                assert_eq!(granted, [Some(QoS::AtLeastOnce), None]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unsubscribe_many() {
+        let packet = build_unsubscribe_many(4, &["a/b", "c"]);
+        assert_eq!(packet, [0xA2, 0x0A, 0x00, 0x04, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x01, b'c']);
+        assert_eq!(build_unsubscribe(4, "c"), build_unsubscribe_many(4, &["c"]));
+    }
+
+    #[test]
+    fn test_validate_topics() {
+        assert_eq!(validate_topic_name("ccr/s1/events"), Ok(()));
+        assert_eq!(validate_topic_name(""), Err(TopicError::Empty));
+        assert_eq!(validate_topic_name("ccr/+"), Err(TopicError::Wildcard));
+        assert_eq!(validate_topic_name("ccr/\0"), Err(TopicError::Nul));
+        assert_eq!(validate_topic_name(&"a".repeat(MAX_TOPIC_LEN + 1)), Err(TopicError::TooLong));
+
+        assert_eq!(validate_topic_filter("#"), Ok(()));
+        assert_eq!(validate_topic_filter("ccr/+/events/#"), Ok(()));
+        assert_eq!(validate_topic_filter("ccr/#/events"), Err(TopicError::MisplacedWildcard));
+        assert_eq!(validate_topic_filter("ccr/ev#"), Err(TopicError::MisplacedWildcard));
+        assert_eq!(validate_topic_filter("ccr/+x"), Err(TopicError::MisplacedWildcard));
+        assert_eq!(validate_topic_filter("ccr/\0/#"), Err(TopicError::Nul));
+    }
+
+    #[test]
+    fn test_pingreq() {
+        let packet = build_pingreq();
+        assert_eq!(packet, vec![0xC0, 0x00]);
+    }
+
+    #[test]
+    fn test_remaining_length_encoding() {
+        let mut buf = Vec::new();
+        encode_remaining_length(&mut buf, 127);
+        assert_eq!(buf, vec![127]);
+
+        buf.clear();
+        encode_remaining_length(&mut buf, 128);
+        assert_eq!(buf, vec![0x80, 0x01]);
+
+        buf.clear();
+        encode_remaining_length(&mut buf, 16383);
+        assert_eq!(buf, vec![0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn test_remaining_length_decoding() {
+        for len in [0, 127, 128, 16_383, 16_384, 2_097_151, 2_097_152, MAX_REMAINING_LEN] {
+            let mut buf = Vec::new();
+            encode_remaining_length(&mut buf, len);
+            assert_eq!(decode_remaining_length(&buf), Ok((len, buf.len())));
+            assert_eq!(decode_remaining_length(&buf[..buf.len() - 1]), Err(ParseError::Incomplete));
+        }
+        assert_eq!(decode_remaining_length(&[0xFF, 0xFF, 0xFF, 0x7F]), Ok((MAX_REMAINING_LEN, 4)));
+
+        // Past four bytes, known as soon as the fourth arrives
+        assert_eq!(decode_remaining_length(&[0xFF, 0xFF, 0xFF, 0xFF]), Err(ParseError::InvalidLength));
+        assert_eq!(peek_packet_len(&[0x30, 0x80, 0x80, 0x80, 0x80]), Err(ParseError::InvalidLength));
+        // Padded with continuation bytes that add nothing
+        assert_eq!(decode_remaining_length(&[0x80, 0x00]), Err(ParseError::InvalidLength));
+        assert_eq!(decode_remaining_length(&[0x85, 0x80, 0x00]), Err(ParseError::InvalidLength));
+    }
+
+    #[test]
+    fn test_strict_parsing() {
+        let strict = |data: &[u8]| parse_packet_ref_with(data, ParseMode::Strict).map(|_| ());
+        let lenient = |data: &[u8]| parse_packet_ref(data).map(|_| ());
+
+        // Well-formed packets pass either way
+        assert_eq!(strict(&build_publish_with_id("a/b", b"x", QoS::AtLeastOnce, Some(1), true)), Ok(()));
+        assert_eq!(strict(&build_pubrel(7)), Ok(()));
+        assert_eq!(strict(&[0x90, 0x04, 0x00, 0x01, 0x02, 0x80]), Ok(()));
+
+        // Violations the lenient parser lets through
+        let cases: [(&[u8], ParseError); 9] = [
+            (&[0x21, 0x02, 0x00, 0x00], ParseError::ReservedBits),
+            (&[0x20, 0x02, 0x02, 0x00], ParseError::ReservedBits),
+            (&[0x60, 0x02, 0x00, 0x01], ParseError::ReservedBits),
+            (&[0x38, 0x04, 0x00, 0x01, b'a', b'x'], ParseError::ReservedBits),
+            (&[0x40, 0x03, 0x00, 0x01, 0x00], ParseError::InvalidLength),
+            (&[0xD0, 0x01, 0x00], ParseError::InvalidLength),
+            (&[0x40, 0x02, 0x00, 0x00], ParseError::ZeroPacketId),
+            (&[0x90, 0x03, 0x00, 0x01, 0x03], ParseError::InvalidQoS),
+            (&[0x30, 0x05, 0x00, 0x03, b'a', b'/', b'#'], ParseError::InvalidTopic(TopicError::Wildcard)),
+        ];
+        for (data, error) in cases {
+            assert_eq!(lenient(data), Ok(()), "{:02x?}", data);
+            assert_eq!(strict(data), Err(error), "{:02x?}", data);
+        }
+
+        // Violations that were already errors, now told apart
+        assert_eq!(strict(&[0x36, 0x04, 0x00, 0x01, b'a', 0x00]), Err(ParseError::InvalidQoS));
+        assert_eq!(strict(&[0x30, 0x00]), Err(ParseError::InvalidLength));
+        assert_eq!(strict(&[0x32, 0x03, 0x00, 0x01, b'a']), Err(ParseError::InvalidLength));
+        assert_eq!(strict(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]), Err(ParseError::InvalidLength));
+        assert_eq!(lenient(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]), Err(ParseError::InvalidLength));
+        // A body cut short inside a complete packet is malformed, not waiting for more
+        assert_eq!(lenient(&[0x32, 0x03, 0x00, 0x01, b'a']), Err(ParseError::InvalidFormat));
+        // Still incomplete is still incomplete
+        assert_eq!(strict(&[0x40, 0x02, 0x00]), Err(ParseError::Incomplete));
+    }
+}
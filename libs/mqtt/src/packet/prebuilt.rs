@@ -0,0 +1,218 @@
+//! Compile-Time Packets
+//!
+//! Packets whose contents are all known when a service is built can be
+//! encoded by the compiler and live in flash as `&'static [u8]`, so sending
+//! one is a single write with nothing built or allocated at run time. That
+//! suits tiny always-on publishers: a keep-alive loop needs [`PINGREQ`], and
+//! the subscriptions and status messages it sends never change.
+//!
+//! Each packet has a `*_len` function giving its size and a `const fn`
+//! builder filling an array of exactly that size. Topics are checked with
+//! [`validate_topic_name`] and [`validate_topic_filter`]; a bad topic, or an
+//! array of the wrong size, panics, which in a `const` fails the build. The
+//! [`prebuilt_subscribe!`], [`prebuilt_unsubscribe!`] and
+//! [`prebuilt_publish!`] macros pair the two up:
+//!
+//! ```rust
+//! use xous_mqtt::packet::{QoS, prebuilt};
+//!
+//! static SUBSCRIBE: &[u8] =
+//!     xous_mqtt::prebuilt_subscribe!(1, "telemetry/cmd/#", QoS::AtLeastOnce);
+//! static ONLINE: &[u8] = xous_mqtt::prebuilt_publish!("telemetry/status", b"online", true);
+//!
+//! // The same, spelled out
+//! const OFFLINE_LEN: usize = prebuilt::publish_len("telemetry/status", b"offline");
+//! const OFFLINE: [u8; OFFLINE_LEN] = prebuilt::publish("telemetry/status", b"offline", true);
+//! ```
+//!
+//! PUBLISH is QoS 0 only: QoS 1 and 2 need a fresh packet id per message,
+//! which a packet fixed at build time can't have. Packets are MQTT 3.1.1.
+//!
+//! [`validate_topic_name`]: super::validate_topic_name
+//! [`validate_topic_filter`]: super::validate_topic_filter
+//! [`prebuilt_subscribe!`]: crate::prebuilt_subscribe
+//! [`prebuilt_unsubscribe!`]: crate::prebuilt_unsubscribe
+//! [`prebuilt_publish!`]: crate::prebuilt_publish
+
+use super::{PacketType, QoS, packet_len, validate_topic_filter, validate_topic_name};
+
+/// MQTT PINGREQ packet
+pub const PINGREQ: [u8; 2] = [(PacketType::Pingreq as u8) << 4, 0x00];
+
+/// MQTT DISCONNECT packet
+pub const DISCONNECT: [u8; 2] = [(PacketType::Disconnect as u8) << 4, 0x00];
+
+/// Size of the SUBSCRIBE packet from [`subscribe`]
+pub const fn subscribe_len(filter: &str) -> usize { packet_len(subscribe_body(filter)) }
+
+/// Build MQTT SUBSCRIBE packet for one topic filter; `N` must be [`subscribe_len`]
+pub const fn subscribe<const N: usize>(packet_id: u16, filter: &str, qos: QoS) -> [u8; N] {
+    if validate_topic_filter(filter).is_err() {
+        panic!("invalid MQTT topic filter");
+    }
+    let mut w = ConstWriter::start(((PacketType::Subscribe as u8) << 4) | 0x02, subscribe_body(filter));
+    w.u16(packet_id);
+    w.field(filter.as_bytes());
+    w.u8(qos as u8);
+    w.finish()
+}
+
+/// Size of the UNSUBSCRIBE packet from [`unsubscribe`]
+pub const fn unsubscribe_len(filter: &str) -> usize { packet_len(unsubscribe_body(filter)) }
+
+/// Build MQTT UNSUBSCRIBE packet for one topic filter; `N` must be [`unsubscribe_len`]
+pub const fn unsubscribe<const N: usize>(packet_id: u16, filter: &str) -> [u8; N] {
+    if validate_topic_filter(filter).is_err() {
+        panic!("invalid MQTT topic filter");
+    }
+    let first_byte = ((PacketType::Unsubscribe as u8) << 4) | 0x02;
+    let mut w = ConstWriter::start(first_byte, unsubscribe_body(filter));
+    w.u16(packet_id);
+    w.field(filter.as_bytes());
+    w.finish()
+}
+
+/// Size of the PUBLISH packet from [`publish`]
+pub const fn publish_len(topic: &str, payload: &[u8]) -> usize { packet_len(publish_body(topic, payload)) }
+
+/// Build MQTT PUBLISH packet (QoS 0); `N` must be [`publish_len`]
+pub const fn publish<const N: usize>(topic: &str, payload: &[u8], retain: bool) -> [u8; N] {
+    if validate_topic_name(topic).is_err() {
+        panic!("invalid MQTT topic name");
+    }
+    let first_byte = ((PacketType::Publish as u8) << 4) | retain as u8;
+    let mut w = ConstWriter::start(first_byte, publish_body(topic, payload));
+    w.field(topic.as_bytes());
+    w.raw(payload);
+    w.finish()
+}
+
+/// SUBSCRIBE packet for one topic filter, as a `&'static [u8]`
+#[macro_export]
+macro_rules! prebuilt_subscribe {
+    ($packet_id:expr, $filter:expr, $qos:expr) => {{
+        const PACKET: [u8; $crate::packet::prebuilt::subscribe_len($filter)] =
+            $crate::packet::prebuilt::subscribe($packet_id, $filter, $qos);
+        &PACKET as &'static [u8]
+    }};
+}
+
+/// UNSUBSCRIBE packet for one topic filter, as a `&'static [u8]`
+#[macro_export]
+macro_rules! prebuilt_unsubscribe {
+    ($packet_id:expr, $filter:expr) => {{
+        const PACKET: [u8; $crate::packet::prebuilt::unsubscribe_len($filter)] =
+            $crate::packet::prebuilt::unsubscribe($packet_id, $filter);
+        &PACKET as &'static [u8]
+    }};
+}
+
+/// QoS 0 PUBLISH packet, as a `&'static [u8]`
+#[macro_export]
+macro_rules! prebuilt_publish {
+    ($topic:expr, $payload:expr, $retain:expr) => {{
+        const PACKET: [u8; $crate::packet::prebuilt::publish_len($topic, $payload)] =
+            $crate::packet::prebuilt::publish($topic, $payload, $retain);
+        &PACKET as &'static [u8]
+    }};
+}
+
+/// Packet id, one length-prefixed filter and its QoS byte
+const fn subscribe_body(filter: &str) -> usize { 2 + 2 + filter.len() + 1 }
+
+/// Packet id and one length-prefixed filter
+const fn unsubscribe_body(filter: &str) -> usize { 2 + 2 + filter.len() }
+
+/// Length-prefixed topic and the payload; QoS 0 has no packet id
+const fn publish_body(topic: &str, payload: &[u8]) -> usize { 2 + topic.len() + payload.len() }
+
+/// Fills a packet array in a `const fn`; `Writer` without the error paths
+struct ConstWriter<const N: usize> {
+    buf: [u8; N],
+    pos: usize,
+}
+
+impl<const N: usize> ConstWriter<N> {
+    /// Write the fixed header of a packet with a `remaining_len` byte body
+    const fn start(first_byte: u8, remaining_len: usize) -> Self {
+        if packet_len(remaining_len) != N {
+            panic!("packet array is the wrong size; use the matching *_len function");
+        }
+        let mut w = Self { buf: [0; N], pos: 0 };
+        w.u8(first_byte);
+        // Remaining length, as a variable byte integer
+        let mut value = remaining_len;
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                w.u8(byte);
+                break;
+            }
+            w.u8(byte | 0x80);
+        }
+        w
+    }
+
+    const fn u8(&mut self, byte: u8) {
+        self.buf[self.pos] = byte;
+        self.pos += 1;
+    }
+
+    const fn u16(&mut self, value: u16) {
+        self.u8((value >> 8) as u8);
+        self.u8(value as u8);
+    }
+
+    /// Length-prefixed field
+    const fn field(&mut self, data: &[u8]) {
+        if data.len() > u16::MAX as usize {
+            panic!("MQTT field longer than 65535 bytes");
+        }
+        self.u16(data.len() as u16);
+        self.raw(data);
+    }
+
+    const fn raw(&mut self, data: &[u8]) {
+        let mut i = 0;
+        while i < data.len() {
+            self.u8(data[i]);
+            i += 1;
+        }
+    }
+
+    const fn finish(self) -> [u8; N] { self.buf }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "encode")]
+    #[test]
+    fn test_matches_vec_builders() {
+        use crate::packet;
+
+        assert_eq!(PINGREQ[..], packet::build_pingreq()[..]);
+        assert_eq!(DISCONNECT[..], packet::build_disconnect()[..]);
+        let subscribe: &[u8] = crate::prebuilt_subscribe!(7, "ccr/+/events", QoS::AtLeastOnce);
+        assert_eq!(subscribe, &packet::build_subscribe(7, "ccr/+/events", QoS::AtLeastOnce)[..]);
+        let unsubscribe: &[u8] = crate::prebuilt_unsubscribe!(8, "ccr/#");
+        assert_eq!(unsubscribe, &packet::build_unsubscribe(8, "ccr/#")[..]);
+        let publish: &[u8] = crate::prebuilt_publish!("ccr/status", b"online", false);
+        assert_eq!(publish, &packet::build_publish("ccr/status", b"online", QoS::AtMostOnce)[..]);
+    }
+
+    #[test]
+    fn test_long_publish() {
+        // A body over 127 bytes takes two bytes of remaining length
+        const PAYLOAD: [u8; 200] = [0x5A; 200];
+        const PACKET: [u8; publish_len("t", &PAYLOAD)] = publish("t", &PAYLOAD, true);
+        assert_eq!(PACKET.len(), 1 + 2 + 3 + 200);
+        assert_eq!(PACKET[..6], [0x31, 0xCB, 0x01, 0x00, 0x01, b't']);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid MQTT topic name")]
+    fn test_rejects_wildcard_topic() { let _: [u8; 9] = publish("a/#", b"x", false); }
+}
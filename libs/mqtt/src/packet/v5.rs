@@ -0,0 +1,1096 @@
+//! MQTT 5.0 Packets
+//!
+//! Builders and parsers for protocol level 5, enabled by the `mqtt5` feature.
+//! The fixed header, QoS flags and packet ids are shared with 3.1.1; what
+//! changes is that most packets carry a [`Property`] list after the variable
+//! header, and every acknowledgement can carry a [`ReasonCode`].
+//!
+//! Acknowledgements that succeed without properties are encoded exactly as in
+//! 3.1.1, so [`build_puback`](super::build_puback) and friends can be sent on
+//! an MQTT 5 connection unchanged.
+//!
+//! ```rust
+//! use xous_mqtt::packet::QoS;
+//! use xous_mqtt::packet::v5::{self, Packet, Property, ReasonCode};
+//!
+//! let publish = v5::build_publish(
+//!     "ccr/events",
+//!     b"{}",
+//!     QoS::AtLeastOnce,
+//!     Some(1),
+//!     false,
+//!     &[Property::MessageExpiryInterval(30)],
+//! );
+//!
+//! // PUBACK refusing the message
+//! let (packet, _) = v5::parse_packet(&[0x40, 0x03, 0x00, 0x01, 0x87]).unwrap();
+//! assert!(matches!(
+//!     packet,
+//!     Packet::Puback { packet_id: 1, reason: ReasonCode::NOT_AUTHORIZED, .. }
+//! ));
+//! ```
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::decode::{
+    ParseError, PublishRef, decode_remaining_length, decode_str, parse_fixed_header, truncated,
+};
+use super::encode::{encode_bytes, encode_remaining_length, encode_string};
+use super::write::{Writer, connect_flags, field_len, publish_flags, varint_len};
+use super::{EncodeError, PacketType, QoS, Will, packet_len};
+
+/// Reason code carried by CONNACK, acknowledgements, DISCONNECT and AUTH
+///
+/// Values below `0x80` report success; the rest are errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReasonCode(pub u8);
+
+impl ReasonCode {
+    pub const ADMINISTRATIVE_ACTION: Self = Self(0x98);
+    pub const BAD_AUTHENTICATION_METHOD: Self = Self(0x8C);
+    pub const BAD_USER_NAME_OR_PASSWORD: Self = Self(0x86);
+    pub const BANNED: Self = Self(0x8A);
+    pub const CLIENT_IDENTIFIER_NOT_VALID: Self = Self(0x85);
+    pub const CONNECTION_RATE_EXCEEDED: Self = Self(0x9F);
+    pub const CONTINUE_AUTHENTICATION: Self = Self(0x18);
+    pub const DISCONNECT_WITH_WILL: Self = Self(0x04);
+    pub const GRANTED_QOS_1: Self = Self(0x01);
+    pub const GRANTED_QOS_2: Self = Self(0x02);
+    pub const IMPLEMENTATION_SPECIFIC_ERROR: Self = Self(0x83);
+    pub const KEEP_ALIVE_TIMEOUT: Self = Self(0x8D);
+    pub const MALFORMED_PACKET: Self = Self(0x81);
+    pub const MAXIMUM_CONNECT_TIME: Self = Self(0xA0);
+    pub const MESSAGE_RATE_TOO_HIGH: Self = Self(0x96);
+    pub const NOT_AUTHORIZED: Self = Self(0x87);
+    pub const NO_MATCHING_SUBSCRIBERS: Self = Self(0x10);
+    pub const NO_SUBSCRIPTION_EXISTED: Self = Self(0x11);
+    pub const PACKET_IDENTIFIER_IN_USE: Self = Self(0x91);
+    pub const PACKET_IDENTIFIER_NOT_FOUND: Self = Self(0x92);
+    pub const PACKET_TOO_LARGE: Self = Self(0x95);
+    pub const PAYLOAD_FORMAT_INVALID: Self = Self(0x99);
+    pub const PROTOCOL_ERROR: Self = Self(0x82);
+    pub const QOS_NOT_SUPPORTED: Self = Self(0x9B);
+    pub const QUOTA_EXCEEDED: Self = Self(0x97);
+    pub const REAUTHENTICATE: Self = Self(0x19);
+    pub const RECEIVE_MAXIMUM_EXCEEDED: Self = Self(0x93);
+    pub const RETAIN_NOT_SUPPORTED: Self = Self(0x9A);
+    pub const SERVER_BUSY: Self = Self(0x89);
+    pub const SERVER_MOVED: Self = Self(0x9D);
+    pub const SERVER_SHUTTING_DOWN: Self = Self(0x8B);
+    pub const SERVER_UNAVAILABLE: Self = Self(0x88);
+    pub const SESSION_TAKEN_OVER: Self = Self(0x8E);
+    pub const SHARED_SUBSCRIPTIONS_NOT_SUPPORTED: Self = Self(0x9E);
+    pub const SUBSCRIPTION_IDENTIFIERS_NOT_SUPPORTED: Self = Self(0xA1);
+    pub const SUCCESS: Self = Self(0x00);
+    pub const TOPIC_ALIAS_INVALID: Self = Self(0x94);
+    pub const TOPIC_FILTER_INVALID: Self = Self(0x8F);
+    pub const TOPIC_NAME_INVALID: Self = Self(0x90);
+    pub const UNSPECIFIED_ERROR: Self = Self(0x80);
+    pub const UNSUPPORTED_PROTOCOL_VERSION: Self = Self(0x84);
+    pub const USE_ANOTHER_SERVER: Self = Self(0x9C);
+    pub const WILDCARD_SUBSCRIPTIONS_NOT_SUPPORTED: Self = Self(0xA2);
+
+    pub fn is_error(&self) -> bool { self.0 >= 0x80 }
+}
+
+/// Packet property
+///
+/// Which properties are allowed on which packet is up to the caller; the
+/// builders encode whatever they are given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Property {
+    PayloadFormatIndicator(u8),
+    MessageExpiryInterval(u32),
+    ContentType(String),
+    ResponseTopic(String),
+    CorrelationData(Vec<u8>),
+    SubscriptionIdentifier(u32),
+    SessionExpiryInterval(u32),
+    AssignedClientIdentifier(String),
+    ServerKeepAlive(u16),
+    AuthenticationMethod(String),
+    AuthenticationData(Vec<u8>),
+    RequestProblemInformation(u8),
+    WillDelayInterval(u32),
+    RequestResponseInformation(u8),
+    ResponseInformation(String),
+    ServerReference(String),
+    ReasonString(String),
+    ReceiveMaximum(u16),
+    TopicAliasMaximum(u16),
+    TopicAlias(u16),
+    MaximumQos(u8),
+    RetainAvailable(u8),
+    UserProperty(String, String),
+    MaximumPacketSize(u32),
+    WildcardSubscriptionAvailable(u8),
+    SubscriptionIdentifierAvailable(u8),
+    SharedSubscriptionAvailable(u8),
+}
+
+impl Property {
+    /// Property identifier on the wire
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::PayloadFormatIndicator(_) => 0x01,
+            Self::MessageExpiryInterval(_) => 0x02,
+            Self::ContentType(_) => 0x03,
+            Self::ResponseTopic(_) => 0x08,
+            Self::CorrelationData(_) => 0x09,
+            Self::SubscriptionIdentifier(_) => 0x0B,
+            Self::SessionExpiryInterval(_) => 0x11,
+            Self::AssignedClientIdentifier(_) => 0x12,
+            Self::ServerKeepAlive(_) => 0x13,
+            Self::AuthenticationMethod(_) => 0x15,
+            Self::AuthenticationData(_) => 0x16,
+            Self::RequestProblemInformation(_) => 0x17,
+            Self::WillDelayInterval(_) => 0x18,
+            Self::RequestResponseInformation(_) => 0x19,
+            Self::ResponseInformation(_) => 0x1A,
+            Self::ServerReference(_) => 0x1C,
+            Self::ReasonString(_) => 0x1F,
+            Self::ReceiveMaximum(_) => 0x21,
+            Self::TopicAliasMaximum(_) => 0x22,
+            Self::TopicAlias(_) => 0x23,
+            Self::MaximumQos(_) => 0x24,
+            Self::RetainAvailable(_) => 0x25,
+            Self::UserProperty(..) => 0x26,
+            Self::MaximumPacketSize(_) => 0x27,
+            Self::WildcardSubscriptionAvailable(_) => 0x28,
+            Self::SubscriptionIdentifierAvailable(_) => 0x29,
+            Self::SharedSubscriptionAvailable(_) => 0x2A,
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(self.id());
+        match self {
+            Self::PayloadFormatIndicator(v)
+            | Self::RequestProblemInformation(v)
+            | Self::RequestResponseInformation(v)
+            | Self::MaximumQos(v)
+            | Self::RetainAvailable(v)
+            | Self::WildcardSubscriptionAvailable(v)
+            | Self::SubscriptionIdentifierAvailable(v)
+            | Self::SharedSubscriptionAvailable(v) => buf.push(*v),
+            Self::ServerKeepAlive(v)
+            | Self::ReceiveMaximum(v)
+            | Self::TopicAliasMaximum(v)
+            | Self::TopicAlias(v) => buf.extend_from_slice(&v.to_be_bytes()),
+            Self::MessageExpiryInterval(v)
+            | Self::SessionExpiryInterval(v)
+            | Self::WillDelayInterval(v)
+            | Self::MaximumPacketSize(v) => buf.extend_from_slice(&v.to_be_bytes()),
+            Self::SubscriptionIdentifier(v) => encode_remaining_length(buf, *v as usize),
+            Self::ContentType(s)
+            | Self::ResponseTopic(s)
+            | Self::AssignedClientIdentifier(s)
+            | Self::AuthenticationMethod(s)
+            | Self::ResponseInformation(s)
+            | Self::ServerReference(s)
+            | Self::ReasonString(s) => encode_string(buf, s),
+            Self::CorrelationData(d) | Self::AuthenticationData(d) => encode_bytes(buf, d),
+            Self::UserProperty(key, value) => {
+                encode_string(buf, key);
+                encode_string(buf, value);
+            }
+        }
+    }
+
+    /// Encoded size, identifier included
+    fn encoded_len(&self) -> Result<usize, EncodeError> {
+        let value_len = match self {
+            Self::PayloadFormatIndicator(_)
+            | Self::RequestProblemInformation(_)
+            | Self::RequestResponseInformation(_)
+            | Self::MaximumQos(_)
+            | Self::RetainAvailable(_)
+            | Self::WildcardSubscriptionAvailable(_)
+            | Self::SubscriptionIdentifierAvailable(_)
+            | Self::SharedSubscriptionAvailable(_) => 1,
+            Self::ServerKeepAlive(_)
+            | Self::ReceiveMaximum(_)
+            | Self::TopicAliasMaximum(_)
+            | Self::TopicAlias(_) => 2,
+            Self::MessageExpiryInterval(_)
+            | Self::SessionExpiryInterval(_)
+            | Self::WillDelayInterval(_)
+            | Self::MaximumPacketSize(_) => 4,
+            Self::SubscriptionIdentifier(v) => varint_len(*v as usize),
+            Self::ContentType(s)
+            | Self::ResponseTopic(s)
+            | Self::AssignedClientIdentifier(s)
+            | Self::AuthenticationMethod(s)
+            | Self::ResponseInformation(s)
+            | Self::ServerReference(s)
+            | Self::ReasonString(s) => field_len(s.len())?,
+            Self::CorrelationData(d) | Self::AuthenticationData(d) => field_len(d.len())?,
+            Self::UserProperty(key, value) => field_len(key.len())? + field_len(value.len())?,
+        };
+        Ok(1 + value_len)
+    }
+
+    /// Write into a buffer sized with `encoded_len`
+    fn write(&self, w: &mut Writer<'_>) {
+        w.u8(self.id());
+        match self {
+            Self::PayloadFormatIndicator(v)
+            | Self::RequestProblemInformation(v)
+            | Self::RequestResponseInformation(v)
+            | Self::MaximumQos(v)
+            | Self::RetainAvailable(v)
+            | Self::WildcardSubscriptionAvailable(v)
+            | Self::SubscriptionIdentifierAvailable(v)
+            | Self::SharedSubscriptionAvailable(v) => w.u8(*v),
+            Self::ServerKeepAlive(v)
+            | Self::ReceiveMaximum(v)
+            | Self::TopicAliasMaximum(v)
+            | Self::TopicAlias(v) => w.u16(*v),
+            Self::MessageExpiryInterval(v)
+            | Self::SessionExpiryInterval(v)
+            | Self::WillDelayInterval(v)
+            | Self::MaximumPacketSize(v) => w.u32(*v),
+            Self::SubscriptionIdentifier(v) => w.varint(*v as usize),
+            Self::ContentType(s)
+            | Self::ResponseTopic(s)
+            | Self::AssignedClientIdentifier(s)
+            | Self::AuthenticationMethod(s)
+            | Self::ResponseInformation(s)
+            | Self::ServerReference(s)
+            | Self::ReasonString(s) => w.bytes(s.as_bytes()),
+            Self::CorrelationData(d) | Self::AuthenticationData(d) => w.bytes(d),
+            Self::UserProperty(key, value) => {
+                w.bytes(key.as_bytes());
+                w.bytes(value.as_bytes());
+            }
+        }
+    }
+
+    /// Decode one property, returns (property, bytes_consumed)
+    fn decode(data: &[u8]) -> Result<(Self, usize), ParseError> {
+        let (&id, rest) = data.split_first().ok_or(ParseError::InvalidFormat)?;
+        let read_u8 = || rest.first().copied().ok_or(ParseError::InvalidFormat);
+        let read_u16 =
+            || rest.get(..2).map(|b| u16::from_be_bytes([b[0], b[1]])).ok_or(ParseError::InvalidFormat);
+        let read_u32 = || {
+            rest.get(..4)
+                .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or(ParseError::InvalidFormat)
+        };
+        let string = |at: usize| -> Result<(String, usize), ParseError> {
+            let (s, len) = decode_str(rest.get(at..).ok_or(ParseError::InvalidFormat)?).map_err(truncated)?;
+            Ok((String::from(s), len))
+        };
+        let (property, len) = match id {
+            0x01 => (Self::PayloadFormatIndicator(read_u8()?), 1),
+            0x02 => (Self::MessageExpiryInterval(read_u32()?), 4),
+            0x03 => string(0).map(|(s, len)| (Self::ContentType(s), len))?,
+            0x08 => string(0).map(|(s, len)| (Self::ResponseTopic(s), len))?,
+            0x09 => {
+                let (d, len) = decode_binary(rest)?;
+                (Self::CorrelationData(d), len)
+            }
+            0x0B => {
+                let (v, len) = decode_remaining_length(rest).map_err(truncated)?;
+                (Self::SubscriptionIdentifier(v as u32), len)
+            }
+            0x11 => (Self::SessionExpiryInterval(read_u32()?), 4),
+            0x12 => string(0).map(|(s, len)| (Self::AssignedClientIdentifier(s), len))?,
+            0x13 => (Self::ServerKeepAlive(read_u16()?), 2),
+            0x15 => string(0).map(|(s, len)| (Self::AuthenticationMethod(s), len))?,
+            0x16 => {
+                let (d, len) = decode_binary(rest)?;
+                (Self::AuthenticationData(d), len)
+            }
+            0x17 => (Self::RequestProblemInformation(read_u8()?), 1),
+            0x18 => (Self::WillDelayInterval(read_u32()?), 4),
+            0x19 => (Self::RequestResponseInformation(read_u8()?), 1),
+            0x1A => string(0).map(|(s, len)| (Self::ResponseInformation(s), len))?,
+            0x1C => string(0).map(|(s, len)| (Self::ServerReference(s), len))?,
+            0x1F => string(0).map(|(s, len)| (Self::ReasonString(s), len))?,
+            0x21 => (Self::ReceiveMaximum(read_u16()?), 2),
+            0x22 => (Self::TopicAliasMaximum(read_u16()?), 2),
+            0x23 => (Self::TopicAlias(read_u16()?), 2),
+            0x24 => (Self::MaximumQos(read_u8()?), 1),
+            0x25 => (Self::RetainAvailable(read_u8()?), 1),
+            0x26 => {
+                let (key, key_len) = string(0)?;
+                let (value, value_len) = string(key_len)?;
+                (Self::UserProperty(key, value), key_len + value_len)
+            }
+            0x27 => (Self::MaximumPacketSize(read_u32()?), 4),
+            0x28 => (Self::WildcardSubscriptionAvailable(read_u8()?), 1),
+            0x29 => (Self::SubscriptionIdentifierAvailable(read_u8()?), 1),
+            0x2A => (Self::SharedSubscriptionAvailable(read_u8()?), 1),
+            _ => return Err(ParseError::InvalidFormat),
+        };
+        Ok((property, 1 + len))
+    }
+}
+
+/// Parsed MQTT 5 packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    Connack {
+        session_present: bool,
+        reason: ReasonCode,
+        properties: Vec<Property>,
+    },
+    Publish {
+        topic: String,
+        payload: Vec<u8>,
+        qos: QoS,
+        packet_id: Option<u16>,
+        retain: bool,
+        dup: bool,
+        properties: Vec<Property>,
+    },
+    Puback {
+        packet_id: u16,
+        reason: ReasonCode,
+        properties: Vec<Property>,
+    },
+    Pubrec {
+        packet_id: u16,
+        reason: ReasonCode,
+        properties: Vec<Property>,
+    },
+    Pubrel {
+        packet_id: u16,
+        reason: ReasonCode,
+        properties: Vec<Property>,
+    },
+    Pubcomp {
+        packet_id: u16,
+        reason: ReasonCode,
+        properties: Vec<Property>,
+    },
+    Suback {
+        packet_id: u16,
+        /// One per requested filter: the granted QoS, or an error
+        reasons: Vec<ReasonCode>,
+        properties: Vec<Property>,
+    },
+    Unsuback {
+        packet_id: u16,
+        reasons: Vec<ReasonCode>,
+        properties: Vec<Property>,
+    },
+    Pingresp,
+    /// Server closing the connection
+    Disconnect {
+        reason: ReasonCode,
+        properties: Vec<Property>,
+    },
+    Auth {
+        reason: ReasonCode,
+        properties: Vec<Property>,
+    },
+}
+
+// ============================================================================
+// Builders
+// ============================================================================
+
+/// Build MQTT 5 CONNECT packet
+///
+/// The Will, if any, is sent without Will properties.
+pub fn build_connect(
+    client_id: &str,
+    username: Option<&str>,
+    password: Option<&[u8]>,
+    clean_start: bool,
+    keep_alive_secs: u16,
+    will: Option<&Will<'_>>,
+    properties: &[Property],
+) -> Vec<u8> {
+    let mut packet = Vec::new();
+
+    // Variable header: protocol name, level 5, flags, keep alive, properties
+    let mut var_header = Vec::new();
+    encode_string(&mut var_header, "MQTT");
+    var_header.push(0x05);
+
+    let mut flags: u8 = 0;
+    if clean_start {
+        flags |= 0x02;
+    }
+    if let Some(will) = will {
+        flags |= 0x04 | ((will.qos as u8) << 3);
+        if will.retain {
+            flags |= 0x20;
+        }
+    }
+    if username.is_some() {
+        flags |= 0x80;
+    }
+    if password.is_some() {
+        flags |= 0x40;
+    }
+    var_header.push(flags);
+    var_header.extend_from_slice(&keep_alive_secs.to_be_bytes());
+    encode_properties(&mut var_header, properties);
+
+    // Payload
+    let mut payload = Vec::new();
+    encode_string(&mut payload, client_id);
+    if let Some(will) = will {
+        encode_properties(&mut payload, &[]);
+        encode_string(&mut payload, will.topic);
+        encode_bytes(&mut payload, will.payload);
+    }
+    if let Some(user) = username {
+        encode_string(&mut payload, user);
+    }
+    if let Some(pass) = password {
+        encode_bytes(&mut payload, pass);
+    }
+
+    packet.push((PacketType::Connect as u8) << 4);
+    encode_remaining_length(&mut packet, var_header.len() + payload.len());
+    packet.extend(var_header);
+    packet.extend(payload);
+    packet
+}
+
+/// Size of the PUBLISH [`build_publish`] would build, without building it
+pub fn publish_len(topic: &str, payload_len: usize, qos: QoS, properties: &[Property]) -> usize {
+    let packet_id_len = if qos == QoS::AtMostOnce { 0 } else { 2 };
+    let mut encoded = Vec::new();
+    encode_properties(&mut encoded, properties);
+    packet_len(2 + topic.len() + packet_id_len + encoded.len() + payload_len)
+}
+
+/// Build MQTT 5 PUBLISH packet
+pub fn build_publish(
+    topic: &str,
+    payload: &[u8],
+    qos: QoS,
+    packet_id: Option<u16>,
+    retain: bool,
+    properties: &[Property],
+) -> Vec<u8> {
+    let mut packet = Vec::new();
+
+    let mut var_header = Vec::new();
+    encode_string(&mut var_header, topic);
+    if let Some(id) = packet_id {
+        var_header.extend_from_slice(&id.to_be_bytes());
+    }
+    encode_properties(&mut var_header, properties);
+
+    let mut flags = (PacketType::Publish as u8) << 4;
+    flags |= (qos as u8) << 1;
+    if retain {
+        flags |= 0x01;
+    }
+    packet.push(flags);
+    encode_remaining_length(&mut packet, var_header.len() + payload.len());
+    packet.extend(var_header);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Build MQTT 5 SUBSCRIBE packet
+///
+/// Subscription options other than the maximum QoS (No Local, Retain As
+/// Published, Retain Handling) are left at their defaults.
+pub fn build_subscribe(packet_id: u16, topics: &[(&str, QoS)], properties: &[Property]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&packet_id.to_be_bytes());
+    encode_properties(&mut body, properties);
+    for &(topic, qos) in topics {
+        encode_string(&mut body, topic);
+        body.push(qos as u8);
+    }
+    with_fixed_header(((PacketType::Subscribe as u8) << 4) | 0x02, body)
+}
+
+/// Build MQTT 5 UNSUBSCRIBE packet
+pub fn build_unsubscribe(packet_id: u16, topics: &[&str], properties: &[Property]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&packet_id.to_be_bytes());
+    encode_properties(&mut body, properties);
+    for topic in topics {
+        encode_string(&mut body, topic);
+    }
+    with_fixed_header(((PacketType::Unsubscribe as u8) << 4) | 0x02, body)
+}
+
+/// Build MQTT 5 PUBACK, PUBREC, PUBREL or PUBCOMP packet
+///
+/// Success without properties uses the short 3.1.1 form.
+pub fn build_ack(
+    packet_type: PacketType,
+    packet_id: u16,
+    reason: ReasonCode,
+    properties: &[Property],
+) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&packet_id.to_be_bytes());
+    if reason != ReasonCode::SUCCESS || !properties.is_empty() {
+        body.push(reason.0);
+        encode_properties(&mut body, properties);
+    }
+    // PUBREL has reserved bits 0010
+    let flags = if packet_type == PacketType::Pubrel { 0x02 } else { 0x00 };
+    with_fixed_header(((packet_type as u8) << 4) | flags, body)
+}
+
+/// Build MQTT 5 DISCONNECT packet
+pub fn build_disconnect(reason: ReasonCode, properties: &[Property]) -> Vec<u8> {
+    build_reason_only(PacketType::Disconnect, reason, properties)
+}
+
+/// Build MQTT 5 AUTH packet
+pub fn build_auth(reason: ReasonCode, properties: &[Property]) -> Vec<u8> {
+    build_reason_only(PacketType::Auth, reason, properties)
+}
+
+fn build_reason_only(packet_type: PacketType, reason: ReasonCode, properties: &[Property]) -> Vec<u8> {
+    let mut body = Vec::new();
+    if reason != ReasonCode::SUCCESS || !properties.is_empty() {
+        body.push(reason.0);
+        encode_properties(&mut body, properties);
+    }
+    with_fixed_header((packet_type as u8) << 4, body)
+}
+
+fn with_fixed_header(first_byte: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(body.len() + 5);
+    packet.push(first_byte);
+    encode_remaining_length(&mut packet, body.len());
+    packet.extend(body);
+    packet
+}
+
+/// Encode a property list with its variable byte integer length prefix
+pub fn encode_properties(buf: &mut Vec<u8>, properties: &[Property]) {
+    let mut encoded = Vec::new();
+    for property in properties {
+        property.encode(&mut encoded);
+    }
+    encode_remaining_length(buf, encoded.len());
+    buf.extend(encoded);
+}
+
+// ============================================================================
+// Builders into a caller's buffer
+// ============================================================================
+
+/// Build MQTT 5 CONNECT packet into `buf`, returning its length
+#[allow(clippy::too_many_arguments)]
+pub fn build_connect_into(
+    buf: &mut [u8],
+    client_id: &str,
+    username: Option<&str>,
+    password: Option<&[u8]>,
+    clean_start: bool,
+    keep_alive_secs: u16,
+    will: Option<&Will<'_>>,
+    properties: &[Property],
+) -> Result<usize, EncodeError> {
+    let properties_len = properties_len(properties)?;
+    let mut remaining_len = 10 + varint_len(properties_len) + properties_len + field_len(client_id.len())?;
+    if let Some(will) = will {
+        // Empty will properties
+        remaining_len += 1 + field_len(will.topic.len())? + field_len(will.payload.len())?;
+    }
+    if let Some(user) = username {
+        remaining_len += field_len(user.len())?;
+    }
+    if let Some(pass) = password {
+        remaining_len += field_len(pass.len())?;
+    }
+
+    let mut w = Writer::start(buf, (PacketType::Connect as u8) << 4, remaining_len)?;
+    w.bytes(b"MQTT");
+    w.u8(0x05);
+    w.u8(connect_flags(username, password, clean_start, will));
+    w.u16(keep_alive_secs);
+    write_properties(&mut w, properties, properties_len);
+    w.bytes(client_id.as_bytes());
+    if let Some(will) = will {
+        w.varint(0);
+        w.bytes(will.topic.as_bytes());
+        w.bytes(will.payload);
+    }
+    if let Some(user) = username {
+        w.bytes(user.as_bytes());
+    }
+    if let Some(pass) = password {
+        w.bytes(pass);
+    }
+    Ok(w.finish())
+}
+
+/// Build MQTT 5 PUBLISH packet into `buf`, returning its length
+pub fn build_publish_into(
+    buf: &mut [u8],
+    topic: &str,
+    payload: &[u8],
+    qos: QoS,
+    packet_id: Option<u16>,
+    retain: bool,
+    properties: &[Property],
+) -> Result<usize, EncodeError> {
+    let properties_len = properties_len(properties)?;
+    let remaining_len = field_len(topic.len())?
+        + if packet_id.is_some() { 2 } else { 0 }
+        + varint_len(properties_len)
+        + properties_len
+        + payload.len();
+
+    let mut w = Writer::start(buf, publish_flags(qos, retain), remaining_len)?;
+    w.bytes(topic.as_bytes());
+    if let Some(id) = packet_id {
+        w.u16(id);
+    }
+    write_properties(&mut w, properties, properties_len);
+    w.raw(payload);
+    Ok(w.finish())
+}
+
+/// Build MQTT 5 SUBSCRIBE packet into `buf`, returning its length
+pub fn build_subscribe_into(
+    buf: &mut [u8],
+    packet_id: u16,
+    topics: &[(&str, QoS)],
+    properties: &[Property],
+) -> Result<usize, EncodeError> {
+    let properties_len = properties_len(properties)?;
+    let mut remaining_len = 2 + varint_len(properties_len) + properties_len;
+    for (topic, _) in topics {
+        remaining_len += field_len(topic.len())? + 1;
+    }
+
+    let mut w = Writer::start(buf, ((PacketType::Subscribe as u8) << 4) | 0x02, remaining_len)?;
+    w.u16(packet_id);
+    write_properties(&mut w, properties, properties_len);
+    for &(topic, qos) in topics {
+        w.bytes(topic.as_bytes());
+        w.u8(qos as u8);
+    }
+    Ok(w.finish())
+}
+
+/// Build MQTT 5 UNSUBSCRIBE packet into `buf`, returning its length
+pub fn build_unsubscribe_into(
+    buf: &mut [u8],
+    packet_id: u16,
+    topics: &[&str],
+    properties: &[Property],
+) -> Result<usize, EncodeError> {
+    let properties_len = properties_len(properties)?;
+    let mut remaining_len = 2 + varint_len(properties_len) + properties_len;
+    for topic in topics {
+        remaining_len += field_len(topic.len())?;
+    }
+
+    let mut w = Writer::start(buf, ((PacketType::Unsubscribe as u8) << 4) | 0x02, remaining_len)?;
+    w.u16(packet_id);
+    write_properties(&mut w, properties, properties_len);
+    for topic in topics {
+        w.bytes(topic.as_bytes());
+    }
+    Ok(w.finish())
+}
+
+/// Build MQTT 5 PUBACK, PUBREC, PUBREL or PUBCOMP packet into `buf`, returning its length
+pub fn build_ack_into(
+    buf: &mut [u8],
+    packet_type: PacketType,
+    packet_id: u16,
+    reason: ReasonCode,
+    properties: &[Property],
+) -> Result<usize, EncodeError> {
+    let flags = if packet_type == PacketType::Pubrel { 0x02 } else { 0x00 };
+    let properties_len = properties_len(properties)?;
+    let long_form = reason != ReasonCode::SUCCESS || !properties.is_empty();
+    let remaining_len = if long_form { 3 + varint_len(properties_len) + properties_len } else { 2 };
+
+    let mut w = Writer::start(buf, ((packet_type as u8) << 4) | flags, remaining_len)?;
+    w.u16(packet_id);
+    if long_form {
+        w.u8(reason.0);
+        write_properties(&mut w, properties, properties_len);
+    }
+    Ok(w.finish())
+}
+
+/// Build MQTT 5 DISCONNECT packet into `buf`, returning its length
+pub fn build_disconnect_into(
+    buf: &mut [u8],
+    reason: ReasonCode,
+    properties: &[Property],
+) -> Result<usize, EncodeError> {
+    build_reason_only_into(buf, PacketType::Disconnect, reason, properties)
+}
+
+/// Build MQTT 5 AUTH packet into `buf`, returning its length
+pub fn build_auth_into(
+    buf: &mut [u8],
+    reason: ReasonCode,
+    properties: &[Property],
+) -> Result<usize, EncodeError> {
+    build_reason_only_into(buf, PacketType::Auth, reason, properties)
+}
+
+fn build_reason_only_into(
+    buf: &mut [u8],
+    packet_type: PacketType,
+    reason: ReasonCode,
+    properties: &[Property],
+) -> Result<usize, EncodeError> {
+    let properties_len = properties_len(properties)?;
+    let long_form = reason != ReasonCode::SUCCESS || !properties.is_empty();
+    let remaining_len = if long_form { 1 + varint_len(properties_len) + properties_len } else { 0 };
+
+    let mut w = Writer::start(buf, (packet_type as u8) << 4, remaining_len)?;
+    if long_form {
+        w.u8(reason.0);
+        write_properties(&mut w, properties, properties_len);
+    }
+    Ok(w.finish())
+}
+
+/// Encoded size of a property list, without its length prefix
+fn properties_len(properties: &[Property]) -> Result<usize, EncodeError> {
+    properties.iter().try_fold(0, |len, property| Ok(len + property.encoded_len()?))
+}
+
+/// Write a property list of `len` bytes with its length prefix
+fn write_properties(w: &mut Writer<'_>, properties: &[Property], len: usize) {
+    w.varint(len);
+    for property in properties {
+        property.write(w);
+    }
+}
+
+// ============================================================================
+// Parsers
+// ============================================================================
+
+/// Parse a complete MQTT 5 packet from buffer
+/// Returns (packet, bytes_consumed) or error
+pub fn parse_packet(data: &[u8]) -> Result<(Packet, usize), ParseError> {
+    let (packet_type, header_len, total_len) = parse_fixed_header(data)?;
+    let first_byte = data[0];
+    let body = &data[header_len..total_len];
+
+    let packet = match packet_type {
+        PacketType::Connack => {
+            if body.len() < 2 {
+                return Err(ParseError::InvalidFormat);
+            }
+            // A 3.1.1 broker answers with a bare two-byte CONNACK
+            let properties = if body.len() > 2 { decode_properties(&body[2..])?.0 } else { Vec::new() };
+            Packet::Connack { session_present: body[0] & 0x01 != 0, reason: ReasonCode(body[1]), properties }
+        }
+        PacketType::Publish => {
+            let (publish, properties) = parse_publish_parts(first_byte, body)?;
+            Packet::Publish {
+                topic: String::from(publish.topic),
+                payload: publish.payload.to_vec(),
+                qos: publish.qos,
+                packet_id: publish.packet_id,
+                retain: publish.retain,
+                dup: publish.dup,
+                properties,
+            }
+        }
+        PacketType::Puback | PacketType::Pubrec | PacketType::Pubrel | PacketType::Pubcomp => {
+            let (packet_id, reason, properties) = parse_ack(body)?;
+            match packet_type {
+                PacketType::Puback => Packet::Puback { packet_id, reason, properties },
+                PacketType::Pubrec => Packet::Pubrec { packet_id, reason, properties },
+                PacketType::Pubrel => Packet::Pubrel { packet_id, reason, properties },
+                _ => Packet::Pubcomp { packet_id, reason, properties },
+            }
+        }
+        PacketType::Suback | PacketType::Unsuback => {
+            if body.len() < 3 {
+                return Err(ParseError::InvalidFormat);
+            }
+            let packet_id = u16::from_be_bytes([body[0], body[1]]);
+            let (properties, len) = decode_properties(&body[2..])?;
+            let reasons = body[2 + len..].iter().map(|&code| ReasonCode(code)).collect();
+            if packet_type == PacketType::Suback {
+                Packet::Suback { packet_id, reasons, properties }
+            } else {
+                Packet::Unsuback { packet_id, reasons, properties }
+            }
+        }
+        PacketType::Pingresp => Packet::Pingresp,
+        PacketType::Disconnect | PacketType::Auth => {
+            let (reason, properties) = match body.split_first() {
+                None => (ReasonCode::SUCCESS, Vec::new()),
+                Some((&code, [])) => (ReasonCode(code), Vec::new()),
+                Some((&code, rest)) => (ReasonCode(code), decode_properties(rest)?.0),
+            };
+            if packet_type == PacketType::Disconnect {
+                Packet::Disconnect { reason, properties }
+            } else {
+                Packet::Auth { reason, properties }
+            }
+        }
+        _ => return Err(ParseError::UnknownType),
+    };
+
+    Ok((packet, total_len))
+}
+
+/// Parse an MQTT 5 PUBLISH packet from buffer without copying topic or payload
+///
+/// The properties are skipped.
+pub fn parse_publish_ref(data: &[u8]) -> Result<(PublishRef<'_>, usize), ParseError> {
+    let (packet_type, header_len, total_len) = parse_fixed_header(data)?;
+    if packet_type != PacketType::Publish {
+        return Err(ParseError::InvalidFormat);
+    }
+    let (publish, _) = parse_publish_body(data[0], &data[header_len..total_len])?;
+    Ok((publish, total_len))
+}
+
+/// Decode a property list with its length prefix, returns (properties, bytes_consumed)
+pub fn decode_properties(data: &[u8]) -> Result<(Vec<Property>, usize), ParseError> {
+    let (len, len_bytes) = decode_remaining_length(data).map_err(truncated)?;
+    let mut rest = data.get(len_bytes..len_bytes + len).ok_or(ParseError::InvalidFormat)?;
+    let mut properties = Vec::new();
+    while !rest.is_empty() {
+        let (property, consumed) = Property::decode(rest)?;
+        properties.push(property);
+        rest = rest.get(consumed..).ok_or(ParseError::InvalidFormat)?;
+    }
+    Ok((properties, len_bytes + len))
+}
+
+fn parse_publish_parts(first_byte: u8, data: &[u8]) -> Result<(PublishRef<'_>, Vec<Property>), ParseError> {
+    let (publish, properties_at) = parse_publish_body(first_byte, data)?;
+    let (properties, _) = decode_properties(&data[properties_at..])?;
+    Ok((publish, properties))
+}
+
+/// Parse a PUBLISH body, returns (publish, offset of the property list)
+fn parse_publish_body(first_byte: u8, data: &[u8]) -> Result<(PublishRef<'_>, usize), ParseError> {
+    let qos = QoS::from_byte((first_byte >> 1) & 0x03).ok_or(ParseError::InvalidFormat)?;
+    let (topic, mut offset) = decode_str(data).map_err(truncated)?;
+
+    let packet_id = if qos != QoS::AtMostOnce {
+        let id = data.get(offset..offset + 2).ok_or(ParseError::InvalidFormat)?;
+        offset += 2;
+        Some(u16::from_be_bytes([id[0], id[1]]))
+    } else {
+        None
+    };
+
+    let properties_at = offset;
+    let (len, len_bytes) = decode_remaining_length(&data[offset..]).map_err(truncated)?;
+    offset += len_bytes + len;
+    let payload = data.get(offset..).ok_or(ParseError::InvalidFormat)?;
+
+    let publish = PublishRef {
+        topic,
+        payload,
+        qos,
+        packet_id,
+        retain: first_byte & 0x01 != 0,
+        dup: first_byte & 0x08 != 0,
+    };
+    Ok((publish, properties_at))
+}
+
+/// Parse a PUBACK/PUBREC/PUBREL/PUBCOMP body; reason and properties are optional
+fn parse_ack(data: &[u8]) -> Result<(u16, ReasonCode, Vec<Property>), ParseError> {
+    if data.len() < 2 {
+        return Err(ParseError::InvalidFormat);
+    }
+    let packet_id = u16::from_be_bytes([data[0], data[1]]);
+    let reason = data.get(2).map_or(ReasonCode::SUCCESS, |&code| ReasonCode(code));
+    let properties = if data.len() > 3 { decode_properties(&data[3..])?.0 } else { Vec::new() };
+    Ok((packet_id, reason, properties))
+}
+
+/// Decode length-prefixed binary data, returns (data, bytes_consumed)
+fn decode_binary(data: &[u8]) -> Result<(Vec<u8>, usize), ParseError> {
+    let len =
+        data.get(..2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize).ok_or(ParseError::InvalidFormat)?;
+    let bytes = data.get(2..2 + len).ok_or(ParseError::InvalidFormat)?;
+    Ok((bytes.to_vec(), 2 + len))
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn test_properties_round_trip() {
+        let properties = vec![
+            Property::PayloadFormatIndicator(1),
+            Property::MessageExpiryInterval(3600),
+            Property::ContentType(String::from("application/json")),
+            Property::CorrelationData(vec![1, 2, 3]),
+            Property::SubscriptionIdentifier(300),
+            Property::TopicAlias(7),
+            Property::UserProperty(String::from("session"), String::from("s1")),
+        ];
+        let mut buf = Vec::new();
+        encode_properties(&mut buf, &properties);
+        let (decoded, consumed) = decode_properties(&buf).unwrap();
+        assert_eq!(decoded, properties);
+        assert_eq!(consumed, buf.len());
+
+        // Unknown identifier
+        assert_eq!(decode_properties(&[0x02, 0x7F, 0x00]), Err(ParseError::InvalidFormat));
+        // Declared length runs past the data
+        assert_eq!(decode_properties(&[0x05, 0x01, 0x00]), Err(ParseError::InvalidFormat));
+    }
+
+    #[test]
+    fn test_publish_round_trip() {
+        let packet = build_publish(
+            "ccr/events",
+            b"{}",
+            QoS::ExactlyOnce,
+            Some(42),
+            true,
+            &[Property::ResponseTopic(String::from("ccr/reply"))],
+        );
+        let (parsed, consumed) = parse_packet(&packet).unwrap();
+        assert_eq!(consumed, packet.len());
+        match parsed {
+            Packet::Publish { topic, payload, qos, packet_id, retain, properties, .. } => {
+                assert_eq!(topic, "ccr/events");
+                assert_eq!(payload, b"{}");
+                assert_eq!(qos, QoS::ExactlyOnce);
+                assert_eq!(packet_id, Some(42));
+                assert!(retain);
+                assert_eq!(properties, [Property::ResponseTopic(String::from("ccr/reply"))]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let (publish, _) = parse_publish_ref(&packet).unwrap();
+        assert_eq!(publish.payload, b"{}");
+    }
+
+    #[test]
+    fn test_acks_with_and_without_reason() {
+        // Short form is identical to 3.1.1
+        assert_eq!(build_ack(PacketType::Puback, 5, ReasonCode::SUCCESS, &[]), [0x40, 0x02, 0x00, 0x05]);
+        assert!(matches!(
+            parse_packet(&[0x40, 0x02, 0x00, 0x05]).unwrap().0,
+            Packet::Puback { packet_id: 5, reason: ReasonCode::SUCCESS, .. }
+        ));
+
+        let pubrec = build_ack(
+            PacketType::Pubrec,
+            6,
+            ReasonCode::QUOTA_EXCEEDED,
+            &[Property::ReasonString(String::from("slow down"))],
+        );
+        match parse_packet(&pubrec).unwrap().0 {
+            Packet::Pubrec { packet_id, reason, properties } => {
+                assert_eq!(packet_id, 6);
+                assert!(reason.is_error());
+                assert_eq!(properties, [Property::ReasonString(String::from("slow down"))]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(build_ack(PacketType::Pubrel, 6, ReasonCode::SUCCESS, &[])[0], 0x62);
+    }
+
+    #[test]
+    fn test_connack_suback_and_disconnect() {
+        // Session present, success, server keep alive 30s
+        let (packet, _) = parse_packet(&[0x20, 0x06, 0x01, 0x00, 0x03, 0x13, 0x00, 0x1E]).unwrap();
+        assert!(matches!(
+            packet,
+            Packet::Connack { session_present: true, reason: ReasonCode::SUCCESS, ref properties }
+                if properties == &[Property::ServerKeepAlive(30)]
+        ));
+        // 3.1.1 broker refusing protocol level 5
+        assert!(matches!(
+            parse_packet(&[0x20, 0x02, 0x00, 0x01]).unwrap().0,
+            Packet::Connack { reason: ReasonCode(0x01), .. }
+        ));
+
+        let (packet, _) = parse_packet(&[0x90, 0x05, 0x00, 0x03, 0x00, 0x01, 0x8F]).unwrap();
+        assert!(matches!(
+            packet,
+            Packet::Suback { packet_id: 3, ref reasons, .. }
+                if reasons == &[ReasonCode::GRANTED_QOS_1, ReasonCode::TOPIC_FILTER_INVALID]
+        ));
+
+        let disconnect = build_disconnect(ReasonCode::SESSION_TAKEN_OVER, &[]);
+        assert!(matches!(
+            parse_packet(&disconnect).unwrap().0,
+            Packet::Disconnect { reason: ReasonCode::SESSION_TAKEN_OVER, .. }
+        ));
+        assert_eq!(build_disconnect(ReasonCode::SUCCESS, &[]), [0xE0, 0x00]);
+    }
+
+    #[test]
+    fn test_into_matches_vec_builders() {
+        let mut buf = [0u8; 256];
+        let properties = [
+            Property::MessageExpiryInterval(30),
+            Property::SubscriptionIdentifier(300),
+            Property::UserProperty(String::from("session"), String::from("s1")),
+            Property::CorrelationData(vec![1, 2, 3]),
+        ];
+        let will = Will { topic: "ccr/status", payload: b"offline", qos: QoS::AtLeastOnce, retain: false };
+
+        let len =
+            build_connect_into(&mut buf, "c", Some("u"), Some(b"p"), false, 60, Some(&will), &properties)
+                .unwrap();
+        assert_eq!(
+            buf[..len],
+            build_connect("c", Some("u"), Some(b"p"), false, 60, Some(&will), &properties)[..]
+        );
+        let payload = [7; 100];
+        let len = build_publish_into(&mut buf, "t", &payload, QoS::AtLeastOnce, Some(3), true, &properties)
+            .unwrap();
+        assert_eq!(
+            buf[..len],
+            build_publish("t", &payload, QoS::AtLeastOnce, Some(3), true, &properties)[..]
+        );
+        let len = build_subscribe_into(&mut buf, 4, &[("a/#", QoS::ExactlyOnce)], &properties).unwrap();
+        assert_eq!(buf[..len], build_subscribe(4, &[("a/#", QoS::ExactlyOnce)], &properties)[..]);
+        let len = build_unsubscribe_into(&mut buf, 5, &["a/#"], &[]).unwrap();
+        assert_eq!(buf[..len], build_unsubscribe(5, &["a/#"], &[])[..]);
+
+        let cases = [(ReasonCode::SUCCESS, &[][..]), (ReasonCode::QUOTA_EXCEEDED, &properties[..])];
+        for (reason, properties) in cases {
+            let len = build_ack_into(&mut buf, PacketType::Pubrel, 6, reason, properties).unwrap();
+            assert_eq!(buf[..len], build_ack(PacketType::Pubrel, 6, reason, properties)[..]);
+            let len = build_disconnect_into(&mut buf, reason, properties).unwrap();
+            assert_eq!(buf[..len], build_disconnect(reason, properties)[..]);
+        }
+        assert_eq!(
+            build_auth_into(&mut buf[..2], ReasonCode::SUCCESS, &properties),
+            Err(EncodeError::BufferTooSmall { needed: build_auth(ReasonCode::SUCCESS, &properties).len() })
+        );
+    }
+
+    #[test]
+    fn test_connect_and_subscribe_layout() {
+        let connect = build_connect("c", None, None, true, 60, None, &[Property::SessionExpiryInterval(0)]);
+        // Protocol name, level 5, clean start, keep alive, 5 bytes of properties, client id
+        assert_eq!(
+            &connect[2..],
+            &[0, 4, b'M', b'Q', b'T', b'T', 5, 0x02, 0, 60, 5, 0x11, 0, 0, 0, 0, 0, 1, b'c']
+        );
+
+        let subscribe = build_subscribe(1, &[("a", QoS::AtLeastOnce)], &[]);
+        assert_eq!(subscribe, [0x82, 0x07, 0x00, 0x01, 0x00, 0x00, 0x01, b'a', 0x01]);
+    }
+}
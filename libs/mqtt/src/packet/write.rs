@@ -0,0 +1,408 @@
+//! MQTT packet builders writing into a caller's buffer
+//!
+//! Each `build_*_into` encodes the same bytes as its `Vec` counterpart into
+//! the front of `buf` and returns the packet's length, so a hot path such as
+//! a periodic publish can reuse one buffer instead of allocating per packet.
+//! The packet is sized before anything is written; if `buf` is too short the
+//! result is [`EncodeError::BufferTooSmall`] with the size needed, and `buf`
+//! is left untouched.
+
+use super::{EncodeError, MAX_REMAINING_LEN, PacketType, ProtocolVersion, QoS, Will, packet_len};
+
+/// Build MQTT CONNECT packet into `buf`
+pub fn build_connect_into(buf: &mut [u8], client_id: &str) -> Result<usize, EncodeError> {
+    build_connect_with_will_into(buf, client_id, None, None, true, 60, None)
+}
+
+/// Build MQTT CONNECT packet with full options into `buf`
+pub fn build_connect_with_options_into(
+    buf: &mut [u8],
+    client_id: &str,
+    username: Option<&str>,
+    password: Option<&[u8]>,
+    clean_session: bool,
+    keep_alive_secs: u16,
+) -> Result<usize, EncodeError> {
+    build_connect_with_will_into(buf, client_id, username, password, clean_session, keep_alive_secs, None)
+}
+
+/// Build MQTT CONNECT packet with full options and an optional Last Will into `buf`
+pub fn build_connect_with_will_into(
+    buf: &mut [u8],
+    client_id: &str,
+    username: Option<&str>,
+    password: Option<&[u8]>,
+    clean_session: bool,
+    keep_alive_secs: u16,
+    will: Option<&Will<'_>>,
+) -> Result<usize, EncodeError> {
+    let protocol = ProtocolVersion::V311;
+    build_connect_at_into(buf, protocol, client_id, username, password, clean_session, keep_alive_secs, will)
+}
+
+/// Build an MQTT 3.1 CONNECT (`MQIsdp`, level 3) into `buf`
+pub fn build_connect_v31_into(
+    buf: &mut [u8],
+    client_id: &str,
+    username: Option<&str>,
+    password: Option<&[u8]>,
+    clean_session: bool,
+    keep_alive_secs: u16,
+    will: Option<&Will<'_>>,
+) -> Result<usize, EncodeError> {
+    let protocol = ProtocolVersion::V31;
+    build_connect_at_into(buf, protocol, client_id, username, password, clean_session, keep_alive_secs, will)
+}
+
+/// CONNECT naming `protocol`, which must be 3.1 or 3.1.1, into `buf`
+#[allow(clippy::too_many_arguments)]
+fn build_connect_at_into(
+    buf: &mut [u8],
+    protocol: ProtocolVersion,
+    client_id: &str,
+    username: Option<&str>,
+    password: Option<&[u8]>,
+    clean_session: bool,
+    keep_alive_secs: u16,
+    will: Option<&Will<'_>>,
+) -> Result<usize, EncodeError> {
+    // Protocol name, level, flags and keep-alive, then the client id
+    let name = protocol.name();
+    let mut remaining_len = field_len(name.len())? + 4 + field_len(client_id.len())?;
+    if let Some(will) = will {
+        remaining_len += field_len(will.topic.len())? + field_len(will.payload.len())?;
+    }
+    if let Some(user) = username {
+        remaining_len += field_len(user.len())?;
+    }
+    if let Some(pass) = password {
+        remaining_len += field_len(pass.len())?;
+    }
+
+    let mut w = Writer::start(buf, (PacketType::Connect as u8) << 4, remaining_len)?;
+    w.bytes(name.as_bytes());
+    w.u8(protocol as u8);
+    w.u8(connect_flags(username, password, clean_session, will));
+    w.u16(keep_alive_secs);
+    w.bytes(client_id.as_bytes());
+    if let Some(will) = will {
+        w.bytes(will.topic.as_bytes());
+        w.bytes(will.payload);
+    }
+    if let Some(user) = username {
+        w.bytes(user.as_bytes());
+    }
+    if let Some(pass) = password {
+        w.bytes(pass);
+    }
+    Ok(w.finish())
+}
+
+/// Build MQTT SUBSCRIBE packet into `buf`
+pub fn build_subscribe_into(
+    buf: &mut [u8],
+    packet_id: u16,
+    topic: &str,
+    qos: QoS,
+) -> Result<usize, EncodeError> {
+    build_subscribe_many_into(buf, packet_id, &[(topic, qos)])
+}
+
+/// Build MQTT SUBSCRIBE packet requesting several topic filters at once into `buf`
+pub fn build_subscribe_many_into(
+    buf: &mut [u8],
+    packet_id: u16,
+    topics: &[(&str, QoS)],
+) -> Result<usize, EncodeError> {
+    let mut remaining_len = 2;
+    for (topic, _) in topics {
+        remaining_len += field_len(topic.len())? + 1;
+    }
+
+    let mut w = Writer::start(buf, ((PacketType::Subscribe as u8) << 4) | 0x02, remaining_len)?;
+    w.u16(packet_id);
+    for &(topic, qos) in topics {
+        w.bytes(topic.as_bytes());
+        w.u8(qos as u8);
+    }
+    Ok(w.finish())
+}
+
+/// Build MQTT UNSUBSCRIBE packet into `buf`
+pub fn build_unsubscribe_into(buf: &mut [u8], packet_id: u16, topic: &str) -> Result<usize, EncodeError> {
+    build_unsubscribe_many_into(buf, packet_id, &[topic])
+}
+
+/// Build MQTT UNSUBSCRIBE packet removing several topic filters at once into `buf`
+pub fn build_unsubscribe_many_into(
+    buf: &mut [u8],
+    packet_id: u16,
+    topics: &[&str],
+) -> Result<usize, EncodeError> {
+    let mut remaining_len = 2;
+    for topic in topics {
+        remaining_len += field_len(topic.len())?;
+    }
+
+    let mut w = Writer::start(buf, ((PacketType::Unsubscribe as u8) << 4) | 0x02, remaining_len)?;
+    w.u16(packet_id);
+    for topic in topics {
+        w.bytes(topic.as_bytes());
+    }
+    Ok(w.finish())
+}
+
+/// Build MQTT PUBLISH packet (QoS 0) into `buf`
+pub fn build_publish_into(
+    buf: &mut [u8],
+    topic: &str,
+    payload: &[u8],
+    qos: QoS,
+) -> Result<usize, EncodeError> {
+    build_publish_with_id_into(buf, topic, payload, qos, None, false)
+}
+
+/// Build MQTT PUBLISH packet with packet ID (for QoS 1/2) into `buf`
+pub fn build_publish_with_id_into(
+    buf: &mut [u8],
+    topic: &str,
+    payload: &[u8],
+    qos: QoS,
+    packet_id: Option<u16>,
+    retain: bool,
+) -> Result<usize, EncodeError> {
+    let remaining_len = field_len(topic.len())? + if packet_id.is_some() { 2 } else { 0 } + payload.len();
+
+    let mut w = Writer::start(buf, publish_flags(qos, retain), remaining_len)?;
+    w.bytes(topic.as_bytes());
+    if let Some(id) = packet_id {
+        w.u16(id);
+    }
+    w.raw(payload);
+    Ok(w.finish())
+}
+
+/// Build MQTT PUBACK packet (QoS 1 acknowledgment) into `buf`
+pub fn build_puback_into(buf: &mut [u8], packet_id: u16) -> Result<usize, EncodeError> {
+    build_ack_into(buf, (PacketType::Puback as u8) << 4, packet_id)
+}
+
+/// Build MQTT PUBREC packet (QoS 2 step 1) into `buf`
+pub fn build_pubrec_into(buf: &mut [u8], packet_id: u16) -> Result<usize, EncodeError> {
+    build_ack_into(buf, (PacketType::Pubrec as u8) << 4, packet_id)
+}
+
+/// Build MQTT PUBREL packet (QoS 2 step 2) into `buf`
+pub fn build_pubrel_into(buf: &mut [u8], packet_id: u16) -> Result<usize, EncodeError> {
+    build_ack_into(buf, ((PacketType::Pubrel as u8) << 4) | 0x02, packet_id)
+}
+
+/// Build MQTT PUBCOMP packet (QoS 2 step 3) into `buf`
+pub fn build_pubcomp_into(buf: &mut [u8], packet_id: u16) -> Result<usize, EncodeError> {
+    build_ack_into(buf, (PacketType::Pubcomp as u8) << 4, packet_id)
+}
+
+/// Build MQTT PINGREQ packet into `buf`
+pub fn build_pingreq_into(buf: &mut [u8]) -> Result<usize, EncodeError> {
+    Ok(Writer::start(buf, (PacketType::Pingreq as u8) << 4, 0)?.finish())
+}
+
+/// Build MQTT DISCONNECT packet into `buf`
+pub fn build_disconnect_into(buf: &mut [u8]) -> Result<usize, EncodeError> {
+    Ok(Writer::start(buf, (PacketType::Disconnect as u8) << 4, 0)?.finish())
+}
+
+// ============================================================================
+// Helper Functions
+// ============================================================================
+
+/// PUBACK, PUBREC, PUBREL and PUBCOMP: fixed header and packet id
+fn build_ack_into(buf: &mut [u8], first_byte: u8, packet_id: u16) -> Result<usize, EncodeError> {
+    let mut w = Writer::start(buf, first_byte, 2)?;
+    w.u16(packet_id);
+    Ok(w.finish())
+}
+
+/// CONNECT flags byte, the same at both protocol levels
+pub(super) fn connect_flags(
+    username: Option<&str>,
+    password: Option<&[u8]>,
+    clean_session: bool,
+    will: Option<&Will<'_>>,
+) -> u8 {
+    let mut flags: u8 = 0;
+    if clean_session {
+        flags |= 0x02;
+    }
+    if let Some(will) = will {
+        flags |= 0x04 | ((will.qos as u8) << 3);
+        if will.retain {
+            flags |= 0x20;
+        }
+    }
+    if username.is_some() {
+        flags |= 0x80;
+    }
+    if password.is_some() {
+        flags |= 0x40;
+    }
+    flags
+}
+
+/// PUBLISH fixed header byte
+pub(super) fn publish_flags(qos: QoS, retain: bool) -> u8 {
+    ((PacketType::Publish as u8) << 4) | ((qos as u8) << 1) | retain as u8
+}
+
+/// Encoded size of a length-prefixed string or binary field
+pub(super) fn field_len(len: usize) -> Result<usize, EncodeError> {
+    if len > u16::MAX as usize {
+        return Err(EncodeError::FieldTooLong);
+    }
+    Ok(2 + len)
+}
+
+/// Encoded size of a variable byte integer
+#[cfg(feature = "mqtt5")]
+pub(super) const fn varint_len(value: usize) -> usize { packet_len(value) - value - 1 }
+
+/// Writes one packet into the front of a buffer known to hold it
+pub(super) struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    /// Check `buf` holds a body of `remaining_len` bytes and write the fixed header
+    pub(super) fn start(
+        buf: &'a mut [u8],
+        first_byte: u8,
+        remaining_len: usize,
+    ) -> Result<Self, EncodeError> {
+        if remaining_len > MAX_REMAINING_LEN {
+            return Err(EncodeError::PacketTooLarge);
+        }
+        let needed = packet_len(remaining_len);
+        if buf.len() < needed {
+            return Err(EncodeError::BufferTooSmall { needed });
+        }
+        let mut w = Writer { buf: &mut buf[..needed], pos: 0 };
+        w.u8(first_byte);
+        w.varint(remaining_len);
+        Ok(w)
+    }
+
+    pub(super) fn u8(&mut self, byte: u8) {
+        self.buf[self.pos] = byte;
+        self.pos += 1;
+    }
+
+    pub(super) fn u16(&mut self, value: u16) { self.raw(&value.to_be_bytes()); }
+
+    #[cfg(feature = "mqtt5")]
+    pub(super) fn u32(&mut self, value: u32) { self.raw(&value.to_be_bytes()); }
+
+    /// Variable byte integer, as used for the remaining length
+    pub(super) fn varint(&mut self, mut value: usize) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.u8(byte);
+                return;
+            }
+            self.u8(byte | 0x80);
+        }
+    }
+
+    /// Length-prefixed field; the caller has checked it with `field_len`
+    pub(super) fn bytes(&mut self, data: &[u8]) {
+        self.u16(data.len() as u16);
+        self.raw(data);
+    }
+
+    pub(super) fn raw(&mut self, data: &[u8]) {
+        self.buf[self.pos..self.pos + data.len()].copy_from_slice(data);
+        self.pos += data.len();
+    }
+
+    /// Length of the packet written
+    pub(super) fn finish(self) -> usize {
+        debug_assert_eq!(self.pos, self.buf.len(), "packet sized wrongly");
+        self.pos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "encode")]
+    #[test]
+    fn test_matches_vec_builders() {
+        use crate::packet;
+
+        let mut buf = [0u8; 256];
+        let will = Will { topic: "ccr/status", payload: b"offline", qos: QoS::AtLeastOnce, retain: true };
+        let len = build_connect_with_will_into(
+            &mut buf,
+            "dev",
+            Some("user"),
+            Some(b"pass"),
+            false,
+            30,
+            Some(&will),
+        );
+        assert_eq!(
+            buf[..len.unwrap()],
+            packet::build_connect_with_will("dev", Some("user"), Some(b"pass"), false, 30, Some(&will))[..]
+        );
+        let len = build_connect_v31_into(&mut buf, "dev", None, None, true, 30, Some(&will)).unwrap();
+        assert_eq!(buf[..len], packet::build_connect_v31("dev", None, None, true, 30, Some(&will))[..]);
+
+        let topics = [("ccr/#", QoS::AtLeastOnce), ("a/+", QoS::ExactlyOnce)];
+        let len = build_subscribe_many_into(&mut buf, 7, &topics).unwrap();
+        assert_eq!(buf[..len], packet::build_subscribe_many(7, &topics)[..]);
+        let len = build_unsubscribe_many_into(&mut buf, 8, &["ccr/#", "a/+"]).unwrap();
+        assert_eq!(buf[..len], packet::build_unsubscribe_many(8, &["ccr/#", "a/+"])[..]);
+
+        // Long enough for a two-byte remaining length
+        let payload = [0x5a; 200];
+        let len =
+            build_publish_with_id_into(&mut buf, "ccr/x", &payload, QoS::ExactlyOnce, Some(9), true).unwrap();
+        assert_eq!(
+            buf[..len],
+            packet::build_publish_with_id("ccr/x", &payload, QoS::ExactlyOnce, Some(9), true)[..]
+        );
+
+        let len = build_pubrel_into(&mut buf, 0x1234).unwrap();
+        assert_eq!(buf[..len], packet::build_pubrel(0x1234)[..]);
+        let len = build_pingreq_into(&mut buf).unwrap();
+        assert_eq!(buf[..len], packet::build_pingreq()[..]);
+    }
+
+    #[test]
+    fn test_buffer_checked_first() {
+        let mut buf = [0xffu8; 16];
+        // 2 + 6 bytes of topic and 6 of payload, behind a two-byte fixed header
+        assert_eq!(build_publish_into(&mut buf, "status", b"online", QoS::AtMostOnce), Ok(16));
+        buf = [0xff; 16];
+        assert_eq!(
+            build_publish_into(&mut buf[..15], "status", b"online", QoS::AtMostOnce),
+            Err(EncodeError::BufferTooSmall { needed: 16 })
+        );
+        assert_eq!(build_puback_into(&mut buf[..3], 1), Err(EncodeError::BufferTooSmall { needed: 4 }));
+        assert_eq!(buf, [0xff; 16]);
+
+        let long = [b'a'; 65_536];
+        let topic = core::str::from_utf8(&long).unwrap();
+        assert_eq!(build_subscribe_into(&mut buf, 1, topic, QoS::AtMostOnce), Err(EncodeError::FieldTooLong));
+    }
+
+    #[cfg(feature = "mqtt5")]
+    #[test]
+    fn test_varint_len() {
+        assert_eq!(varint_len(127), 1);
+        assert_eq!(varint_len(16_384), 3);
+    }
+}
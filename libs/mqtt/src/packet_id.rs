@@ -0,0 +1,105 @@
+//! Packet Identifiers
+//!
+//! SUBSCRIBE, UNSUBSCRIBE and QoS 1/2 PUBLISH packets carry a 16-bit packet
+//! id that stays bound to the exchange until the broker acknowledges it.
+//! Reusing an id early lets the acknowledgement of the old packet complete the
+//! new one, so [`PacketIdAllocator`] hands ids out in turn, skipping those
+//! still outstanding, and refuses once all 65535 are taken.
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+/// Number of usable packet ids; 0 is reserved
+const ID_COUNT: usize = u16::MAX as usize;
+
+/// Hands out packet ids not currently in use
+pub struct PacketIdAllocator {
+    next: u16,
+    /// Outstanding ids, sorted
+    in_use: Vec<u16>,
+}
+
+impl Default for PacketIdAllocator {
+    fn default() -> Self { Self { next: 1, in_use: Vec::new() } }
+}
+
+impl PacketIdAllocator {
+    pub fn new() -> Self { Self::default() }
+
+    /// Take the next free id, or `None` if every id is outstanding
+    pub fn allocate(&mut self) -> Option<u16> {
+        if self.in_use.len() == ID_COUNT {
+            return None;
+        }
+        loop {
+            let id = self.next;
+            self.next = if id == u16::MAX { 1 } else { id + 1 };
+            if let Err(index) = self.in_use.binary_search(&id) {
+                self.in_use.insert(index, id);
+                return Some(id);
+            }
+        }
+    }
+
+    /// Return `id` once its exchange is over; false if it wasn't outstanding
+    pub fn release(&mut self, id: u16) -> bool {
+        match self.in_use.binary_search(&id) {
+            Ok(index) => {
+                self.in_use.remove(index);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn is_in_use(&self, id: u16) -> bool { self.in_use.binary_search(&id).is_ok() }
+
+    /// Keep only the outstanding ids for which `keep` returns true
+    pub fn retain(&mut self, keep: impl FnMut(&u16) -> bool) { self.in_use.retain(keep); }
+
+    /// Release every id, e.g. when a clean session starts
+    pub fn clear(&mut self) { self.in_use.clear(); }
+
+    pub fn len(&self) -> usize { self.in_use.len() }
+
+    pub fn is_empty(&self) -> bool { self.in_use.is_empty() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skips_outstanding_ids() {
+        let mut ids = PacketIdAllocator::new();
+        assert_eq!(ids.allocate(), Some(1));
+        assert_eq!(ids.allocate(), Some(2));
+        assert_eq!(ids.allocate(), Some(3));
+        assert!(ids.release(2));
+        assert!(!ids.release(2));
+
+        // Wrap around past 0; 1 and 3 are still outstanding
+        ids.next = u16::MAX;
+        assert_eq!(ids.allocate(), Some(u16::MAX));
+        assert_eq!(ids.allocate(), Some(2));
+        assert_eq!(ids.allocate(), Some(4));
+        assert!(ids.is_in_use(3));
+
+        ids.retain(|&id| id < 4);
+        assert_eq!(ids.len(), 3);
+        assert!(!ids.is_in_use(4));
+    }
+
+    #[test]
+    fn test_exhausted() {
+        let mut ids = PacketIdAllocator::new();
+        for _ in 0..ID_COUNT {
+            assert!(ids.allocate().is_some());
+        }
+        assert_eq!(ids.allocate(), None);
+        ids.release(1234);
+        assert_eq!(ids.allocate(), Some(1234));
+        ids.clear();
+        assert!(ids.is_empty());
+    }
+}
@@ -0,0 +1,118 @@
+//! At-Least-Once Delivery
+//!
+//! Outbound QoS 1 state: every PUBLISH that expects a PUBACK is kept in an
+//! [`InflightStore`] under its packet id until the broker acknowledges it.
+//! Entries that stay unacknowledged for longer than the retry interval are
+//! handed back for re-sending with the DUP flag set, and all of them are
+//! re-sent when a session resumes after a reconnect.
+//!
+//! The QoS 2 sender ([`crate::qos2::Qos2Sender`]) builds on the same store.
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::packet::PacketType;
+
+/// DUP flag in the first byte of a PUBLISH
+const DUP_FLAG: u8 = 0x08;
+
+/// Packet awaiting acknowledgement
+struct Entry {
+    packet_id: u16,
+    packet: Vec<u8>,
+    sent_ms: u64,
+}
+
+/// Unacknowledged outbound packets keyed by packet id, oldest first
+#[derive(Default)]
+pub struct InflightStore {
+    entries: Vec<Entry>,
+}
+
+impl InflightStore {
+    pub fn new() -> Self { Self::default() }
+
+    /// Track `packet`, sent at `now`, until `packet_id` is acknowledged
+    pub fn insert(&mut self, packet_id: u16, packet: Vec<u8>, now: u64) {
+        self.entries.retain(|e| e.packet_id != packet_id);
+        self.entries.push(Entry { packet_id, packet, sent_ms: now });
+    }
+
+    /// Replace the packet tracked under `packet_id`, e.g. PUBLISH with PUBREL
+    ///
+    /// Returns false if nothing is in flight under that id.
+    pub fn replace(&mut self, packet_id: u16, packet: Vec<u8>, now: u64) -> bool {
+        match self.entries.iter_mut().find(|e| e.packet_id == packet_id) {
+            Some(entry) => {
+                entry.packet = packet;
+                entry.sent_ms = now;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Correlate an acknowledgement, returning false if `packet_id` wasn't in flight
+    pub fn ack(&mut self, packet_id: u16) -> bool {
+        match self.entries.iter().position(|e| e.packet_id == packet_id) {
+            Some(pos) => {
+                self.entries.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether `packet_id` is awaiting acknowledgement
+    pub fn contains(&self, packet_id: u16) -> bool { self.entries.iter().any(|e| e.packet_id == packet_id) }
+
+    /// Collect packets unacknowledged for `retry_ms` or longer (all of them if
+    /// `all`), marking PUBLISHes as duplicates and restarting their timers
+    pub fn due(&mut self, now: u64, retry_ms: u64, all: bool) -> Vec<Vec<u8>> {
+        let mut due = Vec::new();
+        for entry in self.entries.iter_mut() {
+            if all || now.saturating_sub(entry.sent_ms) >= retry_ms {
+                if PacketType::from_byte(entry.packet[0]) == Some(PacketType::Publish) {
+                    entry.packet[0] |= DUP_FLAG;
+                }
+                entry.sent_ms = now;
+                due.push(entry.packet.clone());
+            }
+        }
+        due
+    }
+
+    /// Forget everything, e.g. when a clean session starts
+    pub fn clear(&mut self) { self.entries.clear(); }
+
+    pub fn len(&self) -> usize { self.entries.len() }
+
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(all(test, feature = "encode"))]
+mod tests {
+    use super::*;
+    use crate::packet::{QoS, build_publish_with_id};
+
+    #[test]
+    fn test_retry_sets_dup_and_ack_correlates() {
+        let mut store = InflightStore::new();
+        store.insert(1, build_publish_with_id("a", b"1", QoS::AtLeastOnce, Some(1), false), 0);
+        store.insert(2, build_publish_with_id("b", b"2", QoS::AtLeastOnce, Some(2), false), 500);
+
+        assert!(store.due(900, 1000, false).is_empty());
+        let due = store.due(1000, 1000, false);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0][0] & DUP_FLAG, DUP_FLAG);
+
+        assert!(store.ack(1));
+        assert!(!store.ack(1));
+        assert!(!store.contains(1));
+        assert_eq!(store.due(1000, 1000, true).len(), 1);
+    }
+}
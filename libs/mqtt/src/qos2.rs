@@ -0,0 +1,330 @@
+//! Exactly-Once Delivery
+//!
+//! Both halves of the QoS 2 handshake, tracked per packet id.
+//!
+//! Sender ([`Qos2Sender`]):
+//!
+//! ```text
+//! PUBLISH ──▶ AwaitingPubrec ──PUBREC──▶ PUBREL ──▶ AwaitingPubcomp ──PUBCOMP──▶ done
+//! ```
+//!
+//! Until PUBREC arrives the PUBLISH is retried with DUP set; after that only
+//! the PUBREL is retried, since the broker already owns the message.
+//!
+//! Receiver ([`Qos2Receiver`]):
+//!
+//! ```text
+//! PUBLISH ──▶ deliver, PUBREC ──▶ AwaitingPubrel ──PUBREL──▶ PUBCOMP, done
+//! ```
+//!
+//! A PUBLISH that arrives again for an id in `AwaitingPubrel` is a
+//! redelivery and is acknowledged without being delivered a second time.
+//! The set of such ids is what the client persists through its
+//! [`SessionStore`](crate::session::SessionStore).
+//!
+//! A broker that never sends PUBREL would grow that set without end, so it
+//! holds at most `max_pending` ids; [`Eviction`] decides what gives when a
+//! new PUBLISH arrives at the limit.
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::packet;
+use crate::qos1::InflightStore;
+
+/// Where an outbound QoS 2 exchange stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SenderState {
+    /// PUBLISH sent, waiting for PUBREC
+    AwaitingPubrec,
+    /// PUBREL sent, waiting for PUBCOMP
+    AwaitingPubcomp,
+}
+
+/// Outbound QoS 2 exchanges
+#[derive(Default)]
+pub struct Qos2Sender {
+    /// PUBLISH or PUBREL to retry for each exchange
+    inflight: InflightStore,
+    /// Exchanges that have reached `AwaitingPubcomp`
+    released: Vec<u16>,
+}
+
+impl Qos2Sender {
+    pub fn new() -> Self { Self::default() }
+
+    /// Start an exchange for a PUBLISH sent at `now`
+    pub fn publish(&mut self, packet_id: u16, publish: Vec<u8>, now: u64) {
+        self.released.retain(|&id| id != packet_id);
+        self.inflight.insert(packet_id, publish, now);
+    }
+
+    /// Handle PUBREC, returning the PUBREL to send
+    ///
+    /// A PUBREL is returned even for an unknown id so the broker can discard
+    /// its state, as the protocol requires.
+    pub fn on_pubrec(&mut self, packet_id: u16, now: u64) -> Vec<u8> {
+        let pubrel = packet::build_pubrel(packet_id);
+        if self.inflight.replace(packet_id, pubrel.clone(), now) {
+            if !self.released.contains(&packet_id) {
+                self.released.push(packet_id);
+            }
+        } else {
+            log::warn!("MQTT: PUBREC for unknown packet id {}", packet_id);
+        }
+        pubrel
+    }
+
+    /// Handle PUBCOMP, returning false if no exchange was awaiting it
+    pub fn on_pubcomp(&mut self, packet_id: u16) -> bool {
+        if !self.released.contains(&packet_id) {
+            return false;
+        }
+        self.released.retain(|&id| id != packet_id);
+        self.inflight.ack(packet_id)
+    }
+
+    /// Drop an exchange the receiver refused with an MQTT 5 error reason in
+    /// PUBREC; no PUBREL follows. Returns false if none was awaiting PUBREC.
+    pub fn abandon(&mut self, packet_id: u16) -> bool {
+        if self.released.contains(&packet_id) {
+            return false;
+        }
+        self.inflight.ack(packet_id)
+    }
+
+    /// Drop the exchange for `packet_id` whichever acknowledgement it awaits,
+    /// e.g. when the broker never answered. Returns false if none was open.
+    pub fn forget(&mut self, packet_id: u16) -> bool {
+        self.released.retain(|&id| id != packet_id);
+        self.inflight.ack(packet_id)
+    }
+
+    /// Drop every exchange awaiting PUBCOMP, e.g. when the broker lost the
+    /// session that held them, returning their ids
+    pub fn forget_released(&mut self) -> Vec<u16> {
+        let released = core::mem::take(&mut self.released);
+        for &packet_id in &released {
+            self.inflight.ack(packet_id);
+        }
+        released
+    }
+
+    /// Where the exchange for `packet_id` stands, if one is open
+    pub fn state(&self, packet_id: u16) -> Option<SenderState> {
+        if !self.inflight.contains(packet_id) {
+            None
+        } else if self.released.contains(&packet_id) {
+            Some(SenderState::AwaitingPubcomp)
+        } else {
+            Some(SenderState::AwaitingPubrec)
+        }
+    }
+
+    /// Packets to retry, as for [`InflightStore::due`]
+    pub fn due(&mut self, now: u64, retry_ms: u64, all: bool) -> Vec<Vec<u8>> {
+        self.inflight.due(now, retry_ms, all)
+    }
+
+    pub fn contains(&self, packet_id: u16) -> bool { self.inflight.contains(packet_id) }
+
+    /// Number of exchanges under way
+    pub fn len(&self) -> usize { self.inflight.len() }
+
+    pub fn is_empty(&self) -> bool { self.inflight.is_empty() }
+
+    /// Abandon all exchanges, e.g. when a clean session starts
+    pub fn clear(&mut self) {
+        self.inflight.clear();
+        self.released.clear();
+    }
+}
+
+/// Default limit on inbound exchanges awaiting PUBREL
+pub const DEFAULT_MAX_PENDING: usize = 32;
+
+/// What the receiver does with a new PUBLISH when it is at its limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Eviction {
+    /// Forget the id that has waited longest for PUBREL. The broker has most
+    /// likely abandoned that exchange; if it hasn't, its redelivery is
+    /// delivered a second time.
+    #[default]
+    Oldest,
+    /// Neither deliver nor acknowledge the new PUBLISH, so the broker sends
+    /// it again later (on MQTT 3.1.1, after a reconnect). Nothing is
+    /// delivered twice, but the message is delayed.
+    Refuse,
+}
+
+/// Outcome of an inbound QoS 2 PUBLISH
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inbound {
+    /// New message: send PUBREC and deliver it
+    Deliver,
+    /// Redelivery of a message already delivered: send PUBREC only
+    Duplicate,
+    /// At the limit under [`Eviction::Refuse`]: send nothing
+    Refused,
+}
+
+/// Inbound message waiting for PUBREL
+struct Received {
+    packet_id: u16,
+    /// When PUBREC was last sent
+    sent_ms: u64,
+}
+
+/// Inbound QoS 2 exchanges
+pub struct Qos2Receiver {
+    /// Oldest first
+    pending: Vec<Received>,
+    max_pending: usize,
+    eviction: Eviction,
+}
+
+impl Default for Qos2Receiver {
+    fn default() -> Self {
+        Self { pending: Vec::new(), max_pending: DEFAULT_MAX_PENDING, eviction: Eviction::default() }
+    }
+}
+
+impl Qos2Receiver {
+    /// Resume with the ids saved by a previous run
+    pub fn with_pending(packet_ids: &[u16], now: u64) -> Self {
+        let pending = packet_ids.iter().map(|&packet_id| Received { packet_id, sent_ms: now }).collect();
+        Self { pending, ..Default::default() }
+    }
+
+    /// Hold at most `max_pending` ids (at least one), making room as `eviction` says
+    ///
+    /// Resumed ids beyond the limit are forgotten, oldest first.
+    pub fn with_limit(mut self, max_pending: usize, eviction: Eviction) -> Self {
+        self.max_pending = max_pending.max(1);
+        self.eviction = eviction;
+        let excess = self.pending.len().saturating_sub(self.max_pending);
+        self.pending.drain(..excess);
+        self
+    }
+
+    /// Handle a PUBLISH
+    ///
+    /// The id's state has changed (and should be persisted) only when this
+    /// returns [`Inbound::Deliver`].
+    pub fn on_publish(&mut self, packet_id: u16, now: u64) -> Inbound {
+        if let Some(received) = self.pending.iter_mut().find(|r| r.packet_id == packet_id) {
+            received.sent_ms = now;
+            return Inbound::Duplicate;
+        }
+        if self.pending.len() >= self.max_pending {
+            match self.eviction {
+                Eviction::Oldest => {
+                    let evicted = self.pending.remove(0);
+                    log::warn!("MQTT: No PUBREL for QoS 2 id {}; forgetting it", evicted.packet_id);
+                }
+                Eviction::Refuse => {
+                    let waiting = self.pending.len();
+                    log::warn!("MQTT: {} QoS 2 ids await PUBREL; refusing id {}", waiting, packet_id);
+                    return Inbound::Refused;
+                }
+            }
+        }
+        self.pending.push(Received { packet_id, sent_ms: now });
+        Inbound::Deliver
+    }
+
+    /// Handle PUBREL, returning true if the id was awaiting it
+    ///
+    /// PUBCOMP must be sent either way.
+    pub fn on_pubrel(&mut self, packet_id: u16) -> bool {
+        let before = self.pending.len();
+        self.pending.retain(|r| r.packet_id != packet_id);
+        self.pending.len() != before
+    }
+
+    /// PUBRECs to resend for ids whose PUBREL is overdue (all of them if `all`)
+    pub fn due(&mut self, now: u64, retry_ms: u64, all: bool) -> Vec<Vec<u8>> {
+        let mut due = Vec::new();
+        for received in self.pending.iter_mut() {
+            if all || now.saturating_sub(received.sent_ms) >= retry_ms {
+                received.sent_ms = now;
+                due.push(packet::build_pubrec(received.packet_id));
+            }
+        }
+        due
+    }
+
+    /// Ids awaiting PUBREL, for persisting
+    pub fn packet_ids(&self) -> Vec<u16> { self.pending.iter().map(|r| r.packet_id).collect() }
+
+    pub fn is_empty(&self) -> bool { self.pending.is_empty() }
+
+    /// Forget all exchanges, e.g. when a clean session starts
+    pub fn clear(&mut self) { self.pending.clear(); }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{PacketType, QoS, build_publish_with_id};
+
+    #[test]
+    fn test_sender_handshake() {
+        let mut sender = Qos2Sender::new();
+        let publish = build_publish_with_id("t", b"x", QoS::ExactlyOnce, Some(3), false);
+        sender.publish(3, publish.clone(), 0);
+        assert_eq!(sender.state(3), Some(SenderState::AwaitingPubrec));
+        // PUBCOMP before PUBREL is out of order
+        assert!(!sender.on_pubcomp(3));
+
+        // The PUBLISH is retried with DUP until PUBREC
+        let due = sender.due(1000, 1000, false);
+        assert_eq!(due, [[&[publish[0] | 0x08], &publish[1..]].concat()]);
+
+        assert_eq!(sender.on_pubrec(3, 1000), packet::build_pubrel(3));
+        assert_eq!(sender.state(3), Some(SenderState::AwaitingPubcomp));
+        // From now on only the PUBREL is retried
+        let due = sender.due(2000, 1000, false);
+        assert_eq!(PacketType::from_byte(due[0][0]), Some(PacketType::Pubrel));
+
+        assert!(sender.on_pubcomp(3));
+        assert_eq!(sender.state(3), None);
+        assert!(!sender.on_pubcomp(3));
+    }
+
+    #[test]
+    fn test_receiver_delivers_once() {
+        let mut receiver = Qos2Receiver::with_pending(&[5], 0);
+        // Redelivery of an id saved before a restart
+        assert_eq!(receiver.on_publish(5, 0), Inbound::Duplicate);
+        assert_eq!(receiver.on_publish(6, 0), Inbound::Deliver);
+        assert_eq!(receiver.on_publish(6, 10), Inbound::Duplicate);
+        assert_eq!(receiver.packet_ids(), [5, 6]);
+
+        assert_eq!(receiver.due(1000, 1000, false), [packet::build_pubrec(5)]);
+        assert!(receiver.on_pubrel(5));
+        assert!(!receiver.on_pubrel(5));
+        assert_eq!(receiver.packet_ids(), [6]);
+    }
+
+    #[test]
+    fn test_receiver_limit() {
+        let mut receiver = Qos2Receiver::with_pending(&[1, 2, 3], 0).with_limit(2, Eviction::Oldest);
+        assert_eq!(receiver.packet_ids(), [2, 3]);
+        assert_eq!(receiver.on_publish(4, 0), Inbound::Deliver);
+        assert_eq!(receiver.packet_ids(), [3, 4]);
+        // The evicted id is no longer recognised as a redelivery
+        assert_eq!(receiver.on_publish(2, 0), Inbound::Deliver);
+
+        let mut receiver = Qos2Receiver::default().with_limit(1, Eviction::Refuse);
+        assert_eq!(receiver.on_publish(1, 0), Inbound::Deliver);
+        assert_eq!(receiver.on_publish(2, 0), Inbound::Refused);
+        assert_eq!(receiver.on_publish(1, 0), Inbound::Duplicate);
+        assert!(receiver.on_pubrel(1));
+        assert_eq!(receiver.on_publish(2, 0), Inbound::Deliver);
+    }
+}
@@ -0,0 +1,126 @@
+//! CONNACK Refusal Handling
+//!
+//! A broker that refuses a connection says why in its CONNACK. The client
+//! turns the code into a [`RefusedReason`] and asks the application's
+//! [`RefusalPolicy`] what to do next. Retrying is pointless after some refusals,
+//! such as bad credentials, but the right thing after others, such as a busy
+//! server. The policy can also hand over new credentials for the next attempt.
+//!
+//! Without a policy of its own the client uses [`DefaultRefusalPolicy`],
+//! which retries only the refusals that may clear up by themselves.
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::packet::ConnackCode;
+#[cfg(feature = "mqtt5")]
+use crate::packet::v5::ReasonCode;
+
+/// Why the broker refused a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefusedReason {
+    /// Protocol level not supported
+    UnsupportedProtocol,
+    /// Client id rejected
+    IdentifierRejected,
+    /// Broker is up but the MQTT service isn't
+    ServerUnavailable,
+    /// Broker is overloaded
+    ServerBusy,
+    /// Username or password not accepted
+    BadCredentials,
+    /// Credentials accepted, but this client may not connect
+    NotAuthorized,
+    /// Client is banned
+    Banned,
+    /// Authentication method not supported
+    BadAuthenticationMethod,
+    /// Client has used up its quota
+    QuotaExceeded,
+    /// Client is connecting too often
+    ConnectionRateExceeded,
+    /// Connect to another broker for now
+    UseAnotherServer,
+    /// Connect to another broker from now on
+    ServerMoved,
+    /// Any other reason code
+    Other(u8),
+}
+
+impl RefusedReason {
+    /// Reason for an MQTT 3.1.1 CONNACK return code
+    pub fn from_connack(code: u8) -> Self {
+        match ConnackCode::from_byte(code) {
+            Some(ConnackCode::UnacceptableProtocol) => Self::UnsupportedProtocol,
+            Some(ConnackCode::IdentifierRejected) => Self::IdentifierRejected,
+            Some(ConnackCode::ServerUnavailable) => Self::ServerUnavailable,
+            Some(ConnackCode::BadCredentials) => Self::BadCredentials,
+            Some(ConnackCode::NotAuthorized) => Self::NotAuthorized,
+            _ => Self::Other(code),
+        }
+    }
+
+    /// Reason for an MQTT 5 CONNACK reason code
+    #[cfg(feature = "mqtt5")]
+    pub fn from_reason(reason: ReasonCode) -> Self {
+        match reason {
+            ReasonCode::UNSUPPORTED_PROTOCOL_VERSION => Self::UnsupportedProtocol,
+            ReasonCode::CLIENT_IDENTIFIER_NOT_VALID => Self::IdentifierRejected,
+            ReasonCode::SERVER_UNAVAILABLE => Self::ServerUnavailable,
+            ReasonCode::SERVER_BUSY => Self::ServerBusy,
+            ReasonCode::BAD_USER_NAME_OR_PASSWORD => Self::BadCredentials,
+            ReasonCode::NOT_AUTHORIZED => Self::NotAuthorized,
+            ReasonCode::BANNED => Self::Banned,
+            ReasonCode::BAD_AUTHENTICATION_METHOD => Self::BadAuthenticationMethod,
+            ReasonCode::QUOTA_EXCEEDED => Self::QuotaExceeded,
+            ReasonCode::CONNECTION_RATE_EXCEEDED => Self::ConnectionRateExceeded,
+            ReasonCode::USE_ANOTHER_SERVER => Self::UseAnotherServer,
+            ReasonCode::SERVER_MOVED => Self::ServerMoved,
+            ReasonCode(code) => Self::Other(code),
+        }
+    }
+
+    /// The same attempt may succeed later without anything changing
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::ServerUnavailable
+                | Self::ServerBusy
+                | Self::QuotaExceeded
+                | Self::ConnectionRateExceeded
+                | Self::Other(_)
+        )
+    }
+}
+
+/// What the client does after a refused connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefusalAction {
+    /// Reconnect after `reconnect_delay_ms`, if `auto_reconnect` is set
+    Retry,
+    /// Reconnect after this many milliseconds, regardless of `auto_reconnect`
+    RetryAfter(u64),
+    /// Reconnect straight away with these credentials, which replace the
+    /// ones in the config
+    UpdateCredentials { username: Option<String>, password: Option<Vec<u8>> },
+    /// Stay disconnected until the application calls `connect`
+    GiveUp,
+}
+
+/// Decides how the client responds to a refused connection
+pub trait RefusalPolicy {
+    /// Called for each refusal; `attempts` counts the refusals in a row,
+    /// including this one, and starts over once a connection is accepted
+    fn on_refused(&mut self, reason: RefusedReason, attempts: u32) -> RefusalAction;
+}
+
+/// Retries transient refusals on the usual reconnect schedule and gives up on the rest
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultRefusalPolicy;
+
+impl RefusalPolicy for DefaultRefusalPolicy {
+    fn on_refused(&mut self, reason: RefusedReason, _attempts: u32) -> RefusalAction {
+        if reason.is_transient() { RefusalAction::Retry } else { RefusalAction::GiveUp }
+    }
+}
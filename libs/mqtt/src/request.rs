@@ -0,0 +1,270 @@
+//! Request/Response over Publish/Subscribe
+//!
+//! [`Requester`] wraps a [`MqttClient`] to ask a question and wait for the
+//! answer, e.g. a permission prompt that someone allows or denies. Each
+//! request gets its own reply topic, `<reply_prefix>/<correlation id>`, and
+//! the requester subscribes to `<reply_prefix>/+` before the first one goes
+//! out. A message arriving on a reply topic resolves that request's
+//! [`RequestToken`] instead of coming out of [`Requester::poll`].
+//!
+//! At MQTT 5 the request carries its reply topic and correlation id as the
+//! Response Topic and Correlation Data properties, so a responder following
+//! the spec answers without further help. 3.1.1 has nowhere to put them:
+//! build the payload with [`Requester::request_with`], which is handed the
+//! reply topic to embed.
+//!
+//! The reply prefix should be unique to this client, e.g. end in its client
+//! id, so nobody else's answers arrive. As with [`ChannelMux`], events are
+//! read with [`MqttClient::poll`], so `borrow_publish` must be left off, and
+//! the reply filter is subscribed again when a clean session reconnects.
+//!
+//! [`ChannelMux`]: crate::channel::ChannelMux
+
+extern crate alloc;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::client::{MqttClient, MqttError, MqttEvent};
+use crate::packet::QoS;
+
+/// Handle to a request made with a [`Requester`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestToken(u32);
+
+/// Where a request stands
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// No answer yet
+    Pending,
+    /// The responder answered with this payload
+    Received(Vec<u8>),
+    /// No answer within the timeout; a late one is dropped
+    TimedOut,
+}
+
+struct Request {
+    token: u32,
+    reply_topic: String,
+    deadline_ms: u64,
+    response: Response,
+}
+
+/// Request/response on top of one [`MqttClient`]
+pub struct Requester {
+    client: MqttClient,
+    reply_prefix: String,
+    timeout_ms: u64,
+    /// Whether the reply filter has been subscribed
+    subscribed: bool,
+    next: u32,
+    requests: Vec<Request>,
+}
+
+impl Requester {
+    /// Take ownership of `client`; requests not answered within `timeout_ms` time out
+    pub fn new(client: MqttClient, reply_prefix: &str, timeout_ms: u64) -> Self {
+        // Counting from the clock makes reusing a recent id after a restart unlikely
+        let next = client.now_ms() as u32;
+        Self {
+            client,
+            reply_prefix: String::from(reply_prefix),
+            timeout_ms,
+            subscribed: false,
+            next,
+            requests: Vec::new(),
+        }
+    }
+
+    /// Get the client
+    pub fn client(&self) -> &MqttClient { &self.client }
+
+    /// Get the client mutably, e.g. to feed it received data
+    pub fn client_mut(&mut self) -> &mut MqttClient { &mut self.client }
+
+    /// Publish `payload` on `topic` as a request
+    ///
+    /// Only a responder on an MQTT 5 connection learns where to answer.
+    pub fn request(&mut self, topic: &str, payload: &[u8], qos: QoS) -> Result<RequestToken, MqttError> {
+        self.request_with(topic, qos, |_| payload.to_vec())
+    }
+
+    /// Publish a request on `topic` whose payload `payload` builds from the reply topic
+    pub fn request_with(
+        &mut self,
+        topic: &str,
+        qos: QoS,
+        payload: impl FnOnce(&str) -> Vec<u8>,
+    ) -> Result<RequestToken, MqttError> {
+        if !self.subscribed {
+            let filter = format!("{}/+", self.reply_prefix);
+            self.client.subscribe(&filter, QoS::AtLeastOnce)?;
+            self.subscribed = true;
+        }
+        let token = self.next;
+        let correlation = format!("{:08x}", token);
+        let reply_topic = format!("{}/{}", self.reply_prefix, correlation);
+        let payload = payload(&reply_topic);
+        self.client.publish_request(topic, &payload, qos, &reply_topic, correlation.as_bytes())?;
+        self.next = self.next.wrapping_add(1);
+        let deadline_ms = self.client.now_ms() + self.timeout_ms;
+        self.requests.push(Request { token, reply_topic, deadline_ms, response: Response::Pending });
+        Ok(RequestToken(token))
+    }
+
+    /// Take the outcome of `token`
+    ///
+    /// A pending request stays pending; an answered or timed out one is
+    /// forgotten once taken, or one more timeout after its deadline if it
+    /// never is. `None` for a token already forgotten.
+    pub fn take(&mut self, token: RequestToken) -> Option<Response> {
+        let index = self.requests.iter().position(|r| r.token == token.0)?;
+        if self.requests[index].response == Response::Pending {
+            return Some(Response::Pending);
+        }
+        Some(self.requests.remove(index).response)
+    }
+
+    /// Number of requests not yet answered or timed out
+    pub fn pending(&self) -> usize {
+        self.requests.iter().filter(|r| r.response == Response::Pending).count()
+    }
+
+    /// Poll the next event that isn't a reply (non-blocking)
+    pub fn poll(&mut self) -> Option<MqttEvent> {
+        let now = self.client.now_ms();
+        for request in self.requests.iter_mut() {
+            if request.response == Response::Pending && now >= request.deadline_ms {
+                log::warn!("MQTT: No reply on {}", request.reply_topic);
+                request.response = Response::TimedOut;
+            }
+        }
+        // Outcomes nobody took would otherwise pile up
+        let timeout_ms = self.timeout_ms;
+        self.requests.retain(|r| r.response == Response::Pending || now < r.deadline_ms + timeout_ms);
+        while let Some(event) = self.client.poll() {
+            match event {
+                MqttEvent::Connected => {
+                    // A clean session starts with no subscriptions on the broker
+                    if self.subscribed && self.client.config().clean_session {
+                        let filter = format!("{}/+", self.reply_prefix);
+                        if let Err(e) = self.client.subscribe(&filter, QoS::AtLeastOnce) {
+                            // Tried again by the next request
+                            log::warn!("MQTT: Resubscribe to {} failed: {:?}", filter, e);
+                            self.subscribed = false;
+                        }
+                    }
+                    return Some(MqttEvent::Connected);
+                }
+                MqttEvent::Message { topic, payload, .. } if self.is_reply(&topic) => {
+                    match self.requests.iter_mut().find(|r| r.reply_topic == topic) {
+                        Some(request) if request.response == Response::Pending => {
+                            request.response = Response::Received(payload);
+                        }
+                        _ => log::debug!("MQTT: Dropping unexpected reply on {}", topic),
+                    }
+                }
+                event => return Some(event),
+            }
+        }
+        None
+    }
+
+    fn is_reply(&self, topic: &str) -> bool {
+        topic.strip_prefix(self.reply_prefix.as_str()).is_some_and(|rest| rest.starts_with('/'))
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::MqttConfig;
+    use crate::packet;
+    use crate::transport::mock;
+
+    #[test]
+    fn test_reply_resolves_request() {
+        let (mut client, clock, broker) = mock::client(MqttConfig::default());
+        mock::accept(&mut client, &broker);
+        mock::sent(&broker);
+        let mut requester = Requester::new(client, "ccr/reply/dev", 5_000);
+
+        let mut reply = String::new();
+        let token = requester
+            .request_with("ccr/permissions/request", QoS::AtLeastOnce, |topic| {
+                reply = String::from(topic);
+                format!(r#"{{"reply":"{}"}}"#, topic).into_bytes()
+            })
+            .unwrap();
+        let sent = mock::sent(&broker);
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0], packet::build_subscribe(1, "ccr/reply/dev/+", QoS::AtLeastOnce));
+        assert!(reply.starts_with("ccr/reply/dev/"));
+        assert_eq!(requester.take(token), Some(Response::Pending));
+
+        // Other messages pass through; the reply is held for the token
+        let other = packet::build_publish("ccr/status", b"idle", QoS::AtMostOnce);
+        let answer = packet::build_publish(&reply, b"allow", QoS::AtMostOnce);
+        requester.client_mut().process_data(&other).unwrap();
+        requester.client_mut().process_data(&answer).unwrap();
+        let events: Vec<MqttEvent> = core::iter::from_fn(|| requester.poll()).collect();
+        assert!(events.iter().all(|e| e.topic() != Some(reply.as_str())));
+        assert!(events.iter().any(|e| e.topic() == Some("ccr/status")));
+        assert_eq!(requester.take(token), Some(Response::Received(b"allow".to_vec())));
+        assert_eq!(requester.take(token), None);
+
+        // Unanswered requests time out, and the reply filter isn't subscribed again
+        let token = requester.request("ccr/permissions/request", b"{}", QoS::AtMostOnce).unwrap();
+        assert_eq!(mock::sent(&broker).len(), 1);
+        assert_eq!(requester.pending(), 1);
+        clock.advance(5_000);
+        assert!(requester.poll().is_none());
+        assert_eq!(requester.take(token), Some(Response::TimedOut));
+        assert_eq!(requester.pending(), 0);
+
+        // One not taken is forgotten a timeout later
+        let token = requester.request("ccr/permissions/request", b"{}", QoS::AtMostOnce).unwrap();
+        clock.advance(5_000);
+        requester.poll();
+        clock.advance(5_000);
+        assert!(requester.poll().is_none());
+        assert_eq!(requester.take(token), None);
+    }
+
+    #[test]
+    fn test_reply_after_reconnect() {
+        let (mut client, clock, broker) = mock::client(MqttConfig::default());
+        mock::accept(&mut client, &broker);
+        let mut requester = Requester::new(client, "ccr/reply/dev", 60_000);
+        let mut reply = String::new();
+        let token = requester
+            .request_with("ccr/permissions/request", QoS::AtLeastOnce, |topic| {
+                reply = String::from(topic);
+                Vec::new()
+            })
+            .unwrap();
+        mock::sent(&broker);
+
+        // The broker forgets the clean session's subscription, so it's made again
+        broker.borrow_mut().closed = true;
+        assert!(matches!(requester.poll(), Some(MqttEvent::Disconnected { .. })));
+        clock.advance(5_000);
+        assert!(requester.poll().is_none());
+        mock::sent(&broker);
+        broker.borrow_mut().rx.extend([0x20, 0x02, 0x00, 0x00]);
+        assert!(matches!(requester.poll(), Some(MqttEvent::Connected)));
+        let sent = mock::sent(&broker);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0][0] >> 4, packet::PacketType::Subscribe as u8);
+        assert!(sent[0].ends_with(b"ccr/reply/dev/+\x01"));
+
+        let answer = packet::build_publish(&reply, b"deny", QoS::AtMostOnce);
+        requester.client_mut().process_data(&answer).unwrap();
+        while requester.poll().is_some() {}
+        assert_eq!(requester.take(token), Some(Response::Received(b"deny".to_vec())));
+    }
+}
@@ -0,0 +1,166 @@
+//! Broker Address Resolution
+//!
+//! `MqttConfig::broker` is `host:port`, where the host is an IPv4 address, a
+//! bracketed IPv6 address such as `[fd00::1]:1883`, or a name such as
+//! `broker.hivemq.com`. Names are looked up through the Xous DNS service by
+//! [`DnsResolver`], which sees every address the name has; the configured
+//! [`AddressFamily`] decides which of them are tried, and in what order.
+
+extern crate alloc;
+use alloc::vec::Vec;
+use std::net::{IpAddr, SocketAddr};
+
+/// Which addresses to try when a broker name has both IPv4 and IPv6 ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressFamily {
+    /// IPv4 addresses first, then IPv6
+    #[default]
+    PreferV4,
+    /// IPv6 addresses first, then IPv4
+    PreferV6,
+    /// IPv4 addresses only
+    V4Only,
+    /// IPv6 addresses only
+    V6Only,
+}
+
+impl AddressFamily {
+    /// Drop the addresses this family excludes and put the preferred ones first,
+    /// keeping the resolver's order otherwise
+    pub fn order(self, addrs: &mut Vec<IpAddr>) {
+        match self {
+            Self::PreferV4 => addrs.sort_by_key(|addr| addr.is_ipv6()),
+            Self::PreferV6 => addrs.sort_by_key(|addr| addr.is_ipv4()),
+            Self::V4Only => addrs.retain(|addr| addr.is_ipv4()),
+            Self::V6Only => addrs.retain(|addr| addr.is_ipv6()),
+        }
+    }
+}
+
+/// Why a broker address couldn't be resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolveError {
+    /// Not `host:port`
+    BadAddress,
+    /// The name doesn't exist
+    NotFound,
+    /// No DNS server is known yet, e.g. before DHCP has finished
+    NoServer,
+    /// The DNS server couldn't be reached
+    Network,
+    /// The DNS server failed or refused the query
+    ServerFailure,
+    /// The name resolved, but to no address of the configured family
+    NoAddress,
+}
+
+/// Looks up the addresses of a host name
+pub trait Resolver {
+    fn lookup(&mut self, host: &str) -> Result<Vec<IpAddr>, ResolveError>;
+}
+
+/// Resolver backed by the Xous DNS service, connected on first use
+#[derive(Default)]
+pub struct DnsResolver {
+    dns: Option<dns::Dns>,
+}
+
+impl DnsResolver {
+    pub fn new() -> Self { Self::default() }
+}
+
+impl Resolver for DnsResolver {
+    fn lookup(&mut self, host: &str) -> Result<Vec<IpAddr>, ResolveError> {
+        if self.dns.is_none() {
+            let xns = xous_names::XousNames::new().map_err(|_| ResolveError::NoServer)?;
+            self.dns = Some(dns::Dns::new(&xns).map_err(|_| ResolveError::NoServer)?);
+        }
+        let dns = self.dns.as_ref().unwrap();
+        dns.lookup_all(host).map_err(|code| match code {
+            dns::DnsResponseCode::NameError => ResolveError::NotFound,
+            dns::DnsResponseCode::NoServerSpecified => ResolveError::NoServer,
+            dns::DnsResponseCode::NetworkError => ResolveError::Network,
+            _ => ResolveError::ServerFailure,
+        })
+    }
+}
+
+/// Split `host:port`, removing the brackets from an IPv6 host
+pub fn split_host_port(broker: &str) -> Result<(&str, u16), ResolveError> {
+    let (host, port) = broker.rsplit_once(':').ok_or(ResolveError::BadAddress)?;
+    let port = port.parse().map_err(|_| ResolveError::BadAddress)?;
+    let host = match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.strip_suffix(']').ok_or(ResolveError::BadAddress)?,
+        // An unbracketed IPv6 address would have been split at its last colon
+        None if host.contains(':') => return Err(ResolveError::BadAddress),
+        None => host,
+    };
+    if host.is_empty() {
+        return Err(ResolveError::BadAddress);
+    }
+    Ok((host, port))
+}
+
+/// Addresses to try for `broker`, best first
+///
+/// Address literals are used as they are, without asking `resolver`.
+pub fn resolve(
+    resolver: &mut dyn Resolver,
+    broker: &str,
+    family: AddressFamily,
+) -> Result<Vec<SocketAddr>, ResolveError> {
+    let (host, port) = split_host_port(broker)?;
+    let mut addrs = match host.parse::<IpAddr>() {
+        Ok(addr) => alloc::vec![addr],
+        Err(_) => resolver.lookup(host)?,
+    };
+    family.order(&mut addrs);
+    if addrs.is_empty() {
+        return Err(ResolveError::NoAddress);
+    }
+    Ok(addrs.into_iter().map(|addr| SocketAddr::new(addr, port)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    const V4: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const V6: IpAddr = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+
+    /// Answers every name with one IPv6 and one IPv4 address
+    struct DualStack;
+
+    impl Resolver for DualStack {
+        fn lookup(&mut self, host: &str) -> Result<Vec<IpAddr>, ResolveError> {
+            if host == "missing.example" { Err(ResolveError::NotFound) } else { Ok(alloc::vec![V6, V4]) }
+        }
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(split_host_port("broker.hivemq.com:1883"), Ok(("broker.hivemq.com", 1883)));
+        assert_eq!(split_host_port("[2001:db8::1]:8883"), Ok(("2001:db8::1", 8883)));
+        assert_eq!(split_host_port("2001:db8::1:8883"), Err(ResolveError::BadAddress));
+        assert_eq!(split_host_port("broker"), Err(ResolveError::BadAddress));
+        assert_eq!(split_host_port(":1883"), Err(ResolveError::BadAddress));
+        assert_eq!(split_host_port("broker:mqtt"), Err(ResolveError::BadAddress));
+    }
+
+    #[test]
+    fn test_family_preference() {
+        let ips = |family| -> Vec<IpAddr> {
+            resolve(&mut DualStack, "broker.example:1883", family).unwrap().iter().map(|a| a.ip()).collect()
+        };
+        assert_eq!(ips(AddressFamily::PreferV4), [V4, V6]);
+        assert_eq!(ips(AddressFamily::PreferV6), [V6, V4]);
+        assert_eq!(ips(AddressFamily::V4Only), [V4]);
+
+        let literal = resolve(&mut DualStack, "192.0.2.7:1883", AddressFamily::V6Only);
+        assert_eq!(literal, Err(ResolveError::NoAddress));
+        let missing = resolve(&mut DualStack, "missing.example:1883", AddressFamily::PreferV4);
+        assert_eq!(missing, Err(ResolveError::NotFound));
+    }
+}
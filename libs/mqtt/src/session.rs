@@ -0,0 +1,211 @@
+//! MQTT Session State Storage
+//!
+//! Session state that has to survive a reboot for QoS guarantees to hold.
+//! The client writes through to a [`SessionStore`] every time the state
+//! changes, so an implementation backed by non-volatile storage (e.g. PDDB)
+//! keeps delivery exactly-once across power cycles.
+//!
+//! For a persistent session (`clean_session` false) the store also keeps the
+//! client id and the granted subscriptions. After a reboot the client comes
+//! back as the same client, and re-subscribes by itself if the broker no
+//! longer holds the session. With the `pddb-session` feature, [`PddbStore`]
+//! keeps all of this in a PDDB dictionary.
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::packet::QoS;
+
+/// A subscription the broker granted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SavedSubscription {
+    pub filter: String,
+    /// QoS granted by the broker
+    pub qos: QoS,
+}
+
+/// Persistent storage for client session state
+pub trait SessionStore {
+    /// Load packet ids of inbound QoS 2 messages that were delivered to the
+    /// application (PUBREC sent) but not yet released by the broker (PUBREL)
+    fn load_incoming_qos2(&mut self) -> Vec<u16>;
+
+    /// Replace the stored inbound QoS 2 packet id window
+    fn save_incoming_qos2(&mut self, packet_ids: &[u16]);
+
+    /// Load the client id used by a previous run, if any
+    fn load_client_id(&mut self) -> Option<String> { None }
+
+    /// Remember the client id, so a persistent session can be resumed
+    fn save_client_id(&mut self, _client_id: &str) {}
+
+    /// Load the subscriptions of the persistent session
+    fn load_subscriptions(&mut self) -> Vec<SavedSubscription> { Vec::new() }
+
+    /// Replace the stored subscriptions
+    fn save_subscriptions(&mut self, _subscriptions: &[SavedSubscription]) {}
+}
+
+/// Volatile session store, used when no persistent store is configured
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    incoming_qos2: Vec<u16>,
+    client_id: Option<String>,
+    subscriptions: Vec<SavedSubscription>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self { Self::default() }
+}
+
+impl SessionStore for MemoryStore {
+    fn load_incoming_qos2(&mut self) -> Vec<u16> { self.incoming_qos2.clone() }
+
+    fn save_incoming_qos2(&mut self, packet_ids: &[u16]) {
+        self.incoming_qos2.clear();
+        self.incoming_qos2.extend_from_slice(packet_ids);
+    }
+
+    fn load_client_id(&mut self) -> Option<String> { self.client_id.clone() }
+
+    fn save_client_id(&mut self, client_id: &str) { self.client_id = Some(String::from(client_id)); }
+
+    fn load_subscriptions(&mut self) -> Vec<SavedSubscription> { self.subscriptions.clone() }
+
+    fn save_subscriptions(&mut self, subscriptions: &[SavedSubscription]) {
+        self.subscriptions = subscriptions.to_vec();
+    }
+}
+
+/// Session store kept in a PDDB dictionary
+///
+/// Each piece of state is a key in the dictionary, rewritten whole when it
+/// changes. A missing or damaged key reads as empty, which at worst makes the
+/// client start a fresh session.
+#[cfg(feature = "pddb-session")]
+pub struct PddbStore {
+    pddb: pddb::Pddb,
+    dict: String,
+}
+
+#[cfg(feature = "pddb-session")]
+impl PddbStore {
+    const KEY_CLIENT_ID: &'static str = "client_id";
+    const KEY_INCOMING_QOS2: &'static str = "incoming_qos2";
+    const KEY_SUBSCRIPTIONS: &'static str = "subscriptions";
+
+    /// Keep the session in `dict`; use one dictionary per client
+    pub fn new(dict: &str) -> Self {
+        let pddb = pddb::Pddb::new();
+        pddb.try_mount();
+        Self { pddb, dict: String::from(dict) }
+    }
+
+    fn read(&self, key: &str) -> Vec<u8> {
+        use std::io::Read;
+        let mut data = Vec::new();
+        // A missing key means nothing was saved yet
+        if let Ok(mut pddb_key) = self.pddb.get(&self.dict, key, None, false, false, None, None::<fn()>) {
+            if let Err(e) = pddb_key.read_to_end(&mut data) {
+                log::warn!("MQTT: Couldn't read session {}: {:?}", key, e);
+                data.clear();
+            }
+        }
+        data
+    }
+
+    fn write(&self, key: &str, value: &[u8]) {
+        use std::io::Write;
+        // delete key first to ensure data in a prior longer key is gone
+        self.pddb.delete_key(&self.dict, key, None).ok();
+        let result = self
+            .pddb
+            .get(&self.dict, key, None, true, true, Some(value.len()), None::<fn()>)
+            .and_then(|mut pddb_key| pddb_key.write_all(value));
+        match result {
+            Ok(()) => {
+                self.pddb.sync().ok();
+            }
+            Err(e) => log::error!("MQTT: Couldn't save session {}: {:?}", key, e),
+        }
+    }
+}
+
+#[cfg(feature = "pddb-session")]
+impl SessionStore for PddbStore {
+    fn load_incoming_qos2(&mut self) -> Vec<u16> {
+        self.read(Self::KEY_INCOMING_QOS2)
+            .chunks_exact(2)
+            .map(|id| u16::from_le_bytes([id[0], id[1]]))
+            .collect()
+    }
+
+    fn save_incoming_qos2(&mut self, packet_ids: &[u16]) {
+        let data: Vec<u8> = packet_ids.iter().flat_map(|id| id.to_le_bytes()).collect();
+        self.write(Self::KEY_INCOMING_QOS2, &data);
+    }
+
+    fn load_client_id(&mut self) -> Option<String> {
+        String::from_utf8(self.read(Self::KEY_CLIENT_ID)).ok().filter(|id| !id.is_empty())
+    }
+
+    fn save_client_id(&mut self, client_id: &str) { self.write(Self::KEY_CLIENT_ID, client_id.as_bytes()); }
+
+    fn load_subscriptions(&mut self) -> Vec<SavedSubscription> {
+        decode_subscriptions(&self.read(Self::KEY_SUBSCRIPTIONS))
+    }
+
+    fn save_subscriptions(&mut self, subscriptions: &[SavedSubscription]) {
+        self.write(Self::KEY_SUBSCRIPTIONS, &encode_subscriptions(subscriptions));
+    }
+}
+
+/// Serialize subscriptions as `[qos][len: u16 le][filter]` records
+pub fn encode_subscriptions(subscriptions: &[SavedSubscription]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for sub in subscriptions {
+        data.push(sub.qos as u8);
+        data.extend_from_slice(&(sub.filter.len() as u16).to_le_bytes());
+        data.extend_from_slice(sub.filter.as_bytes());
+    }
+    data
+}
+
+/// Inverse of [`encode_subscriptions`]; a truncated or invalid record ends the list
+pub fn decode_subscriptions(mut data: &[u8]) -> Vec<SavedSubscription> {
+    let mut subscriptions = Vec::new();
+    while data.len() >= 3 {
+        let len = u16::from_le_bytes([data[1], data[2]]) as usize;
+        let (Some(qos), Some(filter)) =
+            (QoS::from_byte(data[0]).filter(|&qos| qos as u8 == data[0]), data.get(3..3 + len))
+        else {
+            break;
+        };
+        let Ok(filter) = core::str::from_utf8(filter) else {
+            break;
+        };
+        subscriptions.push(SavedSubscription { filter: String::from(filter), qos });
+        data = &data[3 + len..];
+    }
+    subscriptions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscription_encoding() {
+        let subs = [
+            SavedSubscription { filter: String::from("ccr/#"), qos: QoS::AtLeastOnce },
+            SavedSubscription { filter: String::from("a/+/c"), qos: QoS::ExactlyOnce },
+        ];
+        let data = encode_subscriptions(&subs);
+        assert_eq!(decode_subscriptions(&data), subs);
+
+        // A torn write keeps the complete records
+        assert_eq!(decode_subscriptions(&data[..data.len() - 1]), subs[..1]);
+        assert!(decode_subscriptions(&[3, 1, 0, b'a']).is_empty());
+    }
+}
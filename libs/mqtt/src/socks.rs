@@ -0,0 +1,240 @@
+//! SOCKS5 Proxy
+//!
+//! On networks that only let traffic out through a bastion, the client
+//! reaches the broker through a SOCKS5 proxy (RFC 1928): the connector
+//! connects to the proxy instead and asks it to CONNECT to
+//! `MqttConfig::broker`, after which the stream carries MQTT, or TLS, as if
+//! it went straight to the broker. A broker name is passed on for the proxy
+//! to resolve, since the device may not be able to. The proxy may ask for a
+//! username and password (RFC 1929).
+
+extern crate alloc;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use std::io::{ErrorKind, Read, Write};
+use std::net::IpAddr;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+/// Version of the username/password subnegotiation
+const AUTH_VERSION: u8 = 1;
+const CONNECT: u8 = 1;
+const ATYP_V4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_V6: u8 = 4;
+
+/// SOCKS5 proxy to reach the broker through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks5Proxy {
+    /// Proxy `host:port`, resolved like a broker address
+    pub address: String,
+    /// Username and password, if the proxy wants them
+    pub credentials: Option<(String, String)>,
+}
+
+/// Why the proxy didn't connect us to the broker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SocksError {
+    /// The proxy didn't answer in time
+    Timeout,
+    /// Reading from or writing to the proxy failed
+    Io(String),
+    /// The proxy's answer isn't SOCKS5
+    Protocol,
+    /// The proxy accepts none of the authentication methods offered
+    NoAcceptableMethod,
+    /// The proxy refused the username and password
+    AuthFailed,
+    /// A username, password or broker name is over 255 bytes
+    TooLong,
+    /// The proxy couldn't connect to the broker; the reply code says why
+    ConnectFailed(u8),
+}
+
+impl fmt::Display for SocksError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "no answer"),
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Protocol => write!(f, "not a SOCKS5 proxy"),
+            Self::NoAcceptableMethod => write!(f, "no acceptable authentication method"),
+            Self::AuthFailed => write!(f, "username and password refused"),
+            Self::TooLong => write!(f, "name or credentials too long"),
+            Self::ConnectFailed(code) => {
+                let reason = match code {
+                    1 => "general failure",
+                    2 => "not allowed by ruleset",
+                    3 => "network unreachable",
+                    4 => "host unreachable",
+                    5 => "connection refused",
+                    6 => "TTL expired",
+                    7 => "command not supported",
+                    8 => "address type not supported",
+                    _ => "unknown reply",
+                };
+                write!(f, "connect to broker failed: {} ({})", reason, code)
+            }
+        }
+    }
+}
+
+fn io(e: std::io::Error) -> SocksError {
+    match e.kind() {
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => SocksError::Timeout,
+        _ => SocksError::Io(format!("{}", e)),
+    }
+}
+
+/// Append a length-prefixed field
+fn push_field(out: &mut Vec<u8>, field: &[u8]) -> Result<(), SocksError> {
+    out.push(u8::try_from(field.len()).map_err(|_| SocksError::TooLong)?);
+    out.extend_from_slice(field);
+    Ok(())
+}
+
+/// Have the proxy at the other end of `stream` connect it to `host:port`
+///
+/// `stream` must already be connected to the proxy; on success it leads to
+/// the broker.
+pub fn connect<S: Read + Write>(
+    stream: &mut S,
+    proxy: &Socks5Proxy,
+    host: &str,
+    port: u16,
+) -> Result<(), SocksError> {
+    let greeting: &[u8] = match proxy.credentials {
+        Some(_) => &[VERSION, 2, NO_AUTH, USERNAME_PASSWORD],
+        None => &[VERSION, 1, NO_AUTH],
+    };
+    stream.write_all(greeting).map_err(io)?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).map_err(io)?;
+    if choice[0] != VERSION {
+        return Err(SocksError::Protocol);
+    }
+    match (choice[1], &proxy.credentials) {
+        (NO_AUTH, _) => {}
+        (USERNAME_PASSWORD, Some((username, password))) => {
+            let mut request = alloc::vec![AUTH_VERSION];
+            push_field(&mut request, username.as_bytes())?;
+            push_field(&mut request, password.as_bytes())?;
+            stream.write_all(&request).map_err(io)?;
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).map_err(io)?;
+            if status[1] != 0 {
+                return Err(SocksError::AuthFailed);
+            }
+        }
+        (NO_ACCEPTABLE_METHOD, _) => return Err(SocksError::NoAcceptableMethod),
+        _ => return Err(SocksError::Protocol),
+    }
+
+    let mut request = alloc::vec![VERSION, CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(addr)) => {
+            request.push(ATYP_V4);
+            request.extend_from_slice(&addr.octets());
+        }
+        Ok(IpAddr::V6(addr)) => {
+            request.push(ATYP_V6);
+            request.extend_from_slice(&addr.octets());
+        }
+        Err(_) => {
+            request.push(ATYP_DOMAIN);
+            push_field(&mut request, host.as_bytes())?;
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).map_err(io)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).map_err(io)?;
+    if reply[0] != VERSION {
+        return Err(SocksError::Protocol);
+    }
+    if reply[1] != 0 {
+        return Err(SocksError::ConnectFailed(reply[1]));
+    }
+    // The address the proxy connected from, which the client has no use for
+    let address_len = match reply[3] {
+        ATYP_V4 => 4,
+        ATYP_V6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).map_err(io)?;
+            len[0] as usize
+        }
+        _ => return Err(SocksError::Protocol),
+    };
+    let mut bound = alloc::vec![0u8; address_len + 2];
+    stream.read_exact(&mut bound).map_err(io)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers from a script, recording what was sent
+    struct Script {
+        rx: std::io::Cursor<Vec<u8>>,
+        sent: Vec<u8>,
+    }
+
+    impl Script {
+        fn new(rx: &[u8]) -> Self { Self { rx: std::io::Cursor::new(rx.to_vec()), sent: Vec::new() } }
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> { self.rx.read(buf) }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.sent.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    fn proxy(credentials: Option<(&str, &str)>) -> Socks5Proxy {
+        Socks5Proxy {
+            address: String::from("bastion:1080"),
+            credentials: credentials.map(|(u, p)| (String::from(u), String::from(p))),
+        }
+    }
+
+    #[test]
+    fn test_connect() {
+        let mut stream = Script::new(&[5, 0, 5, 0, 0, 1, 10, 0, 0, 1, 0x04, 0x38]);
+        connect(&mut stream, &proxy(None), "broker", 1883).unwrap();
+        let mut expected = alloc::vec![5, 1, 0, 5, 1, 0, ATYP_DOMAIN, 6];
+        expected.extend_from_slice(b"broker");
+        expected.extend_from_slice(&[0x07, 0x5b]);
+        assert_eq!(stream.sent, expected);
+
+        // Authenticated, to an address literal, which the proxy can't reach
+        let mut stream = Script::new(&[5, 2, 1, 0, 5, 5, 0, 1]);
+        let result = connect(&mut stream, &proxy(Some(("me", "pw"))), "192.0.2.1", 8883);
+        assert_eq!(result, Err(SocksError::ConnectFailed(5)));
+        assert_eq!(
+            stream.sent,
+            [5, 2, 0, 2, 1, 2, b'm', b'e', 2, b'p', b'w', 5, 1, 0, ATYP_V4, 192, 0, 2, 1, 0x22, 0xb3]
+        );
+
+        let mut stream = Script::new(&[5, 2, 1, 1]);
+        assert_eq!(
+            connect(&mut stream, &proxy(Some(("me", "no"))), "broker", 1883),
+            Err(SocksError::AuthFailed)
+        );
+        let mut stream = Script::new(&[5, 0xff]);
+        assert_eq!(connect(&mut stream, &proxy(None), "broker", 1883), Err(SocksError::NoAcceptableMethod));
+        let mut stream = Script::new(b"HTTP/1.1 400");
+        assert_eq!(connect(&mut stream, &proxy(None), "broker", 1883), Err(SocksError::Protocol));
+    }
+}
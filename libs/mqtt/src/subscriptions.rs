@@ -0,0 +1,132 @@
+//! Topic Handlers
+//!
+//! [`Subscriptions`] maps topic filters to callbacks, so an application
+//! registers what it wants to hear about once instead of matching every
+//! `MqttEvent::Message` against its topics by hand. [`MqttClient`] keeps one
+//! and dispatches to it before queueing messages as events; see
+//! [`MqttClient::subscribe_with`].
+//!
+//! [`MqttClient`]: crate::client::MqttClient
+//! [`MqttClient::subscribe_with`]: crate::client::MqttClient::subscribe_with
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use crate::packet::QoS;
+use crate::topic::TopicFilter;
+
+/// Callback for a message: topic and payload
+pub type Handler = Box<dyn FnMut(&str, &[u8])>;
+
+/// Identifies a registered handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId(u32);
+
+struct Entry {
+    id: HandlerId,
+    filter: TopicFilter,
+    qos: QoS,
+    handler: Handler,
+}
+
+/// Registry of topic filters and their handlers
+#[derive(Default)]
+pub struct Subscriptions {
+    entries: Vec<Entry>,
+    next_id: u32,
+}
+
+impl Subscriptions {
+    pub fn new() -> Self { Self::default() }
+
+    /// Register `handler` for messages matching `filter`
+    pub fn add(&mut self, filter: TopicFilter, qos: QoS, handler: Handler) -> HandlerId {
+        let id = HandlerId(self.next_id);
+        self.next_id = self.next_id.wrapping_add(1);
+        self.entries.push(Entry { id, filter, qos, handler });
+        id
+    }
+
+    /// Remove a handler, returning its filter
+    pub fn remove(&mut self, id: HandlerId) -> Option<TopicFilter> {
+        let index = self.entries.iter().position(|entry| entry.id == id)?;
+        Some(self.entries.remove(index).filter)
+    }
+
+    /// Highest QoS any handler wants for `filter`, if any handler holds it
+    pub fn qos_for(&self, filter: &TopicFilter) -> Option<QoS> {
+        self.entries
+            .iter()
+            .filter(|entry| &entry.filter == filter)
+            .map(|entry| entry.qos)
+            .max_by_key(|&qos| qos as u8)
+    }
+
+    /// Every distinct filter with the highest QoS wanted for it
+    pub fn filters(&self) -> Vec<(TopicFilter, QoS)> {
+        let mut filters: Vec<(TopicFilter, QoS)> = Vec::new();
+        for entry in self.entries.iter() {
+            match filters.iter_mut().find(|(filter, _)| filter == &entry.filter) {
+                Some((_, qos)) if (*qos as u8) < (entry.qos as u8) => *qos = entry.qos,
+                Some(_) => {}
+                None => filters.push((entry.filter.clone(), entry.qos)),
+            }
+        }
+        filters
+    }
+
+    /// Run every handler whose filter matches `topic`; returns whether any did
+    pub fn dispatch(&mut self, topic: &str, payload: &[u8]) -> bool {
+        let mut handled = false;
+        for entry in self.entries.iter_mut().filter(|entry| entry.filter.matches(topic)) {
+            (entry.handler)(topic, payload);
+            handled = true;
+        }
+        handled
+    }
+
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+    pub fn len(&self) -> usize { self.entries.len() }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::rc::Rc;
+    use alloc::string::String;
+    use core::cell::RefCell;
+
+    use super::*;
+
+    #[test]
+    fn test_dispatch_by_filter() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut subs = Subscriptions::new();
+        let log = seen.clone();
+        let events = subs.add(
+            TopicFilter::parse("ccr/+/events").unwrap(),
+            QoS::AtMostOnce,
+            Box::new(move |topic, _| log.borrow_mut().push(String::from(topic))),
+        );
+        let log = seen.clone();
+        subs.add(
+            TopicFilter::parse("ccr/#").unwrap(),
+            QoS::AtLeastOnce,
+            Box::new(move |_, payload| log.borrow_mut().push(String::from_utf8_lossy(payload).into_owned())),
+        );
+        subs.add(TopicFilter::parse("ccr/#").unwrap(), QoS::AtMostOnce, Box::new(|_, _| {}));
+
+        // Every matching handler sees the message
+        assert!(subs.dispatch("ccr/s1/events", b"hi"));
+        assert_eq!(*seen.borrow(), ["ccr/s1/events", "hi"]);
+        assert!(!subs.dispatch("other", b""));
+
+        let filter = TopicFilter::parse("ccr/#").unwrap();
+        assert_eq!(subs.qos_for(&filter), Some(QoS::AtLeastOnce));
+        assert_eq!(subs.filters().len(), 2);
+        assert_eq!(subs.remove(events), Some(TopicFilter::parse("ccr/+/events").unwrap()));
+        assert_eq!(subs.remove(events), None);
+        assert_eq!(subs.len(), 2);
+    }
+}
@@ -0,0 +1,411 @@
+//! Structured MQTT Topics
+//!
+//! Newtypes for topic names (publish) and topic filters (subscribe) built
+//! from validated segments instead of ad-hoc `format!` concatenation.
+//!
+//! Segments known at build time can be checked by the compiler:
+//!
+//! ```rust
+//! use xous_mqtt::topic::{Segment, Topic, TopicFilter};
+//!
+//! const CCR: Segment = Segment::new("ccr");
+//! const EVENTS: Segment = Segment::new("events");
+//!
+//! let session_id = "abc123";
+//! let topic = Topic::from_segment(CCR).join(session_id).unwrap().push(EVENTS);
+//! assert_eq!(topic.as_str(), "ccr/abc123/events");
+//!
+//! let filter = TopicFilter::from_segment(CCR).any().push(EVENTS);
+//! assert_eq!(filter.as_str(), "ccr/+/events");
+//! ```
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+#[cfg(feature = "alloc")]
+use alloc::string::String;
+#[cfg(feature = "alloc")]
+use core::fmt;
+
+#[cfg(feature = "alloc")]
+use crate::packet::{validate_topic_filter, validate_topic_name};
+
+/// Topic level separator
+pub const SEPARATOR: char = '/';
+
+/// Single-level wildcard
+pub const WILDCARD_SINGLE: char = '+';
+
+/// Multi-level wildcard
+pub const WILDCARD_MULTI: char = '#';
+
+/// Maximum encoded length of a topic (UTF-8 string length prefix is 16 bits)
+pub const MAX_TOPIC_LEN: usize = 65535;
+
+/// Topic construction error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopicError {
+    /// Topic or segment is empty
+    Empty,
+    /// Topic exceeds 65535 bytes
+    TooLong,
+    /// Segment contains a `/` separator
+    Separator,
+    /// Wildcard character where none is allowed
+    Wildcard,
+    /// NUL character
+    Nul,
+    /// `#` used anywhere but as the last level
+    MisplacedWildcard,
+}
+
+/// Check a single topic level, returning an error for separators,
+/// wildcards and NUL characters.
+///
+/// This is a `const fn` so that [`Segment::new`] fails the build when used
+/// in a `const` with a bad literal.
+pub const fn check_segment(s: &str) -> Result<(), TopicError> {
+    let bytes = s.as_bytes();
+    if bytes.is_empty() {
+        return Err(TopicError::Empty);
+    }
+    if bytes.len() > MAX_TOPIC_LEN {
+        return Err(TopicError::TooLong);
+    }
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'/' => return Err(TopicError::Separator),
+            b'+' | b'#' => return Err(TopicError::Wildcard),
+            0 => return Err(TopicError::Nul),
+            _ => {}
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+/// Check whether `topic` matches `filter` under MQTT wildcard rules
+///
+/// Topics beginning with `$` are not matched by a leading wildcard, so `#`
+/// does not pick up broker-internal topics such as `$SYS/...`.
+pub fn filter_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && leading_wildcard(filter) {
+        return false;
+    }
+    let mut filter_levels = filter.split(SEPARATOR);
+    let mut topic_levels = topic.split(SEPARATOR);
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Check whether every topic matched by `inner` is also matched by `outer`
+///
+/// Used to decide whether a subscription stays inside a permitted namespace:
+/// `ccr/#` covers `ccr/+/events`, but `ccr/+` does not cover `ccr/#`.
+pub fn filter_covers(outer: &str, inner: &str) -> bool {
+    if leading_wildcard(outer) && inner.starts_with('$') {
+        return false;
+    }
+    let mut outer_levels = outer.split(SEPARATOR);
+    let mut inner_levels = inner.split(SEPARATOR);
+    loop {
+        match (outer_levels.next(), inner_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(i)) if i != "#" => {}
+            (Some(o), Some(i)) if o == i => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Check whether some topic is matched by both `a` and `b`
+pub fn filters_overlap(a: &str, b: &str) -> bool {
+    if (leading_wildcard(a) && b.starts_with('$')) || (leading_wildcard(b) && a.starts_with('$')) {
+        return false;
+    }
+    let mut a_levels = a.split(SEPARATOR);
+    let mut b_levels = b.split(SEPARATOR);
+    loop {
+        match (a_levels.next(), b_levels.next()) {
+            (Some("#"), _) | (_, Some("#")) => return true,
+            (Some("+"), Some(_)) | (Some(_), Some("+")) => {}
+            (Some(x), Some(y)) if x == y => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+fn leading_wildcard(filter: &str) -> bool {
+    filter.starts_with(WILDCARD_SINGLE) || filter.starts_with(WILDCARD_MULTI)
+}
+
+/// A validated topic level
+///
+/// Create with [`Segment::new`] in a `const` to have invalid literals rejected
+/// at compile time, or with [`Segment::try_new`] for runtime values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment<'a>(&'a str);
+
+impl<'a> Segment<'a> {
+    /// Create a segment, panicking on invalid input.
+    ///
+    /// When evaluated in a `const` context the panic becomes a compile error.
+    pub const fn new(s: &'a str) -> Self {
+        match check_segment(s) {
+            Ok(()) => Self(s),
+            Err(_) => panic!("invalid MQTT topic segment"),
+        }
+    }
+
+    /// Create a segment from a runtime value
+    pub fn try_new(s: &'a str) -> Result<Self, TopicError> {
+        check_segment(s)?;
+        Ok(Self(s))
+    }
+
+    /// Get the segment text
+    pub const fn as_str(&self) -> &'a str { self.0 }
+}
+
+/// A topic name, valid for PUBLISH (no wildcards)
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Topic(String);
+
+#[cfg(feature = "alloc")]
+impl Topic {
+    /// Start a topic from a single runtime segment
+    pub fn new(first: &str) -> Result<Self, TopicError> { Ok(Self::from_segment(Segment::try_new(first)?)) }
+
+    /// Start a topic from a pre-validated segment
+    pub fn from_segment(first: Segment<'_>) -> Self { Self(String::from(first.as_str())) }
+
+    /// Parse a full topic name such as `ccr/abc123/events`
+    pub fn parse(s: &str) -> Result<Self, TopicError> {
+        validate_topic_name(s)?;
+        Ok(Self(String::from(s)))
+    }
+
+    /// Append a runtime segment
+    pub fn join(self, segment: &str) -> Result<Self, TopicError> { self.try_push(Segment::try_new(segment)?) }
+
+    /// Append a pre-validated segment
+    ///
+    /// Panics if the result would exceed [`MAX_TOPIC_LEN`]; use
+    /// [`Topic::try_push`] when that can't be ruled out.
+    pub fn push(self, segment: Segment<'_>) -> Self {
+        self.try_push(segment).expect("topic longer than MAX_TOPIC_LEN")
+    }
+
+    /// Append a pre-validated segment, failing past [`MAX_TOPIC_LEN`]
+    pub fn try_push(mut self, segment: Segment<'_>) -> Result<Self, TopicError> {
+        if self.0.len() + 1 + segment.as_str().len() > MAX_TOPIC_LEN {
+            return Err(TopicError::TooLong);
+        }
+        self.0.push(SEPARATOR);
+        self.0.push_str(segment.as_str());
+        Ok(self)
+    }
+
+    /// Get the rendered topic
+    pub fn as_str(&self) -> &str { &self.0 }
+
+    /// Consume into the rendered string
+    pub fn into_string(self) -> String { self.0 }
+
+    /// Iterate over topic levels
+    pub fn levels(&self) -> core::str::Split<'_, char> { self.0.split(SEPARATOR) }
+}
+
+#[cfg(feature = "alloc")]
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str(&self.0) }
+}
+
+#[cfg(feature = "alloc")]
+impl AsRef<str> for Topic {
+    fn as_ref(&self) -> &str { &self.0 }
+}
+
+/// A topic filter, valid for SUBSCRIBE/UNSUBSCRIBE (wildcards allowed)
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TopicFilter {
+    filter: String,
+    /// Set once `#` has been appended; no further levels are allowed
+    terminated: bool,
+}
+
+#[cfg(feature = "alloc")]
+impl TopicFilter {
+    /// Start a filter from a single runtime segment
+    pub fn new(first: &str) -> Result<Self, TopicError> { Ok(Self::from_segment(Segment::try_new(first)?)) }
+
+    /// Start a filter from a pre-validated segment
+    pub fn from_segment(first: Segment<'_>) -> Self {
+        Self { filter: String::from(first.as_str()), terminated: false }
+    }
+
+    /// Filter matching every topic (`#`)
+    pub fn all() -> Self { Self { filter: String::from("#"), terminated: true } }
+
+    /// Parse a full topic filter such as `ccr/+/events` or `ccr/#`
+    pub fn parse(s: &str) -> Result<Self, TopicError> {
+        validate_topic_filter(s)?;
+        let terminated = s.rsplit(SEPARATOR).next() == Some("#");
+        Ok(Self { filter: String::from(s), terminated })
+    }
+
+    /// Append a runtime segment
+    pub fn join(self, segment: &str) -> Result<Self, TopicError> { self.try_push(Segment::try_new(segment)?) }
+
+    /// Append a pre-validated segment
+    ///
+    /// Panics if the filter already ends in `#` or would exceed
+    /// [`MAX_TOPIC_LEN`]; use [`TopicFilter::try_push`] when that can't be
+    /// ruled out.
+    pub fn push(self, segment: Segment<'_>) -> Self {
+        self.try_push(segment).expect("level appended after `#` or past MAX_TOPIC_LEN")
+    }
+
+    /// Append a pre-validated segment, failing after `#` or past [`MAX_TOPIC_LEN`]
+    pub fn try_push(mut self, segment: Segment<'_>) -> Result<Self, TopicError> {
+        self.push_level(segment.as_str())?;
+        Ok(self)
+    }
+
+    /// Append a single-level wildcard (`+`)
+    pub fn any(mut self) -> Self {
+        self.push_level("+").expect("level appended after `#` or past MAX_TOPIC_LEN");
+        self
+    }
+
+    /// Append the multi-level wildcard (`#`), terminating the filter
+    pub fn rest(mut self) -> Self {
+        self.push_level("#").expect("level appended after `#` or past MAX_TOPIC_LEN");
+        self.terminated = true;
+        self
+    }
+
+    fn push_level(&mut self, level: &str) -> Result<(), TopicError> {
+        if self.terminated {
+            return Err(TopicError::MisplacedWildcard);
+        }
+        if self.filter.len() + 1 + level.len() > MAX_TOPIC_LEN {
+            return Err(TopicError::TooLong);
+        }
+        self.filter.push(SEPARATOR);
+        self.filter.push_str(level);
+        Ok(())
+    }
+
+    /// Check whether `topic` is matched by this filter
+    pub fn matches(&self, topic: &str) -> bool { filter_matches(&self.filter, topic) }
+
+    /// Get the rendered filter
+    pub fn as_str(&self) -> &str { &self.filter }
+
+    /// Consume into the rendered string
+    pub fn into_string(self) -> String { self.filter }
+}
+
+#[cfg(feature = "alloc")]
+impl From<Topic> for TopicFilter {
+    fn from(topic: Topic) -> Self { Self { filter: topic.0, terminated: false } }
+}
+
+#[cfg(feature = "alloc")]
+impl fmt::Display for TopicFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str(&self.filter) }
+}
+
+#[cfg(feature = "alloc")]
+impl AsRef<str> for TopicFilter {
+    fn as_ref(&self) -> &str { &self.filter }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+
+    const CCR: Segment = Segment::new("ccr");
+
+    #[test]
+    fn test_topic_join() {
+        let topic = Topic::from_segment(CCR).join("s1").unwrap().push(Segment::new("events"));
+        assert_eq!(topic.as_str(), "ccr/s1/events");
+        assert_eq!(topic.levels().count(), 3);
+    }
+
+    #[test]
+    fn test_topic_rejects_bad_segments() {
+        assert_eq!(Topic::new("").unwrap_err(), TopicError::Empty);
+        assert_eq!(Topic::from_segment(CCR).join("a/b").unwrap_err(), TopicError::Separator);
+        assert_eq!(Topic::from_segment(CCR).join("+").unwrap_err(), TopicError::Wildcard);
+        assert_eq!(Topic::parse("ccr/#").unwrap_err(), TopicError::Wildcard);
+    }
+
+    #[test]
+    fn test_join_checks_length() {
+        let long = "x".repeat(MAX_TOPIC_LEN - CCR.as_str().len() - 1);
+        let topic = Topic::from_segment(CCR).join(&long).unwrap();
+        assert_eq!(topic.as_str().len(), MAX_TOPIC_LEN);
+        assert_eq!(topic.clone().join("y").unwrap_err(), TopicError::TooLong);
+        assert_eq!(topic.try_push(Segment::new("y")).unwrap_err(), TopicError::TooLong);
+        let filter = TopicFilter::from_segment(CCR).join(&long).unwrap();
+        assert_eq!(filter.join("y").unwrap_err(), TopicError::TooLong);
+    }
+
+    #[test]
+    fn test_filter_wildcards() {
+        let filter = TopicFilter::from_segment(CCR).any().rest();
+        assert_eq!(filter.as_str(), "ccr/+/#");
+        assert_eq!(filter.join("x").unwrap_err(), TopicError::MisplacedWildcard);
+
+        assert!(TopicFilter::parse("ccr/+/events").is_ok());
+        assert_eq!(TopicFilter::parse("ccr/#/events").unwrap_err(), TopicError::MisplacedWildcard);
+        assert_eq!(TopicFilter::parse("ccr/ev+").unwrap_err(), TopicError::MisplacedWildcard);
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        let filter = TopicFilter::from_segment(CCR).any().push(Segment::new("events"));
+        assert!(filter.matches("ccr/s1/events"));
+        assert!(!filter.matches("ccr/s1/events/x"));
+        assert!(!filter.matches("ccr/events"));
+
+        assert!(filter_matches("ccr/#", "ccr"));
+        assert!(filter_matches("ccr/#", "ccr/a/b"));
+        assert!(filter_matches("#", "ccr/a"));
+        assert!(!filter_matches("#", "$SYS/uptime"));
+        assert!(filter_matches("$SYS/#", "$SYS/uptime"));
+    }
+
+    #[test]
+    fn test_filter_covers_and_overlaps() {
+        assert!(filter_covers("ccr/#", "ccr/+/events"));
+        assert!(filter_covers("ccr/#", "ccr"));
+        assert!(filter_covers("ccr/+/events", "ccr/s1/events"));
+        assert!(!filter_covers("ccr/+", "ccr/#"));
+        assert!(!filter_covers("ccr/s1/#", "ccr/+/events"));
+        assert!(!filter_covers("#", "$SYS/#"));
+
+        assert!(filters_overlap("ccr/+/events", "ccr/s1/#"));
+        assert!(filters_overlap("#", "ccr"));
+        assert!(!filters_overlap("ccr/+/events", "ccr/s1/status"));
+        assert!(!filters_overlap("+/uptime", "$SYS/uptime"));
+    }
+}
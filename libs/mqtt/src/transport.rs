@@ -0,0 +1,670 @@
+//! Broker Transport
+//!
+//! The client talks to the broker through a [`Transport`] opened by a
+//! [`Connector`]. [`TcpConnector`] resolves the broker name with a
+//! [`Resolver`] and connects with `std::net`, which on Xous targets is backed
+//! by the Net service; tests substitute an in-memory transport. With
+//! `tls-support`, [`TlsConnector`] runs TLS over the same connection,
+//! presenting a [`ClientIdentity`] to brokers that require mutual TLS and
+//! checking the broker against its [`ServerTrust`].
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+#[cfg(feature = "tls-support")]
+use alloc::sync::Arc;
+#[cfg(feature = "tls-support")]
+use alloc::vec::Vec;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+#[cfg(feature = "tls-support")]
+use rustls::client::WantsClientCert;
+#[cfg(feature = "tls-support")]
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+#[cfg(feature = "tls-support")]
+use rustls::crypto::{WebPkiSupportedAlgorithms, ring, verify_tls12_signature, verify_tls13_signature};
+#[cfg(feature = "tls-support")]
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+#[cfg(feature = "tls-support")]
+use rustls::{
+    CertificateError, ClientConfig, ClientConnection, ConfigBuilder, DigitallySignedStruct, RootCertStore,
+    SignatureScheme, StreamOwned,
+};
+#[cfg(feature = "tls-support")]
+use sha2::{Digest, Sha256};
+
+use crate::resolve::{self, AddressFamily, DnsResolver, ResolveError, Resolver};
+use crate::socks::{self, Socks5Proxy, SocksError};
+
+/// Result of a non-blocking read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recv {
+    /// This many bytes were read into the buffer
+    Data(usize),
+    /// Nothing available right now
+    Idle,
+    /// The peer closed the connection
+    Closed,
+}
+
+/// Byte stream to the broker
+pub trait Transport {
+    /// Write all of `data`
+    fn send(&mut self, data: &[u8]) -> Result<(), String>;
+
+    /// Read whatever is available without waiting for more
+    fn recv(&mut self, buf: &mut [u8]) -> Result<Recv, String>;
+}
+
+/// Why a connector couldn't open a transport
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OpenError {
+    /// The broker didn't answer within the timeout
+    Timeout,
+    /// The broker address couldn't be resolved
+    Resolve(ResolveError),
+    /// Connecting failed
+    Failed(String),
+}
+
+/// Opens transports to a broker address
+pub trait Connector {
+    /// Connect to `broker`, giving up after `timeout_ms` (0 waits as long as the stack does)
+    fn open(&mut self, broker: &str, timeout_ms: u64) -> Result<Box<dyn Transport>, OpenError>;
+}
+
+/// Plain TCP connector (`host:port`)
+///
+/// Tries each address the broker resolves to, in the order `family` gives,
+/// until one accepts the connection. With a proxy, it's the proxy's
+/// addresses that are tried, and the proxy that connects to the broker.
+pub struct TcpConnector {
+    resolver: Box<dyn Resolver>,
+    family: AddressFamily,
+    proxy: Option<Socks5Proxy>,
+}
+
+impl TcpConnector {
+    /// Connector resolving names through the Xous DNS service
+    pub fn new(family: AddressFamily) -> Self { Self::with_resolver(Box::new(DnsResolver::new()), family) }
+
+    pub fn with_resolver(resolver: Box<dyn Resolver>, family: AddressFamily) -> Self {
+        Self { resolver, family, proxy: None }
+    }
+
+    /// Reach brokers through `proxy`, if any
+    pub fn with_proxy(mut self, proxy: Option<Socks5Proxy>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+}
+
+impl Default for TcpConnector {
+    fn default() -> Self { Self::new(AddressFamily::default()) }
+}
+
+impl TcpConnector {
+    /// Connect to the first address of `broker`, or of the proxy, that accepts
+    fn connect(&mut self, broker: &str, timeout_ms: u64) -> Result<TcpStream, OpenError> {
+        let (host, port) = resolve::split_host_port(broker).map_err(OpenError::Resolve)?;
+        let target = self.proxy.as_ref().map_or(broker, |proxy| proxy.address.as_str());
+        let addrs =
+            resolve::resolve(self.resolver.as_mut(), target, self.family).map_err(OpenError::Resolve)?;
+        let mut result = Err(OpenError::Resolve(ResolveError::NoAddress));
+        for addr in addrs {
+            let attempt = if timeout_ms == 0 {
+                TcpStream::connect(addr)
+            } else {
+                TcpStream::connect_timeout(&addr, Duration::from_millis(timeout_ms))
+            };
+            result = match attempt {
+                Ok(stream) => Ok(stream),
+                Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => {
+                    Err(OpenError::Timeout)
+                }
+                Err(e) => Err(failed(target, e)),
+            };
+            if result.is_ok() {
+                break;
+            }
+        }
+        let mut stream = result?;
+        stream.set_nodelay(true).ok();
+        if let Some(proxy) = self.proxy.as_ref() {
+            set_handshake_timeouts(&stream, timeout_ms).map_err(|e| failed(target, e))?;
+            socks::connect(&mut stream, proxy, host, port).map_err(|e| match e {
+                SocksError::Timeout => OpenError::Timeout,
+                e => OpenError::Failed(format!("SOCKS5 proxy {}: {}", proxy.address, e)),
+            })?;
+        }
+        Ok(stream)
+    }
+}
+
+impl Connector for TcpConnector {
+    fn open(&mut self, broker: &str, timeout_ms: u64) -> Result<Box<dyn Transport>, OpenError> {
+        let stream = self.connect(broker, timeout_ms)?;
+        set_poll_timeouts(&stream).map_err(|e| failed(broker, e))?;
+        Ok(Box::new(TcpTransport { stream }))
+    }
+}
+
+fn failed(broker: &str, e: std::io::Error) -> OpenError {
+    OpenError::Failed(format!("connect to {}: {}", broker, e))
+}
+
+/// Timeouts for a handshake, which may take up to `timeout_ms` (0 for no limit)
+fn set_handshake_timeouts(stream: &TcpStream, timeout_ms: u64) -> std::io::Result<()> {
+    let timeout = if timeout_ms == 0 { None } else { Some(Duration::from_millis(timeout_ms)) };
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)
+}
+
+/// Short read timeout so `recv` behaves as a poll; writes may block briefly
+fn set_poll_timeouts(stream: &TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_millis(1)))?;
+    stream.set_write_timeout(Some(Duration::from_millis(5000)))
+}
+
+/// Transport over a connected [`TcpStream`]
+pub struct TcpTransport {
+    stream: TcpStream,
+}
+
+impl Transport for TcpTransport {
+    fn send(&mut self, data: &[u8]) -> Result<(), String> {
+        self.stream.write_all(data).and_then(|_| self.stream.flush()).map_err(|e| format!("{}", e))
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> Result<Recv, String> {
+        match self.stream.read(buf) {
+            Ok(0) => Ok(Recv::Closed),
+            Ok(n) => Ok(Recv::Data(n)),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => Ok(Recv::Idle),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+}
+
+/// Client certificate for brokers that require mutual TLS
+///
+/// `cert_chain` is DER, leaf first, with any intermediates appended back to
+/// back. `private_key` is DER in PKCS#8, PKCS#1 (RSA) or SEC1 (EC) form;
+/// which one is read from its layout.
+#[cfg(feature = "tls-support")]
+#[derive(Clone)]
+pub struct ClientIdentity {
+    pub cert_chain: Vec<u8>,
+    pub private_key: Vec<u8>,
+}
+
+#[cfg(feature = "tls-support")]
+impl ClientIdentity {
+    const KEY_CERT_CHAIN: &'static str = "client_cert";
+    const KEY_PRIVATE_KEY: &'static str = "client_key";
+
+    /// Load the identity saved in PDDB dictionary `dict`, under the keys
+    /// `client_cert` and `client_key`
+    ///
+    /// Returns `None`, having logged why, if either key is missing or empty.
+    pub fn from_pddb(dict: &str) -> Option<Self> {
+        let pddb = open_pddb();
+        let cert_chain = read_pddb(&pddb, dict, Self::KEY_CERT_CHAIN);
+        let private_key = read_pddb(&pddb, dict, Self::KEY_PRIVATE_KEY);
+        match (cert_chain, private_key) {
+            (Some(cert_chain), Some(private_key)) => Some(Self { cert_chain, private_key }),
+            _ => {
+                log::warn!("MQTT: No TLS client certificate and key in {}", dict);
+                None
+            }
+        }
+    }
+
+    fn certificates(&self) -> Result<Vec<CertificateDer<'static>>, String> {
+        der::split(&self.cert_chain)
+            .filter(|certs| !certs.is_empty())
+            .map(|certs| certs.into_iter().map(|cert| CertificateDer::from(cert.to_vec())).collect())
+            .ok_or_else(|| String::from("client certificate chain isn't DER"))
+    }
+
+    fn key(&self) -> Result<PrivateKeyDer<'static>, String> {
+        let key = self.private_key.clone();
+        match der::key_format(&self.private_key) {
+            Some(der::KeyFormat::Pkcs8) => Ok(PrivateKeyDer::Pkcs8(key.into())),
+            Some(der::KeyFormat::Pkcs1) => Ok(PrivateKeyDer::Pkcs1(key.into())),
+            Some(der::KeyFormat::Sec1) => Ok(PrivateKeyDer::Sec1(key.into())),
+            None => Err(String::from("client key isn't a DER PKCS#8, PKCS#1 or SEC1 key")),
+        }
+    }
+}
+
+// Keep the key out of logs
+#[cfg(feature = "tls-support")]
+impl core::fmt::Debug for ClientIdentity {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ClientIdentity")
+            .field("cert_chain", &self.cert_chain.len())
+            .field("private_key", &"..")
+            .finish()
+    }
+}
+
+/// What the broker's certificate is checked against
+#[cfg(feature = "tls-support")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ServerTrust {
+    /// The trust anchors saved with the `tls` library, the same ones the
+    /// rest of the system trusts
+    #[default]
+    System,
+    /// Only these CA certificates, DER back to back; for a self-hosted
+    /// broker with a private CA
+    Roots(Vec<u8>),
+    /// Only a certificate whose SHA-256 fingerprint is one of these,
+    /// whoever signed it and whatever name and dates it carries
+    Pinned(Vec<[u8; 32]>),
+}
+
+#[cfg(feature = "tls-support")]
+impl ServerTrust {
+    const KEY_CA_CERTS: &'static str = "ca_certs";
+    const KEY_PINNED_SHA256: &'static str = "pinned_sha256";
+
+    /// Load the trust saved in PDDB dictionary `dict`
+    ///
+    /// Fingerprints under `pinned_sha256`, 32 bytes each, win over CA
+    /// certificates under `ca_certs`; with neither, the system trust
+    /// anchors are used.
+    pub fn from_pddb(dict: &str) -> Self {
+        let pddb = open_pddb();
+        if let Some(pins) = read_pddb(&pddb, dict, Self::KEY_PINNED_SHA256) {
+            if pins.len() % 32 == 0 {
+                return Self::Pinned(
+                    pins.chunks_exact(32).map(|pin| pin.try_into().expect("32-byte chunk")).collect(),
+                );
+            }
+            log::warn!("MQTT: {}:{} isn't a list of SHA-256 fingerprints", dict, Self::KEY_PINNED_SHA256);
+        }
+        match read_pddb(&pddb, dict, Self::KEY_CA_CERTS) {
+            Some(roots) => Self::Roots(roots),
+            None => Self::System,
+        }
+    }
+
+    fn builder(&self) -> Result<ConfigBuilder<ClientConfig, WantsClientCert>, String> {
+        let builder = ClientConfig::builder();
+        Ok(match self {
+            Self::System => builder.with_root_certificates(tls::Tls::new().root_store()),
+            Self::Roots(der) => {
+                let mut roots = RootCertStore::empty();
+                for cert in der::split(der).ok_or_else(|| String::from("CA certificates aren't DER"))? {
+                    roots.add(CertificateDer::from(cert)).map_err(|e| format!("CA certificate: {}", e))?;
+                }
+                if roots.is_empty() {
+                    return Err(String::from("no CA certificates"));
+                }
+                builder.with_root_certificates(roots)
+            }
+            Self::Pinned(pins) => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier::new(pins.clone()))),
+        })
+    }
+}
+
+/// Accepts exactly the pinned certificates, still checking the handshake
+/// signatures made with them
+#[cfg(feature = "tls-support")]
+#[derive(Debug)]
+struct PinnedVerifier {
+    pins: Vec<[u8; 32]>,
+    supported: WebPkiSupportedAlgorithms,
+}
+
+#[cfg(feature = "tls-support")]
+impl PinnedVerifier {
+    fn new(pins: Vec<[u8; 32]>) -> Self {
+        Self { pins, supported: ring::default_provider().signature_verification_algorithms }
+    }
+}
+
+#[cfg(feature = "tls-support")]
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = Sha256::digest(end_entity.as_ref());
+        if self.pins.iter().any(|pin| pin[..] == fingerprint[..]) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.supported)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.supported)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> { self.supported.supported_schemes() }
+}
+
+/// Settings for [`TlsConnector`]
+#[cfg(feature = "tls-support")]
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// What the broker's certificate is checked against
+    pub server_trust: ServerTrust,
+    /// Certificate to present when the broker asks for one; without it the
+    /// client doesn't authenticate at the TLS layer
+    pub client_identity: Option<ClientIdentity>,
+    /// Name to send as SNI and verify the certificate against, in place of
+    /// the broker's host; for ingresses that route on SNI to a broker
+    /// reached by another name, e.g. a bare address
+    pub server_name: Option<String>,
+    /// ALPN protocols to offer, most preferred first, e.g. [`ALPN_MQTT`]
+    /// for a broker sharing port 443 with other services; none offers no
+    /// ALPN extension
+    pub alpn_protocols: Vec<Vec<u8>>,
+}
+
+/// ALPN protocol id registered for MQTT
+#[cfg(feature = "tls-support")]
+pub const ALPN_MQTT: &[u8] = b"mqtt";
+
+#[cfg(feature = "tls-support")]
+impl TlsConfig {
+    fn client_config(&self) -> Result<ClientConfig, String> {
+        let builder = self.server_trust.builder()?;
+        let mut config = match &self.client_identity {
+            Some(identity) => builder
+                .with_client_auth_cert(identity.certificates()?, identity.key()?)
+                .map_err(|e| format!("client certificate: {}", e))?,
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = self.alpn_protocols.clone();
+        Ok(config)
+    }
+}
+
+/// TLS connector (`host:port`, usually [`MQTTS_PORT`](crate::MQTTS_PORT))
+///
+/// Connects over TCP like [`TcpConnector`], then completes the TLS
+/// handshake, verifying the broker's certificate for `host`, or
+/// [`TlsConfig::server_name`] if set, before handing the transport over.
+#[cfg(feature = "tls-support")]
+pub struct TlsConnector {
+    tcp: TcpConnector,
+    config: TlsConfig,
+}
+
+#[cfg(feature = "tls-support")]
+impl TlsConnector {
+    pub fn new(family: AddressFamily, config: TlsConfig) -> Self {
+        Self::with_tcp(TcpConnector::new(family), config)
+    }
+
+    /// Connector making its TCP connections with `tcp`
+    pub fn with_tcp(tcp: TcpConnector, config: TlsConfig) -> Self { Self { tcp, config } }
+}
+
+#[cfg(feature = "tls-support")]
+impl Connector for TlsConnector {
+    fn open(&mut self, broker: &str, timeout_ms: u64) -> Result<Box<dyn Transport>, OpenError> {
+        let tls_failed = |e: String| OpenError::Failed(format!("TLS to {}: {}", broker, e));
+        // Checked before connecting, so a bad certificate or name doesn't cost a round trip
+        let config = self.config.client_config().map_err(tls_failed)?;
+        let (host, _) = resolve::split_host_port(broker).map_err(OpenError::Resolve)?;
+        let host = self.config.server_name.as_deref().unwrap_or(host);
+        let server_name = ServerName::try_from(String::from(host))
+            .map_err(|e| tls_failed(format!("{} isn't a valid server name: {}", host, e)))?;
+        let mut connection =
+            ClientConnection::new(Arc::new(config), server_name).map_err(|e| tls_failed(format!("{}", e)))?;
+
+        let mut stream = self.tcp.connect(broker, timeout_ms)?;
+        set_handshake_timeouts(&stream, timeout_ms).map_err(|e| failed(broker, e))?;
+        while connection.is_handshaking() {
+            match connection.complete_io(&mut stream) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => {
+                    return Err(OpenError::Timeout);
+                }
+                Err(e) => return Err(tls_failed(format!("handshake: {}", e))),
+            }
+        }
+        set_poll_timeouts(&stream).map_err(|e| failed(broker, e))?;
+        Ok(Box::new(TlsTransport { stream: StreamOwned::new(connection, stream) }))
+    }
+}
+
+/// Transport over an established TLS session
+#[cfg(feature = "tls-support")]
+pub struct TlsTransport {
+    stream: StreamOwned<ClientConnection, TcpStream>,
+}
+
+#[cfg(feature = "tls-support")]
+impl Transport for TlsTransport {
+    fn send(&mut self, data: &[u8]) -> Result<(), String> {
+        self.stream.write_all(data).and_then(|_| self.stream.flush()).map_err(|e| format!("{}", e))
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> Result<Recv, String> {
+        match self.stream.read(buf) {
+            Ok(0) => Ok(Recv::Closed),
+            Ok(n) => Ok(Recv::Data(n)),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => Ok(Recv::Idle),
+            // The broker hung up without a close_notify
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(Recv::Closed),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+}
+
+#[cfg(feature = "tls-support")]
+fn open_pddb() -> pddb::Pddb {
+    let pddb = pddb::Pddb::new();
+    pddb.try_mount();
+    pddb
+}
+
+/// Contents of `dict:key`, or `None` if it's missing or empty
+#[cfg(feature = "tls-support")]
+fn read_pddb(pddb: &pddb::Pddb, dict: &str, key: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut pddb_key = pddb.get(dict, key, None, false, false, None, None::<fn()>).ok()?;
+    if let Err(e) = pddb_key.read_to_end(&mut data) {
+        log::warn!("MQTT: Couldn't read TLS {}:{}: {:?}", dict, key, e);
+        return None;
+    }
+    if data.is_empty() { None } else { Some(data) }
+}
+
+/// Just enough DER to take certificates and keys apart
+#[cfg(feature = "tls-support")]
+mod der {
+    use alloc::vec::Vec;
+
+    const INTEGER: u8 = 0x02;
+    const OCTET_STRING: u8 = 0x04;
+    const SEQUENCE: u8 = 0x30;
+
+    /// Header length and contents length of the value `der` starts with
+    fn header(der: &[u8]) -> Option<(usize, usize)> {
+        let first = *der.get(1)?;
+        if first < 0x80 {
+            return Some((2, first as usize));
+        }
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = der.get(2..2 + n)?.iter().fold(0usize, |len, b| (len << 8) | *b as usize);
+        Some((2 + n, len))
+    }
+
+    /// The values `der` holds back to back
+    pub(super) fn split(mut der: &[u8]) -> Option<Vec<&[u8]>> {
+        let mut values = Vec::new();
+        while !der.is_empty() {
+            let (header, len) = header(der)?;
+            let end = header.checked_add(len).filter(|end| *end <= der.len())?;
+            let (value, rest) = der.split_at(end);
+            values.push(value);
+            der = rest;
+        }
+        Some(values)
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub(super) enum KeyFormat {
+        Pkcs8,
+        Pkcs1,
+        Sec1,
+    }
+
+    /// Each form is a SEQUENCE opening with a version INTEGER; what follows
+    /// the version tells them apart
+    pub(super) fn key_format(der: &[u8]) -> Option<KeyFormat> {
+        if der.first() != Some(&SEQUENCE) {
+            return None;
+        }
+        let (outer, _) = header(der)?;
+        let version = der.get(outer..)?;
+        if version.first() != Some(&INTEGER) {
+            return None;
+        }
+        let (header, len) = header(version)?;
+        match *version.get(header + len)? {
+            // AlgorithmIdentifier
+            SEQUENCE => Some(KeyFormat::Pkcs8),
+            // RSA modulus
+            INTEGER => Some(KeyFormat::Pkcs1),
+            // EC private key
+            OCTET_STRING => Some(KeyFormat::Sec1),
+            _ => None,
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_der() {
+            let long = [&[SEQUENCE, 0x81, 0x80][..], &[0u8; 0x80]].concat();
+            let chain = [&[SEQUENCE, 0x01, 0x05][..], &long].concat();
+            assert_eq!(split(&chain).unwrap(), [&chain[..3], &long[..]]);
+            assert_eq!(split(&chain[..chain.len() - 1]), None);
+
+            let pkcs8 = [SEQUENCE, 0x08, INTEGER, 0x01, 0x00, SEQUENCE, 0x00, OCTET_STRING, 0x01, 0x00];
+            assert_eq!(key_format(&pkcs8), Some(KeyFormat::Pkcs8));
+            let pkcs1 = [SEQUENCE, 0x06, INTEGER, 0x01, 0x00, INTEGER, 0x01, 0x07];
+            assert_eq!(key_format(&pkcs1), Some(KeyFormat::Pkcs1));
+            let sec1 = [SEQUENCE, 0x05, INTEGER, 0x01, 0x01, OCTET_STRING, 0x00];
+            assert_eq!(key_format(&sec1), Some(KeyFormat::Sec1));
+            assert_eq!(key_format(&[SEQUENCE, 0x03, INTEGER, 0x01, 0x00]), None);
+        }
+    }
+}
+
+/// In-memory broker connection for unit tests, here and (with the `test-util` feature) in crates built on the
+/// client
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock {
+    use alloc::collections::VecDeque;
+    use alloc::rc::Rc;
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    use super::*;
+    use crate::client::{MqttClient, MqttConfig, MqttEvent};
+    use crate::clock::ManualClock;
+
+    /// What the "broker" will send, and what the client sent it
+    #[derive(Default)]
+    pub struct MockBroker {
+        pub rx: VecDeque<u8>,
+        pub sent: Vec<Vec<u8>>,
+        pub closed: bool,
+        /// Connection attempts time out
+        pub unreachable: bool,
+    }
+
+    pub type Shared = Rc<RefCell<MockBroker>>;
+
+    struct MockTransport(Shared);
+
+    impl Transport for MockTransport {
+        fn send(&mut self, data: &[u8]) -> Result<(), String> {
+            self.0.borrow_mut().sent.push(data.to_vec());
+            Ok(())
+        }
+
+        fn recv(&mut self, buf: &mut [u8]) -> Result<Recv, String> {
+            let mut broker = self.0.borrow_mut();
+            if broker.rx.is_empty() {
+                return Ok(if broker.closed { Recv::Closed } else { Recv::Idle });
+            }
+            let n = buf.len().min(broker.rx.len());
+            for (dst, src) in buf.iter_mut().zip(broker.rx.drain(..n)) {
+                *dst = src;
+            }
+            Ok(Recv::Data(n))
+        }
+    }
+
+    struct MockConnector(Shared);
+
+    impl Connector for MockConnector {
+        fn open(&mut self, _broker: &str, _timeout_ms: u64) -> Result<Box<dyn Transport>, OpenError> {
+            if self.0.borrow().unreachable {
+                return Err(OpenError::Timeout);
+            }
+            self.0.borrow_mut().closed = false;
+            Ok(Box::new(MockTransport(self.0.clone())))
+        }
+    }
+
+    pub fn connector(broker: &Shared) -> Box<dyn Connector> { Box::new(MockConnector(broker.clone())) }
+
+    /// Client wired to a mock broker and a manual clock
+    pub fn client(config: MqttConfig) -> (MqttClient, Rc<ManualClock>, Shared) {
+        let clock = Rc::new(ManualClock::new(0));
+        let broker = Shared::default();
+        let client =
+            MqttClient::with_clock(config, Box::new(clock.clone())).with_connector(connector(&broker));
+        (client, clock, broker)
+    }
+
+    /// Connect `client` and have the broker accept it
+    pub fn accept(client: &mut MqttClient, broker: &Shared) {
+        client.connect().unwrap();
+        broker.borrow_mut().rx.extend([0x20, 0x02, 0x00, 0x00]);
+        assert!(matches!(client.poll(), Some(MqttEvent::Connected)));
+    }
+
+    /// Take everything the client has sent so far
+    pub fn sent(broker: &Shared) -> Vec<Vec<u8>> { core::mem::take(&mut broker.borrow_mut().sent) }
+}
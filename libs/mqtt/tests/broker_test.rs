@@ -1,50 +1,148 @@
-//! Integration test for MQTT packet implementation against real broker
+//! Integration test for MQTT packet implementation against real brokers
 //!
-//! Run with: cargo test -p xous-mqtt --test broker_test -- --nocapture
+//! Brokers differ in how they handle redelivery, session resume and large
+//! packets, so the suite can be pointed at several with `MQTT_TEST_BROKER`:
+//!
+//! - unset or `local` - a broker on 127.0.0.1:1883 (e.g. `mosquitto -v`)
+//! - `mosquitto` - test.mosquitto.org:1883
+//! - `emqx` - broker.emqx.io:1883
+//! - `hivemq` - broker.hivemq.com:1883
+//! - anything else - used as `host:port`
+//!
+//! Client ids and topics carry a per-run suffix, so runs against the public
+//! brokers don't see each other's traffic.
+//!
+//! Run with: MQTT_TEST_BROKER=emqx cargo test -p xous-mqtt --test broker_test -- --nocapture
 
 use std::io::{Read, Write};
 use std::net::TcpStream;
-use std::time::Duration;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 // Import from the library
-use xous_mqtt::packet::{self, QoS, Packet};
+use xous_mqtt::packet::{self, Packet, ParseError, ParseMode, QoS};
+
+fn broker_addr() -> String {
+    match std::env::var("MQTT_TEST_BROKER").as_deref() {
+        Err(_) | Ok("") | Ok("local") => String::from("127.0.0.1:1883"),
+        Ok("mosquitto") => String::from("test.mosquitto.org:1883"),
+        Ok("emqx") => String::from("broker.emqx.io:1883"),
+        Ok("hivemq") => String::from("broker.hivemq.com:1883"),
+        Ok(addr) => String::from(addr),
+    }
+}
 
-fn connect_to_broker() -> std::io::Result<TcpStream> {
-    let stream = TcpStream::connect("127.0.0.1:1883")?;
-    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
-    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
-    Ok(stream)
+/// Suffix unique to this run
+fn run_id() -> &'static str {
+    static RUN_ID: OnceLock<String> = OnceLock::new();
+    RUN_ID.get_or_init(|| {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.subsec_nanos());
+        format!("{}-{:08x}", std::process::id(), nanos)
+    })
 }
 
-#[test]
-fn test_connect_and_disconnect() {
-    println!("\n=== Test: CONNECT and DISCONNECT ===");
+fn client_id(name: &str) -> String { format!("xous-mqtt-{}-{}", name, run_id()) }
+
+fn topic(name: &str) -> String { format!("test/xous-mqtt/{}/{}", run_id(), name) }
 
-    let mut stream = connect_to_broker().expect("Failed to connect to broker");
-    println!("TCP connected to 127.0.0.1:1883");
+/// One connection to the broker
+struct Session {
+    stream: TcpStream,
+    rx: Vec<u8>,
+}
 
-    // Send CONNECT
-    let connect_packet = packet::build_connect("xous-mqtt-test");
-    println!("Sending CONNECT packet ({} bytes)", connect_packet.len());
-    stream.write_all(&connect_packet).expect("Failed to send CONNECT");
+impl Session {
+    /// Connect and wait for CONNACK; returns the session and its session-present flag
+    fn open(client_id: &str, clean_session: bool) -> (Self, bool) {
+        let addr = broker_addr();
+        let stream =
+            TcpStream::connect(&addr).unwrap_or_else(|e| panic!("Failed to connect to {}: {}", addr, e));
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.set_write_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.set_nodelay(true).unwrap();
+        let mut session = Self { stream, rx: Vec::new() };
+
+        session.send(&packet::build_connect_with_options(client_id, None, None, clean_session, 60));
+        match session.recv() {
+            Packet::Connack { session_present, code } => {
+                assert_eq!(code, packet::ConnackCode::Accepted);
+                (session, session_present)
+            }
+            other => panic!("Expected CONNACK, got {:?}", other),
+        }
+    }
 
-    // Read CONNACK
-    let mut buf = [0u8; 256];
-    let n = stream.read(&mut buf).expect("Failed to read CONNACK");
-    println!("Received {} bytes", n);
+    fn send(&mut self, data: &[u8]) { self.stream.write_all(data).expect("Failed to send packet"); }
+
+    /// Send `data` a few bytes at a time, so the broker sees it split across TCP segments
+    fn send_fragmented(&mut self, data: &[u8], chunk: usize) {
+        for piece in data.chunks(chunk) {
+            self.stream.write_all(piece).expect("Failed to send packet");
+            self.stream.flush().unwrap();
+        }
+    }
+
+    /// Receive the next packet, however the broker's bytes were split up
+    fn recv(&mut self) -> Packet {
+        let mut buf = [0u8; 4096];
+        loop {
+            // Brokers under test are held to the letter of the spec
+            match packet::parse_packet_with(&self.rx, ParseMode::Strict) {
+                Ok((packet, consumed)) => {
+                    self.rx.drain(..consumed);
+                    return packet;
+                }
+                Err(ParseError::Incomplete) => {}
+                Err(e) => panic!("Failed to parse packet: {:?}", e),
+            }
+            let n = self.stream.read(&mut buf).expect("Failed to read from broker");
+            assert!(n > 0, "Broker closed the connection");
+            self.rx.extend_from_slice(&buf[..n]);
+        }
+    }
 
-    let (parsed, _) = packet::parse_packet(&buf[..n]).expect("Failed to parse CONNACK");
-    match parsed {
-        Packet::Connack { session_present, code } => {
-            println!("CONNACK: session_present={}, code={:?}", session_present, code);
-            assert_eq!(code, packet::ConnackCode::Accepted);
+    /// Subscribe to one filter and check the broker granted `qos`
+    fn subscribe(&mut self, packet_id: u16, filter: &str, qos: QoS) {
+        self.send(&packet::build_subscribe(packet_id, filter, qos));
+        match self.recv() {
+            Packet::Suback { packet_id: id, return_codes } => {
+                assert_eq!(id, packet_id);
+                assert_eq!(return_codes, [qos as u8], "broker granted {:?}", return_codes);
+            }
+            other => panic!("Expected SUBACK, got {:?}", other),
         }
-        _ => panic!("Expected CONNACK, got {:?}", parsed),
     }
 
-    // Send DISCONNECT
-    let disconnect_packet = packet::build_disconnect();
-    stream.write_all(&disconnect_packet).expect("Failed to send DISCONNECT");
+    /// Publish at QoS 1 and wait for the PUBACK
+    fn publish_qos1(&mut self, packet_id: u16, topic: &str, payload: &[u8]) {
+        self.send(&packet::build_publish_with_id(topic, payload, QoS::AtLeastOnce, Some(packet_id), false));
+        match self.recv() {
+            Packet::Puback { packet_id: id } => assert_eq!(id, packet_id),
+            other => panic!("Expected PUBACK, got {:?}", other),
+        }
+    }
+
+    fn disconnect(mut self) { self.send(&packet::build_disconnect()); }
+
+    /// Lose the connection without a DISCONNECT, as a radio dropout would
+    fn drop_connection(self) { self.stream.shutdown(std::net::Shutdown::Both).ok(); }
+}
+
+/// Discard the persistent session held for `client_id`
+fn end_session(client_id: &str) {
+    let (session, _) = Session::open(client_id, true);
+    session.disconnect();
+}
+
+#[test]
+fn test_connect_and_disconnect() {
+    println!("\n=== Test: CONNECT and DISCONNECT ===");
+
+    let (session, session_present) = Session::open(&client_id("test"), true);
+    println!("Connected to {}: session_present={}", broker_addr(), session_present);
+    assert!(!session_present);
+
+    session.disconnect();
     println!("Sent DISCONNECT");
 
     println!("=== PASS ===\n");
@@ -54,54 +152,32 @@ fn test_connect_and_disconnect() {
 fn test_subscribe_and_publish() {
     println!("\n=== Test: SUBSCRIBE and PUBLISH ===");
 
-    let mut stream = connect_to_broker().expect("Failed to connect to broker");
-
-    // Connect
-    let connect_packet = packet::build_connect("xous-mqtt-pubsub-test");
-    stream.write_all(&connect_packet).expect("Failed to send CONNECT");
-
-    let mut buf = [0u8; 256];
-    let n = stream.read(&mut buf).expect("Failed to read CONNACK");
-    let (parsed, _) = packet::parse_packet(&buf[..n]).expect("Failed to parse CONNACK");
-    assert!(matches!(parsed, Packet::Connack { code: packet::ConnackCode::Accepted, .. }));
+    let (mut session, _) = Session::open(&client_id("pubsub"), true);
     println!("Connected");
 
     // Subscribe
-    let subscribe_packet = packet::build_subscribe(1, "test/xous-mqtt/#", QoS::AtMostOnce);
-    println!("Sending SUBSCRIBE to test/xous-mqtt/# ({} bytes)", subscribe_packet.len());
-    stream.write_all(&subscribe_packet).expect("Failed to send SUBSCRIBE");
-
-    // Read SUBACK
-    let n = stream.read(&mut buf).expect("Failed to read SUBACK");
-    let (parsed, _) = packet::parse_packet(&buf[..n]).expect("Failed to parse SUBACK");
-    match parsed {
-        Packet::Suback { packet_id, return_codes } => {
-            println!("SUBACK: packet_id={}, return_codes={:?}", packet_id, return_codes);
-            assert_eq!(packet_id, 1);
-        }
-        _ => panic!("Expected SUBACK, got {:?}", parsed),
-    }
+    let filter = format!("{}/#", topic("pubsub"));
+    println!("Sending SUBSCRIBE to {}", filter);
+    session.subscribe(1, &filter, QoS::AtMostOnce);
 
     // Publish (QoS 0 - no response expected)
     let payload = b"Hello from xous-mqtt test!";
-    let publish_packet = packet::build_publish("test/xous-mqtt/greeting", payload, QoS::AtMostOnce);
-    println!("Sending PUBLISH to test/xous-mqtt/greeting ({} bytes)", publish_packet.len());
-    stream.write_all(&publish_packet).expect("Failed to send PUBLISH");
+    let greeting = topic("pubsub/greeting");
+    println!("Sending PUBLISH to {}", greeting);
+    session.send(&packet::build_publish(&greeting, payload, QoS::AtMostOnce));
 
     // We should receive our own message back (since we're subscribed)
-    let n = stream.read(&mut buf).expect("Failed to read PUBLISH");
-    let (parsed, _) = packet::parse_packet(&buf[..n]).expect("Failed to parse PUBLISH");
-    match parsed {
+    match session.recv() {
         Packet::Publish { topic, payload: recv_payload, .. } => {
-            println!("Received PUBLISH: topic={}, payload={:?}", topic, String::from_utf8_lossy(&recv_payload));
-            assert_eq!(topic, "test/xous-mqtt/greeting");
+            let text = String::from_utf8_lossy(&recv_payload);
+            println!("Received PUBLISH: topic={}, payload={:?}", topic, text);
+            assert_eq!(topic, greeting);
             assert_eq!(recv_payload, payload);
         }
-        _ => panic!("Expected PUBLISH, got {:?}", parsed),
+        other => panic!("Expected PUBLISH, got {:?}", other),
     }
 
-    // Disconnect
-    stream.write_all(&packet::build_disconnect()).ok();
+    session.disconnect();
     println!("=== PASS ===\n");
 }
 
@@ -109,44 +185,13 @@ fn test_subscribe_and_publish() {
 fn test_qos1_publish() {
     println!("\n=== Test: QoS 1 PUBLISH ===");
 
-    let mut stream = connect_to_broker().expect("Failed to connect to broker");
-
-    // Connect
-    let connect_packet = packet::build_connect("xous-mqtt-qos1-test");
-    stream.write_all(&connect_packet).expect("Failed to send CONNECT");
-
-    let mut buf = [0u8; 256];
-    let n = stream.read(&mut buf).expect("Failed to read CONNACK");
-    let (parsed, _) = packet::parse_packet(&buf[..n]).expect("Failed to parse CONNACK");
-    assert!(matches!(parsed, Packet::Connack { code: packet::ConnackCode::Accepted, .. }));
+    let (mut session, _) = Session::open(&client_id("qos1"), true);
     println!("Connected");
 
-    // Publish with QoS 1
-    let payload = b"QoS 1 message";
-    let packet_id = 42u16;
-    let publish_packet = packet::build_publish_with_id(
-        "test/xous-mqtt/qos1",
-        payload,
-        QoS::AtLeastOnce,
-        Some(packet_id),
-        false,
-    );
-    println!("Sending QoS 1 PUBLISH (packet_id={}, {} bytes)", packet_id, publish_packet.len());
-    stream.write_all(&publish_packet).expect("Failed to send PUBLISH");
-
-    // Should receive PUBACK
-    let n = stream.read(&mut buf).expect("Failed to read PUBACK");
-    let (parsed, _) = packet::parse_packet(&buf[..n]).expect("Failed to parse PUBACK");
-    match parsed {
-        Packet::Puback { packet_id: recv_id } => {
-            println!("Received PUBACK: packet_id={}", recv_id);
-            assert_eq!(recv_id, packet_id);
-        }
-        _ => panic!("Expected PUBACK, got {:?}", parsed),
-    }
+    session.publish_qos1(42, &topic("qos1"), b"QoS 1 message");
+    println!("Received PUBACK");
 
-    // Disconnect
-    stream.write_all(&packet::build_disconnect()).ok();
+    session.disconnect();
     println!("=== PASS ===\n");
 }
 
@@ -154,62 +199,42 @@ fn test_qos1_publish() {
 fn test_qos2_publish() {
     println!("\n=== Test: QoS 2 PUBLISH (Exactly Once) ===");
 
-    let mut stream = connect_to_broker().expect("Failed to connect to broker");
-
-    // Connect
-    let connect_packet = packet::build_connect("xous-mqtt-qos2-test");
-    stream.write_all(&connect_packet).expect("Failed to send CONNECT");
-
-    let mut buf = [0u8; 256];
-    let n = stream.read(&mut buf).expect("Failed to read CONNACK");
-    let (parsed, _) = packet::parse_packet(&buf[..n]).expect("Failed to parse CONNACK");
-    assert!(matches!(parsed, Packet::Connack { code: packet::ConnackCode::Accepted, .. }));
+    let (mut session, _) = Session::open(&client_id("qos2"), true);
     println!("Connected");
 
     // Publish with QoS 2
-    let payload = b"QoS 2 exactly-once message";
     let packet_id = 100u16;
-    let publish_packet = packet::build_publish_with_id(
-        "test/xous-mqtt/qos2",
-        payload,
-        QoS::ExactlyOnce,
-        Some(packet_id),
-        false,
-    );
+    let payload = b"QoS 2 exactly-once message";
+    let publish_packet =
+        packet::build_publish_with_id(&topic("qos2"), payload, QoS::ExactlyOnce, Some(packet_id), false);
     println!("Sending QoS 2 PUBLISH (packet_id={}, {} bytes)", packet_id, publish_packet.len());
-    stream.write_all(&publish_packet).expect("Failed to send PUBLISH");
+    session.send(&publish_packet);
 
     // Step 1: Should receive PUBREC
-    let n = stream.read(&mut buf).expect("Failed to read PUBREC");
-    let (parsed, _) = packet::parse_packet(&buf[..n]).expect("Failed to parse PUBREC");
-    match parsed {
+    match session.recv() {
         Packet::Pubrec { packet_id: recv_id } => {
             println!("Received PUBREC: packet_id={}", recv_id);
             assert_eq!(recv_id, packet_id);
         }
-        _ => panic!("Expected PUBREC, got {:?}", parsed),
+        other => panic!("Expected PUBREC, got {:?}", other),
     }
 
     // Step 2: Send PUBREL
-    let pubrel_packet = packet::build_pubrel(packet_id);
     println!("Sending PUBREL (packet_id={})", packet_id);
-    stream.write_all(&pubrel_packet).expect("Failed to send PUBREL");
+    session.send(&packet::build_pubrel(packet_id));
 
     // Step 3: Should receive PUBCOMP
-    let n = stream.read(&mut buf).expect("Failed to read PUBCOMP");
-    let (parsed, _) = packet::parse_packet(&buf[..n]).expect("Failed to parse PUBCOMP");
-    match parsed {
+    match session.recv() {
         Packet::Pubcomp { packet_id: recv_id } => {
             println!("Received PUBCOMP: packet_id={}", recv_id);
             assert_eq!(recv_id, packet_id);
         }
-        _ => panic!("Expected PUBCOMP, got {:?}", parsed),
+        other => panic!("Expected PUBCOMP, got {:?}", other),
     }
 
     println!("QoS 2 handshake complete!");
 
-    // Disconnect
-    stream.write_all(&packet::build_disconnect()).ok();
+    session.disconnect();
     println!("=== PASS ===\n");
 }
 
@@ -217,34 +242,165 @@ fn test_qos2_publish() {
 fn test_ping() {
     println!("\n=== Test: PINGREQ/PINGRESP ===");
 
-    let mut stream = connect_to_broker().expect("Failed to connect to broker");
+    let (mut session, _) = Session::open(&client_id("ping"), true);
+    println!("Connected");
 
-    // Connect
-    let connect_packet = packet::build_connect("xous-mqtt-ping-test");
-    stream.write_all(&connect_packet).expect("Failed to send CONNECT");
+    session.send(&packet::build_pingreq());
+    match session.recv() {
+        Packet::Pingresp => println!("Received PINGRESP"),
+        other => panic!("Expected PINGRESP, got {:?}", other),
+    }
 
-    let mut buf = [0u8; 256];
-    let n = stream.read(&mut buf).expect("Failed to read CONNACK");
-    let (parsed, _) = packet::parse_packet(&buf[..n]).expect("Failed to parse CONNACK");
-    assert!(matches!(parsed, Packet::Connack { code: packet::ConnackCode::Accepted, .. }));
-    println!("Connected");
+    session.disconnect();
+    println!("=== PASS ===\n");
+}
+
+#[test]
+fn test_qos1_redelivery_after_drop() {
+    println!("\n=== Test: QoS 1 redelivery after a dropped connection ===");
+
+    let id = client_id("redeliver1");
+    let topic = topic("redeliver1");
+    let (mut subscriber, _) = Session::open(&id, false);
+    subscriber.subscribe(1, &topic, QoS::AtLeastOnce);
+
+    let (mut publisher, _) = Session::open(&client_id("redeliver1-pub"), true);
+    publisher.publish_qos1(7, &topic, b"must arrive");
+    publisher.disconnect();
+
+    // Receive without acknowledging, then lose the link
+    let first_id = match subscriber.recv() {
+        Packet::Publish { packet_id: Some(packet_id), dup: false, .. } => packet_id,
+        other => panic!("Expected PUBLISH, got {:?}", other),
+    };
+    subscriber.drop_connection();
+    println!("Dropped connection holding unacknowledged packet {}", first_id);
+
+    // The broker must resend with the original id and DUP set
+    let (mut subscriber, session_present) = Session::open(&id, false);
+    assert!(session_present);
+    match subscriber.recv() {
+        Packet::Publish { packet_id, payload, dup, qos, .. } => {
+            println!("Redelivered: packet_id={:?}, dup={}", packet_id, dup);
+            assert_eq!(packet_id, Some(first_id));
+            assert_eq!(payload, b"must arrive");
+            assert_eq!(qos, QoS::AtLeastOnce);
+            assert!(dup);
+        }
+        other => panic!("Expected redelivered PUBLISH, got {:?}", other),
+    }
+    subscriber.send(&packet::build_puback(first_id));
+    subscriber.disconnect();
+    end_session(&id);
+    println!("=== PASS ===\n");
+}
+
+#[test]
+fn test_qos2_release_after_drop() {
+    println!("\n=== Test: QoS 2 release resumed after a dropped connection ===");
+
+    let id = client_id("redeliver2");
+    let topic = topic("redeliver2");
+    let (mut subscriber, _) = Session::open(&id, false);
+    subscriber.subscribe(1, &topic, QoS::ExactlyOnce);
+
+    let (mut publisher, _) = Session::open(&client_id("redeliver2-pub"), true);
+    publisher.send(&packet::build_publish_with_id(&topic, b"exactly once", QoS::ExactlyOnce, Some(9), false));
+    assert!(matches!(publisher.recv(), Packet::Pubrec { packet_id: 9 }));
+    publisher.send(&packet::build_pubrel(9));
+    assert!(matches!(publisher.recv(), Packet::Pubcomp { packet_id: 9 }));
+    publisher.disconnect();
+
+    // Take the message, acknowledge receipt, then lose the link before PUBREL
+    let first_id = match subscriber.recv() {
+        Packet::Publish { packet_id: Some(packet_id), qos: QoS::ExactlyOnce, .. } => packet_id,
+        other => panic!("Expected PUBLISH, got {:?}", other),
+    };
+    subscriber.send(&packet::build_pubrec(first_id));
+    subscriber.drop_connection();
+
+    // The message was received, so only the release is resent
+    let (mut subscriber, session_present) = Session::open(&id, false);
+    assert!(session_present);
+    match subscriber.recv() {
+        Packet::Pubrel { packet_id } => assert_eq!(packet_id, first_id),
+        other => panic!("Expected PUBREL, got {:?}", other),
+    }
+    subscriber.send(&packet::build_pubcomp(first_id));
+    subscriber.disconnect();
+    end_session(&id);
+    println!("=== PASS ===\n");
+}
+
+#[test]
+fn test_session_resume() {
+    println!("\n=== Test: persistent session resume ===");
+
+    let id = client_id("resume");
+    let topic = topic("resume");
+    let (mut subscriber, session_present) = Session::open(&id, false);
+    assert!(!session_present);
+    subscriber.subscribe(1, &topic, QoS::AtLeastOnce);
+    subscriber.disconnect();
+
+    // Published while the subscriber is offline
+    let (mut publisher, _) = Session::open(&client_id("resume-pub"), true);
+    publisher.publish_qos1(3, &topic, b"queued while offline");
+    publisher.disconnect();
+
+    // The subscription survived, so the message arrives without subscribing again
+    let (mut subscriber, session_present) = Session::open(&id, false);
+    assert!(session_present);
+    match subscriber.recv() {
+        Packet::Publish { topic: recv_topic, payload, packet_id: Some(packet_id), .. } => {
+            assert_eq!(recv_topic, topic);
+            assert_eq!(payload, b"queued while offline");
+            subscriber.send(&packet::build_puback(packet_id));
+        }
+        other => panic!("Expected queued PUBLISH, got {:?}", other),
+    }
+    subscriber.disconnect();
+
+    // A clean connect ends the session
+    end_session(&id);
+    let (session, session_present) = Session::open(&id, false);
+    assert!(!session_present);
+    session.disconnect();
+    end_session(&id);
+    println!("=== PASS ===\n");
+}
+
+#[test]
+fn test_large_payload_fragmentation() {
+    println!("\n=== Test: large payloads split across segments ===");
+
+    let topic = topic("large");
+    let (mut subscriber, _) = Session::open(&client_id("large"), true);
+    subscriber.subscribe(1, &topic, QoS::AtLeastOnce);
+    let (mut publisher, _) = Session::open(&client_id("large-pub"), true);
+
+    // Sizes straddling each step of the remaining-length encoding
+    for (n, &len) in [127usize, 128, 16_383, 16_384, 65_535, 131_072].iter().enumerate() {
+        let packet_id = n as u16 + 1;
+        let payload: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let data = packet::build_publish_with_id(&topic, &payload, QoS::AtLeastOnce, Some(packet_id), false);
+        publisher.send_fragmented(&data, 1000);
+        match publisher.recv() {
+            Packet::Puback { packet_id: id } => assert_eq!(id, packet_id),
+            other => panic!("Expected PUBACK, got {:?}", other),
+        }
 
-    // Send PINGREQ
-    let ping_packet = packet::build_pingreq();
-    println!("Sending PINGREQ ({} bytes)", ping_packet.len());
-    stream.write_all(&ping_packet).expect("Failed to send PINGREQ");
-
-    // Should receive PINGRESP
-    let n = stream.read(&mut buf).expect("Failed to read PINGRESP");
-    let (parsed, _) = packet::parse_packet(&buf[..n]).expect("Failed to parse PINGRESP");
-    match parsed {
-        Packet::Pingresp => {
-            println!("Received PINGRESP");
+        match subscriber.recv() {
+            Packet::Publish { payload: received, packet_id: Some(id), .. } => {
+                println!("Received {} byte payload", received.len());
+                assert_eq!(received, payload);
+                subscriber.send(&packet::build_puback(id));
+            }
+            other => panic!("Expected PUBLISH, got {:?}", other),
         }
-        _ => panic!("Expected PINGRESP, got {:?}", parsed),
     }
 
-    // Disconnect
-    stream.write_all(&packet::build_disconnect()).ok();
+    publisher.disconnect();
+    subscriber.disconnect();
     println!("=== PASS ===\n");
 }
@@ -0,0 +1,284 @@
+//! Property-based tests for the packet layer
+//!
+//! Every packet the builders can produce must parse back to what was put in,
+//! and no input at all may make a parser panic: the parsers are the first code
+//! to see bytes from the network.
+//!
+//! Run with: cargo test -p xous-mqtt --features mqtt5 --test roundtrip
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+use xous_mqtt::packet::{self, ConnackCode, Packet, ParseError, ParseMode, QoS};
+
+fn qos() -> impl Strategy<Value = QoS> {
+    prop_oneof![Just(QoS::AtMostOnce), Just(QoS::AtLeastOnce), Just(QoS::ExactlyOnce)]
+}
+
+/// Any UTF-8 that fits an MQTT string
+fn mqtt_string() -> impl Strategy<Value = String> { ".{0,64}" }
+
+/// Payloads long enough to need a multi-byte remaining length
+fn payload() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![vec(any::<u8>(), 0..16), vec(any::<u8>(), 100..400)]
+}
+
+fn packet_id() -> impl Strategy<Value = u16> { 1..=u16::MAX }
+
+/// Prepend a fixed header to `body`
+fn with_header(first_byte: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![first_byte];
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+proptest! {
+    #[test]
+    fn publish_round_trip(
+        topic in mqtt_string(),
+        payload in payload(),
+        qos in qos(),
+        id in packet_id(),
+        retain in any::<bool>(),
+    ) {
+        let packet_id = (qos != QoS::AtMostOnce).then_some(id);
+        let data = packet::build_publish_with_id(&topic, &payload, qos, packet_id, retain);
+        prop_assert_eq!(packet::publish_len(&topic, payload.len(), qos), data.len());
+        prop_assert_eq!(packet::peek_packet_len(&data), Ok(data.len()));
+        let mut buf = vec![0; data.len()];
+        prop_assert_eq!(
+            packet::build_publish_with_id_into(&mut buf, &topic, &payload, qos, packet_id, retain),
+            Ok(data.len())
+        );
+        prop_assert_eq!(&buf, &data);
+        let (parsed, consumed) = packet::parse_packet(&data).unwrap();
+        prop_assert_eq!(consumed, data.len());
+        prop_assert_eq!(parsed, Packet::Publish { topic, payload, qos, packet_id, retain, dup: false });
+    }
+
+    #[test]
+    fn ack_round_trip(id in any::<u16>(), kind in 0..4usize) {
+        let (data, expected) = match kind {
+            0 => (packet::build_puback(id), Packet::Puback { packet_id: id }),
+            1 => (packet::build_pubrec(id), Packet::Pubrec { packet_id: id }),
+            2 => (packet::build_pubrel(id), Packet::Pubrel { packet_id: id }),
+            _ => (packet::build_pubcomp(id), Packet::Pubcomp { packet_id: id }),
+        };
+        prop_assert_eq!(packet::parse_packet(&data).unwrap(), (expected, data.len()));
+    }
+
+    #[test]
+    fn broker_packets_parse(
+        session_present in any::<bool>(),
+        code in 0..=5u8,
+        id in any::<u16>(),
+        return_codes in vec(prop_oneof![0..=2u8, Just(packet::SUBACK_FAILURE)], 1..8),
+    ) {
+        let data = with_header(0x20, &[session_present as u8, code]);
+        let (parsed, _) = packet::parse_packet(&data).unwrap();
+        match parsed {
+            Packet::Connack { session_present: sp, code: c } => {
+                prop_assert_eq!(sp, session_present);
+                prop_assert_eq!(c as u8, code);
+                prop_assert_eq!(c == ConnackCode::Accepted, code == 0);
+            }
+            other => prop_assert!(false, "expected CONNACK, got {:?}", other),
+        }
+
+        let mut body = id.to_be_bytes().to_vec();
+        body.extend_from_slice(&return_codes);
+        let data = with_header(0x90, &body);
+        let expected = Packet::Suback { packet_id: id, return_codes: return_codes.clone() };
+        prop_assert_eq!(packet::parse_packet(&data).unwrap(), (expected, data.len()));
+
+        let data = with_header(0xB0, &id.to_be_bytes());
+        prop_assert_eq!(packet::parse_packet(&data).unwrap(), (Packet::Unsuback { packet_id: id }, 4));
+    }
+
+    /// What the builders produce is conformant, so strict parsing accepts it
+    #[test]
+    fn strict_accepts_built_packets(
+        topic in "[a-z0-9]{1,8}(/[a-z0-9]{1,8}){0,3}",
+        payload in payload(),
+        qos in qos(),
+        id in packet_id(),
+        retain in any::<bool>(),
+    ) {
+        let packet_id = (qos != QoS::AtMostOnce).then_some(id);
+        let publish = packet::build_publish_with_id(&topic, &payload, qos, packet_id, retain);
+        for data in [publish, packet::build_puback(id), packet::build_pubrel(id), packet::build_pubcomp(id)] {
+            let strict = packet::parse_packet_with(&data, ParseMode::Strict);
+            prop_assert!(strict.is_ok(), "{:?}", strict);
+            prop_assert_eq!(strict, packet::parse_packet(&data));
+        }
+    }
+
+    /// A packet cut short reads as incomplete, never as something else
+    #[test]
+    fn truncated_packet_is_incomplete(
+        topic in mqtt_string(),
+        payload in payload(),
+        qos in qos(),
+        cut in any::<prop::sample::Index>(),
+    ) {
+        let packet_id = (qos != QoS::AtMostOnce).then_some(7);
+        let data = packet::build_publish_with_id(&topic, &payload, qos, packet_id, false);
+        let cut = cut.index(data.len());
+        prop_assert_eq!(packet::parse_packet(&data[..cut]).unwrap_err(), ParseError::Incomplete);
+        prop_assert_eq!(packet::parse_packet_ref(&data[..cut]).unwrap_err(), ParseError::Incomplete);
+    }
+
+    /// Back-to-back packets in one buffer are taken one at a time
+    #[test]
+    fn stream_of_packets(ids in vec(any::<u16>(), 1..10)) {
+        let stream: Vec<u8> = ids.iter().flat_map(|&id| packet::build_puback(id)).collect();
+        let mut rest = &stream[..];
+        for &id in &ids {
+            let (parsed, consumed) = packet::parse_packet(rest).unwrap();
+            prop_assert_eq!(parsed, Packet::Puback { packet_id: id });
+            rest = &rest[consumed..];
+        }
+        prop_assert!(rest.is_empty());
+    }
+
+    /// Garbage never panics, and the borrowed and owned parsers agree on it
+    #[test]
+    fn arbitrary_bytes_do_not_panic(data in vec(any::<u8>(), 0..512)) {
+        let owned = packet::parse_packet(&data);
+        let borrowed = packet::parse_packet_ref(&data);
+        match (owned, borrowed) {
+            (Ok((packet, n)), Ok((packet_ref, m))) => {
+                prop_assert_eq!(n, m);
+                prop_assert_eq!(packet, Packet::from(packet_ref));
+            }
+            (Err(a), Err(b)) => prop_assert_eq!(a, b),
+            (a, b) => prop_assert!(false, "parsers disagree: {:?} vs {:?}", a, b),
+        }
+        let _ = packet::parse_publish_ref(&data);
+        let _ = packet::parse_fixed_header(&data);
+    }
+
+    /// Valid framing around a random body: the body parsers must cope with anything
+    #[test]
+    fn arbitrary_body_does_not_panic(first_byte in any::<u8>(), body in vec(any::<u8>(), 0..300)) {
+        let data = with_header(first_byte, &body);
+        let _ = packet::parse_packet(&data);
+        let _ = packet::parse_packet_ref(&data);
+    }
+}
+
+#[cfg(feature = "mqtt5")]
+mod v5 {
+    use xous_mqtt::packet::PacketType;
+    use xous_mqtt::packet::v5::{self, Property, ReasonCode};
+
+    use super::*;
+
+    fn property() -> impl Strategy<Value = Property> {
+        prop_oneof![
+            any::<u8>().prop_map(Property::PayloadFormatIndicator),
+            any::<u32>().prop_map(Property::MessageExpiryInterval),
+            mqtt_string().prop_map(Property::ContentType),
+            mqtt_string().prop_map(Property::ResponseTopic),
+            vec(any::<u8>(), 0..32).prop_map(Property::CorrelationData),
+            (1..=268_435_455u32).prop_map(Property::SubscriptionIdentifier),
+            any::<u16>().prop_map(Property::ServerKeepAlive),
+            vec(any::<u8>(), 0..32).prop_map(Property::AuthenticationData),
+            mqtt_string().prop_map(Property::ReasonString),
+            any::<u16>().prop_map(Property::TopicAlias),
+            (mqtt_string(), mqtt_string()).prop_map(|(k, v)| Property::UserProperty(k, v)),
+            any::<u32>().prop_map(Property::MaximumPacketSize),
+        ]
+    }
+
+    fn properties() -> impl Strategy<Value = Vec<Property>> { vec(property(), 0..6) }
+
+    fn reason() -> impl Strategy<Value = ReasonCode> { any::<u8>().prop_map(ReasonCode) }
+
+    proptest! {
+        #[test]
+        fn properties_round_trip(properties in properties()) {
+            let mut buf = Vec::new();
+            v5::encode_properties(&mut buf, &properties);
+            prop_assert_eq!(v5::decode_properties(&buf).unwrap(), (properties, buf.len()));
+        }
+
+        #[test]
+        fn publish_round_trip(
+            topic in mqtt_string(),
+            payload in payload(),
+            qos in qos(),
+            id in packet_id(),
+            retain in any::<bool>(),
+            properties in properties(),
+        ) {
+            let packet_id = (qos != QoS::AtMostOnce).then_some(id);
+            let data = v5::build_publish(&topic, &payload, qos, packet_id, retain, &properties);
+            prop_assert_eq!(v5::publish_len(&topic, payload.len(), qos, &properties), data.len());
+            let mut buf = vec![0; data.len()];
+            let len = v5::build_publish_into(&mut buf, &topic, &payload, qos, packet_id, retain, &properties);
+            prop_assert_eq!(len, Ok(data.len()));
+            prop_assert_eq!(&buf, &data);
+            let expected =
+                v5::Packet::Publish { topic, payload, qos, packet_id, retain, dup: false, properties };
+            prop_assert_eq!(v5::parse_packet(&data).unwrap(), (expected, data.len()));
+        }
+
+        #[test]
+        fn ack_round_trip(
+            id in any::<u16>(),
+            kind in 0..4usize,
+            reason in reason(),
+            properties in properties(),
+        ) {
+            let data = match kind {
+                0 => v5::build_ack(PacketType::Puback, id, reason, &properties),
+                1 => v5::build_ack(PacketType::Pubrec, id, reason, &properties),
+                2 => v5::build_ack(PacketType::Pubrel, id, reason, &properties),
+                _ => v5::build_ack(PacketType::Pubcomp, id, reason, &properties),
+            };
+            let (packet_id, reason, properties) = (id, reason, properties);
+            let expected = match kind {
+                0 => v5::Packet::Puback { packet_id, reason, properties },
+                1 => v5::Packet::Pubrec { packet_id, reason, properties },
+                2 => v5::Packet::Pubrel { packet_id, reason, properties },
+                _ => v5::Packet::Pubcomp { packet_id, reason, properties },
+            };
+            prop_assert_eq!(v5::parse_packet(&data).unwrap(), (expected, data.len()));
+        }
+
+        #[test]
+        fn disconnect_and_auth_round_trip(reason in reason(), properties in properties()) {
+            let data = v5::build_disconnect(reason, &properties);
+            let expected = v5::Packet::Disconnect { reason, properties: properties.clone() };
+            prop_assert_eq!(v5::parse_packet(&data).unwrap(), (expected, data.len()));
+
+            let data = v5::build_auth(reason, &properties);
+            let expected = v5::Packet::Auth { reason, properties };
+            prop_assert_eq!(v5::parse_packet(&data).unwrap(), (expected, data.len()));
+        }
+
+        #[test]
+        fn arbitrary_bytes_do_not_panic(data in vec(any::<u8>(), 0..512)) {
+            let _ = v5::parse_packet(&data);
+            let _ = v5::parse_publish_ref(&data);
+            let _ = v5::decode_properties(&data);
+        }
+
+        #[test]
+        fn arbitrary_body_does_not_panic(first_byte in any::<u8>(), body in vec(any::<u8>(), 0..300)) {
+            let _ = v5::parse_packet(&with_header(first_byte, &body));
+        }
+    }
+}
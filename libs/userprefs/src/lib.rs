@@ -50,6 +50,25 @@ pub struct UserPrefs {
     pub headset_volume: u32,
     pub autotype_rate: usize,
     pub lefty_mode: bool,
+    // Claude Code Remote. Empty strings select the app's built-in defaults, except for
+    // `ccr_quick_replies`, where an empty (but present) record means "no templates".
+    pub ccr_broker: String,
+    pub ccr_dnd: String,
+    pub ccr_quick_replies: String,
+    // Hex Ed25519 public key that signs `ccr/policy` updates, and the last signed policy accepted
+    pub ccr_policy_key: String,
+    pub ccr_policy: String,
+    // Only the F1 + center key chord can allow a permission request
+    pub ccr_chord_approval: bool,
+    // Hex X25519 public key of the bridge; when set, session traffic is end-to-end encrypted
+    pub ccr_e2e_bridge_key: String,
+    // Seconds between `ccr/device/<id>/status` heartbeats; 0 selects the default
+    pub ccr_heartbeat_secs: u64,
+    // On-device MQTT broker. An empty bind address listens on loopback only; any other needs
+    // a username, which clients must then connect with along with the password.
+    pub mqtt_broker_bind: String,
+    pub mqtt_broker_username: String,
+    pub mqtt_broker_password: String,
 }
 
 pub struct Manager {
@@ -1,4 +1,4 @@
-use std::net::ToSocketAddrs;
+use std::net::{IpAddr, ToSocketAddrs};
 
 use net::NetIpAddr;
 
@@ -32,6 +32,21 @@ impl Dns {
         }
     }
 
+    /// Like `lookup`, but returns every address the resolver has for `name`.
+    pub fn lookup_all(&self, name: &str) -> Result<Vec<IpAddr>, DnsResponseCode> {
+        log::debug!("looking up all of {}", name);
+        match (name, 80).to_socket_addrs() {
+            Ok(iter) => {
+                let addrs: Vec<IpAddr> = iter.map(|addr| addr.ip()).collect();
+                if addrs.is_empty() { Err(DnsResponseCode::NameError) } else { Ok(addrs) }
+            }
+            Err(e) => {
+                log::debug!("format error: {:?}", e);
+                Err(DnsResponseCode::FormatError)
+            }
+        }
+    }
+
     pub fn flush_cache(&self) -> Result<(), xous::Error> {
         log::warn!("DNS cache flush not implemented in hosted mode!");
         Ok(())
@@ -1,8 +1,9 @@
 #![cfg_attr(target_os = "none", no_std)]
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
 
 use net::NetIpAddr;
-use num_traits::ToPrimitive;
+use num_traits::{FromPrimitive, ToPrimitive};
 use xous::CID;
 use xous_ipc::Buffer;
 
@@ -36,6 +37,22 @@ impl Dns {
         }
     }
 
+    /// Like `lookup`, but returns every address the resolver has for `name`, IPv4 and IPv6,
+    /// so the caller can choose between them.
+    pub fn lookup_all(&self, name: &str) -> Result<Vec<IpAddr>, DnsResponseCode> {
+        if let Ok(simple_ip) = name.parse::<IpAddr>() {
+            return Ok(vec![simple_ip]);
+        }
+        if name.is_empty() || name.len() > DNS_NAME_LENGTH_LIMIT {
+            return Err(DnsResponseCode::FormatError);
+        }
+        // RawLookup takes the bare name, and overwrites the page with the answer
+        let mut buf = xous::StringBuffer::from_str(name).or(Err(DnsResponseCode::UnknownError))?;
+        buf.lend_mut(self.conn, Opcode::RawLookup.to_u32().unwrap())
+            .or(Err(DnsResponseCode::UnknownError))?;
+        parse_raw_response(buf.as_bytes())
+    }
+
     pub fn flush_cache(&self) -> Result<(), xous::Error> {
         xous::send_message(
             self.conn,
@@ -45,6 +62,35 @@ impl Dns {
     }
 }
 
+/// Decode a RawLookup answer: a status byte, then an error code or an entry count, then tagged
+/// addresses (4 and four octets for IPv4, 6 and sixteen for IPv6)
+fn parse_raw_response(data: &[u8]) -> Result<Vec<IpAddr>, DnsResponseCode> {
+    match data {
+        [0, count, entries @ ..] => {
+            let mut addrs = Vec::new();
+            let mut rest = entries;
+            for _ in 0..*count {
+                match rest {
+                    [4, a, b, c, d, tail @ ..] => {
+                        addrs.push(IpAddr::V4(Ipv4Addr::new(*a, *b, *c, *d)));
+                        rest = tail;
+                    }
+                    [6, tail @ ..] if tail.len() >= 16 => {
+                        let mut octets = [0u8; 16];
+                        octets.copy_from_slice(&tail[..16]);
+                        addrs.push(IpAddr::V6(Ipv6Addr::from(octets)));
+                        rest = &tail[16..];
+                    }
+                    _ => return Err(DnsResponseCode::UnknownError),
+                }
+            }
+            Ok(addrs)
+        }
+        [1, code, ..] => Err(DnsResponseCode::from_u8(*code).unwrap_or(DnsResponseCode::UnknownError)),
+        _ => Err(DnsResponseCode::UnknownError),
+    }
+}
+
 use core::sync::atomic::{AtomicU32, Ordering};
 static REFCOUNT: AtomicU32 = AtomicU32::new(0);
 impl Drop for Dns {
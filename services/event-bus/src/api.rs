@@ -0,0 +1,48 @@
+use rkyv::{Archive, Deserialize, Serialize};
+
+pub(crate) const SERVER_NAME_EVENT_BUS: &str = "_System event bus_";
+
+/// Upper bound on a message payload, so one publisher can't exhaust the bus's memory
+pub const MAX_PAYLOAD_LEN: usize = 2048;
+
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug)]
+pub(crate) enum Opcode {
+    /// Register a listener for a topic filter. Memory message containing a `Subscription`.
+    Subscribe = 0,
+    /// Remove a listener. Memory message containing a `Subscription`.
+    Unsubscribe = 1,
+    /// Deliver a message to every matching listener. Memory message containing a `BusMessage`.
+    Publish = 2,
+    /// Set (or clear) the topics mirrored to an external MQTT broker. Memory message containing a
+    /// `BridgeConfig`.
+    ConfigureBridge = 3,
+    /// Exits the server
+    Quit = 4,
+}
+
+/// A message on the bus
+///
+/// Listeners receive it as a memory message with the opcode they registered.
+#[derive(Debug, Archive, Serialize, Deserialize, Clone)]
+pub struct BusMessage {
+    /// MQTT-style topic, levels separated by `/`
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Archive, Serialize, Deserialize, Clone)]
+pub(crate) struct Subscription {
+    pub sid: [u32; 4],
+    pub opcode: u32,
+    /// Topic filter; `+` and `#` wildcards as in MQTT
+    pub filter: String,
+}
+
+/// Which local topics to mirror to an external broker
+#[derive(Debug, Archive, Serialize, Deserialize, Clone)]
+pub(crate) struct BridgeConfig {
+    /// Broker address (host:port); empty to stop bridging
+    pub broker: String,
+    /// Topic filters selecting the mirrored messages
+    pub filters: Vec<String>,
+}
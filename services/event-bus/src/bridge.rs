@@ -0,0 +1,104 @@
+//! Mirrors selected bus topics to an external MQTT broker.
+//!
+//! The bridge runs its own thread so a slow or unreachable broker never holds up local delivery.
+//! While the broker can't be reached, the most recent messages are kept and sent once the
+//! connection comes back; older ones are dropped.
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, channel};
+use std::time::Duration;
+
+use xous_mqtt::{MqttClient, MqttConfig, MqttEvent, QoS, TopicFilter};
+
+use crate::api::BusMessage;
+
+/// Messages held while the broker is unreachable
+const BACKLOG: usize = 32;
+/// How often the MQTT client is serviced when the bus is quiet
+const POLL_INTERVAL_MS: u64 = 50;
+
+pub(crate) struct Bridge {
+    filters: Vec<TopicFilter>,
+    tx: Sender<BusMessage>,
+}
+
+impl Bridge {
+    /// Connect to `broker` and start mirroring messages matching `filters`
+    pub(crate) fn start(broker: &str, filters: &[String]) -> Self {
+        let filters = filters
+            .iter()
+            .filter_map(|filter| match TopicFilter::parse(filter) {
+                Ok(filter) => Some(filter),
+                Err(e) => {
+                    log::warn!("Ignoring invalid bridge filter {}: {:?}", filter, e);
+                    None
+                }
+            })
+            .collect();
+        let config = MqttConfig {
+            broker: String::from(broker),
+            client_id: String::from("xous-event-bus"),
+            ..Default::default()
+        };
+        log::info!("Bridging to {}", broker);
+        let (tx, rx) = channel();
+        std::thread::spawn(move || run(config, rx));
+        Bridge { filters, tx }
+    }
+
+    /// Queue `msg` for the broker if it is one of the mirrored topics
+    pub(crate) fn offer(&self, msg: &BusMessage) {
+        if self.filters.iter().any(|filter| filter.matches(&msg.topic)) {
+            self.tx.send(msg.clone()).ok();
+        }
+    }
+}
+
+/// Bridge thread: runs until the `Bridge` is dropped
+fn run(config: MqttConfig, rx: Receiver<BusMessage>) {
+    let mut client = MqttClient::new(config);
+    // A failed attempt schedules a reconnect
+    client.connect().ok();
+    let mut backlog: VecDeque<BusMessage> = VecDeque::with_capacity(BACKLOG);
+    let mut dropped = 0u32;
+
+    loop {
+        match rx.recv_timeout(Duration::from_millis(POLL_INTERVAL_MS)) {
+            Ok(msg) => {
+                if backlog.len() == BACKLOG {
+                    backlog.pop_front();
+                    dropped += 1;
+                }
+                backlog.push_back(msg);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        while let Some(event) = client.poll() {
+            if let MqttEvent::Disconnected { reason } = event {
+                log::info!("Bridge disconnected: {:?}", reason);
+            }
+        }
+        if !client.is_connected() {
+            continue;
+        }
+        if dropped > 0 {
+            log::warn!("Bridge dropped {} messages while the broker was unreachable", dropped);
+            dropped = 0;
+        }
+        while let Some(msg) = backlog.front() {
+            match client.publish(&msg.topic, &msg.payload, QoS::AtLeastOnce) {
+                Ok(_) => {
+                    backlog.pop_front();
+                }
+                Err(e) => {
+                    log::warn!("Bridge couldn't publish {}: {:?}", msg.topic, e);
+                    break;
+                }
+            }
+        }
+    }
+    client.disconnect().ok();
+    log::info!("Bridge stopped");
+}
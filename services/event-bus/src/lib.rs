@@ -0,0 +1,86 @@
+#![cfg_attr(target_os = "none", no_std)]
+
+//! In-device publish/subscribe bus.
+//!
+//! Processes publish messages on MQTT-style topic strings and receive the ones matching the
+//! filters they subscribed to, without any network involved. Selected topics can additionally be
+//! mirrored to an external MQTT broker by the bus itself (the `mqtt-bridge` feature), so a
+//! publisher works the same whether or not the device is online.
+
+pub mod api;
+use api::*;
+pub use api::{BusMessage, MAX_PAYLOAD_LEN};
+use num_traits::ToPrimitive;
+use xous::CID;
+use xous_ipc::Buffer;
+
+pub struct EventBus {
+    conn: CID,
+}
+impl EventBus {
+    pub fn new() -> Self {
+        let xns = xous_names::XousNames::new().expect("couldn't connect to XousNames");
+        REFCOUNT.fetch_add(1, Ordering::Relaxed);
+        let conn =
+            xns.request_connection_blocking(SERVER_NAME_EVENT_BUS).expect("Can't connect to EventBus server");
+        EventBus { conn }
+    }
+
+    /// Register `sid` to receive memory messages with `opcode` for every message whose topic
+    /// matches `filter`. Each message holds a [`BusMessage`]; see [`EventBus::message_from`].
+    pub fn subscribe(&self, sid: xous::SID, opcode: u32, filter: &str) -> Result<(), xous::Error> {
+        let sub = Subscription { sid: sid.to_array(), opcode, filter: String::from(filter) };
+        let buf = Buffer::into_buf(sub).or(Err(xous::Error::InternalError))?;
+        buf.send(self.conn, Opcode::Subscribe.to_u32().unwrap()).map(|_| ())
+    }
+
+    pub fn unsubscribe(&self, sid: xous::SID, opcode: u32, filter: &str) -> Result<(), xous::Error> {
+        let sub = Subscription { sid: sid.to_array(), opcode, filter: String::from(filter) };
+        let buf = Buffer::into_buf(sub).or(Err(xous::Error::InternalError))?;
+        buf.send(self.conn, Opcode::Unsubscribe.to_u32().unwrap()).map(|_| ())
+    }
+
+    /// Publish `payload` on `topic`. Payloads over [`MAX_PAYLOAD_LEN`] are refused.
+    pub fn publish(&self, topic: &str, payload: &[u8]) -> Result<(), xous::Error> {
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return Err(xous::Error::OutOfMemory);
+        }
+        let msg = BusMessage { topic: String::from(topic), payload: payload.to_vec() };
+        let buf = Buffer::into_buf(msg).or(Err(xous::Error::InternalError))?;
+        buf.send(self.conn, Opcode::Publish.to_u32().unwrap()).map(|_| ())
+    }
+
+    /// Mirror local messages matching any of `filters` to the MQTT broker at `broker`
+    /// (`host:port`). An empty `broker` stops mirroring. Has no effect unless the bus was built
+    /// with the `mqtt-bridge` feature.
+    pub fn set_bridge(&self, broker: &str, filters: &[&str]) -> Result<(), xous::Error> {
+        let config = BridgeConfig {
+            broker: String::from(broker),
+            filters: filters.iter().map(|f| String::from(*f)).collect(),
+        };
+        let buf = Buffer::into_buf(config).or(Err(xous::Error::InternalError))?;
+        buf.send(self.conn, Opcode::ConfigureBridge.to_u32().unwrap()).map(|_| ())
+    }
+
+    /// Decode a message delivered to a subscriber
+    pub fn message_from(msg: &xous::MessageEnvelope) -> Option<BusMessage> {
+        let mem = msg.body.memory_message()?;
+        let buffer = unsafe { Buffer::from_memory_message(mem) };
+        buffer.to_original::<BusMessage, _>().ok()
+    }
+}
+
+use core::sync::atomic::{AtomicU32, Ordering};
+static REFCOUNT: AtomicU32 = AtomicU32::new(0);
+impl Drop for EventBus {
+    fn drop(&mut self) {
+        // the connection to the server side must be reference counted, so that multiple instances of this
+        // object within a single process do not end up de-allocating the CID on other threads before they
+        // go out of scope.
+        if REFCOUNT.fetch_sub(1, Ordering::Relaxed) == 1 {
+            unsafe {
+                xous::disconnect(self.conn).unwrap();
+            }
+        }
+    }
+}
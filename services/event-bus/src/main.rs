@@ -0,0 +1,141 @@
+#![cfg_attr(target_os = "none", no_std)]
+#![cfg_attr(target_os = "none", no_main)]
+
+mod api;
+#[cfg(feature = "mqtt-bridge")]
+mod bridge;
+use api::*;
+use num_traits::*;
+use xous_ipc::Buffer;
+use xous_mqtt::{Topic, TopicFilter};
+
+struct Subscriber {
+    cid: xous::CID,
+    sid: [u32; 4],
+    opcode: usize,
+    filter: TopicFilter,
+}
+
+fn deliver(sub: &Subscriber, msg: &BusMessage) {
+    let buf = match Buffer::into_buf(msg.clone()) {
+        Ok(buf) => buf,
+        Err(_) => return,
+    };
+    if let Err(e) = buf.send(sub.cid, sub.opcode as u32) {
+        log::warn!("Couldn't deliver {} to subscriber: {:?}", msg.topic, e);
+    }
+}
+
+/// `xous::connect` hands out one CID per server, shared by every subscription from that server, so it's only
+/// disconnected once the last of them is gone.
+fn release(subscribers: &[Subscriber], gone: &Subscriber) {
+    if !subscribers.iter().any(|s| s.sid == gone.sid) {
+        unsafe { xous::disconnect(gone.cid).ok() };
+    }
+}
+
+fn main() -> ! {
+    log_server::init_wait().unwrap();
+    log::set_max_level(log::LevelFilter::Info);
+    log::info!("my PID is {}", xous::process::id());
+
+    let xns = xous_names::XousNames::new().unwrap();
+    let bus_sid = xns.register_name(SERVER_NAME_EVENT_BUS, None).expect("can't register server");
+
+    let mut subscribers: Vec<Subscriber> = Vec::new();
+    #[cfg(feature = "mqtt-bridge")]
+    let mut bridge: Option<bridge::Bridge> = None;
+
+    log::trace!("ready to accept requests");
+    loop {
+        let msg = xous::receive_message(bus_sid).unwrap();
+        match FromPrimitive::from_usize(msg.body.id()) {
+            Some(Opcode::Subscribe) => {
+                let buffer = unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let sub = buffer.to_original::<Subscription, _>().unwrap();
+                let filter = match TopicFilter::parse(&sub.filter) {
+                    Ok(filter) => filter,
+                    Err(e) => {
+                        log::warn!("Refusing subscription to invalid filter {}: {:?}", sub.filter, e);
+                        continue;
+                    }
+                };
+                if subscribers
+                    .iter()
+                    .any(|s| s.sid == sub.sid && s.opcode == sub.opcode as usize && s.filter == filter)
+                {
+                    continue;
+                }
+                let cid = match xous::connect(xous::SID::from_array(sub.sid)) {
+                    Ok(cid) => cid,
+                    Err(e) => {
+                        log::warn!("Couldn't connect to bus subscriber: {:?}", e);
+                        continue;
+                    }
+                };
+                subscribers.push(Subscriber { cid, sid: sub.sid, opcode: sub.opcode as usize, filter });
+            }
+            Some(Opcode::Unsubscribe) => {
+                let buffer = unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let sub = buffer.to_original::<Subscription, _>().unwrap();
+                if let Some(index) = subscribers.iter().position(|s| {
+                    s.sid == sub.sid && s.opcode == sub.opcode as usize && s.filter.as_str() == sub.filter
+                }) {
+                    let gone = subscribers.remove(index);
+                    release(&subscribers, &gone);
+                }
+            }
+            Some(Opcode::Publish) => {
+                let buffer = unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let message = match buffer.to_original::<BusMessage, _>() {
+                    Ok(message) => message,
+                    Err(_) => continue,
+                };
+                if let Err(e) = Topic::parse(&message.topic) {
+                    log::warn!("Dropping message on invalid topic {}: {:?}", message.topic, e);
+                    continue;
+                }
+                if message.payload.len() > MAX_PAYLOAD_LEN {
+                    log::warn!("Dropping oversized message on {}", message.topic);
+                    continue;
+                }
+                for sub in subscribers.iter().filter(|s| s.filter.matches(&message.topic)) {
+                    deliver(sub, &message);
+                }
+                #[cfg(feature = "mqtt-bridge")]
+                if let Some(bridge) = bridge.as_ref() {
+                    bridge.offer(&message);
+                }
+            }
+            Some(Opcode::ConfigureBridge) => {
+                let buffer = unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let config = buffer.to_original::<BridgeConfig, _>().unwrap();
+                #[cfg(feature = "mqtt-bridge")]
+                {
+                    // Dropping the old bridge stops its thread
+                    bridge = if config.broker.is_empty() {
+                        None
+                    } else {
+                        Some(bridge::Bridge::start(&config.broker, &config.filters))
+                    };
+                }
+                #[cfg(not(feature = "mqtt-bridge"))]
+                log::warn!("MQTT bridge to {} requested, but not built in", config.broker);
+            }
+            Some(Opcode::Quit) => {
+                log::warn!("Quit received, goodbye world!");
+                break;
+            }
+            None => {
+                log::error!("couldn't convert opcode: {:?}", msg);
+            }
+        }
+    }
+    while let Some(gone) = subscribers.pop() {
+        release(&subscribers, &gone);
+    }
+    xns.unregister_server(bus_sid).unwrap();
+    xous::destroy_server(bus_sid).unwrap();
+    log::trace!("quitting");
+    xous::terminate_process(0)
+}
@@ -0,0 +1,15 @@
+pub(crate) const SERVER_NAME_MQTT_BROKER: &str = "_MQTT broker_";
+
+/// TCP port the broker listens on
+pub(crate) const BROKER_PORT: u16 = xous_mqtt::MQTT_PORT;
+/// Address the broker listens on unless the `mqtt_broker_bind` preference
+/// names another, so by default only processes on this device can connect
+pub(crate) const DEFAULT_BIND_ADDRESS: std::net::Ipv4Addr = std::net::Ipv4Addr::LOCALHOST;
+
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug)]
+pub(crate) enum Opcode {
+    /// Number of connected clients. Blocking scalar, returns a scalar1.
+    ClientCount = 0,
+    /// Exits the server
+    Quit = 1,
+}
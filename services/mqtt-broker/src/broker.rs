@@ -0,0 +1,660 @@
+//! Broker state, independent of the network.
+//!
+//! Each TCP connection gets a `ConnId`; the network side feeds in the bytes
+//! it reads and writes out whatever `Output` asks for. Everything is held in
+//! memory and forgotten when the client goes away: every session is clean,
+//! CONNACK never reports a session present, and retained messages last until
+//! the broker restarts. With [`Credentials`] set, CONNECT must carry that
+//! username and password. Subscriptions are granted at QoS 1 at most. A QoS 2 PUBLISH is acknowledged with
+//! the full PUBREC/PUBREL/PUBCOMP exchange but forwarded at QoS 1.
+
+use xous_mqtt::TopicFilter;
+use xous_mqtt::packet::{self, PacketType, QoS};
+
+pub(crate) type ConnId = u32;
+
+/// Most clients connected at once
+pub(crate) const MAX_CLIENTS: usize = 16;
+/// Largest packet accepted from a client
+pub(crate) const MAX_PACKET_SIZE: usize = 64 * 1024;
+/// Most retained messages kept; a new topic beyond this is not retained
+pub(crate) const MAX_RETAINED: usize = 64;
+
+const PROTOCOL_LEVEL_311: u8 = 4;
+const PROTOCOL_LEVEL_31: u8 = 3;
+
+const CONNACK_ACCEPTED: u8 = 0;
+const CONNACK_BAD_PROTOCOL: u8 = 1;
+const CONNACK_BAD_CLIENT_ID: u8 = 2;
+const CONNACK_BAD_CREDENTIALS: u8 = 4;
+const CONNACK_NOT_AUTHORIZED: u8 = 5;
+const SUBACK_FAILURE: u8 = 0x80;
+
+/// What the network side should do after the broker has handled some input
+#[derive(Default)]
+pub(crate) struct Output {
+    /// Packets to write, in order, each to its connection
+    pub packets: Vec<(ConnId, Vec<u8>)>,
+    /// Connections to shut down
+    pub close: Vec<ConnId>,
+}
+
+/// The username and password every client must connect with
+pub(crate) struct Credentials {
+    pub username: String,
+    pub password: Vec<u8>,
+}
+
+impl Credentials {
+    /// Whether a CONNECT's username and password match, taking as long for any
+    /// password of the right length
+    fn accept(&self, username: &str, password: &[u8]) -> bool {
+        let differ = self.password.iter().zip(password).fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        username == self.username && password.len() == self.password.len() && differ == 0
+    }
+}
+
+struct Will {
+    topic: String,
+    payload: Vec<u8>,
+    qos: QoS,
+    retain: bool,
+}
+
+struct Session {
+    conn: ConnId,
+    rx: Vec<u8>,
+    /// Set once CONNECT has been accepted
+    client_id: Option<String>,
+    keep_alive_secs: u16,
+    subscriptions: Vec<(TopicFilter, QoS)>,
+    will: Option<Will>,
+    next_packet_id: u16,
+    /// Incoming QoS 2 ids awaiting PUBREL, so a resent PUBLISH isn't forwarded twice
+    awaiting_pubrel: Vec<u16>,
+}
+
+impl Session {
+    fn packet_id(&mut self) -> u16 {
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        self.next_packet_id
+    }
+}
+
+struct Retained {
+    topic: String,
+    payload: Vec<u8>,
+    qos: QoS,
+}
+
+#[derive(Default)]
+pub(crate) struct Broker {
+    sessions: Vec<Session>,
+    retained: Vec<Retained>,
+    next_conn: ConnId,
+    credentials: Option<Credentials>,
+}
+
+impl Broker {
+    pub(crate) fn new() -> Self { Self::default() }
+
+    /// Require `credentials` from clients connecting from now on
+    pub(crate) fn set_credentials(&mut self, credentials: Option<Credentials>) {
+        self.credentials = credentials;
+    }
+
+    /// Admit a new connection, if there is room for it
+    pub(crate) fn open(&mut self) -> Option<ConnId> {
+        if self.sessions.len() >= MAX_CLIENTS {
+            return None;
+        }
+        self.next_conn = self.next_conn.wrapping_add(1);
+        let conn = self.next_conn;
+        self.sessions.push(Session {
+            conn,
+            rx: Vec::new(),
+            client_id: None,
+            keep_alive_secs: 0,
+            subscriptions: Vec::new(),
+            will: None,
+            next_packet_id: 0,
+            awaiting_pubrel: Vec::new(),
+        });
+        Some(conn)
+    }
+
+    /// Clients that have completed CONNECT
+    pub(crate) fn connected(&self) -> usize { self.sessions.iter().filter(|s| s.client_id.is_some()).count() }
+
+    /// Keep-alive interval `conn` asked for in CONNECT, 0 meaning none; `None` before CONNECT
+    pub(crate) fn keep_alive_secs(&self, conn: ConnId) -> Option<u16> {
+        self.session(conn).filter(|s| s.client_id.is_some()).map(|s| s.keep_alive_secs)
+    }
+
+    /// The connection has gone; publish its will unless it said DISCONNECT
+    pub(crate) fn closed(&mut self, conn: ConnId, out: &mut Output) {
+        let Some(index) = self.sessions.iter().position(|s| s.conn == conn) else { return };
+        let session = self.sessions.remove(index);
+        if let Some(will) = session.will {
+            let client_id = session.client_id.as_deref().unwrap_or("?");
+            log::info!("{} gone, publishing its will on {}", client_id, will.topic);
+            self.publish(&will.topic, &will.payload, will.qos, will.retain, out);
+        }
+    }
+
+    /// Handle bytes read from `conn`
+    pub(crate) fn receive(&mut self, conn: ConnId, data: &[u8], out: &mut Output) {
+        let Some(session) = self.session_mut(conn) else { return };
+        session.rx.extend_from_slice(data);
+        loop {
+            let Some(session) = self.session_mut(conn) else { return };
+            let len = match packet::peek_packet_len(&session.rx) {
+                Ok(len) if len > MAX_PACKET_SIZE => {
+                    log::warn!("Closing connection {}: {} byte packet", conn, len);
+                    return self.abort(conn, out);
+                }
+                Ok(len) if len <= session.rx.len() => len,
+                Ok(_) | Err(packet::ParseError::Incomplete) => return,
+                Err(_) => return self.abort(conn, out),
+            };
+            let bytes: Vec<u8> = session.rx.drain(..len).collect();
+            if self.handle(conn, &bytes, out).is_err() {
+                log::warn!("Closing connection {}: malformed or unexpected packet", conn);
+                return self.abort(conn, out);
+            }
+            if out.close.contains(&conn) {
+                return;
+            }
+        }
+    }
+
+    /// Close `conn` over a protocol error; its will is still published
+    fn abort(&mut self, conn: ConnId, out: &mut Output) {
+        if let Some(session) = self.session_mut(conn) {
+            session.rx.clear();
+        }
+        out.close.push(conn);
+    }
+
+    fn handle(&mut self, conn: ConnId, bytes: &[u8], out: &mut Output) -> Result<(), ()> {
+        let (packet_type, header_len, _) = packet::parse_fixed_header(bytes).map_err(|_| ())?;
+        let flags = bytes[0] & 0x0F;
+        let body = &bytes[header_len..];
+        let connected = self.session(conn).is_some_and(|s| s.client_id.is_some());
+        match packet_type {
+            PacketType::Connect if !connected => self.connect(conn, body, out),
+            _ if !connected => Err(()),
+            PacketType::Publish => self.incoming_publish(conn, flags, body, out),
+            PacketType::Puback => Ok(()),
+            PacketType::Pubrel => {
+                let packet_id = read_u16(body)?;
+                let session = self.session_mut(conn).ok_or(())?;
+                session.awaiting_pubrel.retain(|&id| id != packet_id);
+                out.packets.push((conn, packet::build_pubcomp(packet_id)));
+                Ok(())
+            }
+            PacketType::Subscribe => self.subscribe(conn, body, out),
+            PacketType::Unsubscribe => self.unsubscribe(conn, body, out),
+            PacketType::Pingreq => {
+                out.packets.push((conn, vec![(PacketType::Pingresp as u8) << 4, 0]));
+                Ok(())
+            }
+            PacketType::Disconnect => {
+                if let Some(session) = self.session_mut(conn) {
+                    session.will = None;
+                }
+                out.close.push(conn);
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn connect(&mut self, conn: ConnId, body: &[u8], out: &mut Output) -> Result<(), ()> {
+        let mut reader = Reader(body);
+        let protocol = reader.string()?;
+        let level = reader.byte()?;
+        if !matches!((protocol, level), ("MQTT", PROTOCOL_LEVEL_311) | ("MQIsdp", PROTOCOL_LEVEL_31)) {
+            out.packets.push((conn, connack(CONNACK_BAD_PROTOCOL)));
+            out.close.push(conn);
+            return Ok(());
+        }
+        let flags = reader.byte()?;
+        let keep_alive_secs = reader.u16()?;
+        let clean_session = flags & 0x02 != 0;
+        let mut client_id = String::from(reader.string()?);
+        let will = if flags & 0x04 != 0 {
+            let topic = String::from(reader.string()?);
+            let payload = reader.bytes()?.to_vec();
+            packet::validate_topic_name(&topic).map_err(|_| ())?;
+            let qos = QoS::from_byte((flags >> 3) & 0x03).ok_or(())?;
+            Some(Will { topic, payload, qos, retain: flags & 0x20 != 0 })
+        } else {
+            None
+        };
+        let username = if flags & 0x80 != 0 { Some(reader.string()?) } else { None };
+        let password = if flags & 0x40 != 0 { Some(reader.bytes()?) } else { None };
+        if let Some(credentials) = self.credentials.as_ref() {
+            let code = match username {
+                None => Some(CONNACK_NOT_AUTHORIZED),
+                Some(username) if !credentials.accept(username, password.unwrap_or_default()) => {
+                    Some(CONNACK_BAD_CREDENTIALS)
+                }
+                Some(_) => None,
+            };
+            if let Some(code) = code {
+                log::warn!("Refusing connection {}: bad username or password", conn);
+                out.packets.push((conn, connack(code)));
+                out.close.push(conn);
+                return Ok(());
+            }
+        }
+
+        if client_id.is_empty() {
+            if !clean_session {
+                out.packets.push((conn, connack(CONNACK_BAD_CLIENT_ID)));
+                out.close.push(conn);
+                return Ok(());
+            }
+            client_id = format!("auto-{}", conn);
+        }
+        // A client connecting again takes over from its old connection
+        for session in self.sessions.iter_mut() {
+            if session.conn != conn && session.client_id.as_deref() == Some(client_id.as_str()) {
+                session.client_id = None;
+                session.subscriptions.clear();
+                session.will = None;
+                out.close.push(session.conn);
+            }
+        }
+        let session = self.session_mut(conn).ok_or(())?;
+        log::info!("{} connected on {}", client_id, conn);
+        session.client_id = Some(client_id);
+        session.keep_alive_secs = keep_alive_secs;
+        session.will = will;
+        out.packets.push((conn, connack(CONNACK_ACCEPTED)));
+        Ok(())
+    }
+
+    fn incoming_publish(&mut self, conn: ConnId, flags: u8, body: &[u8], out: &mut Output) -> Result<(), ()> {
+        let mut reader = Reader(body);
+        let topic = reader.string()?;
+        packet::validate_topic_name(topic).map_err(|_| ())?;
+        let qos = QoS::from_byte((flags >> 1) & 0x03).ok_or(())?;
+        let retain = flags & 0x01 != 0;
+        match qos {
+            QoS::AtMostOnce => {}
+            QoS::AtLeastOnce => out.packets.push((conn, packet::build_puback(reader.u16()?))),
+            QoS::ExactlyOnce => {
+                let packet_id = reader.u16()?;
+                out.packets.push((conn, packet::build_pubrec(packet_id)));
+                let session = self.session_mut(conn).ok_or(())?;
+                if session.awaiting_pubrel.contains(&packet_id) {
+                    return Ok(());
+                }
+                session.awaiting_pubrel.push(packet_id);
+            }
+        }
+        let payload = reader.0;
+        self.publish(topic, payload, qos, retain, out);
+        Ok(())
+    }
+
+    /// Deliver a message to every matching subscriber, and retain it if asked
+    fn publish(&mut self, topic: &str, payload: &[u8], qos: QoS, retain: bool, out: &mut Output) {
+        let qos = cap(qos);
+        if retain {
+            let existing = self.retained.iter().position(|r| r.topic == topic);
+            let entry = Retained { topic: String::from(topic), payload: payload.to_vec(), qos };
+            match (existing, payload.is_empty()) {
+                // An empty retained message clears the topic
+                (Some(index), true) => {
+                    self.retained.remove(index);
+                }
+                (Some(index), false) => self.retained[index] = entry,
+                (None, true) => {}
+                (None, false) if self.retained.len() >= MAX_RETAINED => {
+                    log::warn!("Not retaining {}: {} topics already retained", topic, MAX_RETAINED);
+                }
+                (None, false) => self.retained.push(entry),
+            }
+        }
+        for session in self.sessions.iter_mut().filter(|s| s.client_id.is_some()) {
+            // Overlapping subscriptions get one copy, at the highest QoS among them
+            let granted = session
+                .subscriptions
+                .iter()
+                .filter(|(filter, _)| filter.matches(topic))
+                .map(|&(_, granted)| granted)
+                .max_by_key(|&granted| granted as u8);
+            if let Some(granted) = granted {
+                out.packets.push((session.conn, forward(session, topic, payload, min(qos, granted), false)));
+            }
+        }
+    }
+
+    fn subscribe(&mut self, conn: ConnId, body: &[u8], out: &mut Output) -> Result<(), ()> {
+        let mut reader = Reader(body);
+        let packet_id = reader.u16()?;
+        let mut requests = Vec::new();
+        while !reader.0.is_empty() {
+            let filter = reader.string()?;
+            let qos = QoS::from_byte(reader.byte()? & 0x03).ok_or(())?;
+            requests.push((filter, qos));
+        }
+        if requests.is_empty() {
+            return Err(());
+        }
+        let session = self.sessions.iter_mut().find(|s| s.conn == conn).ok_or(())?;
+        let mut suback = vec![(PacketType::Suback as u8) << 4];
+        encode_remaining_length(&mut suback, 2 + requests.len());
+        suback.extend_from_slice(&packet_id.to_be_bytes());
+        let mut granted_filters = Vec::new();
+        for (filter, qos) in requests {
+            match TopicFilter::parse(filter) {
+                Ok(filter) => {
+                    let granted = cap(qos);
+                    session.subscriptions.retain(|(f, _)| *f != filter);
+                    session.subscriptions.push((filter.clone(), granted));
+                    suback.push(granted as u8);
+                    granted_filters.push((filter, granted));
+                }
+                Err(e) => {
+                    log::warn!("Refusing subscription to {}: {:?}", filter, e);
+                    suback.push(SUBACK_FAILURE);
+                }
+            }
+        }
+        out.packets.push((conn, suback));
+        // Retained messages follow the SUBACK, with RETAIN set
+        for retained in self.retained.iter() {
+            let granted = granted_filters
+                .iter()
+                .filter(|(filter, _)| filter.matches(&retained.topic))
+                .map(|&(_, granted)| granted)
+                .max_by_key(|&granted| granted as u8);
+            if let Some(granted) = granted {
+                let qos = min(retained.qos, granted);
+                out.packets.push((conn, forward(session, &retained.topic, &retained.payload, qos, true)));
+            }
+        }
+        Ok(())
+    }
+
+    fn unsubscribe(&mut self, conn: ConnId, body: &[u8], out: &mut Output) -> Result<(), ()> {
+        let mut reader = Reader(body);
+        let packet_id = reader.u16()?;
+        let session = self.session_mut(conn).ok_or(())?;
+        while !reader.0.is_empty() {
+            let filter = reader.string()?;
+            session.subscriptions.retain(|(f, _)| f.as_str() != filter);
+        }
+        let mut unsuback = vec![(PacketType::Unsuback as u8) << 4, 2];
+        unsuback.extend_from_slice(&packet_id.to_be_bytes());
+        out.packets.push((conn, unsuback));
+        Ok(())
+    }
+
+    fn session(&self, conn: ConnId) -> Option<&Session> { self.sessions.iter().find(|s| s.conn == conn) }
+
+    fn session_mut(&mut self, conn: ConnId) -> Option<&mut Session> {
+        self.sessions.iter_mut().find(|s| s.conn == conn)
+    }
+}
+
+/// PUBLISH to a subscriber; QoS 1 ones aren't tracked, since the session ends with the connection
+fn forward(session: &mut Session, topic: &str, payload: &[u8], qos: QoS, retain: bool) -> Vec<u8> {
+    let packet_id = if qos == QoS::AtMostOnce { None } else { Some(session.packet_id()) };
+    packet::build_publish_with_id(topic, payload, qos, packet_id, retain)
+}
+
+/// The highest QoS the broker delivers at
+fn cap(qos: QoS) -> QoS { min(qos, QoS::AtLeastOnce) }
+
+fn min(a: QoS, b: QoS) -> QoS { if (a as u8) <= (b as u8) { a } else { b } }
+
+fn connack(code: u8) -> Vec<u8> { vec![(PacketType::Connack as u8) << 4, 2, 0, code] }
+
+fn encode_remaining_length(out: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+fn read_u16(data: &[u8]) -> Result<u16, ()> { Reader(data).u16() }
+
+/// Reads the fields of a packet body in order
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ()> {
+        if self.0.len() < n {
+            return Err(());
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn byte(&mut self) -> Result<u8, ()> { Ok(self.take(1)?[0]) }
+
+    fn u16(&mut self) -> Result<u16, ()> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], ()> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<&'a str, ()> { core::str::from_utf8(self.bytes()?).map_err(|_| ()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Open a connection and complete CONNECT as `client_id`
+    fn connect(broker: &mut Broker, client_id: &str) -> ConnId {
+        let conn = broker.open().unwrap();
+        let mut out = Output::default();
+        broker.receive(conn, &packet::build_connect(client_id), &mut out);
+        assert_eq!(out.packets, vec![(conn, connack(CONNACK_ACCEPTED))]);
+        conn
+    }
+
+    fn receive(broker: &mut Broker, conn: ConnId, data: &[u8]) -> Output {
+        let mut out = Output::default();
+        broker.receive(conn, data, &mut out);
+        out
+    }
+
+    #[test]
+    fn test_connect() {
+        let mut broker = Broker::new();
+        let conn = broker.open().unwrap();
+        // Split across reads, as TCP may deliver it
+        let connect = packet::build_connect("device");
+        assert!(receive(&mut broker, conn, &connect[..4]).packets.is_empty());
+        assert_eq!(broker.keep_alive_secs(conn), None);
+        let out = receive(&mut broker, conn, &connect[4..]);
+        assert_eq!(out.packets, vec![(conn, connack(CONNACK_ACCEPTED))]);
+        assert_eq!(broker.connected(), 1);
+
+        // Nothing but CONNECT is accepted first
+        let early = broker.open().unwrap();
+        let out = receive(&mut broker, early, &packet::build_pingreq());
+        assert_eq!(out.close, vec![early]);
+
+        // Connecting again with the same id takes over
+        let again = broker.open().unwrap();
+        let out = receive(&mut broker, again, &packet::build_connect("device"));
+        assert_eq!(out.packets, vec![(again, connack(CONNACK_ACCEPTED))]);
+        assert_eq!(out.close, vec![conn]);
+    }
+
+    #[test]
+    fn test_credentials() {
+        let mut broker = Broker::new();
+        broker.set_credentials(Some(Credentials { username: "host".into(), password: b"secret".to_vec() }));
+        let connect =
+            |username, password| packet::build_connect_with_options("c", username, password, true, 60);
+
+        for (username, password, code) in [
+            (None, None, CONNACK_NOT_AUTHORIZED),
+            (Some("host"), None, CONNACK_BAD_CREDENTIALS),
+            (Some("host"), Some(&b"secreT"[..]), CONNACK_BAD_CREDENTIALS),
+            (Some("other"), Some(&b"secret"[..]), CONNACK_BAD_CREDENTIALS),
+        ] {
+            let conn = broker.open().unwrap();
+            let out = receive(&mut broker, conn, &connect(username, password));
+            assert_eq!(out.packets, vec![(conn, connack(code))]);
+            assert_eq!(out.close, vec![conn]);
+            broker.closed(conn, &mut Output::default());
+        }
+        assert_eq!(broker.connected(), 0);
+
+        let conn = broker.open().unwrap();
+        let out = receive(&mut broker, conn, &connect(Some("host"), Some(b"secret")));
+        assert_eq!(out.packets, vec![(conn, connack(CONNACK_ACCEPTED))]);
+    }
+
+    #[test]
+    fn test_wildcard_subscribe_routing() {
+        let mut broker = Broker::new();
+        let ccr = connect(&mut broker, "ccr");
+        let status = connect(&mut broker, "status");
+        let publisher = connect(&mut broker, "publisher");
+        receive(&mut broker, ccr, &packet::build_subscribe(1, "ccr/+/events", QoS::AtMostOnce));
+        let out = receive(
+            &mut broker,
+            status,
+            &packet::build_subscribe_many(2, &[("ccr/#", QoS::AtMostOnce), ("bad/#/x", QoS::AtMostOnce)]),
+        );
+        // The invalid filter is refused on its own
+        assert_eq!(out.packets, vec![(status, vec![0x90, 4, 0, 2, 0, SUBACK_FAILURE])]);
+
+        let out =
+            receive(&mut broker, publisher, &packet::build_publish("ccr/s1/events", b"hi", QoS::AtMostOnce));
+        let message = packet::build_publish("ccr/s1/events", b"hi", QoS::AtMostOnce);
+        assert_eq!(out.packets, vec![(ccr, message.clone()), (status, message)]);
+
+        let out =
+            receive(&mut broker, publisher, &packet::build_publish("ccr/s1/input", b"x", QoS::AtMostOnce));
+        assert_eq!(out.packets, vec![(status, packet::build_publish("ccr/s1/input", b"x", QoS::AtMostOnce))]);
+
+        let out = receive(&mut broker, publisher, &packet::build_publish("other", b"x", QoS::AtMostOnce));
+        assert!(out.packets.is_empty());
+    }
+
+    #[test]
+    fn test_retained_messages() {
+        let mut broker = Broker::new();
+        let publisher = connect(&mut broker, "publisher");
+        receive(
+            &mut broker,
+            publisher,
+            &packet::build_publish_with_id("ccr/device/status", b"online", QoS::AtMostOnce, None, true),
+        );
+
+        let late = connect(&mut broker, "late");
+        let out = receive(&mut broker, late, &packet::build_subscribe(1, "ccr/device/+", QoS::AtLeastOnce));
+        assert_eq!(
+            out.packets,
+            vec![
+                (late, vec![0x90, 3, 0, 1, QoS::AtLeastOnce as u8]),
+                (
+                    late,
+                    packet::build_publish_with_id(
+                        "ccr/device/status",
+                        b"online",
+                        QoS::AtMostOnce,
+                        None,
+                        true
+                    )
+                ),
+            ]
+        );
+
+        // An empty retained message clears the topic
+        receive(
+            &mut broker,
+            publisher,
+            &packet::build_publish_with_id("ccr/device/status", b"", QoS::AtMostOnce, None, true),
+        );
+        let later = connect(&mut broker, "later");
+        let out = receive(&mut broker, later, &packet::build_subscribe(1, "ccr/#", QoS::AtMostOnce));
+        assert_eq!(out.packets, vec![(later, vec![0x90, 3, 0, 1, QoS::AtMostOnce as u8])]);
+    }
+
+    #[test]
+    fn test_qos1_acks() {
+        let mut broker = Broker::new();
+        let subscriber = connect(&mut broker, "subscriber");
+        let publisher = connect(&mut broker, "publisher");
+        // QoS 2 is granted as QoS 1
+        let out = receive(&mut broker, subscriber, &packet::build_subscribe(5, "a/b", QoS::ExactlyOnce));
+        assert_eq!(out.packets, vec![(subscriber, vec![0x90, 3, 0, 5, QoS::AtLeastOnce as u8])]);
+
+        let out = receive(
+            &mut broker,
+            publisher,
+            &packet::build_publish_with_id("a/b", b"1", QoS::AtLeastOnce, Some(9), false),
+        );
+        assert_eq!(
+            out.packets,
+            vec![
+                (publisher, packet::build_puback(9)),
+                (subscriber, packet::build_publish_with_id("a/b", b"1", QoS::AtLeastOnce, Some(1), false)),
+            ]
+        );
+        // The subscriber's PUBACK needs no answer
+        assert!(receive(&mut broker, subscriber, &packet::build_puback(1)).packets.is_empty());
+
+        // A QoS 2 PUBLISH sent twice is forwarded once, at QoS 1
+        let twice = packet::build_publish_with_id("a/b", b"2", QoS::ExactlyOnce, Some(4), false);
+        let mut out = receive(&mut broker, publisher, &twice);
+        broker.receive(publisher, &twice, &mut out);
+        assert_eq!(
+            out.packets,
+            vec![
+                (publisher, packet::build_pubrec(4)),
+                (subscriber, packet::build_publish_with_id("a/b", b"2", QoS::AtLeastOnce, Some(2), false)),
+                (publisher, packet::build_pubrec(4)),
+            ]
+        );
+        let out = receive(&mut broker, publisher, &packet::build_pubrel(4));
+        assert_eq!(out.packets, vec![(publisher, packet::build_pubcomp(4))]);
+    }
+
+    #[test]
+    fn test_unsubscribe() {
+        let mut broker = Broker::new();
+        let subscriber = connect(&mut broker, "subscriber");
+        let publisher = connect(&mut broker, "publisher");
+        receive(
+            &mut broker,
+            subscriber,
+            &packet::build_subscribe_many(1, &[("a/+", QoS::AtMostOnce), ("a/#", QoS::AtMostOnce)]),
+        );
+        let out = receive(&mut broker, subscriber, &packet::build_unsubscribe(7, "a/+"));
+        assert_eq!(out.packets, vec![(subscriber, vec![0xB0, 2, 0, 7])]);
+
+        // The other filter still matches
+        let out = receive(&mut broker, publisher, &packet::build_publish("a/b", b"x", QoS::AtMostOnce));
+        assert_eq!(out.packets.len(), 1);
+
+        receive(&mut broker, subscriber, &packet::build_unsubscribe(8, "a/#"));
+        let out = receive(&mut broker, publisher, &packet::build_publish("a/b", b"x", QoS::AtMostOnce));
+        assert!(out.packets.is_empty());
+    }
+}
@@ -0,0 +1,210 @@
+#![cfg_attr(target_os = "none", no_std)]
+#![cfg_attr(target_os = "none", no_main)]
+
+mod api;
+mod broker;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use api::*;
+use broker::{Broker, ConnId, Credentials, Output};
+use num_traits::*;
+
+/// How long a new connection has to send CONNECT
+const CONNECT_TIMEOUT_MS: u64 = 10_000;
+/// Writes to a subscriber that stops reading give up after this long
+const WRITE_TIMEOUT_MS: u64 = 5_000;
+/// Packets queued for a connection before it is dropped as too slow
+const WRITE_QUEUE_LEN: usize = 64;
+
+struct Shared {
+    broker: Broker,
+    /// Outgoing packet queues of the open connections
+    writers: Vec<(ConnId, SyncSender<Vec<u8>>)>,
+}
+
+impl Shared {
+    /// Carry out what the broker asked for. Packets are only queued here, so a
+    /// slow subscriber doesn't hold up everyone waiting on the lock.
+    fn flush(&mut self, out: Output) {
+        for (conn, packet) in out.packets {
+            let Some(index) = self.writers.iter().position(|(c, _)| *c == conn) else { continue };
+            match self.writers[index].1.try_send(packet) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("Dropping connection {}: not reading", conn);
+                    self.writers.remove(index);
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    self.writers.remove(index);
+                }
+            }
+        }
+        // Dropping the queue makes the writer thread send what's left and shut the
+        // connection down; the connection's own thread then reports it closed
+        for conn in out.close {
+            self.writers.retain(|(c, _)| *c != conn);
+        }
+    }
+}
+
+/// Writer thread: sends queued packets to `stream` until the queue is dropped
+fn write(conn: ConnId, mut stream: TcpStream, queue: Receiver<Vec<u8>>) {
+    for packet in queue {
+        if let Err(e) = stream.write_all(&packet) {
+            log::warn!("Write to connection {} failed: {:?}", conn, e);
+            break;
+        }
+    }
+    stream.shutdown(Shutdown::Both).ok();
+}
+
+/// Address to listen on and the credentials to require, from the user preferences
+///
+/// Listening beyond this device needs a username and password; without them
+/// the broker stays on loopback.
+fn listen_config() -> (Ipv4Addr, Option<Credentials>) {
+    pddb::Pddb::new().is_mounted_blocking();
+    let prefs = userprefs::Manager::new();
+    let username = prefs.mqtt_broker_username_or_default().unwrap_or_default();
+    let password = prefs.mqtt_broker_password_or_default().unwrap_or_default();
+    let credentials =
+        (!username.is_empty()).then(|| Credentials { username, password: password.into_bytes() });
+    let bind = prefs.mqtt_broker_bind_or_default().unwrap_or_default();
+    let address = match bind.trim() {
+        "" => DEFAULT_BIND_ADDRESS,
+        bind => match bind.parse::<Ipv4Addr>() {
+            Ok(address) if address.is_loopback() || credentials.is_some() => address,
+            Ok(_) => {
+                log::error!("Not listening on {} without a username and password", bind);
+                DEFAULT_BIND_ADDRESS
+            }
+            Err(_) => {
+                log::error!("Bad bind address {:?}", bind);
+                DEFAULT_BIND_ADDRESS
+            }
+        },
+    };
+    (address, credentials)
+}
+
+fn listen(shared: Arc<Mutex<Shared>>) {
+    let (address, credentials) = listen_config();
+    shared.lock().unwrap().broker.set_credentials(credentials);
+    let listener = match TcpListener::bind((address, BROKER_PORT)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Can't listen on {}:{}: {:?}", address, BROKER_PORT, e);
+            return;
+        }
+    };
+    log::info!("Listening on {}:{}", address, BROKER_PORT);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("Accept failed: {:?}", e);
+                continue;
+            }
+        };
+        let writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(e) => {
+                log::warn!("Couldn't clone connection: {:?}", e);
+                continue;
+            }
+        };
+        writer.set_write_timeout(Some(Duration::from_millis(WRITE_TIMEOUT_MS))).ok();
+        let (queue, packets) = std::sync::mpsc::sync_channel(WRITE_QUEUE_LEN);
+        let conn = {
+            let mut shared = shared.lock().unwrap();
+            let Some(conn) = shared.broker.open() else {
+                log::warn!("Refusing connection: {} clients already", broker::MAX_CLIENTS);
+                continue;
+            };
+            shared.writers.push((conn, queue));
+            conn
+        };
+        std::thread::spawn(move || write(conn, writer, packets));
+        let shared = shared.clone();
+        std::thread::spawn(move || serve(shared, conn, stream));
+    }
+}
+
+/// Connection thread: reads from `stream` until it closes
+fn serve(shared: Arc<Mutex<Shared>>, conn: ConnId, mut stream: TcpStream) {
+    stream.set_nodelay(true).ok();
+    stream.set_read_timeout(Some(Duration::from_millis(CONNECT_TIMEOUT_MS))).ok();
+    let mut buf = [0u8; 1024];
+    let mut keep_alive_secs = None;
+    loop {
+        let n = match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                log::info!("Connection {} ended: {:?}", conn, e);
+                break;
+            }
+        };
+        let mut shared = shared.lock().unwrap();
+        let mut out = Output::default();
+        shared.broker.receive(conn, &buf[..n], &mut out);
+        // A silent client is dropped after one and a half keep-alive intervals
+        let asked = shared.broker.keep_alive_secs(conn);
+        if asked != keep_alive_secs {
+            keep_alive_secs = asked;
+            let timeout = match asked {
+                Some(secs) if secs > 0 => Some(Duration::from_millis(u64::from(secs) * 1500)),
+                _ => None,
+            };
+            stream.set_read_timeout(timeout).ok();
+        }
+        shared.flush(out);
+    }
+    let mut shared = shared.lock().unwrap();
+    let mut out = Output::default();
+    shared.broker.closed(conn, &mut out);
+    shared.writers.retain(|(c, _)| *c != conn);
+    shared.flush(out);
+}
+
+fn main() -> ! {
+    log_server::init_wait().unwrap();
+    log::set_max_level(log::LevelFilter::Info);
+    log::info!("my PID is {}", xous::process::id());
+
+    let xns = xous_names::XousNames::new().unwrap();
+    let broker_sid = xns.register_name(SERVER_NAME_MQTT_BROKER, None).expect("can't register server");
+
+    let shared = Arc::new(Mutex::new(Shared { broker: Broker::new(), writers: Vec::new() }));
+    std::thread::spawn({
+        let shared = shared.clone();
+        move || listen(shared)
+    });
+
+    log::trace!("ready to accept requests");
+    loop {
+        let msg = xous::receive_message(broker_sid).unwrap();
+        match FromPrimitive::from_usize(msg.body.id()) {
+            Some(Opcode::ClientCount) => xous::msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                let count = shared.lock().unwrap().broker.connected();
+                xous::return_scalar(msg.sender, count).ok();
+            }),
+            Some(Opcode::Quit) => {
+                log::warn!("Quit received, goodbye world!");
+                break;
+            }
+            None => {
+                log::error!("couldn't convert opcode: {:?}", msg);
+            }
+        }
+    }
+    shared.lock().unwrap().writers.clear();
+    xns.unregister_server(broker_sid).unwrap();
+    xous::destroy_server(broker_sid).unwrap();
+    log::trace!("quitting");
+    xous::terminate_process(0)
+}
@@ -0,0 +1,119 @@
+use rkyv::{Archive, Deserialize, Serialize};
+
+pub(crate) const SERVER_NAME_MQTT: &str = "_Shared MQTT client_";
+
+/// Upper bound on a payload, published or received
+pub const MAX_PAYLOAD_LEN: usize = 4096;
+/// Received messages on longer topics are dropped
+pub(crate) const MAX_TOPIC_LEN: usize = 256;
+/// Room for the largest `PollRequest`
+pub(crate) const POLL_BUFFER_LEN: usize = 8192;
+/// Messages held for a process that hasn't polled; the oldest go first
+pub(crate) const QUEUE_DEPTH: usize = 32;
+/// How often the MQTT client is serviced
+pub(crate) const PUMP_INTERVAL_MS: u64 = 50;
+
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug)]
+pub(crate) enum Opcode {
+    /// Join the shared session, connecting it first if needed. Blocking memory message containing a
+    /// `ConnectRequest`, returned with `error` set on failure.
+    Connect = 0,
+    /// Add a topic filter for the calling process. Blocking memory message containing a
+    /// `SubscribeRequest`.
+    Subscribe = 1,
+    /// Remove a topic filter of the calling process. Blocking memory message containing a
+    /// `SubscribeRequest`.
+    Unsubscribe = 2,
+    /// Publish through the shared session. Blocking memory message containing a `PublishRequest`.
+    Publish = 3,
+    /// Take the oldest message queued for the calling process. Blocking memory message containing a
+    /// `PollRequest`.
+    Poll = 4,
+    /// Whether the session is up. Blocking scalar, returns 1 if connected.
+    IsConnected = 5,
+    /// Drop the calling process's filters and queue; the last one out closes the session. Scalar.
+    Detach = 6,
+    /// Service the MQTT client. Scalar, sent by the server's own pump thread.
+    Pump = 7,
+    /// Exits the server
+    Quit = 8,
+}
+
+/// Why the service didn't do what was asked
+#[derive(Debug, Archive, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum MqttServiceError {
+    /// The session is connected to another broker, named here
+    OtherBroker(String),
+    /// No process has connected the session yet
+    NotConnected,
+    /// The topic or filter isn't valid MQTT
+    InvalidTopic,
+    /// The payload is over [`MAX_PAYLOAD_LEN`]
+    TooLarge,
+    /// TLS was asked for, but the service was built without the `tls` feature
+    TlsUnavailable,
+    /// The MQTT client refused the request; its error, formatted
+    Client(String),
+    /// Talking to the service failed
+    Ipc,
+}
+
+/// How to reach the broker
+#[derive(Debug, Archive, Serialize, Deserialize, Clone, Default)]
+pub struct ConnectOptions {
+    /// Broker address (host:port)
+    pub broker: String,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<Vec<u8>>,
+    /// 0 leaves the client's default
+    pub keep_alive_secs: u16,
+    /// Connect over TLS, checking the broker's certificate against the system trust anchors
+    pub tls: bool,
+}
+
+impl ConnectOptions {
+    pub fn new(broker: &str, client_id: &str) -> Self {
+        Self { broker: String::from(broker), client_id: String::from(client_id), ..Default::default() }
+    }
+}
+
+/// A message received on one of the calling process's filters
+#[derive(Debug, Archive, Serialize, Deserialize, Clone)]
+pub struct MqttMessage {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    /// The broker's stored value for the topic rather than a new message
+    pub retain: bool,
+}
+
+#[derive(Debug, Archive, Serialize, Deserialize, Clone)]
+pub(crate) struct ConnectRequest {
+    pub options: ConnectOptions,
+    pub error: Option<MqttServiceError>,
+}
+
+#[derive(Debug, Archive, Serialize, Deserialize, Clone)]
+pub(crate) struct SubscribeRequest {
+    /// Topic filter; `+` and `#` wildcards as in MQTT
+    pub filter: String,
+    /// 0, 1 or 2; ignored when unsubscribing
+    pub qos: u8,
+    pub error: Option<MqttServiceError>,
+}
+
+#[derive(Debug, Archive, Serialize, Deserialize, Clone)]
+pub(crate) struct PublishRequest {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+    pub error: Option<MqttServiceError>,
+}
+
+#[derive(Debug, Archive, Serialize, Deserialize, Clone, Default)]
+pub(crate) struct PollRequest {
+    pub message: Option<MqttMessage>,
+    /// Messages dropped from the caller's queue since its last poll
+    pub dropped: u32,
+}
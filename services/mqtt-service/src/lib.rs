@@ -0,0 +1,129 @@
+#![cfg_attr(target_os = "none", no_std)]
+
+//! Shared MQTT client session.
+//!
+//! One service owns the device's connection to the broker, so CCR, telemetry and apps share a
+//! single TCP or TLS session instead of each opening their own. Processes publish through it and
+//! subscribe to topic filters; messages matching a process's filters are queued for it until it
+//! polls. The first process to connect chooses the broker, later ones join that session, and the
+//! session closes when the last process detaches.
+
+pub mod api;
+use api::*;
+pub use api::{ConnectOptions, MAX_PAYLOAD_LEN, MqttMessage, MqttServiceError};
+use num_traits::ToPrimitive;
+use xous::{CID, Message, send_message};
+use xous_ipc::Buffer;
+pub use xous_mqtt::QoS;
+
+pub struct MqttService {
+    conn: CID,
+}
+impl MqttService {
+    pub fn new() -> Self {
+        let xns = xous_names::XousNames::new().expect("couldn't connect to XousNames");
+        REFCOUNT.fetch_add(1, Ordering::Relaxed);
+        let conn = xns.request_connection_blocking(SERVER_NAME_MQTT).expect("Can't connect to MQTT server");
+        MqttService { conn }
+    }
+
+    /// Join the shared session, connecting to `options.broker` if no other process has yet.
+    ///
+    /// Fails with [`MqttServiceError::OtherBroker`] if the session is already connected somewhere
+    /// else. A first attempt that fails is reported, but the service keeps retrying in the
+    /// background.
+    pub fn connect(&self, options: ConnectOptions) -> Result<(), MqttServiceError> {
+        let request = ConnectRequest { options, error: None };
+        let mut buf = Buffer::into_buf(request).or(Err(MqttServiceError::Ipc))?;
+        buf.lend_mut(self.conn, Opcode::Connect.to_u32().unwrap()).or(Err(MqttServiceError::Ipc))?;
+        let response = buf.to_original::<ConnectRequest, _>().or(Err(MqttServiceError::Ipc))?;
+        response.error.map_or(Ok(()), Err)
+    }
+
+    /// Queue messages matching `filter` for this process; see [`MqttService::poll`]
+    pub fn subscribe(&self, filter: &str, qos: QoS) -> Result<(), MqttServiceError> {
+        let request = SubscribeRequest { filter: String::from(filter), qos: qos as u8, error: None };
+        self.subscription(request, Opcode::Subscribe)
+    }
+
+    pub fn unsubscribe(&self, filter: &str) -> Result<(), MqttServiceError> {
+        let request = SubscribeRequest { filter: String::from(filter), qos: 0, error: None };
+        self.subscription(request, Opcode::Unsubscribe)
+    }
+
+    fn subscription(&self, request: SubscribeRequest, op: Opcode) -> Result<(), MqttServiceError> {
+        let mut buf = Buffer::into_buf(request).or(Err(MqttServiceError::Ipc))?;
+        buf.lend_mut(self.conn, op.to_u32().unwrap()).or(Err(MqttServiceError::Ipc))?;
+        let response = buf.to_original::<SubscribeRequest, _>().or(Err(MqttServiceError::Ipc))?;
+        response.error.map_or(Ok(()), Err)
+    }
+
+    /// Publish `payload` on `topic`. Payloads over [`MAX_PAYLOAD_LEN`] are refused.
+    pub fn publish(&self, topic: &str, payload: &[u8], qos: QoS) -> Result<(), MqttServiceError> {
+        self.send_publish(topic, payload, qos, false)
+    }
+
+    /// Publish a message the broker keeps as the topic's current value
+    pub fn publish_retained(&self, topic: &str, payload: &[u8], qos: QoS) -> Result<(), MqttServiceError> {
+        self.send_publish(topic, payload, qos, true)
+    }
+
+    fn send_publish(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+    ) -> Result<(), MqttServiceError> {
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return Err(MqttServiceError::TooLarge);
+        }
+        let request = PublishRequest {
+            topic: String::from(topic),
+            payload: payload.to_vec(),
+            qos: qos as u8,
+            retain,
+            error: None,
+        };
+        let mut buf = Buffer::into_buf(request).or(Err(MqttServiceError::Ipc))?;
+        buf.lend_mut(self.conn, Opcode::Publish.to_u32().unwrap()).or(Err(MqttServiceError::Ipc))?;
+        let response = buf.to_original::<PublishRequest, _>().or(Err(MqttServiceError::Ipc))?;
+        response.error.map_or(Ok(()), Err)
+    }
+
+    /// Take the oldest message queued for this process, if any; doesn't wait
+    pub fn poll(&self) -> Result<Option<MqttMessage>, MqttServiceError> {
+        let mut buf = Buffer::new(POLL_BUFFER_LEN);
+        buf.replace(PollRequest::default()).or(Err(MqttServiceError::Ipc))?;
+        buf.lend_mut(self.conn, Opcode::Poll.to_u32().unwrap()).or(Err(MqttServiceError::Ipc))?;
+        let response = buf.to_original::<PollRequest, _>().or(Err(MqttServiceError::Ipc))?;
+        if response.dropped > 0 {
+            log::warn!("{} MQTT messages dropped before they were polled", response.dropped);
+        }
+        Ok(response.message)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        let response = send_message(
+            self.conn,
+            Message::new_blocking_scalar(Opcode::IsConnected.to_usize().unwrap(), 0, 0, 0, 0),
+        );
+        matches!(response, Ok(xous::Result::Scalar1(1)))
+    }
+}
+
+use core::sync::atomic::{AtomicU32, Ordering};
+static REFCOUNT: AtomicU32 = AtomicU32::new(0);
+impl Drop for MqttService {
+    fn drop(&mut self) {
+        // the connection to the server side must be reference counted, so that multiple instances of this
+        // object within a single process do not end up de-allocating the CID on other threads before they
+        // go out of scope.
+        if REFCOUNT.fetch_sub(1, Ordering::Relaxed) == 1 {
+            send_message(self.conn, Message::new_scalar(Opcode::Detach.to_usize().unwrap(), 0, 0, 0, 0)).ok();
+            unsafe {
+                xous::disconnect(self.conn).unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,132 @@
+#![cfg_attr(target_os = "none", no_std)]
+#![cfg_attr(target_os = "none", no_main)]
+
+mod api;
+mod session;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use api::*;
+use num_traits::*;
+use session::{Pid, Session};
+use xous::{Message, send_message};
+use xous_ipc::Buffer;
+
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug)]
+enum PumpOp {
+    /// Send `Opcode::Pump` every `PUMP_INTERVAL_MS` until told to stop
+    Run,
+    Quit,
+}
+
+/// Process that sent `msg`
+fn caller(msg: &xous::MessageEnvelope) -> Pid { msg.sender.pid().map_or(0, |pid| pid.get()) }
+
+fn main() -> ! {
+    log_server::init_wait().unwrap();
+    log::set_max_level(log::LevelFilter::Info);
+    log::info!("my PID is {}", xous::process::id());
+
+    let xns = xous_names::XousNames::new().unwrap();
+    let mqtt_sid = xns.register_name(SERVER_NAME_MQTT, None).expect("can't register server");
+
+    // The MQTT client only makes progress when polled, so poke the main loop regularly while there is one
+    let self_cid = xous::connect(mqtt_sid).expect("couldn't create self-connection");
+    let run = Arc::new(AtomicBool::new(false));
+    let pump_sid = xous::create_server().unwrap();
+    let pump_cid = xous::connect(pump_sid).unwrap();
+    std::thread::spawn({
+        let run = run.clone();
+        move || {
+            loop {
+                let msg = xous::receive_message(pump_sid).unwrap();
+                match FromPrimitive::from_usize(msg.body.id()) {
+                    Some(PumpOp::Run) => {
+                        while run.load(Ordering::SeqCst) {
+                            std::thread::sleep(Duration::from_millis(PUMP_INTERVAL_MS));
+                            let pump = Message::new_scalar(Opcode::Pump.to_usize().unwrap(), 0, 0, 0, 0);
+                            if send_message(self_cid, pump).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    Some(PumpOp::Quit) => break,
+                    None => log::error!("couldn't convert pump opcode: {:?}", msg),
+                }
+            }
+            xous::destroy_server(pump_sid).unwrap();
+        }
+    });
+
+    let mut session = Session::default();
+
+    log::trace!("ready to accept requests");
+    loop {
+        let mut msg = xous::receive_message(mqtt_sid).unwrap();
+        let pid = caller(&msg);
+        match FromPrimitive::from_usize(msg.body.id()) {
+            Some(Opcode::Connect) => {
+                let mut buffer =
+                    unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut request = buffer.to_original::<ConnectRequest, _>().unwrap();
+                request.error = session.connect(pid, request.options.clone()).err();
+                buffer.replace(request).unwrap();
+            }
+            Some(Opcode::Subscribe) => {
+                let mut buffer =
+                    unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut request = buffer.to_original::<SubscribeRequest, _>().unwrap();
+                request.error = session.subscribe(pid, &request.filter, request.qos).err();
+                buffer.replace(request).unwrap();
+            }
+            Some(Opcode::Unsubscribe) => {
+                let mut buffer =
+                    unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut request = buffer.to_original::<SubscribeRequest, _>().unwrap();
+                request.error = session.unsubscribe(pid, &request.filter).err();
+                buffer.replace(request).unwrap();
+            }
+            Some(Opcode::Publish) => {
+                let mut buffer =
+                    unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut request = buffer.to_original::<PublishRequest, _>().unwrap();
+                request.error = session.publish(&request).err();
+                // No need to copy the payload back
+                request.payload.clear();
+                buffer.replace(request).unwrap();
+            }
+            Some(Opcode::Poll) => {
+                let mut buffer =
+                    unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                buffer.replace(session.poll(pid)).unwrap();
+            }
+            Some(Opcode::IsConnected) => xous::msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                xous::return_scalar(msg.sender, session.is_connected() as usize).ok();
+            }),
+            Some(Opcode::Detach) => session.detach(pid),
+            Some(Opcode::Pump) => session.pump(),
+            Some(Opcode::Quit) => {
+                log::warn!("Quit received, goodbye world!");
+                break;
+            }
+            None => {
+                log::error!("couldn't convert opcode: {:?}", msg);
+            }
+        }
+        // Start pumping when a client appears; the pump thread stops by itself once it's gone
+        if !session.is_open() {
+            run.store(false, Ordering::SeqCst);
+        } else if !run.swap(true, Ordering::SeqCst) {
+            send_message(pump_cid, Message::new_scalar(PumpOp::Run.to_usize().unwrap(), 0, 0, 0, 0))
+                .expect("couldn't start the pump");
+        }
+    }
+    run.store(false, Ordering::SeqCst);
+    send_message(pump_cid, Message::new_scalar(PumpOp::Quit.to_usize().unwrap(), 0, 0, 0, 0)).ok();
+    session.close();
+    xns.unregister_server(mqtt_sid).unwrap();
+    xous::destroy_server(mqtt_sid).unwrap();
+    log::trace!("quitting");
+    xous::terminate_process(0)
+}
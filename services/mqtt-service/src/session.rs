@@ -0,0 +1,386 @@
+//! The shared session and the processes using it.
+//!
+//! Each filter is subscribed at the broker once, at the highest QoS any process asked for, and
+//! unsubscribed when the last process using it lets go. Incoming messages are copied into the
+//! queue of every process with a matching filter.
+
+use std::collections::VecDeque;
+
+use xous_mqtt::{MqttClient, MqttConfig, MqttError, MqttEvent, QoS, TopicFilter};
+
+use crate::api::*;
+
+/// Process id of a caller
+pub(crate) type Pid = u8;
+
+struct Subscriber {
+    pid: Pid,
+    filter: TopicFilter,
+    qos: QoS,
+}
+
+/// Messages waiting for one process
+#[derive(Default)]
+struct Inbox {
+    messages: VecDeque<MqttMessage>,
+    /// Dropped since the process last polled
+    dropped: u32,
+}
+
+#[derive(Default)]
+pub(crate) struct Session {
+    client: Option<MqttClient>,
+    /// Processes that joined with `connect`
+    attached: Vec<Pid>,
+    subscribers: Vec<Subscriber>,
+    inboxes: Vec<(Pid, Inbox)>,
+}
+
+impl Session {
+    /// Join `pid` to the session, connecting it first if nobody has
+    pub(crate) fn connect(&mut self, pid: Pid, options: ConnectOptions) -> Result<(), MqttServiceError> {
+        if let Some(client) = self.client.as_ref() {
+            if client.config().broker != options.broker {
+                return Err(MqttServiceError::OtherBroker(client.config().broker.clone()));
+            }
+            self.attach(pid);
+            return Ok(());
+        }
+        let mut config = MqttConfig {
+            broker: options.broker,
+            client_id: options.client_id,
+            username: options.username,
+            password: options.password,
+            ..Default::default()
+        };
+        if options.keep_alive_secs != 0 {
+            config.keep_alive_secs = options.keep_alive_secs;
+        }
+        let mut client = MqttClient::new(config);
+        if options.tls {
+            client = with_tls(client)?;
+        }
+        log::info!("Connecting to {}", client.config().broker);
+        // A failed attempt schedules a reconnect
+        let result = client.connect().map_err(client_error);
+        self.client = Some(client);
+        self.attach(pid);
+        result
+    }
+
+    fn attach(&mut self, pid: Pid) {
+        if !self.attached.contains(&pid) {
+            self.attached.push(pid);
+        }
+    }
+
+    /// Drop everything `pid` holds; the session closes once nobody is using it
+    pub(crate) fn detach(&mut self, pid: Pid) {
+        self.attached.retain(|p| *p != pid);
+        self.inboxes.retain(|(p, _)| *p != pid);
+        let filters: Vec<String> = self
+            .subscribers
+            .iter()
+            .filter(|s| s.pid == pid)
+            .map(|s| String::from(s.filter.as_str()))
+            .collect();
+        for filter in filters {
+            self.unsubscribe(pid, &filter).ok();
+        }
+        if self.attached.is_empty() && self.subscribers.is_empty() {
+            if let Some(mut client) = self.client.take() {
+                log::info!("Last process detached, closing the session");
+                client.disconnect().ok();
+            }
+        }
+    }
+
+    /// Disconnect from the broker, e.g. because the service is quitting
+    pub(crate) fn close(&mut self) {
+        if let Some(mut client) = self.client.take() {
+            client.disconnect().ok();
+        }
+    }
+
+    /// Whether there is a client to pump, connected or not
+    pub(crate) fn is_open(&self) -> bool { self.client.is_some() }
+
+    pub(crate) fn is_connected(&self) -> bool {
+        self.client.as_ref().is_some_and(|client| client.is_connected())
+    }
+
+    pub(crate) fn subscribe(&mut self, pid: Pid, filter: &str, qos: u8) -> Result<(), MqttServiceError> {
+        if self.client.is_none() {
+            return Err(MqttServiceError::NotConnected);
+        }
+        let filter = TopicFilter::parse(filter).map_err(|_| MqttServiceError::InvalidTopic)?;
+        let qos = QoS::from_byte(qos.min(2)).unwrap_or_default();
+        let before = self.granted(filter.as_str());
+        // The QoS `pid` had before, if it was already subscribed
+        let previous = match self.subscribers.iter_mut().find(|s| s.pid == pid && s.filter == filter) {
+            Some(subscriber) => Some(core::mem::replace(&mut subscriber.qos, qos)),
+            None => {
+                self.subscribers.push(Subscriber { pid, filter: filter.clone(), qos });
+                None
+            }
+        };
+        let wanted = self.granted(filter.as_str());
+        if before.map(|q| q as u8) >= wanted.map(|q| q as u8) {
+            return Ok(());
+        }
+        // Subscribed on the next CONNACK otherwise
+        let result = match self.client.as_mut() {
+            Some(client) if client.is_connected() => client
+                .subscribe(filter.as_str(), wanted.unwrap_or_default())
+                .map(|_| ())
+                .map_err(client_error),
+            _ => Ok(()),
+        };
+        // The broker wasn't asked, so forget the request rather than resubscribe it on reconnect
+        if result.is_err() {
+            match previous {
+                Some(qos) => {
+                    if let Some(subscriber) =
+                        self.subscribers.iter_mut().find(|s| s.pid == pid && s.filter == filter)
+                    {
+                        subscriber.qos = qos;
+                    }
+                }
+                None => self.subscribers.retain(|s| !(s.pid == pid && s.filter == filter)),
+            }
+        }
+        result
+    }
+
+    pub(crate) fn unsubscribe(&mut self, pid: Pid, filter: &str) -> Result<(), MqttServiceError> {
+        self.subscribers.retain(|s| !(s.pid == pid && s.filter.as_str() == filter));
+        if self.granted(filter).is_some() {
+            return Ok(());
+        }
+        match self.client.as_mut() {
+            Some(client) if client.is_connected() => {
+                client.unsubscribe(filter).map(|_| ()).map_err(client_error)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Highest QoS any process wants on `filter`
+    fn granted(&self, filter: &str) -> Option<QoS> {
+        self.subscribers
+            .iter()
+            .filter(|s| s.filter.as_str() == filter)
+            .map(|s| s.qos)
+            .max_by_key(|qos| *qos as u8)
+    }
+
+    pub(crate) fn publish(&mut self, request: &PublishRequest) -> Result<(), MqttServiceError> {
+        let client = self.client.as_mut().ok_or(MqttServiceError::NotConnected)?;
+        if request.payload.len() > MAX_PAYLOAD_LEN {
+            return Err(MqttServiceError::TooLarge);
+        }
+        let qos = QoS::from_byte(request.qos.min(2)).unwrap_or_default();
+        let result = if request.retain {
+            client.publish_retained(&request.topic, &request.payload, qos)
+        } else {
+            client.publish(&request.topic, &request.payload, qos)
+        };
+        result.map(|_| ()).map_err(client_error)
+    }
+
+    /// Take the oldest message queued for `pid`
+    pub(crate) fn poll(&mut self, pid: Pid) -> PollRequest {
+        match self.inboxes.iter_mut().find(|(p, _)| *p == pid) {
+            Some((_, inbox)) => PollRequest {
+                message: inbox.messages.pop_front(),
+                dropped: core::mem::take(&mut inbox.dropped),
+            },
+            None => PollRequest::default(),
+        }
+    }
+
+    /// Service the client and sort what it received
+    pub(crate) fn pump(&mut self) {
+        let Some(client) = self.client.as_mut() else {
+            return;
+        };
+        let events: Vec<MqttEvent> = core::iter::from_fn(|| client.poll()).collect();
+        for event in events {
+            match event {
+                MqttEvent::Connected => self.resubscribe(),
+                MqttEvent::Disconnected { reason } => log::info!("Session disconnected: {:?}", reason),
+                MqttEvent::Message { topic, payload, retain, .. } => {
+                    self.deliver(MqttMessage { topic, payload, retain })
+                }
+                MqttEvent::Error(e) => log::warn!("Session error: {:?}", e),
+                _ => {}
+            }
+        }
+    }
+
+    /// Subscribe every filter in use, e.g. after the broker started a clean session
+    fn resubscribe(&mut self) {
+        let mut filters: Vec<(&str, QoS)> = Vec::new();
+        for subscriber in self.subscribers.iter() {
+            if !filters.iter().any(|(filter, _)| *filter == subscriber.filter.as_str()) {
+                let qos = self.granted(subscriber.filter.as_str()).unwrap_or_default();
+                filters.push((subscriber.filter.as_str(), qos));
+            }
+        }
+        if filters.is_empty() {
+            return;
+        }
+        if let Some(client) = self.client.as_mut() {
+            if let Err(e) = client.subscribe_many(&filters) {
+                log::warn!("Couldn't subscribe {} filters: {:?}", filters.len(), e);
+            }
+        }
+    }
+
+    fn deliver(&mut self, message: MqttMessage) {
+        if message.payload.len() > MAX_PAYLOAD_LEN || message.topic.len() > MAX_TOPIC_LEN {
+            log::warn!("Dropping oversized message on {}", message.topic);
+            return;
+        }
+        let mut pids: Vec<Pid> =
+            self.subscribers.iter().filter(|s| s.filter.matches(&message.topic)).map(|s| s.pid).collect();
+        pids.sort_unstable();
+        pids.dedup();
+        for pid in pids {
+            let inbox = match self.inboxes.iter().position(|(p, _)| *p == pid) {
+                Some(index) => &mut self.inboxes[index].1,
+                None => {
+                    self.inboxes.push((pid, Inbox::default()));
+                    &mut self.inboxes.last_mut().unwrap().1
+                }
+            };
+            if inbox.messages.len() == QUEUE_DEPTH {
+                inbox.messages.pop_front();
+                inbox.dropped += 1;
+            }
+            inbox.messages.push_back(message.clone());
+        }
+    }
+}
+
+fn client_error(e: MqttError) -> MqttServiceError {
+    match e {
+        MqttError::NotConnected => MqttServiceError::NotConnected,
+        MqttError::InvalidTopic(_) => MqttServiceError::InvalidTopic,
+        e => MqttServiceError::Client(e.to_string()),
+    }
+}
+
+#[cfg(feature = "tls")]
+fn with_tls(client: MqttClient) -> Result<MqttClient, MqttServiceError> {
+    let family = client.config().address_family;
+    let connector = xous_mqtt::TlsConnector::new(family, xous_mqtt::TlsConfig::default());
+    Ok(client.with_connector(Box::new(connector)))
+}
+
+#[cfg(not(feature = "tls"))]
+fn with_tls(_client: MqttClient) -> Result<MqttClient, MqttServiceError> {
+    Err(MqttServiceError::TlsUnavailable)
+}
+
+#[cfg(test)]
+mod tests {
+    use xous_mqtt::TopicAcl;
+    use xous_mqtt::clock::ManualClock;
+    use xous_mqtt::packet::{self, PacketType};
+    use xous_mqtt::transport::mock::{self, Shared};
+
+    use super::*;
+
+    /// A session whose client has been accepted by a mock broker
+    fn connected(acl: TopicAcl) -> (Session, Shared) {
+        let broker = Shared::default();
+        let config =
+            MqttConfig { broker: "mock:1883".into(), client_id: "test".into(), acl, ..Default::default() };
+        let mut client = MqttClient::with_clock(config, Box::new(ManualClock::new(0)))
+            .with_connector(mock::connector(&broker));
+        client.connect().unwrap();
+        broker.borrow_mut().rx.extend([0x20, 0x02, 0x00, 0x00]);
+        let mut session = Session { client: Some(client), ..Default::default() };
+        session.pump();
+        assert!(session.is_connected());
+        broker.borrow_mut().sent.clear();
+        (session, broker)
+    }
+
+    /// Packet types sent since the last call, with the last byte of each (a SUBSCRIBE's QoS)
+    fn sent(broker: &Shared) -> Vec<(u8, u8)> {
+        mock::sent(broker).iter().map(|p| (p[0] >> 4, *p.last().unwrap())).collect()
+    }
+
+    const SUBSCRIBE: u8 = PacketType::Subscribe as u8;
+    const UNSUBSCRIBE: u8 = PacketType::Unsubscribe as u8;
+
+    #[test]
+    fn test_shared_filter_subscribed_once() {
+        let (mut session, broker) = connected(TopicAcl::new());
+        session.subscribe(1, "ccr/+/events", 1).unwrap();
+        assert_eq!(sent(&broker), vec![(SUBSCRIBE, 1)]);
+        session.subscribe(2, "ccr/+/events", 0).unwrap();
+        session.subscribe(1, "ccr/+/events", 1).unwrap();
+        assert!(sent(&broker).is_empty());
+    }
+
+    #[test]
+    fn test_qos_upgrade() {
+        let (mut session, broker) = connected(TopicAcl::new());
+        session.subscribe(1, "a/b", 0).unwrap();
+        assert_eq!(sent(&broker), vec![(SUBSCRIBE, 0)]);
+        // Another process wanting more gets the filter subscribed again at the higher QoS
+        session.subscribe(2, "a/b", 2).unwrap();
+        assert_eq!(sent(&broker), vec![(SUBSCRIBE, 2)]);
+        assert_eq!(session.granted("a/b"), Some(QoS::ExactlyOnce));
+    }
+
+    #[test]
+    fn test_unsubscribe_by_last_user() {
+        let (mut session, broker) = connected(TopicAcl::new());
+        session.subscribe(1, "a/#", 0).unwrap();
+        session.subscribe(2, "a/#", 0).unwrap();
+        sent(&broker);
+        session.unsubscribe(1, "a/#").unwrap();
+        assert!(sent(&broker).is_empty());
+        session.unsubscribe(2, "a/#").unwrap();
+        assert_eq!(sent(&broker).iter().map(|(kind, _)| *kind).collect::<Vec<_>>(), vec![UNSUBSCRIBE]);
+    }
+
+    #[test]
+    fn test_delivery_to_inboxes() {
+        let (mut session, broker) = connected(TopicAcl::new());
+        session.subscribe(1, "a/+", 0).unwrap();
+        // Overlapping filters of one process still deliver one copy
+        session.subscribe(1, "a/#", 0).unwrap();
+        session.subscribe(2, "a/b", 0).unwrap();
+        session.subscribe(3, "c", 0).unwrap();
+        broker.borrow_mut().rx.extend(packet::build_publish("a/b", b"hi", QoS::AtMostOnce));
+        session.pump();
+
+        for pid in [1, 2] {
+            let poll = session.poll(pid);
+            let message = poll.message.unwrap();
+            assert_eq!((message.topic.as_str(), message.payload.as_slice()), ("a/b", &b"hi"[..]));
+            assert!(session.poll(pid).message.is_none());
+        }
+        assert!(session.poll(3).message.is_none());
+    }
+
+    #[test]
+    fn test_failed_subscribe_rolled_back() {
+        let acl = TopicAcl::new().deny_subscribe(TopicFilter::parse("secret/#").unwrap());
+        let (mut session, _broker) = connected(acl);
+        assert!(session.subscribe(1, "secret/x", 0).is_err());
+        assert!(session.subscribers.is_empty());
+
+        // A failed upgrade keeps the QoS asked for before, as if it was made while disconnected
+        let filter = TopicFilter::parse("secret/x").unwrap();
+        session.subscribers.push(Subscriber { pid: 1, filter, qos: QoS::AtMostOnce });
+        assert!(session.subscribe(1, "secret/x", 1).is_err());
+        assert_eq!(session.granted("secret/x"), Some(QoS::AtMostOnce));
+        assert_eq!(session.subscribers.len(), 1);
+    }
+}
@@ -0,0 +1,38 @@
+use rkyv::{Archive, Deserialize, Serialize};
+
+pub(crate) const SERVER_NAME_NET_POWER: &str = "_Network power coordinator_";
+
+/// Network availability as seen by clients that hold long-lived connections.
+///
+/// Delivered to subscribers as the first argument of a scalar message.
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Connectivity {
+    /// No usable link; reconnect attempts will only burn power
+    Offline = 0,
+    /// Link is up and has an address
+    Online = 1,
+    /// The device is about to suspend; close connections and don't retry until told otherwise
+    LowPower = 2,
+}
+
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug)]
+pub(crate) enum Opcode {
+    /// Register a listener. Memory message containing a `Subscription`.
+    Subscribe = 0,
+    /// Remove a listener. Memory message containing a `Subscription`.
+    Unsubscribe = 1,
+    /// Blocking scalar returning the current `Connectivity`
+    GetConnectivity = 2,
+    /// WiFi state callback from the net server
+    WifiState = 3,
+    /// Suspend/resume callback
+    SuspendResume = 4,
+    /// Exits the server
+    Quit = 5,
+}
+
+#[derive(Debug, Archive, Serialize, Deserialize, Copy, Clone)]
+pub(crate) struct Subscription {
+    pub sid: [u32; 4],
+    pub opcode: u32,
+}
@@ -0,0 +1,69 @@
+#![cfg_attr(target_os = "none", no_std)]
+
+//! Power-aware network coordinator.
+//!
+//! Tracks WiFi link state and suspend/resume, and tells registered clients when connectivity
+//! comes and goes, so that services holding long-lived connections (MQTT, for example) only
+//! attempt to reconnect when the radio can actually carry traffic.
+
+pub mod api;
+pub use api::Connectivity;
+use api::*;
+use num_traits::{FromPrimitive, ToPrimitive};
+use xous::{CID, Message, send_message};
+use xous_ipc::Buffer;
+
+pub struct NetPower {
+    conn: CID,
+}
+impl NetPower {
+    pub fn new() -> Self {
+        let xns = xous_names::XousNames::new().expect("couldn't connect to XousNames");
+        REFCOUNT.fetch_add(1, Ordering::Relaxed);
+        let conn =
+            xns.request_connection_blocking(SERVER_NAME_NET_POWER).expect("Can't connect to NetPower server");
+        NetPower { conn }
+    }
+
+    /// Register `sid` to receive scalar messages with `opcode` whenever connectivity changes.
+    /// The first argument of each message is a [`Connectivity`]; the current state is sent
+    /// immediately on registration.
+    pub fn subscribe(&self, sid: xous::SID, opcode: u32) -> Result<(), xous::Error> {
+        let sub = Subscription { sid: sid.to_array(), opcode };
+        let buf = Buffer::into_buf(sub).or(Err(xous::Error::InternalError))?;
+        buf.send(self.conn, Opcode::Subscribe.to_u32().unwrap()).map(|_| ())
+    }
+
+    pub fn unsubscribe(&self, sid: xous::SID, opcode: u32) -> Result<(), xous::Error> {
+        let sub = Subscription { sid: sid.to_array(), opcode };
+        let buf = Buffer::into_buf(sub).or(Err(xous::Error::InternalError))?;
+        buf.send(self.conn, Opcode::Unsubscribe.to_u32().unwrap()).map(|_| ())
+    }
+
+    pub fn connectivity(&self) -> Result<Connectivity, xous::Error> {
+        let response = send_message(
+            self.conn,
+            Message::new_blocking_scalar(Opcode::GetConnectivity.to_usize().unwrap(), 0, 0, 0, 0),
+        )?;
+        if let xous::Result::Scalar1(state) = response {
+            Connectivity::from_usize(state).ok_or(xous::Error::InternalError)
+        } else {
+            Err(xous::Error::InternalError)
+        }
+    }
+}
+
+use core::sync::atomic::{AtomicU32, Ordering};
+static REFCOUNT: AtomicU32 = AtomicU32::new(0);
+impl Drop for NetPower {
+    fn drop(&mut self) {
+        // the connection to the server side must be reference counted, so that multiple instances of this
+        // object within a single process do not end up de-allocating the CID on other threads before they
+        // go out of scope.
+        if REFCOUNT.fetch_sub(1, Ordering::Relaxed) == 1 {
+            unsafe {
+                xous::disconnect(self.conn).unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+#![cfg_attr(target_os = "none", no_std)]
+#![cfg_attr(target_os = "none", no_main)]
+
+mod api;
+use api::*;
+use com::{WlanStatus, WlanStatusIpc};
+use num_traits::*;
+use xous_ipc::Buffer;
+
+struct Subscriber {
+    cid: xous::CID,
+    sid: [u32; 4],
+    opcode: usize,
+}
+
+fn connectivity(link_up: bool, suspending: bool) -> Connectivity {
+    if suspending {
+        Connectivity::LowPower
+    } else if link_up {
+        Connectivity::Online
+    } else {
+        Connectivity::Offline
+    }
+}
+
+fn notify(sub: &Subscriber, state: Connectivity) {
+    match xous::try_send_message(
+        sub.cid,
+        xous::Message::new_scalar(sub.opcode, state.to_usize().unwrap(), 0, 0, 0),
+    ) {
+        Ok(_) => (),
+        Err(e) => log::warn!("Couldn't notify connectivity subscriber: {:?}", e),
+    }
+}
+
+/// Subscriptions from one server on different opcodes share the CID `xous::connect` handed out, so it's only
+/// disconnected with the last of them.
+fn release(subscribers: &[Subscriber], gone: &Subscriber) {
+    if !subscribers.iter().any(|s| s.sid == gone.sid) {
+        unsafe { xous::disconnect(gone.cid).ok() };
+    }
+}
+
+fn main() -> ! {
+    log_server::init_wait().unwrap();
+    log::set_max_level(log::LevelFilter::Info);
+    log::info!("my PID is {}", xous::process::id());
+
+    let xns = xous_names::XousNames::new().unwrap();
+    let np_sid = xns.register_name(SERVER_NAME_NET_POWER, None).expect("can't register server");
+    let self_cid = xous::connect(np_sid).unwrap();
+
+    let mut netmgr = net::NetManager::new();
+    netmgr
+        .wifi_state_subscribe(self_cid, Opcode::WifiState.to_u32().unwrap())
+        .expect("couldn't subscribe to wifi state");
+    // notify subscribers before the net server itself suspends
+    let mut susres = susres::Susres::new(
+        Some(susres::SuspendOrder::Early),
+        &xns,
+        Opcode::SuspendResume.to_u32().unwrap(),
+        self_cid,
+    )
+    .expect("couldn't create suspend/resume object");
+
+    // hosted mode has no WiFi chip: the host's network is always considered up
+    let mut link_up = cfg!(not(target_os = "xous"));
+    let mut suspending = false;
+    let mut current = connectivity(link_up, suspending);
+    let mut subscribers: Vec<Subscriber> = Vec::new();
+
+    log::trace!("ready to accept requests");
+    loop {
+        let msg = xous::receive_message(np_sid).unwrap();
+        match FromPrimitive::from_usize(msg.body.id()) {
+            Some(Opcode::Subscribe) => {
+                let buffer = unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let sub = buffer.to_original::<Subscription, _>().unwrap();
+                if subscribers.iter().any(|s| s.sid == sub.sid && s.opcode == sub.opcode as usize) {
+                    continue;
+                }
+                let cid = match xous::connect(xous::SID::from_array(sub.sid)) {
+                    Ok(cid) => cid,
+                    Err(e) => {
+                        log::warn!("Couldn't connect to connectivity subscriber: {:?}", e);
+                        continue;
+                    }
+                };
+                let subscriber = Subscriber { cid, sid: sub.sid, opcode: sub.opcode as usize };
+                notify(&subscriber, current);
+                subscribers.push(subscriber);
+            }
+            Some(Opcode::Unsubscribe) => {
+                let buffer = unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let sub = buffer.to_original::<Subscription, _>().unwrap();
+                if let Some(index) =
+                    subscribers.iter().position(|s| s.sid == sub.sid && s.opcode == sub.opcode as usize)
+                {
+                    let gone = subscribers.remove(index);
+                    release(&subscribers, &gone);
+                }
+            }
+            Some(Opcode::GetConnectivity) => xous::msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                xous::return_scalar(msg.sender, current.to_usize().unwrap()).unwrap();
+            }),
+            Some(Opcode::WifiState) => {
+                let buffer = unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let status = WlanStatus::from_ipc(buffer.to_original::<WlanStatusIpc, _>().unwrap());
+                if cfg!(target_os = "xous") {
+                    link_up = status.link_state == com_rs::LinkState::Connected
+                        && status.ipv4.dhcp == com_rs::DhcpState::Bound;
+                }
+            }
+            Some(Opcode::SuspendResume) => xous::msg_scalar_unpack!(msg, token, _, _, _, {
+                suspending = true;
+                current = connectivity(link_up, suspending);
+                for sub in subscribers.iter() {
+                    notify(sub, current);
+                }
+                susres.suspend_until_resume(token).expect("couldn't execute suspend/resume");
+                suspending = false;
+            }),
+            Some(Opcode::Quit) => {
+                log::warn!("Quit received, goodbye world!");
+                break;
+            }
+            None => {
+                log::error!("couldn't convert opcode: {:?}", msg);
+            }
+        }
+
+        let next = connectivity(link_up, suspending);
+        if next != current {
+            log::info!("connectivity {:?} -> {:?}", current, next);
+            current = next;
+            for sub in subscribers.iter() {
+                notify(sub, current);
+            }
+        }
+    }
+    while let Some(gone) = subscribers.pop() {
+        release(&subscribers, &gone);
+    }
+    xns.unregister_server(np_sid).unwrap();
+    xous::destroy_server(np_sid).unwrap();
+    log::trace!("quitting");
+    xous::terminate_process(0)
+}
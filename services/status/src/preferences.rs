@@ -30,6 +30,8 @@ enum DevicePrefsOp {
     AudioOff,
     HeadsetVolume,
     EarpieceVolume,
+    CcrChordApproval,
+    MqttStatus,
 
     // Those are reserved for internal use
     UpdateMenuAudioEnabled = 399,
@@ -60,6 +62,8 @@ impl Display for DevicePrefsOp {
             Self::AudioOff => write!(f, "{}", t!("prefs.disable_audio", locales::LANG)),
             Self::HeadsetVolume => write!(f, "{}", t!("prefs.headphone_volume", locales::LANG)),
             Self::EarpieceVolume => write!(f, "{}", t!("prefs.speaker_volume", locales::LANG)),
+            Self::CcrChordApproval => write!(f, "{}", t!("prefs.ccr_chord_approval", locales::LANG)),
+            Self::MqttStatus => write!(f, "{}", t!("prefs.mqtt_status", locales::LANG)),
 
             _ => unimplemented!("should not end up here!"),
         }
@@ -234,6 +238,8 @@ impl DevicePrefs {
         } else {
             ret.push(AudioOn)
         }
+        ret.push(CcrChordApproval);
+        ret.push(MqttStatus);
 
         ret
     }
@@ -260,6 +266,8 @@ impl DevicePrefs {
             HeadsetVolume => self.headset_volume(),
             #[cfg(not(feature = "no-codec"))]
             EarpieceVolume => self.earpiece_volume(),
+            CcrChordApproval => self.ccr_chord_approval(),
+            MqttStatus => self.mqtt_status(),
 
             _ => unimplemented!("should not end up here!"),
         };
@@ -630,6 +638,64 @@ impl DevicePrefs {
         self.up.set_earpiece_volume(slider_val)?;
         Ok(())
     }
+
+    fn ccr_chord_approval(&mut self) -> Result<(), DevicePrefsError> {
+        let cv = self.up.ccr_chord_approval_or_default()?;
+
+        self.modals.add_list(vec![t!("prefs.yes", locales::LANG), t!("prefs.no", locales::LANG)]).unwrap();
+
+        let new_result = yes_no_to_bool(
+            self.modals
+                .get_radiobutton(&format!(
+                    "{} {}",
+                    t!("prefs.current_setting", locales::LANG),
+                    bool_to_yes_no(cv)
+                ))
+                .unwrap()
+                .as_str(),
+        );
+        self.up.set_ccr_chord_approval(new_result)?;
+
+        // CCR caches its preferences; if it isn't running it reads the new value on start
+        let xns = xous_names::XousNames::new()?;
+        if let Ok(ccr) = ccr_api::Ccr::new(&xns) {
+            ccr.settings_changed()?;
+        }
+        Ok(())
+    }
+
+    fn mqtt_status(&mut self) -> Result<(), DevicePrefsError> {
+        // CCR holds the device's MQTT broker connection
+        let xns = xous_names::XousNames::new()?;
+        let ccr = match ccr_api::Ccr::new(&xns) {
+            Ok(ccr) => ccr,
+            Err(_) => {
+                self.modals.show_notification(t!("prefs.mqtt_no_ccr", locales::LANG), None).unwrap();
+                return Ok(());
+            }
+        };
+        let status = ccr.mqtt_status()?;
+
+        let mut text = format!(
+            "{} {}",
+            match status.connected {
+                true => t!("prefs.mqtt_connected", locales::LANG),
+                false => t!("prefs.mqtt_disconnected", locales::LANG),
+            },
+            status.broker
+        );
+        if let Some(last_error) = status.last_error {
+            text.push_str(&format!("\n{} {}", t!("prefs.mqtt_last_error", locales::LANG), last_error));
+        }
+
+        let reconnect = t!("prefs.mqtt_reconnect", locales::LANG);
+        self.modals.add_list(vec![reconnect, t!("mainmenu.closemenu", locales::LANG)]).unwrap();
+        if self.modals.get_radiobutton(&text).unwrap() == reconnect {
+            ccr.reconnect()?;
+        }
+
+        Ok(())
+    }
 }
 
 pub fn percentage_to_db(value: u32) -> i32 {
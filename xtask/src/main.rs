@@ -92,6 +92,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             "com",
             "net",
             "dns",
+            "net-power",
+            "event-bus",
             // UX abstractions
             "gam",
             "ime-frontend",